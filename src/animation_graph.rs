@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use serde_json;
+
+use armature;
+use armature::Pose;
+use armature_animation::{Playback, LoopMode};
+use armature_blend::{Motion, CrossFade};
+use vec;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Parameter
+{
+    Float(f64),
+    Bool(bool),
+    /// stays set until a transition uses it
+    Trigger(bool),
+}
+
+/// Condition on a named parameter of the graph.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Condition
+{
+    Greater(String, f64),
+    Less(String, f64),
+    True(String),
+    False(String),
+    Trigger(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Transition
+{
+    pub to : String,
+    /// cross fade duration in seconds
+    #[serde(default)]
+    pub duration : f64,
+    /// normalized time (0 to 1) of the current state after which the transition is possible
+    #[serde(default)]
+    pub exit_time : Option<f64>,
+    /// all the conditions must be true
+    #[serde(default)]
+    pub conditions : Vec<Condition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnimationState
+{
+    pub name : String,
+    pub motion : Motion,
+    #[serde(default="default_speed")]
+    pub speed : f64,
+    #[serde(default="default_loop_mode")]
+    pub loop_mode : LoopMode,
+    /// float parameters giving the value of a blend space motion, one for 1D, two for 2D
+    #[serde(default)]
+    pub blend_parameters : Vec<String>,
+    #[serde(default)]
+    pub transitions : Vec<Transition>,
+}
+
+fn default_speed() -> f64
+{
+    1f64
+}
+
+fn default_loop_mode() -> LoopMode
+{
+    LoopMode::Loop
+}
+
+/// States bound to motions, and transitions between them depending on parameters.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnimationGraph
+{
+    pub name : String,
+    pub entry : String,
+    pub states : Vec<AnimationState>,
+    #[serde(default)]
+    pub parameters : HashMap<String, Parameter>,
+    /// transitions which can happen from any state
+    #[serde(default)]
+    pub any_state : Vec<Transition>,
+
+    #[serde(skip_serializing, skip_deserializing)]
+    current : Option<usize>,
+    #[serde(skip_serializing, skip_deserializing, default="Playback::new")]
+    playback : Playback,
+    #[serde(skip_serializing, skip_deserializing)]
+    fade : Option<CrossFade>,
+    // state left during the last update, and the segments it played before leaving
+    #[serde(skip_serializing, skip_deserializing)]
+    exited : Option<(usize, Vec<(f64, f64)>)>,
+}
+
+impl AnimationState
+{
+    pub fn new(name : &str, motion : Motion) -> AnimationState
+    {
+        AnimationState {
+            name : name.to_owned(),
+            motion : motion,
+            speed : 1f64,
+            loop_mode : LoopMode::Loop,
+            blend_parameters : Vec::new(),
+            transitions : Vec::new()
+        }
+    }
+}
+
+impl Condition
+{
+    fn is_true(&self, parameters : &HashMap<String, Parameter>) -> bool
+    {
+        match *self {
+            Condition::Greater(ref n, v) => {
+                match parameters.get(n) {
+                    Some(&Parameter::Float(f)) => f > v,
+                    _ => false
+                }
+            },
+            Condition::Less(ref n, v) => {
+                match parameters.get(n) {
+                    Some(&Parameter::Float(f)) => f < v,
+                    _ => false
+                }
+            },
+            Condition::True(ref n) => {
+                match parameters.get(n) {
+                    Some(&Parameter::Bool(b)) => b,
+                    _ => false
+                }
+            },
+            Condition::False(ref n) => {
+                match parameters.get(n) {
+                    Some(&Parameter::Bool(b)) => !b,
+                    _ => false
+                }
+            },
+            Condition::Trigger(ref n) => {
+                match parameters.get(n) {
+                    Some(&Parameter::Trigger(t)) => t,
+                    _ => false
+                }
+            },
+        }
+    }
+}
+
+impl Transition
+{
+    fn can_happen(
+        &self,
+        parameters : &HashMap<String, Parameter>,
+        normalized_time : f64,
+        finished : bool) -> bool
+    {
+        if let Some(exit) = self.exit_time {
+            if normalized_time < exit && !finished {
+                return false;
+            }
+        }
+
+        self.conditions.iter().all(|c| c.is_true(parameters))
+    }
+}
+
+impl AnimationGraph
+{
+    pub fn new(name : &str, entry : &str) -> AnimationGraph
+    {
+        AnimationGraph {
+            name : name.to_owned(),
+            entry : entry.to_owned(),
+            states : Vec::new(),
+            parameters : HashMap::new(),
+            any_state : Vec::new(),
+            current : None,
+            playback : Playback::new(),
+            fade : None,
+            exited : None
+        }
+    }
+
+    pub fn new_from_file(file_path : &str) -> AnimationGraph
+    {
+        let mut file = String::new();
+        File::open(&Path::new(file_path)).ok().unwrap().read_to_string(&mut file);
+        let graph : AnimationGraph = serde_json::from_str(&file).unwrap();
+        graph
+    }
+
+    pub fn save(&self)
+    {
+        let path : &Path = self.name.as_ref();
+        let mut file = File::create(path).ok().unwrap();
+
+        let s = serde_json::to_string_pretty(self).unwrap();
+        let result = file.write(s.as_bytes());
+    }
+
+    pub fn add_state(&mut self, state : AnimationState)
+    {
+        self.states.push(state);
+    }
+
+    pub fn find_state(&self, name : &str) -> Option<usize>
+    {
+        self.states.iter().position(|s| s.name == name)
+    }
+
+    pub fn current_state(&self) -> Option<&str>
+    {
+        self.current.map(|i| self.states[i].name.as_str())
+    }
+
+    pub fn current_motion(&self) -> Option<&Motion>
+    {
+        self.current.map(|i| &self.states[i].motion)
+    }
+
+    /// Playback of the current state.
+    pub fn get_playback(&self) -> &Playback
+    {
+        &self.playback
+    }
+
+    /// The fade of the previous state, while the transition plays.
+    pub fn get_fade(&self) -> Option<&CrossFade>
+    {
+        self.fade.as_ref()
+    }
+
+    /// When a transition happened during the last update, the motion of the state
+    /// which was left and the segments it played before : the playback of the
+    /// current state starts after the transition.
+    pub fn get_exited(&self) -> Option<(&Motion, &[(f64, f64)])>
+    {
+        self.exited.as_ref().map(|&(i, ref segments)| (&self.states[i].motion, segments.as_slice()))
+    }
+
+    pub fn get_parameter(&self, name : &str) -> Option<Parameter>
+    {
+        self.parameters.get(name).cloned()
+    }
+
+    pub fn set_float(&mut self, name : &str, value : f64)
+    {
+        self.parameters.insert(name.to_owned(), Parameter::Float(value));
+    }
+
+    pub fn set_bool(&mut self, name : &str, value : bool)
+    {
+        self.parameters.insert(name.to_owned(), Parameter::Bool(value));
+    }
+
+    pub fn set_trigger(&mut self, name : &str)
+    {
+        self.parameters.insert(name.to_owned(), Parameter::Trigger(true));
+    }
+
+    pub fn reset_trigger(&mut self, name : &str)
+    {
+        self.parameters.insert(name.to_owned(), Parameter::Trigger(false));
+    }
+
+    /// Goes back to the entry state.
+    pub fn reset(&mut self)
+    {
+        self.current = None;
+        self.fade = None;
+        self.exited = None;
+        self.playback = Playback::new();
+    }
+
+    fn get_float(&self, name : &str) -> f64
+    {
+        match self.parameters.get(name) {
+            Some(&Parameter::Float(f)) => f,
+            _ => 0f64
+        }
+    }
+
+    fn enter(&mut self, index : usize, duration : f64)
+    {
+        let previous = ::std::mem::replace(&mut self.playback, Playback::new());
+        self.exited = self.current.map(|c| (c, previous.get_segments().to_vec()));
+
+        let previous_fade = self.fade.take();
+        self.fade = match self.current {
+            Some(c) if duration > 0f64 => {
+                let fade = CrossFade::new(self.states[c].motion.clone(), previous, duration);
+                Some(fade.with_previous(previous_fade))
+            },
+            _ => None
+        };
+
+        self.current = Some(index);
+        self.playback.speed = self.states[index].speed;
+        self.playback.loop_mode = self.states[index].loop_mode;
+        self.playback.play();
+    }
+
+    fn update_blend_value(&mut self, index : usize)
+    {
+        let values : Vec<f64> =
+            self.states[index].blend_parameters.iter().map(|p| self.get_float(p)).collect();
+
+        match self.states[index].motion {
+            Motion::BlendSpace1D(ref mut b) => {
+                if values.len() > 0 {
+                    b.value = values[0];
+                }
+            },
+            Motion::BlendSpace2D(ref mut b) => {
+                if values.len() > 1 {
+                    b.value = vec::Vec2::new(values[0], values[1]);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// First transition which can happen from the current state.
+    fn find_transition(&self, index : usize, normalized_time : f64, finished : bool)
+        -> Option<Transition>
+    {
+        let any = self.any_state.iter().filter(|t| t.to != self.states[index].name);
+        let own = self.states[index].transitions.iter();
+
+        for t in any.chain(own) {
+            if t.can_happen(&self.parameters, normalized_time, finished) {
+                return Some(t.clone());
+            }
+        }
+
+        None
+    }
+
+    /// Evaluates the transitions, moves the time and returns the pose of the graph.
+    /// The bool is true when the clip of the current state finishes.
+    pub fn update(&mut self, dt : f64, armature : &armature::Armature) -> (Option<Pose>, bool)
+    {
+        self.exited = None;
+
+        if self.current.is_none() {
+            match self.find_state(&self.entry) {
+                Some(i) => self.enter(i, 0f64),
+                None => {
+                    println!("animation graph '{}' : no entry state '{}'", self.name, self.entry);
+                    return (None, false);
+                }
+            }
+        }
+
+        let mut current = self.current.unwrap();
+        self.update_blend_value(current);
+
+        let length = self.states[current].motion.length(armature);
+        let finished = self.playback.update(dt, length);
+
+        let fade_done = if let Some(ref mut fade) = self.fade {
+            fade.update(dt, armature);
+            fade.is_done()
+        }
+        else {
+            false
+        };
+
+        if fade_done {
+            self.fade = None;
+        }
+
+        let normalized = if length > 0f64 { self.playback.get_time() / length } else { 1f64 };
+
+        if let Some(t) = self.find_transition(current, normalized, finished) {
+            match self.find_state(&t.to) {
+                Some(i) => {
+                    for c in t.conditions.iter() {
+                        if let Condition::Trigger(ref n) = *c {
+                            self.reset_trigger(n);
+                        }
+                    }
+                    self.enter(i, t.duration);
+                    current = i;
+                    self.update_blend_value(current);
+                },
+                None => println!("animation graph '{}' : no state '{}'", self.name, t.to)
+            }
+        }
+
+        let pose = match self.states[current].motion.sample(armature, self.playback.get_time()) {
+            Some(p) => p,
+            None => return (None, finished)
+        };
+
+        let pose = match self.fade {
+            Some(ref fade) => fade.blend(armature, &pose),
+            None => pose
+        };
+
+        (Some(pose), finished)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use armature::{Armature, Action, Bone, Curve, Data, FrameData, Marker};
+
+    fn armature_with_actions() -> Armature
+    {
+        let mut arm = Armature::new("test");
+        arm.frame_rate = 10f64;
+        arm.add_bone(Bone {
+            name : String::from("root"),
+            position_base : vec::Vec3::zero(),
+            rotation_base : vec::Quat::identity(),
+            parent : None,
+            children : Vec::new(),
+            head_from_arm : vec::Vec3::zero(),
+            head : vec::Vec3::zero(),
+            tail : vec::Vec3::new(0f64, 1f64, 0f64),
+            position_diff : vec::Vec3::zero(),
+            rotation_diff : vec::Quat::identity(),
+            scale_diff : vec::Vec3::one(),
+        });
+
+        for name in ["walk", "idle"].iter() {
+            let mut curve = Curve::new_empty("root", 0, Data::Position);
+            curve.add_frame(0f64, FrameData::Position(vec::Vec3::zero()));
+            curve.add_frame(10f64, FrameData::Position(vec::Vec3::new(0f64, 0f64, 1f64)));
+            let mut action = Action::new_empty(name);
+            action.add_curve(curve);
+            if *name == "walk" {
+                action.add_marker(Marker { frame : 5f64, name : String::from("step"), payload : None });
+            }
+            arm.add_action(action);
+        }
+
+        arm
+    }
+
+    #[test]
+    fn test_markers_across_transition()
+    {
+        let arm = armature_with_actions();
+
+        let mut walk = AnimationState::new("walk", Motion::Action(String::from("walk")));
+        walk.transitions.push(Transition {
+            to : String::from("idle"),
+            duration : 0f64,
+            exit_time : Some(0.5f64),
+            conditions : Vec::new()
+        });
+
+        let mut graph = AnimationGraph::new("graph", "walk");
+        graph.add_state(walk);
+        graph.add_state(AnimationState::new("idle", Motion::Action(String::from("idle"))));
+
+        graph.update(0.2f64, &arm);
+        assert_eq!(graph.current_state(), Some("walk"));
+        assert!(graph.get_exited().is_none());
+
+        // the marker at 0.5 is crossed on the update where walk is left
+        graph.update(0.4f64, &arm);
+        assert_eq!(graph.current_state(), Some("idle"));
+        assert!(graph.get_playback().get_segments().is_empty());
+
+        let (motion, segments) = graph.get_exited().unwrap();
+        let markers = motion.markers_crossed(&arm, segments);
+        assert_eq!(markers.len(), 1);
+        assert_eq!(markers[0].name, "step");
+
+        graph.update(0.1f64, &arm);
+        assert!(graph.get_exited().is_none());
+    }
+}
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Occupied,Vacant};
 use std::fs::File;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::path::Path;
-use std::io::Read;
+use std::io::{self, Read, Write};
+use serde_json;
 
 //use libc::{c_char, c_int, c_uint, c_void};
 use libc::{c_uint, c_void};
@@ -11,12 +12,13 @@ use std::mem;
 use resource;
 use geometry;
 use vec;
+use transform;
 
 use property::{PropertyRead, PropertyGet, PropertyWrite, WriteValue};
 use std::any::Any;
 
 
-fn read_string(file : &mut File) -> String
+fn read_string<T:Read>(file : &mut T) -> String
 {
     /*
     let typelen = file.read_u16::<LittleEndian>().unwrap();
@@ -37,7 +39,7 @@ fn read_string(file : &mut File) -> String
     name
 }
 
-fn read_vec3(file : &mut File) -> vec::Vec3
+fn read_vec3<T:Read>(file : &mut T) -> vec::Vec3
 {
     let x = file.read_f32::<LittleEndian>().unwrap() as f64;
     let y = file.read_f32::<LittleEndian>().unwrap() as f64;
@@ -46,7 +48,7 @@ fn read_vec3(file : &mut File) -> vec::Vec3
     vec::Vec3::new(x,y,z)
 }
 
-fn read_quat(file : &mut File) -> vec::Quat
+fn read_quat<T:Read>(file : &mut T) -> vec::Quat
 {
     let x = file.read_f32::<LittleEndian>().unwrap() as f64;
     let y = file.read_f32::<LittleEndian>().unwrap() as f64;
@@ -56,9 +58,99 @@ fn read_quat(file : &mut File) -> vec::Quat
     vec::Quat::new(x,y,z,w)
 }
 
+fn write_string<T:Write>(file : &mut T, s : &str) -> io::Result<()>
+{
+    file.write_u16::<LittleEndian>(s.len() as u16)?;
+    file.write_all(s.as_bytes())
+}
+
+fn write_vec3<T:Write>(file : &mut T, v : &vec::Vec3) -> io::Result<()>
+{
+    file.write_f32::<LittleEndian>(v.x as f32)?;
+    file.write_f32::<LittleEndian>(v.y as f32)?;
+    file.write_f32::<LittleEndian>(v.z as f32)
+}
 
+fn write_quat<T:Write>(file : &mut T, q : &vec::Quat) -> io::Result<()>
+{
+    file.write_f32::<LittleEndian>(q.x as f32)?;
+    file.write_f32::<LittleEndian>(q.y as f32)?;
+    file.write_f32::<LittleEndian>(q.z as f32)?;
+    file.write_f32::<LittleEndian>(q.w as f32)
+}
 
-#[derive(Clone)]
+const QUAT_QUANTIZE : f64 = 32767f64;
+const VEC3_QUANTIZE : f64 = 65535f64;
+
+fn quantize_quat(q : &vec::Quat) -> [i16; 4]
+{
+    let q = q.normalized();
+    [(q.x * QUAT_QUANTIZE).round() as i16,
+     (q.y * QUAT_QUANTIZE).round() as i16,
+     (q.z * QUAT_QUANTIZE).round() as i16,
+     (q.w * QUAT_QUANTIZE).round() as i16]
+}
+
+fn dequantize_quat(v : &[i16; 4]) -> vec::Quat
+{
+    vec::Quat::new(
+        v[0] as f64 / QUAT_QUANTIZE,
+        v[1] as f64 / QUAT_QUANTIZE,
+        v[2] as f64 / QUAT_QUANTIZE,
+        v[3] as f64 / QUAT_QUANTIZE).normalized()
+}
+
+fn quantize_component(v : f64, min : f64, max : f64) -> u16
+{
+    if max > min {
+        ((v - min) / (max - min) * VEC3_QUANTIZE).round().max(0f64).min(VEC3_QUANTIZE) as u16
+    }
+    else {
+        0u16
+    }
+}
+
+fn dequantize_component(v : u16, min : f64, max : f64) -> f64
+{
+    min + (max - min) * (v as f64 / VEC3_QUANTIZE)
+}
+
+fn quantize_vec3(v : &vec::Vec3, min : &vec::Vec3, max : &vec::Vec3) -> [u16; 3]
+{
+    [quantize_component(v.x, min.x, max.x),
+     quantize_component(v.y, min.y, max.y),
+     quantize_component(v.z, min.z, max.z)]
+}
+
+fn dequantize_vec3(v : &[u16; 3], min : &vec::Vec3, max : &vec::Vec3) -> vec::Vec3
+{
+    vec::Vec3::new(
+        dequantize_component(v[0], min.x, max.x),
+        dequantize_component(v[1], min.y, max.y),
+        dequantize_component(v[2], min.z, max.z))
+}
+
+fn read_quat_quantized<T:Read>(file : &mut T) -> vec::Quat
+{
+    let mut v = [0i16; 4];
+    for c in v.iter_mut() {
+        *c = file.read_i16::<LittleEndian>().unwrap();
+    }
+    dequantize_quat(&v)
+}
+
+fn read_vec3_quantized<T:Read>(file : &mut T, min : &vec::Vec3, max : &vec::Vec3) -> vec::Vec3
+{
+    let mut v = [0u16; 3];
+    for c in v.iter_mut() {
+        *c = file.read_u16::<LittleEndian>().unwrap();
+    }
+    dequantize_vec3(&v, min, max)
+}
+
+
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Bone
 {
     //doesnt change
@@ -72,12 +164,16 @@ pub struct Bone
     pub head : vec::Vec3,
     pub tail : vec::Vec3,
 
+    #[serde(skip_serializing, skip_deserializing)]
     pub position_diff : vec::Vec3,
+    #[serde(skip_serializing, skip_deserializing, default="vec::Quat::identity")]
     pub rotation_diff : vec::Quat,
+    #[serde(skip_serializing, skip_deserializing, default="vec::Vec3::one")]
+    pub scale_diff : vec::Vec3,
 }
 
 impl Bone {
-    fn new(file : &mut File) -> Bone 
+    fn new<T:Read>(file : &mut T) -> Bone 
     {
         let name = read_string(file);
         println!("------------------------------------------------name---------------------: {}", name);
@@ -104,6 +200,7 @@ impl Bone {
             head_from_arm: head_from_arm,
             tail: tail,
             rotation_diff : vec::Quat::identity(),
+            scale_diff : vec::Vec3::one(),
             //position_relative: head,
             //rotation_relative : vec::Quat::identity(),
         };
@@ -128,73 +225,115 @@ impl Bone {
         bone
     }
 
-    /*
-    fn add_child(&mut self, child : Bone)
+    fn write<T:Write>(&self, file : &mut T) -> io::Result<()>
     {
-        self.children.push(child);
+        write_string(file, &self.name)?;
+        // the position is not read back, the head is used instead
+        write_vec3(file, &self.position_base)?;
+        write_vec3(file, &self.head)?;
+        write_vec3(file, &self.head_from_arm)?;
+        write_vec3(file, &self.tail)?;
+        write_quat(file, &self.rotation_base)?;
+
+        match self.parent {
+            Some(p) => {
+                file.write_u8(1)?;
+                file.write_u16::<LittleEndian>(p as u16)?;
+            },
+            None => file.write_u8(0)?
+        }
+
+        file.write_u16::<LittleEndian>(self.children.len() as u16)?;
+        for c in self.children.iter() {
+            file.write_u16::<LittleEndian>(*c as u16)?;
+        }
+
+        Ok(())
     }
-    */
 
     /*
-    pub fn get_position_relative_to_armature(&self, arm: &ArmatureInstance) -> Vec3
+    fn add_child(&mut self, child : Bone)
     {
-        if let Some(
+        self.children.push(child);
     }
     */
 
 }
 
-#[derive(Clone)]
-enum FrameData
+#[derive(Clone, Serialize, Deserialize)]
+pub enum FrameData
 {
     Position(vec::Vec3),
     Orientation(vec::Quat),
     Scale(vec::Vec3),
+    /// weight of a morph target
+    Weight(f64),
 }
 
-#[derive(Clone)]
-struct Frame {
-   time : f64,
-   data : FrameData
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Frame {
+   pub time : f64,
+   pub data : FrameData
 }
 
-#[derive(Clone, Debug)]
-enum Data
+/// Kind of the frames of a curve.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Data
 {
     Position,
     Quaternion,
-    Euler,
-    Scale
+    Euler(vec::RotationOrder),
+    Scale,
+    /// the bone name of the curve is the name of the morph target
+    Morph
 }
 
-#[derive(Clone)]
-struct Curve
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Curve
 {
-    bone_name : String,
-    bone_index : usize, //Bone, //TODO just reference index, ou autre chose
-    data : Data,
-    frames : Vec<Frame>,
+    pub bone_name : String,
+    pub bone_index : usize, //Bone, //TODO just reference index, ou autre chose
+    pub data : Data,
+    pub frames : Vec<Frame>,
+    #[serde(skip_serializing, skip_deserializing)]
     frame_start : f64,
+    #[serde(skip_serializing, skip_deserializing)]
     frame_end : f64
 }
 
 impl Curve
 {
-    fn new(file : &mut File, bone_name : String, bone_index : usize) -> Curve
+    fn new<T:Read>(file : &mut T, bone_name : String, bone_index : usize) -> Curve
     {
         let data_kind_str = read_string(file);
 
-        let data_kind = match data_kind_str.as_ref() {
+        // quantized curves store 16 bits integers instead of floats
+        let quantized = data_kind_str.ends_with("_quantized");
+
+        let data_kind = match data_kind_str.trim_right_matches("_quantized") {
             "position" => Data::Position,
             "quaternion" => Data::Quaternion,
-            "euler" => Data::Euler,
+            // "euler" alone is the blender default mode
+            "euler" | "euler_xyz" => Data::Euler(vec::RotationOrder::XYZ),
+            "euler_xzy" => Data::Euler(vec::RotationOrder::XZY),
+            "euler_yxz" => Data::Euler(vec::RotationOrder::YXZ),
+            "euler_yzx" => Data::Euler(vec::RotationOrder::YZX),
+            "euler_zxy" => Data::Euler(vec::RotationOrder::ZXY),
+            "euler_zyx" => Data::Euler(vec::RotationOrder::ZYX),
             "scale" => Data::Scale,
+            "morph" => Data::Morph,
             _ => panic!("armature curve : no such kind")
         };
 
         let frames_nb = file.read_u16::<LittleEndian>().unwrap() as usize;
         println!("frames nb : {}", frames_nb);
 
+        // range of the quantized vectors
+        let (min, max) = match data_kind {
+            Data::Position | Data::Scale if quantized => (read_vec3(file), read_vec3(file)),
+            _ => (vec::Vec3::zero(), vec::Vec3::zero())
+        };
+
         let mut curve = Curve {
             bone_name : bone_name,
             bone_index : bone_index,
@@ -208,28 +347,44 @@ impl Curve
         {
             let time = file.read_f32::<LittleEndian>().unwrap() as f64;
 
-            if time < curve.frame_start {
+            if i == 0 || time < curve.frame_start {
                 curve.frame_start = time;
             }
 
-            if time > curve.frame_end {
+            if i == 0 || time > curve.frame_end {
                 curve.frame_end = time;
             }
 
             let data = match curve.data {
+                Data::Position if quantized => {
+                    FrameData::Position(read_vec3_quantized(file, &min, &max))
+                },
                 Data::Position => {
                     let pos = read_vec3(file);
                     FrameData::Position(pos)
                 } ,
+                Data::Quaternion if quantized => {
+                    FrameData::Orientation(read_quat_quantized(file))
+                },
                 Data::Quaternion => {
                     let q = read_quat(file);
                     FrameData::Orientation(q)
                 },
+                Data::Euler(order) => {
+                    // euler curves are converted to quaternions so they can be slerped
+                    let angles = read_vec3(file);
+                    FrameData::Orientation(vec::Quat::new_euler_rad(&angles, order))
+                },
+                Data::Scale if quantized => {
+                    FrameData::Scale(read_vec3_quantized(file, &min, &max))
+                },
                 Data::Scale => {
                     let s = read_vec3(file);
                     FrameData::Scale(s)
                 },
-                _ => panic!("not done yet :  {:?} ", curve.data )
+                Data::Morph => {
+                    FrameData::Weight(file.read_f32::<LittleEndian>().unwrap() as f64)
+                },
             };
 
             let frame = Frame {
@@ -243,6 +398,260 @@ impl Curve
         curve
     }
 
+    pub fn new_empty(bone_name : &str, bone_index : usize, data : Data) -> Curve
+    {
+        Curve {
+            bone_name : bone_name.to_owned(),
+            bone_index : bone_index,
+            data : data,
+            frames : Vec::new(),
+            frame_start : 0f64,
+            frame_end : 0f64
+        }
+    }
+
+    /// Frames must be added in time order.
+    pub fn add_frame(&mut self, time : f64, data : FrameData)
+    {
+        if self.frames.is_empty() || time < self.frame_start {
+            self.frame_start = time;
+        }
+        if self.frames.is_empty() || time > self.frame_end {
+            self.frame_end = time;
+        }
+
+        self.frames.push(Frame { time : time, data : data });
+    }
+
+    fn update_range(&mut self)
+    {
+        let times : Vec<f64> = self.frames.iter().map(|f| f.time).collect();
+        self.frame_start = times.iter().cloned().fold(::std::f64::INFINITY, f64::min);
+        self.frame_end = times.iter().cloned().fold(::std::f64::NEG_INFINITY, f64::max);
+        if times.is_empty() {
+            self.frame_start = 0f64;
+            self.frame_end = 0f64;
+        }
+    }
+
+    fn write<T:Write>(&self, file : &mut T, quantize : bool) -> io::Result<()>
+    {
+        // euler curves are converted to quaternions when read
+        let kind = match self.data {
+            Data::Position => "position",
+            Data::Quaternion | Data::Euler(_) => "quaternion",
+            Data::Scale => "scale",
+            Data::Morph => "morph"
+        };
+
+        // morph weights are not quantized
+        let quantize = match self.data {
+            Data::Morph => false,
+            _ => quantize
+        };
+
+        if quantize {
+            write_string(file, &format!("{}_quantized", kind))?;
+        }
+        else {
+            write_string(file, kind)?;
+        }
+        file.write_u16::<LittleEndian>(self.frames.len() as u16)?;
+
+        let (min, max) = self.bounds();
+        match self.data {
+            Data::Position | Data::Scale if quantize => {
+                write_vec3(file, &min)?;
+                write_vec3(file, &max)?;
+            },
+            _ => {}
+        }
+
+        for f in self.frames.iter() {
+            file.write_f32::<LittleEndian>(f.time as f32)?;
+            match f.data {
+                FrameData::Position(ref v) | FrameData::Scale(ref v) if quantize => {
+                    for c in quantize_vec3(v, &min, &max).iter() {
+                        file.write_u16::<LittleEndian>(*c)?;
+                    }
+                },
+                FrameData::Orientation(ref q) if quantize => {
+                    for c in quantize_quat(q).iter() {
+                        file.write_i16::<LittleEndian>(*c)?;
+                    }
+                },
+                FrameData::Position(ref v) => write_vec3(file, v)?,
+                FrameData::Orientation(ref q) => write_quat(file, q)?,
+                FrameData::Scale(ref v) => write_vec3(file, v)?,
+                FrameData::Weight(w) => file.write_f32::<LittleEndian>(w as f32)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Smallest and biggest components of the vectors of the curve.
+    fn bounds(&self) -> (vec::Vec3, vec::Vec3)
+    {
+        let mut bounds : Option<(vec::Vec3, vec::Vec3)> = None;
+
+        for f in self.frames.iter() {
+            let v = match f.data {
+                FrameData::Position(v) | FrameData::Scale(v) => v,
+                FrameData::Orientation(_) | FrameData::Weight(_) => continue
+            };
+
+            bounds = Some(match bounds {
+                Some((min, max)) => (
+                    vec::Vec3::new(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z)),
+                    vec::Vec3::new(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z))),
+                None => (v, v)
+            });
+        }
+
+        bounds.unwrap_or((vec::Vec3::zero(), vec::Vec3::zero()))
+    }
+
+    /// Bytes used by the frames in the file.
+    fn byte_size(&self, quantize : bool) -> usize
+    {
+        let (value, header) = match (&self.data, quantize) {
+            (&Data::Morph, _) => (4, 0),
+            (&Data::Quaternion, false) | (&Data::Euler(_), false) => (16, 0),
+            (&Data::Quaternion, true) | (&Data::Euler(_), true) => (8, 0),
+            (_, false) => (12, 0),
+            (_, true) => (6, 24)
+        };
+
+        header + self.frames.len() * (4 + value)
+    }
+
+    /// Rounds the values like they are when read from a quantized curve.
+    pub fn quantize(&mut self)
+    {
+        let (min, max) = self.bounds();
+
+        for f in self.frames.iter_mut() {
+            f.data = match f.data {
+                FrameData::Position(v) => FrameData::Position(dequantize_vec3(&quantize_vec3(&v, &min, &max), &min, &max)),
+                FrameData::Scale(v) => FrameData::Scale(dequantize_vec3(&quantize_vec3(&v, &min, &max), &min, &max)),
+                FrameData::Orientation(q) => FrameData::Orientation(dequantize_quat(&quantize_quat(&q))),
+                FrameData::Weight(w) => FrameData::Weight(w)
+            };
+        }
+    }
+
+    /// Why the curve cannot be applied : no frames, or frames which are not of
+    /// the kind of the curve. None when the curve is fine.
+    pub fn check(&self) -> Option<&'static str>
+    {
+        if self.frames.is_empty() {
+            return Some("no frames");
+        }
+
+        let fits = |f : &Frame| match (&self.data, &f.data) {
+            (&Data::Position, &FrameData::Position(_)) |
+            (&Data::Quaternion, &FrameData::Orientation(_)) |
+            (&Data::Euler(_), &FrameData::Orientation(_)) |
+            (&Data::Scale, &FrameData::Scale(_)) |
+            (&Data::Morph, &FrameData::Weight(_)) => true,
+            (_, _) => false
+        };
+
+        if self.frames.iter().all(fits) {
+            None
+        }
+        else {
+            Some("frames of a different kind than the curve, only morph curves can have weight frames")
+        }
+    }
+
+    /// Value of the curve at the frame, None if there is no frame.
+    pub fn sample(&self, frame : f64) -> Option<FrameData>
+    {
+        if self.frames.is_empty() {
+            return None;
+        }
+
+        let (start, end) = self.get_frames(frame);
+
+        let ratio = if start.time != end.time {
+            (frame - start.time) / (end.time - start.time)
+        }
+        else {
+            0f64
+        };
+
+        interpolate(&start.data, &end.data, ratio)
+    }
+
+    /// Removes the frames which the interpolation of their neighbours gives back
+    /// within the tolerances : a distance for positions and scales, an angle in radians
+    /// for orientations. Morph weights use the position tolerance.
+    pub fn simplify(&mut self, position_tolerance : f64, angle_tolerance : f64)
+    {
+        let count = self.frames.len();
+        if count < 3 {
+            return;
+        }
+
+        let mut keep = vec![false; count];
+        keep[0] = true;
+        keep[count - 1] = true;
+        self.simplify_range(0, count - 1, position_tolerance, angle_tolerance, &mut keep);
+
+        let frames = ::std::mem::replace(&mut self.frames, Vec::new());
+        self.frames = frames.into_iter().zip(keep.into_iter())
+            .filter(|&(_, k)| k)
+            .map(|(f, _)| f)
+            .collect();
+    }
+
+    // keeps the frame the furthest from the interpolation between first and last,
+    // then does the same on both sides
+    fn simplify_range(
+        &self,
+        first : usize,
+        last : usize,
+        position_tolerance : f64,
+        angle_tolerance : f64,
+        keep : &mut Vec<bool>)
+    {
+        if last <= first + 1 {
+            return;
+        }
+
+        let a = &self.frames[first];
+        let b = &self.frames[last];
+        let mut worst = first;
+        let mut worst_score = 0f64;
+
+        for k in (first + 1)..last {
+            let f = &self.frames[k];
+            let ratio = if b.time != a.time { (f.time - a.time) / (b.time - a.time) } else { 0f64 };
+
+            let score = match interpolate(&a.data, &b.data, ratio) {
+                Some(i) => {
+                    let (position_error, angle_error, weight_error) = data_error(&i, &f.data);
+                    (position_error.max(weight_error) / position_tolerance.max(1e-12f64))
+                        .max(angle_error / angle_tolerance.max(1e-12f64))
+                },
+                None => ::std::f64::INFINITY
+            };
+
+            if score > worst_score {
+                worst = k;
+                worst_score = score;
+            }
+        }
+
+        if worst_score > 1f64 {
+            keep[worst] = true;
+            self.simplify_range(first, worst, position_tolerance, angle_tolerance, keep);
+            self.simplify_range(worst, last, position_tolerance, angle_tolerance, keep);
+        }
+    }
+
     fn get_frames(&self, time : f64) -> (&Frame,&Frame)
     {
         let mut start = None;
@@ -274,27 +683,136 @@ impl Curve
     }
 }
 
-#[derive(Clone)]
+fn interpolate(start : &FrameData, end : &FrameData, ratio : f64) -> Option<FrameData>
+{
+    match (start, end) {
+        (&FrameData::Position(s), &FrameData::Position(e)) => {
+            Some(FrameData::Position(vec::vec3_lerp(s, e, ratio)))
+        },
+        (&FrameData::Orientation(s), &FrameData::Orientation(e)) => {
+            Some(FrameData::Orientation(vec::quat_slerp(s, e, ratio)))
+        },
+        (&FrameData::Scale(s), &FrameData::Scale(e)) => {
+            Some(FrameData::Scale(vec::vec3_lerp(s, e, ratio)))
+        },
+        (&FrameData::Weight(s), &FrameData::Weight(e)) => {
+            Some(FrameData::Weight(s + (e - s) * ratio))
+        },
+        (_,_) => None
+    }
+}
+
+/// Distance, angle and weight difference between two values.
+fn data_error(a : &FrameData, b : &FrameData) -> (f64, f64, f64)
+{
+    match (a, b) {
+        (&FrameData::Position(va), &FrameData::Position(vb)) |
+        (&FrameData::Scale(va), &FrameData::Scale(vb)) => ((va - vb).length(), 0f64, 0f64),
+        (&FrameData::Orientation(qa), &FrameData::Orientation(qb)) => {
+            let d = qa.normalized().dot(&qb.normalized()).abs().min(1f64);
+            (0f64, 2f64 * d.acos(), 0f64)
+        },
+        (&FrameData::Weight(a), &FrameData::Weight(b)) => (0f64, 0f64, (a - b).abs()),
+        (_,_) => (::std::f64::INFINITY, ::std::f64::INFINITY, ::std::f64::INFINITY)
+    }
+}
+
+/// Result of Action::compress.
+#[derive(Clone, Debug)]
+pub struct CompressionReport
+{
+    pub action : String,
+    pub frames_before : usize,
+    pub frames_after : usize,
+    pub bytes_before : usize,
+    pub bytes_after : usize,
+    /// biggest distance between the original frames and the compressed curves
+    pub max_position_error : f64,
+    /// biggest angle in radians
+    pub max_angle_error : f64,
+    /// biggest difference of the morph weights
+    pub max_weight_error : f64
+}
+
+impl CompressionReport
+{
+    /// size before divided by size after
+    pub fn ratio(&self) -> f64
+    {
+        if self.bytes_after > 0 {
+            self.bytes_before as f64 / self.bytes_after as f64
+        }
+        else {
+            1f64
+        }
+    }
+}
+
+/// Named event at a frame of an action, like a footstep.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Marker
+{
+    pub frame : f64,
+    pub name : String,
+    #[serde(default)]
+    pub payload : Option<String>
+}
+
+impl Marker
+{
+    fn new<T:Read>(file : &mut T) -> Marker
+    {
+        let frame = file.read_f32::<LittleEndian>().unwrap() as f64;
+        let name = read_string(file);
+        let has_payload = file.read_u8().unwrap();
+        let payload = if has_payload == 1 {
+            Some(read_string(file))
+        }
+        else {
+            None
+        };
+
+        Marker {
+            frame : frame,
+            name : name,
+            payload : payload
+        }
+    }
+
+    fn write<T:Write>(&self, file : &mut T) -> io::Result<()>
+    {
+        file.write_f32::<LittleEndian>(self.frame as f32)?;
+        write_string(file, &self.name)?;
+        match self.payload {
+            Some(ref p) => {
+                file.write_u8(1)?;
+                write_string(file, p)
+            },
+            None => file.write_u8(0)
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Action
 {
     name : String,
     curves : Vec<Curve>,
+    #[serde(skip_serializing, skip_deserializing)]
     frame_start : f64,
-    frame_end : f64
+    #[serde(skip_serializing, skip_deserializing)]
+    frame_end : f64,
+    #[serde(default)]
+    markers : Vec<Marker>
 }
 
 impl Action {
-    fn new(file : &mut File, armature : &Armature) -> Action 
+    fn new<T:Read>(file : &mut T, armature : &Armature) -> Action 
     {
         let name = read_string(file);
         println!("!!!!!!!! action name : {}",name);
 
-        let mut action = Action {
-            name : name,
-            curves : Vec::new(),
-            frame_start: 0f64,
-            frame_end : 0f64
-        };
+        let mut action = Action::new_empty(&name);
 
         let curves_nb = file.read_u16::<LittleEndian>().unwrap() as usize;
         println!(".....curves count : {} ", curves_nb);
@@ -306,80 +824,489 @@ impl Action {
             let bone_index = file.read_u16::<LittleEndian>().unwrap() as usize;
             println!("...............bone : {}, {} ", bone_name, bone_index);
             let curve = Curve::new(file, bone_name, bone_index);
-            if curve.frame_start < action.frame_start {
-                action.frame_start = curve.frame_start;
-            }
-            if curve.frame_end > action.frame_end {
-                action.frame_end = curve.frame_end;
-            }
-            action.curves.push(curve);
+            action.add_curve(curve);
         }
 
+        action.check_curves();
         action
     }
 
-    fn add_curve(&mut self, curve : Curve)
+    /// Computes the frame range from the curves, after they are deserialized.
+    fn update_range(&mut self)
     {
-        if curve.frame_start < self.frame_start {
-            self.frame_start = curve.frame_start;
-        }
-        if curve.frame_end > self.frame_end {
-            self.frame_end = curve.frame_end;
+        let curves = ::std::mem::replace(&mut self.curves, Vec::new());
+        for mut c in curves.into_iter() {
+            c.update_range();
+            self.add_curve(c);
         }
 
-        self.curves.push(curve);
+        self.check_curves();
     }
-}
-
-#[derive(Clone, Serialize, Deserialize, Default)]
-pub struct ArmaturePath {
-   pub name : String
-}
-
-//TODO clone : only have one instance of the base armature
-// and have ArmatureInstance for the variation
-#[derive(Clone)]
-pub struct Armature
-{
-    pub name : String,
-    position : vec::Vec3,
-    rotation : vec::Quat,
-    scale : vec::Vec3,
-    actions : Vec<Action>,
-    bones : Vec<Bone>,
-    pub state : usize
-}
-
-impl Armature {
 
-    pub fn new(file : &str) -> Armature 
+    /// Reports the curves apply skips, once when the action is loaded.
+    fn check_curves(&self)
     {
-        Armature {
-            name : String::from(file),
-            position : vec::Vec3::zero(),
-            rotation : vec::Quat::identity(),
-            scale : vec::Vec3::zero(),
-            actions : Vec::new(),
-            bones : Vec::new(),
-            state : 0usize
+        for c in self.curves.iter() {
+            if let Some(reason) = c.check() {
+                println!("action '{}', the curve of '{}' is not applied : {}",
+                         self.name, c.bone_name, reason);
+            }
         }
     }
 
-    pub fn create_instance(&self) -> ArmatureInstance
+    fn write<T:Write>(&self, file : &mut T, quantize : bool) -> io::Result<()>
     {
-        let pos = vec![vec::Vec3::zero(); self.bones.len()];
-        let rot = vec![vec::Quat::identity(); self.bones.len()];
+        write_string(file, &self.name)?;
+        file.write_u16::<LittleEndian>(self.curves.len() as u16)?;
 
-        let mut instance = ArmatureInstance {
-            position : self.position,
-            rotation : self.rotation,
-            scale : self.scale,
-            bones : self.bones.clone(),
-            position_relative : pos,
-            rotation_relative : rot,
-        };
+        for c in self.curves.iter() {
+            write_string(file, &c.bone_name)?;
+            file.write_u16::<LittleEndian>(c.bone_index as u16)?;
+            c.write(file, quantize)?;
+        }
 
-        instance.update_relative_coords();
+        Ok(())
+    }
+
+    /// Simplifies the curves, and rounds their values when they are going to be
+    /// written quantized, then measures the error on the original frames.
+    pub fn compress(
+        &mut self,
+        position_tolerance : f64,
+        angle_tolerance : f64,
+        quantize : bool) -> CompressionReport
+    {
+        let original = self.curves.clone();
+
+        for c in self.curves.iter_mut() {
+            c.simplify(position_tolerance, angle_tolerance);
+            if quantize {
+                c.quantize();
+            }
+        }
+
+        let mut report = CompressionReport {
+            action : self.name.clone(),
+            frames_before : original.iter().fold(0, |n, c| n + c.frames.len()),
+            frames_after : self.curves.iter().fold(0, |n, c| n + c.frames.len()),
+            bytes_before : original.iter().fold(0, |n, c| n + c.byte_size(false)),
+            bytes_after : self.curves.iter().fold(0, |n, c| n + c.byte_size(quantize)),
+            max_position_error : 0f64,
+            max_angle_error : 0f64,
+            max_weight_error : 0f64
+        };
+
+        for (before, after) in original.iter().zip(self.curves.iter()) {
+            for f in before.frames.iter() {
+                if let Some(v) = after.sample(f.time) {
+                    let (position_error, angle_error, weight_error) = data_error(&v, &f.data);
+                    report.max_position_error = report.max_position_error.max(position_error);
+                    report.max_angle_error = report.max_angle_error.max(angle_error);
+                    report.max_weight_error = report.max_weight_error.max(weight_error);
+                }
+            }
+        }
+
+        report
+    }
+
+    pub fn from_json(s : &str) -> Result<Action, serde_json::Error>
+    {
+        let mut action : Action = serde_json::from_str(s)?;
+        action.update_range();
+        Ok(action)
+    }
+
+    pub fn to_json(&self) -> String
+    {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn new_empty(name : &str) -> Action
+    {
+        Action {
+            name : name.to_owned(),
+            curves : Vec::new(),
+            frame_start: 0f64,
+            frame_end : 0f64,
+            markers : Vec::new()
+        }
+    }
+
+    pub fn add_curve(&mut self, curve : Curve)
+    {
+        if self.curves.is_empty() || curve.frame_start < self.frame_start {
+            self.frame_start = curve.frame_start;
+        }
+        if self.curves.is_empty() || curve.frame_end > self.frame_end {
+            self.frame_end = curve.frame_end;
+        }
+
+        self.curves.push(curve);
+    }
+
+    pub fn get_name(&self) -> &str
+    {
+        self.name.as_ref()
+    }
+
+    pub fn get_frame_start(&self) -> f64
+    {
+        self.frame_start
+    }
+
+    pub fn get_frame_end(&self) -> f64
+    {
+        self.frame_end
+    }
+
+    /// Length of the action in seconds.
+    pub fn length(&self, frame_rate : f64) -> f64
+    {
+        (self.frame_end - self.frame_start) / frame_rate
+    }
+
+    pub fn get_curves(&self) -> &[Curve]
+    {
+        &self.curves
+    }
+
+    /// Frames of all the curves, sorted and without duplicates.
+    pub fn key_frames(&self) -> Vec<f64>
+    {
+        let mut frames : Vec<f64> = self.curves.iter()
+            .flat_map(|c| c.frames.iter().map(|f| f.time))
+            .collect();
+        frames.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        frames.dedup();
+        frames
+    }
+
+    pub fn get_markers(&self) -> &[Marker]
+    {
+        &self.markers
+    }
+
+    /// Keeps the markers sorted by frame.
+    pub fn add_marker(&mut self, marker : Marker)
+    {
+        let index = self.markers.iter().position(|m| m.frame > marker.frame)
+            .unwrap_or(self.markers.len());
+        self.markers.insert(index, marker);
+    }
+
+    /// Markers played going from time from to time to, in seconds from the start of the action.
+    /// The interval is half open in the direction it is played : the marker at to is left
+    /// for the next interval. When from is after to, the action is played backward.
+    /// An interval of no length gives the markers at from, Playback records one
+    /// when the clip stops or loops at one of its ends.
+    pub fn markers_between(&self, frame_rate : f64, from : f64, to : f64) -> Vec<&Marker>
+    {
+        let a = self.frame_start + from * frame_rate;
+        let b = self.frame_start + to * frame_rate;
+        let epsilon = 1e-6f64;
+
+        self.markers.iter().filter(|m| {
+            if (a - b).abs() <= epsilon {
+                (m.frame - a).abs() <= epsilon
+            }
+            else if a < b {
+                m.frame >= a && m.frame < b
+            }
+            else {
+                m.frame <= a && m.frame > b
+            }
+        }).collect()
+    }
+
+    /// True for each bone which has a curve in this action.
+    pub fn animated_bones(&self, bone_count : usize) -> Vec<bool>
+    {
+        let mut bones = vec![false; bone_count];
+        for curve in self.curves.iter() {
+            if let Data::Morph = curve.data {
+                continue;
+            }
+            if curve.bone_index < bone_count {
+                bones[curve.bone_index] = true;
+            }
+        }
+
+        bones
+    }
+
+    /// Writes the bones animated by this action in the pose,
+    /// time is in seconds from the start of the action.
+    pub fn apply(&self, frame_rate : f64, time : f64, pose : &mut Pose)
+    {
+        let frame = {
+            let mut f = self.frame_start + time*frame_rate;
+
+            if f < self.frame_start {
+                f = self.frame_start;
+            }
+            if f > self.frame_end {
+                f = self.frame_end;
+            }
+
+            f
+        };
+
+        for curve in self.curves.iter()
+        {
+            if let Data::Morph = curve.data {
+                if let Some(FrameData::Weight(w)) = curve.sample(frame) {
+                    pose.morph.insert(curve.bone_name.clone(), w);
+                }
+                continue;
+            }
+
+            if curve.bone_index >= pose.len() {
+                continue;
+            }
+
+            let b = curve.bone_index;
+
+            match curve.sample(frame) {
+                Some(FrameData::Position(p)) => pose.position[b] = p,
+                Some(FrameData::Orientation(q)) => pose.rotation[b] = q,
+                Some(FrameData::Scale(s)) => pose.scale[b] = s,
+                // reported by check_curves when the action is loaded
+                Some(FrameData::Weight(_)) | None => {}
+            };
+        }
+    }
+}
+
+/// Position, rotation and scale of every bone, relative to the rest pose
+/// like Bone::position_diff, rotation_diff and scale_diff.
+#[derive(Clone, Debug)]
+pub struct Pose
+{
+    pub position : Vec<vec::Vec3>,
+    pub rotation : Vec<vec::Quat>,
+    pub scale : Vec<vec::Vec3>,
+    /// weights of the morph targets animated by the action
+    pub morph : HashMap<String, f64>,
+}
+
+impl Pose
+{
+    /// Rest pose
+    pub fn new(bone_count : usize) -> Pose
+    {
+        Pose {
+            position : vec![vec::Vec3::zero(); bone_count],
+            rotation : vec![vec::Quat::identity(); bone_count],
+            scale : vec![vec::Vec3::one(); bone_count],
+            morph : HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.position.len()
+    }
+
+    /// t = 0 gives self, t = 1 gives other
+    pub fn blend(&self, other : &Pose, t : f64) -> Pose
+    {
+        let mut p = self.clone();
+        p.blend_with(other, t);
+        p
+    }
+
+    pub fn blend_with(&mut self, other : &Pose, t : f64)
+    {
+        for i in 0..self.len().min(other.len()) {
+            self.position[i] = vec::vec3_lerp(self.position[i], other.position[i], t);
+            self.rotation[i] = vec::quat_slerp(self.rotation[i], other.rotation[i], t);
+            self.scale[i] = vec::vec3_lerp(self.scale[i], other.scale[i], t);
+        }
+
+        self.blend_morph(other, t);
+    }
+
+    // a morph weight missing from a pose is 0
+    fn blend_morph(&mut self, other : &Pose, t : f64)
+    {
+        for (name, w) in other.morph.iter() {
+            let current = self.morph.entry(name.clone()).or_insert(0f64);
+            *current = *current + (*w - *current) * t;
+        }
+
+        for (name, w) in self.morph.iter_mut() {
+            if !other.morph.contains_key(name) {
+                *w = *w * (1f64 - t);
+            }
+        }
+    }
+
+    /// Blends only the bones of the mask, all of them if there is no mask, and the morph
+    /// weights of the other pose : the morphs it does not animate keep their weight.
+    pub fn blend_masked(&mut self, other : &Pose, t : f64, mask : Option<&[bool]>)
+    {
+        for i in 0..self.len().min(other.len()) {
+            if let Some(m) = mask {
+                if !m[i] {
+                    continue;
+                }
+            }
+            self.position[i] = vec::vec3_lerp(self.position[i], other.position[i], t);
+            self.rotation[i] = vec::quat_slerp(self.rotation[i], other.rotation[i], t);
+            self.scale[i] = vec::vec3_lerp(self.scale[i], other.scale[i], t);
+        }
+
+        for (name, w) in other.morph.iter() {
+            let current = self.morph.entry(name.clone()).or_insert(0f64);
+            *current = *current + (*w - *current) * t;
+        }
+    }
+
+    /// What must be added to the reference to get this pose.
+    pub fn difference(&self, reference : &Pose) -> Pose
+    {
+        let mut delta = Pose::new(self.len());
+
+        for i in 0..self.len().min(reference.len()) {
+            let rs = reference.scale[i];
+            let s = self.scale[i];
+            delta.position[i] = self.position[i] - reference.position[i];
+            delta.rotation[i] = reference.rotation[i].conj() * self.rotation[i];
+            delta.scale[i] = vec::Vec3::new(
+                if rs.x != 0f64 { s.x / rs.x } else { 1f64 },
+                if rs.y != 0f64 { s.y / rs.y } else { 1f64 },
+                if rs.z != 0f64 { s.z / rs.z } else { 1f64 });
+        }
+
+        for (name, w) in self.morph.iter() {
+            let r = reference.morph.get(name).cloned().unwrap_or(0f64);
+            delta.morph.insert(name.clone(), *w - r);
+        }
+
+        delta
+    }
+
+    /// Adds a difference made by difference, scaled by weight.
+    pub fn add(&mut self, delta : &Pose, weight : f64, mask : Option<&[bool]>)
+    {
+        for i in 0..self.len().min(delta.len()) {
+            if let Some(m) = mask {
+                if !m[i] {
+                    continue;
+                }
+            }
+            self.position[i] = self.position[i] + delta.position[i] * weight;
+            self.rotation[i] = self.rotation[i] *
+                vec::quat_slerp(vec::Quat::identity(), delta.rotation[i], weight);
+            self.scale[i] = self.scale[i] *
+                vec::vec3_lerp(vec::Vec3::one(), delta.scale[i], weight);
+        }
+
+        for (name, w) in delta.morph.iter() {
+            let current = self.morph.entry(name.clone()).or_insert(0f64);
+            *current = *current + *w * weight;
+        }
+    }
+
+    /// Blends all the poses, the weights are normalized.
+    pub fn blend_weighted(poses : &[(&Pose, f64)]) -> Option<Pose>
+    {
+        let mut out : Option<Pose> = None;
+        let mut total = 0f64;
+
+        for &(p, w) in poses {
+            if w <= 0f64 {
+                continue;
+            }
+
+            total = total + w;
+
+            if out.is_none() {
+                out = Some(p.clone());
+            }
+            else if let Some(ref mut o) = out {
+                // each new pose gets its share of the weight accumulated so far
+                o.blend_with(p, w / total);
+            }
+        }
+
+        out
+    }
+}
+
+const DEFAULT_FRAME_RATE : f64 = 30f64;
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct ArmaturePath {
+   pub name : String
+}
+
+//TODO clone : only have one instance of the base armature
+// and have ArmatureInstance for the variation
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Armature
+{
+    pub name : String,
+    position : vec::Vec3,
+    rotation : vec::Quat,
+    scale : vec::Vec3,
+    actions : Vec<Action>,
+    bones : Vec<Bone>,
+    #[serde(skip_serializing, skip_deserializing)]
+    bone_map : HashMap<String, usize>,
+    #[serde(default="default_frame_rate")]
+    pub frame_rate : f64,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub state : usize
+}
+
+fn default_frame_rate() -> f64
+{
+    DEFAULT_FRAME_RATE
+}
+
+impl Armature {
+
+    pub fn new(file : &str) -> Armature 
+    {
+        Armature {
+            name : String::from(file),
+            position : vec::Vec3::zero(),
+            rotation : vec::Quat::identity(),
+            scale : vec::Vec3::zero(),
+            actions : Vec::new(),
+            bones : Vec::new(),
+            bone_map : HashMap::new(),
+            frame_rate : DEFAULT_FRAME_RATE,
+            state : 0usize
+        }
+    }
+
+    pub fn create_instance(&self) -> ArmatureInstance
+    {
+        let pos = vec![vec::Vec3::zero(); self.bones.len()];
+        let rot = vec![vec::Quat::identity(); self.bones.len()];
+        let scale = vec![vec::Vec3::one(); self.bones.len()];
+        let rest = vec![vec::Quat::identity(); self.bones.len()];
+
+        let mut instance = ArmatureInstance {
+            position : self.position,
+            rotation : self.rotation,
+            scale : self.scale,
+            bones : self.bones.clone(),
+            bone_map : self.bone_map.clone(),
+            position_relative : pos,
+            rotation_relative : rot,
+            scale_relative : scale,
+            rotation_rest : rest,
+        };
+
+        for b in 0..instance.bones.len() {
+            instance.rotation_rest[b] = instance.get_bone_rest_rotation(&instance.bones[b]);
+        }
+
+        instance.update_relative_coords();
 
         instance
     }
@@ -392,35 +1319,51 @@ impl Armature {
         }
         */
 
-        let path : &Path = self.name.as_ref();
-        let mut file = match File::open(path) {
-            Ok(f) => {f},
-            Err(e) => {
-                println!("Error reading file '{}'. Error: {}", self.name, e);
-                return;
+        let mut file = {
+            let path : &Path = self.name.as_ref();
+            match File::open(path) {
+                Ok(f) => {f},
+                Err(e) => {
+                    println!("Error reading file '{}'. Error: {}", self.name, e);
+                    return;
+                }
             }
         };
 
-        let string_type = read_string(&mut file);
-        let yop = read_string(&mut file);
+        self.read(&mut file);
+
+        let sidecar = format!("{}.markers.json", self.name);
+        if Path::new(&sidecar).exists() {
+            self.read_markers(&sidecar);
+        }
+
+        self.state = 1usize;
+    }
+
+    /// Reads the binary format, the name of the armature is not changed.
+    pub fn read<T:Read>(&mut self, file : &mut T)
+    {
+        let string_type = read_string(file);
+        let yop = read_string(file);
         //self.name = yop.clone();
 
 
         println!("______file read _________ name :::: {}, {}",
                  string_type, self.name);
 
-        self.position = read_vec3(&mut file);
+        self.position = read_vec3(file);
         println!("______ position _________ : {:?}", self.position);
-        self.rotation = read_quat(&mut file);
+        self.rotation = read_quat(file);
         println!("______ rotation _________ : {:?}", self.rotation);
-        self.scale = read_vec3(&mut file);
+        self.scale = read_vec3(file);
         println!("______ scale _________ : {:?}", self.scale);
 
         let bone_count = file.read_u16::<LittleEndian>().unwrap() as usize;
         println!("bone count : {} ", bone_count);
 
         for i in 0usize..bone_count {
-            let bone = Bone::new(&mut file);
+            let bone = Bone::new(file);
+            self.bone_map.insert(bone.name.clone(), i);
             self.bones.push(bone);
         }
 
@@ -428,31 +1371,166 @@ impl Armature {
         println!("action count : {} ", action_count);
 
         for i in 0usize..action_count {
-            let action = Action::new(&mut file, self);
+            let action = Action::new(file, self);
             self.actions.push(action);
         }
 
-        self.state = 1usize;
+        // older files don't have the frame rate
+        self.frame_rate = match file.read_f32::<LittleEndian>() {
+            Ok(f) if f > 0f32 => f as f64,
+            _ => DEFAULT_FRAME_RATE
+        };
 
+        // optional markers block : actions with markers, then for each action
+        // its name and its markers
+        if let Ok(count) = file.read_u16::<LittleEndian>() {
+            for _ in 0usize..count as usize {
+                let action_name = read_string(file);
+                let marker_count = file.read_u16::<LittleEndian>().unwrap() as usize;
+                for _ in 0usize..marker_count {
+                    let marker = Marker::new(file);
+                    match self.find_action_mut(&action_name) {
+                        Some(a) => a.add_marker(marker),
+                        None => println!("marker for unknown action '{}'", action_name)
+                    }
+                }
+            }
+        }
     }
 
-    /*
-    pub fn find_bone(&self, name : &str) -> usize
+    /// Writes the binary format read by file_read.
+    /// With quantize, the curves use 16 bits integers instead of floats.
+    pub fn write<T:Write>(&self, file : &mut T, quantize : bool) -> io::Result<()>
     {
-        for i in 0..self.bones.len() {
-            if self.bones[i].name == name{
-                return i;
+        write_string(file, "armature")?;
+        write_string(file, &self.name)?;
+        write_vec3(file, &self.position)?;
+        write_quat(file, &self.rotation)?;
+        write_vec3(file, &self.scale)?;
+
+        file.write_u16::<LittleEndian>(self.bones.len() as u16)?;
+        for b in self.bones.iter() {
+            b.write(file)?;
+        }
+
+        file.write_u16::<LittleEndian>(self.actions.len() as u16)?;
+        for a in self.actions.iter() {
+            a.write(file, quantize)?;
+        }
+
+        file.write_f32::<LittleEndian>(self.frame_rate as f32)?;
+
+        let with_markers : Vec<&Action> = self.actions.iter().filter(|a| !a.markers.is_empty()).collect();
+        file.write_u16::<LittleEndian>(with_markers.len() as u16)?;
+        for a in with_markers {
+            write_string(file, &a.name)?;
+            file.write_u16::<LittleEndian>(a.markers.len() as u16)?;
+            for m in a.markers.iter() {
+                m.write(file)?;
             }
         }
 
-        return 0usize;
+        Ok(())
+    }
+
+    pub fn file_write(&self, file_path : &str, quantize : bool)
+    {
+        let result = File::create(&Path::new(file_path)).and_then(|mut f| self.write(&mut f, quantize));
+        if let Err(e) = result {
+            println!("Error writing file '{}'. Error: {}", file_path, e);
+        }
+    }
+
+    /// Compresses all the actions, see Action::compress.
+    pub fn compress(
+        &mut self,
+        position_tolerance : f64,
+        angle_tolerance : f64,
+        quantize : bool) -> Vec<CompressionReport>
+    {
+        self.actions.iter_mut()
+            .map(|a| a.compress(position_tolerance, angle_tolerance, quantize))
+            .collect()
+    }
+
+    /// Deserialized armatures are ready to be used, like after file_read.
+    pub fn from_json(s : &str) -> Result<Armature, serde_json::Error>
+    {
+        let mut armature : Armature = serde_json::from_str(s)?;
+
+        for (i, b) in armature.bones.iter().enumerate() {
+            armature.bone_map.insert(b.name.clone(), i);
+        }
+
+        for a in armature.actions.iter_mut() {
+            a.update_range();
+        }
+
+        armature.state = 1usize;
+        Ok(armature)
+    }
+
+    pub fn to_json(&self) -> String
+    {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn read_json(file_path : &str) -> Option<Armature>
+    {
+        let mut file = String::new();
+        match File::open(&Path::new(file_path)) {
+            Ok(mut f) => { f.read_to_string(&mut file); },
+            Err(e) => {
+                println!("Error reading file '{}'. Error: {}", file_path, e);
+                return None;
+            }
+        };
+
+        match Armature::from_json(&file) {
+            Ok(a) => Some(a),
+            Err(e) => {
+                println!("{}, line {}: error reading armature '{}': {:?}",
+                         file!(),
+                         line!(),
+                         file_path,
+                         e);
+                None
+            }
+        }
+    }
+
+    pub fn save_json(&self, file_path : &str)
+    {
+        let result = File::create(&Path::new(file_path))
+            .and_then(|mut f| f.write_all(self.to_json().as_bytes()));
+        if let Err(e) = result {
+            println!("Error writing file '{}'. Error: {}", file_path, e);
+        }
+    }
+
+    pub fn find_bone(&self, name : &str) -> Option<usize>
+    {
+        self.bone_map.get(name).cloned()
     }
 
     pub fn get_bone(&self, index : usize) -> &Bone
     {
         &self.bones[index]
     }
-    */
+
+    /// True if the bone is root or one of its descendants.
+    pub fn is_in_subtree(&self, index : usize, root : usize) -> bool
+    {
+        let mut current = Some(index);
+        while let Some(i) = current {
+            if i == root {
+                return true;
+            }
+            current = self.bones[i].parent;
+        }
+
+        false
+    }
 
 
     pub fn find_action(&self, name : &str) -> Option<&Action>
@@ -466,6 +1544,122 @@ impl Armature {
         None
     }
 
+    /// The parent of the bone must already be added, returns the index of the bone.
+    pub fn add_bone(&mut self, bone : Bone) -> usize
+    {
+        let index = self.bones.len();
+        if let Some(p) = bone.parent {
+            self.bones[p].children.push(index);
+        }
+
+        self.bone_map.insert(bone.name.clone(), index);
+        self.bones.push(bone);
+        index
+    }
+
+    /// Replaces the action with the same name if there is one.
+    pub fn add_action(&mut self, action : Action)
+    {
+        match self.actions.iter().position(|a| a.name == action.name) {
+            Some(i) => self.actions[i] = action,
+            None => self.actions.push(action)
+        }
+    }
+
+    /// Rest orientation of the bone in armature space.
+    pub fn bone_rest_rotation(&self, index : usize) -> vec::Quat
+    {
+        let b = &self.bones[index];
+        match b.parent {
+            Some(p) => self.bone_rest_rotation(p) * b.rotation_base,
+            None => b.rotation_base
+        }
+    }
+
+    fn find_action_mut(&mut self, name : &str) -> Option<&mut Action>
+    {
+        self.actions.iter_mut().find(|a| a.name == name)
+    }
+
+    /// Adds the markers of a json file : an object with action names as keys
+    /// and arrays of markers as values.
+    pub fn read_markers(&mut self, file_path : &str)
+    {
+        let mut file = String::new();
+        match File::open(&Path::new(file_path)) {
+            Ok(mut f) => { f.read_to_string(&mut file); },
+            Err(e) => {
+                println!("Error reading file '{}'. Error: {}", file_path, e);
+                return;
+            }
+        };
+
+        let markers : HashMap<String, Vec<Marker>> = match serde_json::from_str(&file) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("{}, line {}: error reading markers '{}': {:?}",
+                         file!(),
+                         line!(),
+                         file_path,
+                         e);
+                return;
+            }
+        };
+
+        for (action_name, list) in markers.into_iter() {
+            match self.find_action_mut(&action_name) {
+                Some(a) => {
+                    for m in list {
+                        a.add_marker(m);
+                    }
+                },
+                None => println!("marker for unknown action '{}'", action_name)
+            }
+        }
+    }
+
+    /// Markers of the action played between the times, in seconds.
+    pub fn markers_between(&self, action_name : &str, from : f64, to : f64) -> Vec<Marker>
+    {
+        match self.find_action(action_name) {
+            Some(a) => a.markers_between(self.frame_rate, from, to).into_iter().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Length of the action in seconds.
+    pub fn action_length(&self, name : &str) -> Option<f64>
+    {
+        self.find_action(name).map(|a| a.length(self.frame_rate))
+    }
+
+    /// True for each bone animated by the action, None if there is no such action.
+    pub fn action_animated_bones(&self, name : &str) -> Option<Vec<bool>>
+    {
+        self.find_action(name).map(|a| a.animated_bones(self.bones.len()))
+    }
+
+    pub fn bone_count(&self) -> usize
+    {
+        self.bones.len()
+    }
+
+    /// Samples the action in a new pose, time is in seconds from the start of the action.
+    pub fn sample_pose(&self, action_name : &str, time : f64) -> Option<Pose>
+    {
+        let action = match self.find_action(action_name) {
+            Some(a) => a,
+            None => {
+                println!("no such action '{}', for armature '{}'", action_name, self.name);
+                return None
+            }
+        };
+
+        let mut pose = Pose::new(self.bones.len());
+        action.apply(self.frame_rate, time, &mut pose);
+        Some(pose)
+    }
+
 
 }
 
@@ -476,8 +1670,12 @@ pub struct ArmatureInstance
     pub rotation : vec::Quat,
     pub scale : vec::Vec3,
     bones : Vec<Bone>,
+    bone_map : HashMap<String, usize>,
     pub position_relative : Vec<vec::Vec3>,
     pub rotation_relative : Vec<vec::Quat>,
+    pub scale_relative : Vec<vec::Vec3>,
+    /// rest orientation of the bones in armature space, the scale is applied in this frame
+    pub rotation_rest : Vec<vec::Quat>,
 }
 
 impl ArmatureInstance
@@ -492,64 +1690,48 @@ impl ArmatureInstance
         &self.bones
     }
 
-    fn get_mut_bone(&mut self, index : usize) -> &mut Bone
+    pub fn find_bone(&self, name : &str) -> Option<usize>
+    {
+        self.bone_map.get(name).cloned()
+    }
+
+    pub fn get_mut_bone(&mut self, index : usize) -> &mut Bone
     {
         &mut self.bones[index]
     }
 
+    /// time is in seconds from the start of the action
     pub fn set_pose(&mut self, armature : &Armature, action_name : &str, time : f64)
     {
-        let action = match armature.find_action(action_name) {
-            Some(a) => a,
-            None => { println!("no such action '{}', for armature '{}'", action_name, armature.name);  return }
-        };
-
-        let frame = {
-            let mut f = time*30f64;
-
-            if f < action.frame_start {
-                f = action.frame_start;
-            }
-            if f > action.frame_end {
-                f = action.frame_end;
-            }
-
-            f
-        };
-
-        for curve in action.curves.iter()
-        {
-            let bone :&mut Bone = self.get_mut_bone(curve.bone_index);
-
-            let (start, end) = curve.get_frames(frame);
-
-            let ratio = if start.time != end.time {
-                (frame - start.time) / (end.time - start.time)
-            }
-            else {
-                0f64
-            };
+        if let Some(pose) = armature.sample_pose(action_name, time) {
+            self.apply_pose(&pose);
+        }
+    }
 
-            match (&start.data, &end.data) {
-                (&FrameData::Position(s), &FrameData::Position(e)) => {
-                    let v1 = s * (1f64 -ratio);
-                    let v2 = e * ratio;
-                    bone.position_diff = v1 + v2;
-                },
-                (&FrameData::Orientation(s), &FrameData::Orientation(e)) => {
-                    bone.rotation_diff = vec::quat_slerp(s, e, ratio);
-                },
-                (&FrameData::Scale(s), &FrameData::Scale(e)) => {
-                    println!("scale not done yet");
-                },
-                (_,_) => println!("not yet")
-            };
+    /// Sets the bones diff from the pose and updates the relative coords.
+    pub fn apply_pose(&mut self, pose : &Pose)
+    {
+        for (i, bone) in self.bones.iter_mut().enumerate().take(pose.len()) {
+            bone.position_diff = pose.position[i];
+            bone.rotation_diff = pose.rotation[i];
+            bone.scale_diff = pose.scale[i];
         }
 
         self.update_relative_coords();
     }
 
-    fn update_relative_coords(&mut self)
+    pub fn get_pose(&self) -> Pose
+    {
+        Pose {
+            position : self.bones.iter().map(|b| b.position_diff).collect(),
+            rotation : self.bones.iter().map(|b| b.rotation_diff).collect(),
+            scale : self.bones.iter().map(|b| b.scale_diff).collect(),
+            morph : HashMap::new(),
+        }
+    }
+
+    /// Computes the armature space coords from the bones diff.
+    pub fn update_relative_coords(&mut self)
     {
         for b in 0..self.bones.len()
         {
@@ -557,9 +1739,60 @@ impl ArmatureInstance
             //self.bones[b].position_relative = self.get_bone_position(&self.bones[b]);
             self.rotation_relative[b] = self.get_bone_rotation(&self.bones[b]);
             self.position_relative[b] = self.get_bone_position(&self.bones[b]);
+            self.scale_relative[b] = self.get_bone_scale(&self.bones[b]);
+        }
+    }
+
+    fn get_bone_rest_rotation(&self, b : &Bone) -> vec::Quat
+    {
+        match b.parent{
+            Some(p) => self.get_bone_rest_rotation(&self.bones[p]) * b.rotation_base,
+            None => b.rotation_base
+        }
+    }
+
+    /// The scale of the parents is multiplied per axis and applied in the rest frame of the bone,
+    /// so only a uniform scale is inherited correctly : a non uniform scale is exact on the bone
+    /// itself, but not on children whose rest orientation differs from the parent one,
+    /// which would need a shear.
+    fn get_bone_scale(&self, b : &Bone) -> vec::Vec3
+    {
+        match b.parent{
+            Some(p) => self.get_bone_scale(&self.bones[p]) * b.scale_diff,
+            None => b.scale_diff
         }
     }
 
+    /// Head, orientation and scale of the bone in armature space.
+    /// The orientation includes the rest orientation of the bone.
+    pub fn bone_transform(&self, index : usize) -> transform::Transform
+    {
+        transform::Transform::from_position_orientation_scale(
+            self.position_relative[index],
+            transform::Orientation::new_with_quat(
+                &(self.rotation_relative[index] * self.rotation_rest[index])),
+            self.scale_relative[index])
+    }
+
+    /// Bone transform in world space, owner is the transform of the object owning the armature.
+    pub fn bone_world_transform(&self, index : usize, owner : &transform::Transform)
+        -> transform::Transform
+    {
+        let local = self.bone_transform(index);
+        let owner_rot = owner.orientation.as_quat();
+
+        transform::Transform::from_position_orientation_scale(
+            owner.position + owner_rot.rotate_vec3(&(local.position * owner.scale)),
+            transform::Orientation::new_with_quat(&(owner_rot * local.orientation.as_quat())),
+            owner.scale * local.scale)
+    }
+
+    /// Scales a vector expressed from the bone head, in the rest frame of the bone.
+    pub fn scale_from_bone(&self, index : usize, v : &vec::Vec3) -> vec::Vec3
+    {
+        scale_in_frame(&self.rotation_rest[index], &self.scale_relative[index], v)
+    }
+
     fn get_bone_rotation(&self, b : &Bone) -> vec::Quat
     {
         let local = b.rotation_diff;
@@ -578,13 +1811,246 @@ impl ArmatureInstance
             Some(p) => {
                 let pbone = &self.bones[p];
                 local = (pbone.tail - pbone.head) + local;
-                self.get_bone_position(pbone) + self.get_bone_rotation(pbone).rotate_vec3(&local)
+                let scaled = scale_in_frame(
+                    &self.rotation_rest[p],
+                    &self.get_bone_scale(pbone),
+                    &local);
+                self.get_bone_position(pbone) + self.get_bone_rotation(pbone).rotate_vec3(&scaled)
             },
             None => local
         }
     }
 }
 
+fn scale_in_frame(frame : &vec::Quat, scale : &vec::Vec3, v : &vec::Vec3) -> vec::Vec3
+{
+    let local = frame.conj().rotate_vec3(v) * *scale;
+    frame.rotate_vec3(&local)
+}
+
 property_set_impl!(ArmaturePath,[name]);
 property_get_impl!(ArmaturePath,[name]);
 
+#[test]
+fn test_armature_round_trip() {
+    let mut arm = Armature::new("test");
+    arm.bones.push(Bone {
+        name : String::from("root"),
+        position_base : vec::Vec3::zero(),
+        rotation_base : vec::Quat::identity(),
+        parent : None,
+        children : Vec::new(),
+        head_from_arm : vec::Vec3::zero(),
+        head : vec::Vec3::zero(),
+        tail : vec::Vec3::new(0f64, 1f64, 0f64),
+        position_diff : vec::Vec3::zero(),
+        rotation_diff : vec::Quat::identity(),
+        scale_diff : vec::Vec3::one(),
+    });
+
+    let mut curve = Curve::new_empty("root", 0, Data::Position);
+    curve.add_frame(1f64, FrameData::Position(vec::Vec3::zero()));
+    curve.add_frame(10f64, FrameData::Position(vec::Vec3::new(1f64, 2f64, 3f64)));
+    let mut action = Action::new_empty("move");
+    action.add_curve(curve);
+    action.add_marker(Marker { frame : 5f64, name : String::from("step"), payload : None });
+    arm.add_action(action);
+
+    let mut bytes = Vec::new();
+    arm.write(&mut bytes, false).unwrap();
+    let mut read = Armature::new("test");
+    read.read(&mut io::Cursor::new(bytes));
+
+    assert_eq!(read.find_bone("root"), Some(0));
+    assert_eq!(read.action_length("move"), arm.action_length("move"));
+    assert_eq!(read.find_action("move").unwrap().get_markers().len(), 1);
+
+    let json = Armature::from_json(&arm.to_json()).unwrap();
+    assert_eq!(json.find_bone("root"), Some(0));
+    assert_eq!(json.find_action("move").unwrap().get_frame_end(), 10f64);
+}
+
+#[test]
+fn test_blend_masked_keeps_morphs() {
+    let mut base = Pose::new(2);
+    base.morph.insert(String::from("smile"), 1f64);
+    base.morph.insert(String::from("blink"), 0.5f64);
+
+    // animates one bone and one morph only
+    let mut layer = Pose::new(2);
+    layer.position[1] = vec::Vec3::new(0f64, 2f64, 0f64);
+    layer.morph.insert(String::from("blink"), 1f64);
+
+    base.blend_masked(&layer, 1f64, Some(&[false, true]));
+
+    assert_eq!(base.position[1], vec::Vec3::new(0f64, 2f64, 0f64));
+    assert_eq!(base.morph["smile"], 1f64);
+    assert_eq!(base.morph["blink"], 1f64);
+}
+
+#[cfg(test)]
+fn test_bone(name : &str, parent : Option<usize>, head : vec::Vec3, rotation : vec::Quat) -> Bone
+{
+    Bone {
+        name : String::from(name),
+        position_base : head,
+        rotation_base : rotation,
+        parent : parent,
+        children : Vec::new(),
+        head_from_arm : head,
+        head : head,
+        tail : head + rotation.rotate_vec3(&vec::Vec3::new(1f64, 0f64, 0f64)),
+        position_diff : vec::Vec3::zero(),
+        rotation_diff : vec::Quat::identity(),
+        scale_diff : vec::Vec3::one(),
+    }
+}
+
+#[test]
+fn test_euler_curve() {
+    use std::f64::consts::PI;
+
+    // same angles, the order changes where the x axis goes
+    let read = |kind : &str| {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, kind).unwrap();
+        bytes.write_u16::<LittleEndian>(2).unwrap();
+        for &(time, angle) in [(1f32, 0f64), (11f32, PI/2f64)].iter() {
+            bytes.write_f32::<LittleEndian>(time).unwrap();
+            write_vec3(&mut bytes, &vec::Vec3::new(angle, angle, 0f64)).unwrap();
+        }
+        Curve::new(&mut io::Cursor::new(bytes), String::from("root"), 0)
+    };
+
+    let x_axis = |curve : &Curve, frame : f64| {
+        match curve.sample(frame) {
+            Some(FrameData::Orientation(q)) => q.rotate_vec3(&vec::Vec3::new(1f64, 0f64, 0f64)),
+            _ => panic!("no orientation")
+        }
+    };
+
+    let xyz = read("euler");
+    let zyx = read("euler_zyx");
+    assert!((x_axis(&xyz, 11f64) - vec::Vec3::new(0f64, 0f64, -1f64)).length() < 1e-6);
+    assert!((x_axis(&zyx, 11f64) - vec::Vec3::new(0f64, 1f64, 0f64)).length() < 1e-6);
+    assert!((x_axis(&xyz, 1f64) - vec::Vec3::new(1f64, 0f64, 0f64)).length() < 1e-6);
+}
+
+#[test]
+fn test_scale_curve() {
+    let mut arm = Armature::new("test");
+    arm.frame_rate = 10f64;
+    let root = arm.add_bone(test_bone("root", None, vec::Vec3::zero(), vec::Quat::identity()));
+    arm.add_bone(test_bone("child", Some(root), vec::Vec3::zero(), vec::Quat::identity()));
+
+    let mut curve = Curve::new_empty("root", root, Data::Scale);
+    curve.add_frame(1f64, FrameData::Scale(vec::Vec3::one()));
+    curve.add_frame(11f64, FrameData::Scale(vec::Vec3::new(2f64, 1f64, 1f64)));
+    let mut action = Action::new_empty("grow");
+    action.add_curve(curve);
+    arm.add_action(action);
+
+    let mut instance = arm.create_instance();
+    instance.set_pose(&arm, "grow", 0.5f64);
+
+    // frame 6, half way
+    assert_eq!(instance.scale_relative[0], vec::Vec3::new(1.5f64, 1f64, 1f64));
+    // the child starts at the tail of the root, which is stretched along x
+    assert!((instance.position_relative[1] - vec::Vec3::new(1.5f64, 0f64, 0f64)).length() < 1e-6);
+    assert!((instance.scale_from_bone(1, &vec::Vec3::new(1f64, 1f64, 0f64))
+             - vec::Vec3::new(1.5f64, 1f64, 0f64)).length() < 1e-6);
+}
+
+#[test]
+fn test_uniform_scale_rotated_parent() {
+    use std::f64::consts::PI;
+
+    let mut arm = Armature::new("test");
+    let quarter = vec::Quat::new_axis_angle_rad(vec::Vec3::new(0f64, 0f64, 1f64), PI/2f64);
+    let root = arm.add_bone(test_bone("root", None, vec::Vec3::zero(), quarter));
+    arm.add_bone(test_bone("child", Some(root), vec::Vec3::zero(), quarter));
+
+    let mut instance = arm.create_instance();
+    let mut pose = Pose::new(2);
+    pose.scale[0] = vec::Vec3::new(2f64, 2f64, 2f64);
+    instance.apply_pose(&pose);
+
+    // the child rest frame is rotated from the parent one, a uniform scale is the same in both
+    let v = vec::Vec3::new(1f64, 2f64, 3f64);
+    assert!((instance.scale_from_bone(1, &v) - v * 2f64).length() < 1e-6);
+}
+
+#[test]
+fn test_simplify_within_tolerance() {
+    // a straight line only needs its ends
+    let mut line = Curve::new_empty("root", 0, Data::Position);
+    for i in 0..10 {
+        line.add_frame(i as f64, FrameData::Position(vec::Vec3::new(i as f64, 0f64, 0f64)));
+    }
+    line.simplify(0.001f64, 0.001f64);
+    assert_eq!(line.frames.len(), 2);
+
+    let mut curve = Curve::new_empty("root", 0, Data::Position);
+    for i in 0..101 {
+        let t = i as f64 / 10f64;
+        curve.add_frame(i as f64, FrameData::Position(vec::Vec3::new(t, t.sin(), 0f64)));
+    }
+    let original = curve.clone();
+    curve.simplify(0.01f64, 0.01f64);
+    assert!(curve.frames.len() < original.frames.len());
+
+    for f in original.frames.iter() {
+        let (position_error, _, _) = data_error(&curve.sample(f.time).unwrap(), &f.data);
+        assert!(position_error <= 0.01f64, "error {} at frame {}", position_error, f.time);
+    }
+}
+
+#[test]
+fn test_quantize_round_trip() {
+    let min = vec::Vec3::new(-2f64, 0f64, 1f64);
+    let max = vec::Vec3::new(3f64, 10f64, 1f64);
+    let v = vec::Vec3::new(0.123456f64, 7.654321f64, 1f64);
+    let back = dequantize_vec3(&quantize_vec3(&v, &min, &max), &min, &max);
+    // half a step on the widest axis
+    assert!((back - v).length() <= 10f64 / VEC3_QUANTIZE);
+
+    let q = vec::Quat::new_axis_angle_rad(vec::Vec3::new(1f64, 2f64, 3f64).normalized(), 1.234f64);
+    let (_, angle, _) = data_error(&FrameData::Orientation(dequantize_quat(&quantize_quat(&q))), &FrameData::Orientation(q));
+    assert!(angle < 1e-3f64);
+
+    // written and read back as a quantized curve
+    let mut curve = Curve::new_empty("root", 0, Data::Position);
+    curve.add_frame(1f64, FrameData::Position(min));
+    curve.add_frame(2f64, FrameData::Position(v));
+    curve.add_frame(3f64, FrameData::Position(max));
+    let mut bytes = Vec::new();
+    curve.write(&mut bytes, true).unwrap();
+    let read = Curve::new(&mut io::Cursor::new(bytes), String::from("root"), 0);
+
+    assert_eq!(read.frames.len(), 3);
+    for (a, b) in curve.frames.iter().zip(read.frames.iter()) {
+        assert_eq!(a.time, b.time);
+        let (position_error, _, _) = data_error(&a.data, &b.data);
+        assert!(position_error < 1e-3f64);
+    }
+}
+
+#[test]
+fn test_compress_weight_error() {
+    assert_eq!(data_error(&FrameData::Weight(0.5f64), &FrameData::Weight(0.25f64)), (0f64, 0f64, 0.25f64));
+
+    let mut curve = Curve::new_empty("smile", 0, Data::Morph);
+    curve.add_frame(0f64, FrameData::Weight(0f64));
+    curve.add_frame(1f64, FrameData::Weight(0.25f64));
+    curve.add_frame(2f64, FrameData::Weight(1f64));
+    let mut action = Action::new_empty("smile");
+    action.add_curve(curve);
+
+    // the middle frame is removed, the line between the others gives 0.5 instead of 0.25
+    let report = action.compress(0.5f64, 0.5f64, false);
+    assert_eq!(report.frames_before, 3);
+    assert_eq!(report.frames_after, 2);
+    assert_eq!(report.max_weight_error, 0.25f64);
+    assert_eq!(report.max_position_error, 0f64);
+    assert_eq!(report.max_angle_error, 0f64);
+}
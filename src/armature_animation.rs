@@ -10,8 +10,12 @@ use resource;
 use vec;
 use input;
 use mesh_render;
+use armature_blend::{Motion, CrossFade, AnimationLayer};
+use animation_graph::AnimationGraph;
+use armature_ik::IkSolver;
+use root_motion::{self, RootMotion, RootMotionOptions};
 
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,Debug,PartialEq)]
 pub enum State
 {
     Idle,
@@ -20,17 +24,291 @@ pub enum State
     Stop
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum LoopMode
+{
+    /// starts again from the beginning
+    Loop,
+    /// plays one time then stops and goes back to the beginning
+    Once,
+    /// plays one time then holds the last frame
+    Clamp,
+    /// goes back and forth
+    PingPong
+}
+
+/// Time of a clip, moved by update depending on the state, speed and loop mode.
+/// A negative speed plays the clip in reverse.
+#[derive(Clone, Debug)]
+pub struct Playback
+{
+    pub state : State,
+    pub speed : f64,
+    pub loop_mode : LoopMode,
+    time : f64,
+    // -1 when going backward in ping pong mode
+    direction : f64,
+    // time must be set at the start of the clip on next update
+    rewind : bool,
+    finished : bool,
+    // time intervals covered by the last update, in the order they were played
+    segments : Vec<(f64, f64)>
+}
+
+impl Playback
+{
+    pub fn new() -> Playback
+    {
+        Playback {
+            state : State::Stop,
+            speed : 1f64,
+            loop_mode : LoopMode::Loop,
+            time : 0f64,
+            direction : 1f64,
+            rewind : true,
+            finished : false,
+            segments : Vec::new()
+        }
+    }
+
+    pub fn play(&mut self)
+    {
+        self.state = State::Play;
+        self.finished = false;
+    }
+
+    pub fn pause(&mut self)
+    {
+        if self.state == State::Play {
+            self.state = State::Pause;
+        }
+    }
+
+    pub fn stop(&mut self)
+    {
+        self.state = State::Stop;
+        self.time = 0f64;
+        self.direction = 1f64;
+        self.rewind = true;
+        self.finished = false;
+    }
+
+    /// time is in seconds from the start of the clip, length is the clip length.
+    /// A time out of the clip wraps in loop and ping pong mode, and is clamped otherwise.
+    pub fn seek(&mut self, time : f64, length : f64)
+    {
+        self.rewind = false;
+        self.finished = false;
+        self.direction = 1f64;
+
+        if length <= 0f64 {
+            self.time = 0f64;
+            return;
+        }
+
+        self.time = match self.loop_mode {
+            LoopMode::Loop => {
+                let t = time % length;
+                if t < 0f64 { t + length } else { t }
+            },
+            LoopMode::Once | LoopMode::Clamp => {
+                if time < 0f64 { 0f64 } else if time > length { length } else { time }
+            },
+            LoopMode::PingPong => {
+                // a back and forth lasts twice the length
+                let mut t = time % (2f64*length);
+                if t < 0f64 {
+                    t = t + 2f64*length;
+                }
+                if t > length {
+                    self.direction = -1f64;
+                    2f64*length - t
+                }
+                else {
+                    t
+                }
+            }
+        };
+    }
+
+    pub fn get_time(&self) -> f64
+    {
+        self.time
+    }
+
+    /// Time intervals played during the last update, split at the loop boundaries.
+    /// An interval going backward has its start after its end.
+    pub fn get_segments(&self) -> &[(f64, f64)]
+    {
+        &self.segments
+    }
+
+    /// true once a clip which is not looping has reached its end
+    pub fn is_finished(&self) -> bool
+    {
+        self.finished
+    }
+
+    /// Moves the time, length is the clip length in seconds.
+    /// Returns true when a clip which is not looping finishes during this update.
+    pub fn update(&mut self, dt : f64, length : f64) -> bool
+    {
+        self.segments.clear();
+
+        if self.state != State::Play {
+            return false;
+        }
+
+        let start = if self.speed < 0f64 { length } else { 0f64 };
+
+        if self.rewind {
+            self.time = start;
+            self.direction = 1f64;
+            self.rewind = false;
+        }
+
+        if length <= 0f64 {
+            self.time = 0f64;
+            return false;
+        }
+
+        let delta = dt * self.speed * self.direction;
+        let from = self.time;
+        self.record_segments(from, delta, length);
+
+        let time = self.time + delta;
+
+        match self.loop_mode {
+            LoopMode::Loop => {
+                self.time = time % length;
+                if self.time < 0f64 {
+                    self.time = self.time + length;
+                }
+                false
+            },
+            LoopMode::Once | LoopMode::Clamp => {
+                if time >= 0f64 && time <= length {
+                    self.time = time;
+                    return false;
+                }
+
+                if self.loop_mode == LoopMode::Once {
+                    self.time = start;
+                    self.state = State::Stop;
+                }
+                else {
+                    self.time = if time < 0f64 { 0f64 } else { length };
+                    self.state = State::Pause;
+                }
+
+                self.rewind = true;
+                self.finished = true;
+                true
+            },
+            LoopMode::PingPong => {
+                let mut t = time;
+                loop {
+                    if t > length {
+                        t = 2f64*length - t;
+                    }
+                    else if t < 0f64 {
+                        t = -t;
+                    }
+                    else {
+                        break;
+                    }
+                    self.direction = -self.direction;
+                }
+                self.time = t;
+                false
+            }
+        }
+    }
+
+    /// The segments are half open, see armature::Action::markers_between : when the clip
+    /// stops or loops at one of its ends, a segment of no length is added at that end.
+    /// In ping pong mode the segment going back starts at the end, so there is none.
+    fn record_segments(&mut self, from : f64, delta : f64, length : f64)
+    {
+        let mut current = from;
+        let mut remaining = delta.abs();
+        let mut forward = delta >= 0f64;
+
+        while remaining > 0f64 {
+            let end = if forward { length } else { 0f64 };
+            let room = (end - current).abs();
+
+            if remaining < room {
+                let to = if forward { current + remaining } else { current - remaining };
+                self.segments.push((current, to));
+                break;
+            }
+
+            // an end reached by the previous update was already recorded
+            if room > 0f64 {
+                self.segments.push((current, end));
+                if self.loop_mode != LoopMode::PingPong {
+                    self.segments.push((end, end));
+                }
+            }
+
+            remaining = remaining - room;
+
+            match self.loop_mode {
+                LoopMode::Loop => current = if forward { 0f64 } else { length },
+                LoopMode::PingPong => {
+                    current = end;
+                    forward = !forward;
+                },
+                LoopMode::Once | LoopMode::Clamp => break
+            }
+        }
+    }
+}
+
+/// What happened during an update of the animation.
+#[derive(Clone)]
+pub struct AnimationUpdate
+{
+    /// a motion which is not looping has finished
+    pub finished : bool,
+    /// to apply on the transform of the object, identity if root motion is disabled
+    pub root_motion : RootMotion,
+    /// markers of the motion crossed since the last update, in the order they were played
+    pub markers : Vec<armature::Marker>
+}
+
+impl AnimationUpdate
+{
+    pub fn new() -> AnimationUpdate
+    {
+        AnimationUpdate {
+            finished : false,
+            root_motion : RootMotion::identity(),
+            markers : Vec::new()
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ArmatureAnimation
 {
-    pub state : State,
     //armature : armature::Armature,
     //armature : Arc<RwLock<armature::Armature>>,
     pub armature : resource::ResTT<armature::Armature>,
     pub arm_instance : armature::ArmatureInstance,
     pub mesh : Option<resource::ResTT<mesh::Mesh>>,
-    pub action : Option<String>,
-    pub time : f64
+    pub motion : Option<Motion>,
+    pub playback : Playback,
+    pub fade : Option<CrossFade>,
+    /// when set, chooses the motion instead of play_action and cross_fade
+    pub graph : Option<AnimationGraph>,
+    /// applied in order on top of the motion or the graph
+    pub layers : Vec<AnimationLayer>,
+    /// solved in order after the pose is set, before skinning
+    pub ik : Vec<IkSolver>,
+    /// when set, the root bone motion is removed from the pose and returned by update
+    pub root_motion : Option<RootMotionOptions>,
 
     //TODO mesh component + dependencies
     //mesh_base : Option<resource::ResTT<MeshRenderComponent>>,
@@ -39,37 +317,249 @@ pub struct ArmatureAnimation
 
 impl ArmatureAnimation
 {
-    fn update(
+    pub fn new(
+        armature : resource::ResTT<armature::Armature>,
+        arm_instance : armature::ArmatureInstance) -> ArmatureAnimation
+    {
+        ArmatureAnimation {
+            armature : armature,
+            arm_instance : arm_instance,
+            mesh : None,
+            motion : None,
+            playback : Playback::new(),
+            fade : None,
+            graph : None,
+            layers : Vec::new(),
+            ik : Vec::new(),
+            root_motion : None,
+        }
+    }
+
+    /// Plays the action from its start.
+    pub fn play_action(&mut self, action : &str)
+    {
+        self.play_motion(Motion::Action(action.to_owned()));
+    }
+
+    /// Plays the motion from its start, without transition.
+    pub fn play_motion(&mut self, motion : Motion)
+    {
+        self.motion = Some(motion);
+        self.fade = None;
+        self.playback.stop();
+        self.playback.play();
+    }
+
+    /// Plays the motion from its start, while the current one fades out during duration seconds.
+    /// A fade which is not done keeps on playing, so the pose does not jump.
+    pub fn cross_fade(&mut self, motion : Motion, duration : f64)
+    {
+        if let Some(from) = self.motion.take() {
+            let previous = self.fade.take();
+            self.fade = Some(CrossFade::new(from, self.playback.clone(), duration).with_previous(previous));
+        }
+
+        self.motion = Some(motion);
+        self.playback.stop();
+        self.playback.play();
+    }
+
+    pub fn cross_fade_action(&mut self, action : &str, duration : f64)
+    {
+        self.cross_fade(Motion::Action(action.to_owned()), duration);
+    }
+
+    /// Sets the value of the 1D blend space being played.
+    pub fn set_blend_value(&mut self, value : f64)
+    {
+        if let Some(Motion::BlendSpace1D(ref mut b)) = self.motion {
+            b.value = value;
+        }
+    }
+
+    /// Sets the value of the 2D blend space being played.
+    pub fn set_blend_value_2d(&mut self, value : vec::Vec2)
+    {
+        if let Some(Motion::BlendSpace2D(ref mut b)) = self.motion {
+            b.value = value;
+        }
+    }
+
+    /// Plays the graph from its entry state, the graph then chooses the motion
+    /// depending on its parameters.
+    pub fn set_graph(&mut self, graph : AnimationGraph)
+    {
+        let mut graph = graph;
+        graph.reset();
+        self.graph = Some(graph);
+    }
+
+    pub fn set_float(&mut self, name : &str, value : f64)
+    {
+        if let Some(ref mut g) = self.graph {
+            g.set_float(name, value);
+        }
+    }
+
+    pub fn set_bool(&mut self, name : &str, value : bool)
+    {
+        if let Some(ref mut g) = self.graph {
+            g.set_bool(name, value);
+        }
+    }
+
+    pub fn set_trigger(&mut self, name : &str)
+    {
+        if let Some(ref mut g) = self.graph {
+            g.set_trigger(name);
+        }
+    }
+
+    pub fn add_layer(&mut self, layer : AnimationLayer)
+    {
+        self.layers.push(layer);
+    }
+
+    pub fn get_layer_mut(&mut self, name : &str) -> Option<&mut AnimationLayer>
+    {
+        self.layers.iter_mut().find(|l| l.name == name)
+    }
+
+    fn update_motion(&mut self, dt : f64, arm_base : &armature::Armature) -> Option<(armature::Pose, bool)>
+    {
+        let motion = if let Some(ref m) = self.motion {
+            m
+        }
+        else {
+            //println!("update armature anim : no action");
+            return None
+        };
+
+        let length = motion.length(arm_base);
+        let finished = self.playback.update(dt, length);
+
+        let mut pose = match motion.sample(arm_base, self.playback.get_time()) {
+            Some(p) => p,
+            None => return None
+        };
+
+        let fade_done = if let Some(ref mut fade) = self.fade {
+            fade.update(dt, arm_base);
+            pose = fade.blend(arm_base, &pose);
+            fade.is_done()
+        }
+        else {
+            false
+        };
+
+        if fade_done {
+            self.fade = None;
+        }
+
+        Some((pose, finished))
+    }
+
+    /// When there is a graph, it is evaluated instead of the motion.
+    pub fn update(
         &mut self,
         dt : f64,
         mr : &mut mesh_render::MeshRender,
         resource : &resource::ResourceGroup
-        )
+        ) -> AnimationUpdate
     {
-        let action = if let Some(ref a) = self.action {
-            a
+        let mut out = AnimationUpdate::new();
+
+        let armature_manager = &mut *resource.armature_manager.borrow_mut();
+        let arm_base = self.armature.get_ref(armature_manager).unwrap();
+
+        let result = if self.graph.is_some() {
+            let g = self.graph.as_mut().unwrap();
+            match g.update(dt, arm_base) {
+                (Some(pose), finished) => Some((pose, finished)),
+                (None, _) => None
+            }
         }
         else {
-            //println!("update armature anim : no action");
-            return
+            self.update_motion(dt, arm_base)
         };
 
-        self.time = self.time + dt;
-        if self.time > 50f64/30f64 {
-            self.time = 0f64;
+        let mut pose = match result {
+            Some((p, finished)) => {
+                out.finished = finished;
+                p
+            },
+            None => return out
+        };
+
+        {
+            let source = match self.graph {
+                Some(ref g) => g.current_motion().map(|m| (m, g.get_fade(), g.get_playback().get_segments())),
+                None => self.motion.as_ref().map(|m| (m, self.fade.as_ref(), self.playback.get_segments()))
+            };
+
+            // when the graph changed state, what the state it left played before
+            let exited = self.graph.as_ref().and_then(|g| g.get_exited());
+
+            if let Some((motion, fade, segments)) = source {
+                if let Some((m, s)) = exited {
+                    out.markers = m.markers_crossed(arm_base, s);
+                }
+                out.markers.extend(motion.markers_crossed(arm_base, segments));
+
+                if let Some(options) = self.root_motion {
+                    out.root_motion =
+                        root_motion::extract(&options, motion, fade, arm_base, segments, &mut pose);
+
+                    // with a fade, the state which was left is in the fade with its segments
+                    if let (Some((m, s)), None) = (exited, fade) {
+                        let before = root_motion::delta(&options, m, arm_base, s);
+                        out.root_motion = before.then(&out.root_motion);
+                    }
+                }
+            }
         }
 
-        let armature_manager = &mut *resource.armature_manager.borrow_mut();
-        let arm_base = self.armature.get_ref(armature_manager).unwrap();
+        for layer in self.layers.iter_mut() {
+            layer.update(dt, arm_base);
+            layer.apply(arm_base, &mut pose);
+        }
+
+        self.arm_instance.apply_pose(&pose);
 
-        self.arm_instance.set_pose(arm_base, action.as_str(), self.time);
+        for solver in self.ik.iter() {
+            solver.solve(&mut self.arm_instance);
+        }
 
         let base_mesh = mr.get_mesh();
         //let base = base_mesh.read().unwrap();
         let mm = &mut *resource.mesh_manager.borrow_mut();
         let base = base_mesh.get_ref(mm).unwrap();
         let mut mi = mr.get_or_create_mesh_instance();
-        update_mesh_with_armature(&base, mi, &self.arm_instance);
+
+        for (name, w) in pose.morph.iter() {
+            mi.set_morph_weight(name, *w);
+        }
+
+        // the morph targets are applied before skinning
+        if base.weights.is_empty() {
+            // not skinned, only the morph targets move the vertices
+            if mi.has_morph_targets() {
+                mi.apply_morph_targets();
+            }
+        }
+        else if mi.has_morph_targets() {
+            if let Some((positions, normals)) = mi.morphed_buffers() {
+                update_mesh_with_armature(&positions, &normals, &base.weights, mi, &self.arm_instance);
+            }
+        }
+        else {
+            match (base.buffer_f32_get("position"), base.buffer_f32_get("normal")) {
+                (Some(p), Some(n)) => {
+                    update_mesh_with_armature(&p.data, &n.data, &base.weights, mi, &self.arm_instance);
+                },
+                _ => println!("no buffer position or normal in base")
+            }
+        }
 
         //let normal_pose = 
 
@@ -78,70 +568,75 @@ impl ArmatureAnimation
         // ...
         //get the original mesh and apply weights 
 
+        out
     }
 
 }
 
 
 //TODO
+/// positions and normals are the vertices before skinning, weights are the bone weights
+/// of each vertex.
 fn update_mesh_with_armature(
-    base : &mesh::Mesh,
+    positions : &[f32],
+    normals : &[f32],
+    weights : &[Vec<mesh::Weight>],
     mesh : &mut mesh::Mesh,
     arm : &armature::ArmatureInstance)
 {
+    if positions.len() < weights.len() * 3 || normals.len() < weights.len() * 3 {
+        println!("no buffer position or normal for all the weights");
+        return;
+    }
+
     let mut i = 0;
-    for v in &base.weights {
-        //TODO get vertex and normal
-        let vertex_pos = if let Some(b) = base.buffer_f32_get("position") {
-            vec::Vec3::new(
-                b.data[i*3] as f64,
-                b.data[i*3+ 1] as f64,
-                b.data[i*3+ 2] as f64)
-        }
-        else {
-            println!("no buffer position in base");
-            return;
-        };
-        let vertex_nor = if let Some(b) = base.buffer_f32_get("normal") {
-            vec::Vec3::new(
-                b.data[i*3] as f64,
-                b.data[i*3+ 1] as f64,
-                b.data[i*3+ 2] as f64)
-        }
-        else {
-            println!("no buffer normal in base");
-            return;
-        };
+    for v in weights {
+        let vertex_pos = vec::Vec3::new(
+            positions[i*3] as f64,
+            positions[i*3+ 1] as f64,
+            positions[i*3+ 2] as f64);
+        let vertex_nor = vec::Vec3::new(
+            normals[i*3] as f64,
+            normals[i*3+ 1] as f64,
+            normals[i*3+ 2] as f64);
 
-        //TODO rotation
         let mut translation = vec::Vec3::zero();
-        let mut rotation = vec::Quat::identity();
+        let mut normal = vec::Vec3::zero();
         for w in v.iter() {
             //TODO TODO
             // slime used to try to find the bone with name
-            let bone = arm.get_bone(w.index as usize);
-            let pos_relative = arm.position_relative[w.index as usize];
-            let rot_relative = arm.rotation_relative[w.index as usize];
+            let index = w.index as usize;
+            let bone = arm.get_bone(index);
+            let pos_relative = arm.position_relative[index];
+            let rot_relative = arm.rotation_relative[index];
 
             if w.weight == 0f32 {
                 continue;
             }
 
             let vpos_from_bone = vertex_pos - bone.head_from_arm;
+            let vpos_scaled = arm.scale_from_bone(index, &vpos_from_bone);
 
             let bone_tr_diff = (pos_relative - bone.head_from_arm) * w.weight +
-                (rot_relative.rotate_vec3(&vpos_from_bone)-vpos_from_bone)*w.weight;
-            let bone_rt_diff = vec::quat_slerp(
-                vec::Quat::identity(),
-                rot_relative,
-                w.weight as f64);
+                (rot_relative.rotate_vec3(&vpos_scaled)-vpos_from_bone)*w.weight;
+
+            // normals get the inverse scale so they stay perpendicular to the surface
+            let s = arm.scale_relative[index];
+            let inv_scale = vec::Vec3::new(1f64/s.x, 1f64/s.y, 1f64/s.z);
+            let nor_scaled = arm.rotation_rest[index].rotate_vec3(
+                &(arm.rotation_rest[index].conj().rotate_vec3(&vertex_nor) * inv_scale));
 
             translation = translation + bone_tr_diff;
-            rotation = rotation * bone_rt_diff;
+            normal = normal + rot_relative.rotate_vec3(&nor_scaled) * w.weight;
         }
 
         let newpos = vertex_pos + translation;
-        let newnor = rotation.rotate_vec3(&vertex_nor);
+        let newnor = if normal.is_zero() {
+            vertex_nor
+        }
+        else {
+            normal.normalized()
+        };
 
         mesh.set_dirty();
 
@@ -173,3 +668,52 @@ fn update_mesh_with_armature(
 
 }
 
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    fn seek(mode : LoopMode, time : f64) -> Playback
+    {
+        let mut p = Playback::new();
+        p.loop_mode = mode;
+        p.seek(time, 2f64);
+        p
+    }
+
+    #[test]
+    fn test_seek()
+    {
+        assert_eq!(seek(LoopMode::Loop, 1.5f64).get_time(), 1.5f64);
+        assert_eq!(seek(LoopMode::Loop, 5f64).get_time(), 1f64);
+        assert_eq!(seek(LoopMode::Loop, -0.5f64).get_time(), 1.5f64);
+
+        assert_eq!(seek(LoopMode::Once, 5f64).get_time(), 2f64);
+        assert_eq!(seek(LoopMode::Clamp, 5f64).get_time(), 2f64);
+        assert_eq!(seek(LoopMode::Clamp, -1f64).get_time(), 0f64);
+
+        assert_eq!(seek(LoopMode::PingPong, 3f64).get_time(), 1f64);
+        assert_eq!(seek(LoopMode::PingPong, 5f64).get_time(), 1f64);
+
+        let mut p = Playback::new();
+        p.seek(1f64, 0f64);
+        assert_eq!(p.get_time(), 0f64);
+    }
+
+    #[test]
+    fn test_seek_then_update()
+    {
+        // going back after seeking in the second half of a back and forth
+        let mut p = seek(LoopMode::PingPong, 3f64);
+        p.play();
+        p.update(0.5f64, 2f64);
+        assert_eq!(p.get_time(), 0.5f64);
+
+        // the update goes on from the seek time instead of the start
+        let mut p = seek(LoopMode::Clamp, 1f64);
+        p.play();
+        assert!(p.update(1.5f64, 2f64));
+        assert_eq!(p.get_time(), 2f64);
+        assert!(p.is_finished());
+    }
+}
@@ -0,0 +1,527 @@
+use armature;
+use armature::Pose;
+use armature_animation::Playback;
+use vec;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BlendPoint1D
+{
+    pub position : f64,
+    pub action : String
+}
+
+/// Blends actions placed on a line, depending on value (for example the speed).
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct BlendSpace1D
+{
+    pub value : f64,
+    pub points : Vec<BlendPoint1D>
+}
+
+impl BlendSpace1D
+{
+    pub fn new() -> BlendSpace1D
+    {
+        BlendSpace1D {
+            value : 0f64,
+            points : Vec::new()
+        }
+    }
+
+    pub fn add_point(&mut self, position : f64, action : &str)
+    {
+        self.points.push(BlendPoint1D { position : position, action : action.to_owned() });
+    }
+
+    /// weight of each point for the current value
+    pub fn weights(&self) -> Vec<f64>
+    {
+        let mut weights = vec![0f64; self.points.len()];
+
+        let mut sorted : Vec<usize> = (0..self.points.len()).collect();
+        sorted.sort_by(|a, b| {
+            self.points[*a].position.partial_cmp(&self.points[*b].position)
+                .unwrap_or(::std::cmp::Ordering::Equal)
+        });
+
+        let (first, last) = match (sorted.first(), sorted.last()) {
+            (Some(f), Some(l)) => (*f, *l),
+            _ => return weights
+        };
+
+        if self.value <= self.points[first].position {
+            weights[first] = 1f64;
+            return weights;
+        }
+
+        if self.value >= self.points[last].position {
+            weights[last] = 1f64;
+            return weights;
+        }
+
+        for w in sorted.windows(2) {
+            let a = &self.points[w[0]];
+            let b = &self.points[w[1]];
+            if self.value >= a.position && self.value <= b.position {
+                let t = (self.value - a.position) / (b.position - a.position);
+                weights[w[0]] = 1f64 - t;
+                weights[w[1]] = t;
+                break;
+            }
+        }
+
+        weights
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BlendPoint2D
+{
+    pub position : vec::Vec2,
+    pub action : String
+}
+
+/// Blends actions placed on a plane, depending on value (for example speed and direction).
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct BlendSpace2D
+{
+    pub value : vec::Vec2,
+    pub points : Vec<BlendPoint2D>
+}
+
+impl BlendSpace2D
+{
+    pub fn new() -> BlendSpace2D
+    {
+        BlendSpace2D {
+            value : vec::Vec2::zero(),
+            points : Vec::new()
+        }
+    }
+
+    pub fn add_point(&mut self, position : vec::Vec2, action : &str)
+    {
+        self.points.push(BlendPoint2D { position : position, action : action.to_owned() });
+    }
+
+    /// weight of each point for the current value, using gradient bands
+    /// so a point only gets weight from the area between it and its neighbours
+    pub fn weights(&self) -> Vec<f64>
+    {
+        let count = self.points.len();
+        let mut weights = vec![0f64; count];
+        let mut total = 0f64;
+
+        for i in 0..count {
+            let pi = self.points[i].position;
+            let mut w = 1f64;
+
+            for j in 0..count {
+                if i == j {
+                    continue;
+                }
+
+                let pij = self.points[j].position - pi;
+                let len2 = pij.length2();
+                if len2 == 0f64 {
+                    continue;
+                }
+
+                let h = 1f64 - (self.value - pi).dot(pij) / len2;
+                w = w.min(h.max(0f64));
+            }
+
+            weights[i] = w;
+            total = total + w;
+        }
+
+        if total > 0f64 {
+            for w in weights.iter_mut() {
+                *w = *w / total;
+            }
+        }
+
+        weights
+    }
+}
+
+/// What an armature animation plays.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Motion
+{
+    Action(String),
+    BlendSpace1D(BlendSpace1D),
+    BlendSpace2D(BlendSpace2D),
+}
+
+impl Motion
+{
+    /// actions used and their weight
+    pub fn clips(&self) -> Vec<(&str, f64)>
+    {
+        match *self {
+            Motion::Action(ref a) => vec![(a.as_str(), 1f64)],
+            Motion::BlendSpace1D(ref b) => {
+                b.points.iter().zip(b.weights().into_iter())
+                    .map(|(p, w)| (p.action.as_str(), w)).collect()
+            },
+            Motion::BlendSpace2D(ref b) => {
+                b.points.iter().zip(b.weights().into_iter())
+                    .map(|(p, w)| (p.action.as_str(), w)).collect()
+            },
+        }
+    }
+
+    /// True for each bone animated by one of the actions with a weight.
+    pub fn animated_bones(&self, armature : &armature::Armature) -> Vec<bool>
+    {
+        let mut bones = vec![false; armature.bone_count()];
+        for (action, w) in self.clips() {
+            if w <= 0f64 {
+                continue;
+            }
+            if let Some(animated) = armature.action_animated_bones(action) {
+                for (b, a) in bones.iter_mut().zip(animated.into_iter()) {
+                    *b = *b || a;
+                }
+            }
+        }
+
+        bones
+    }
+
+    /// Length in seconds, for blend spaces this is the weighted length of the actions.
+    pub fn length(&self, armature : &armature::Armature) -> f64
+    {
+        let mut length = 0f64;
+        for (action, w) in self.clips() {
+            if let Some(l) = armature.action_length(action) {
+                length = length + l * w;
+            }
+        }
+
+        length
+    }
+
+    /// Markers crossed during the played segments, for blend spaces
+    /// they come from the action with the most weight.
+    pub fn markers_crossed(
+        &self,
+        armature : &armature::Armature,
+        segments : &[(f64, f64)]) -> Vec<armature::Marker>
+    {
+        let clips = self.clips();
+
+        let mut best : Option<(&str, f64)> = None;
+        for &(action, w) in clips.iter() {
+            let better = match best {
+                Some((_, bw)) => w > bw,
+                None => true
+            };
+            if better {
+                best = Some((action, w));
+            }
+        }
+
+        let action = match best {
+            Some((a, _)) => a,
+            None => return Vec::new()
+        };
+
+        // blend spaces play their actions at the same ratio of their length
+        let scale = if clips.len() == 1 {
+            1f64
+        }
+        else {
+            let length = self.length(armature);
+            let l = armature.action_length(action).unwrap_or(0f64);
+            if length > 0f64 { l / length } else { 0f64 }
+        };
+
+        let mut markers = Vec::new();
+        for &(a, b) in segments {
+            markers.extend(armature.markers_between(action, a * scale, b * scale));
+        }
+
+        markers
+    }
+
+    /// The actions of a blend space are synchronized : they are all sampled
+    /// at the same ratio of their length.
+    pub fn sample(&self, armature : &armature::Armature, time : f64) -> Option<Pose>
+    {
+        let clips = self.clips();
+
+        if clips.len() == 1 {
+            return armature.sample_pose(clips[0].0, time);
+        }
+
+        let length = self.length(armature);
+        let phase = if length > 0f64 { time / length } else { 0f64 };
+
+        let mut poses = Vec::with_capacity(clips.len());
+        for (action, w) in clips {
+            if w <= 0f64 {
+                continue;
+            }
+            let l = armature.action_length(action).unwrap_or(0f64);
+            if let Some(p) = armature.sample_pose(action, phase * l) {
+                poses.push((p, w));
+            }
+        }
+
+        let refs : Vec<(&Pose, f64)> = poses.iter().map(|&(ref p, w)| (p, w)).collect();
+        Pose::blend_weighted(&refs)
+    }
+}
+
+/// Fades out a motion, which keeps on playing, during duration seconds.
+#[derive(Clone, Debug)]
+pub struct CrossFade
+{
+    pub from : Motion,
+    pub playback : Playback,
+    pub duration : f64,
+    /// fade which was not done when this one started, from is blended with it
+    /// so the fade starts from the pose which was played
+    pub previous : Option<Box<CrossFade>>,
+    elapsed : f64
+}
+
+impl CrossFade
+{
+    pub fn new(from : Motion, playback : Playback, duration : f64) -> CrossFade
+    {
+        CrossFade {
+            from : from,
+            playback : playback,
+            duration : duration,
+            previous : None,
+            elapsed : 0f64
+        }
+    }
+
+    /// Keeps the fade which was playing, so the motion fading out is the blended one.
+    pub fn with_previous(self, previous : Option<CrossFade>) -> CrossFade
+    {
+        let mut fade = self;
+        fade.previous = previous.map(|p| box p);
+        fade
+    }
+
+    pub fn update(&mut self, dt : f64, armature : &armature::Armature)
+    {
+        self.elapsed = self.elapsed + dt;
+        let length = self.from.length(armature);
+        self.playback.update(dt, length);
+
+        let previous_done = match self.previous {
+            Some(ref mut p) => {
+                p.update(dt, armature);
+                p.is_done()
+            },
+            None => false
+        };
+
+        if previous_done {
+            self.previous = None;
+        }
+    }
+
+    /// weight of the new motion, goes from 0 to 1
+    pub fn weight(&self) -> f64
+    {
+        if self.duration <= 0f64 {
+            1f64
+        }
+        else {
+            (self.elapsed / self.duration).min(1f64)
+        }
+    }
+
+    pub fn is_done(&self) -> bool
+    {
+        self.weight() >= 1f64
+    }
+
+    /// Blends the pose of the motion fading out with the pose of the new one.
+    pub fn blend(&self, armature : &armature::Armature, to : &Pose) -> Pose
+    {
+        let from = match self.from.sample(armature, self.playback.get_time()) {
+            Some(from) => from,
+            None => return to.clone()
+        };
+
+        let from = match self.previous {
+            Some(ref p) => p.blend(armature, &from),
+            None => from
+        };
+
+        from.blend(to, self.weight())
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum LayerBlend
+{
+    /// replaces the pose of the layers below, for the bones the motion animates
+    Override,
+    /// adds the difference between the motion and the reference pose
+    Additive
+}
+
+/// Bones a layer is applied to.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum BoneMask
+{
+    Bones(Vec<usize>),
+    /// the bone with this name and all its descendants
+    Subtree(String)
+}
+
+impl BoneMask
+{
+    /// true for each bone of the armature in the mask
+    pub fn bones(&self, armature : &armature::Armature) -> Vec<bool>
+    {
+        let mut mask = vec![false; armature.bone_count()];
+
+        match *self {
+            BoneMask::Bones(ref bones) => {
+                for b in bones {
+                    if *b < mask.len() {
+                        mask[*b] = true;
+                    }
+                }
+            },
+            BoneMask::Subtree(ref name) => {
+                match armature.find_bone(name) {
+                    Some(root) => {
+                        for i in 0..mask.len() {
+                            mask[i] = armature.is_in_subtree(i, root);
+                        }
+                    },
+                    None => println!("bone mask : no bone '{}' in '{}'", name, armature.name)
+                }
+            }
+        }
+
+        mask
+    }
+}
+
+/// Pose an additive motion is relative to.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ReferencePose
+{
+    Rest,
+    /// first frame of the motion of the layer
+    FirstFrame,
+    /// action and time in seconds
+    Action(String, f64)
+}
+
+/// Motion played on top of the main one.
+#[derive(Clone, Debug)]
+pub struct AnimationLayer
+{
+    pub name : String,
+    pub motion : Option<Motion>,
+    pub playback : Playback,
+    pub weight : f64,
+    pub blend : LayerBlend,
+    pub mask : Option<BoneMask>,
+    pub reference : ReferencePose
+}
+
+impl AnimationLayer
+{
+    pub fn new(name : &str, blend : LayerBlend) -> AnimationLayer
+    {
+        AnimationLayer {
+            name : name.to_owned(),
+            motion : None,
+            playback : Playback::new(),
+            weight : 1f64,
+            blend : blend,
+            mask : None,
+            reference : ReferencePose::FirstFrame
+        }
+    }
+
+    pub fn play_motion(&mut self, motion : Motion)
+    {
+        self.motion = Some(motion);
+        self.playback.stop();
+        self.playback.play();
+    }
+
+    pub fn play_action(&mut self, action : &str)
+    {
+        self.play_motion(Motion::Action(action.to_owned()));
+    }
+
+    pub fn stop(&mut self)
+    {
+        self.motion = None;
+        self.playback.stop();
+    }
+
+    /// Returns true when a motion which is not looping finishes.
+    pub fn update(&mut self, dt : f64, armature : &armature::Armature) -> bool
+    {
+        match self.motion {
+            Some(ref m) => self.playback.update(dt, m.length(armature)),
+            None => false
+        }
+    }
+
+    fn reference_pose(&self, armature : &armature::Armature, motion : &Motion) -> Option<Pose>
+    {
+        match self.reference {
+            ReferencePose::Rest => Some(Pose::new(armature.bone_count())),
+            ReferencePose::FirstFrame => motion.sample(armature, 0f64),
+            ReferencePose::Action(ref a, time) => armature.sample_pose(a, time)
+        }
+    }
+
+    /// Applies the layer on the pose of the layers below.
+    pub fn apply(&self, armature : &armature::Armature, pose : &mut Pose)
+    {
+        let motion = match self.motion {
+            Some(ref m) => m,
+            None => return
+        };
+
+        if self.weight <= 0f64 {
+            return;
+        }
+
+        let sampled = match motion.sample(armature, self.playback.get_time()) {
+            Some(p) => p,
+            None => return
+        };
+
+        let mask = self.mask.as_ref().map(|m| m.bones(armature));
+        let mask = mask.as_ref().map(|m| m.as_slice());
+
+        match self.blend {
+            LayerBlend::Override => {
+                // the bones the motion does not animate are at rest in the sampled pose
+                let mut bones = motion.animated_bones(armature);
+                if let Some(m) = mask {
+                    for (b, in_mask) in bones.iter_mut().zip(m.iter()) {
+                        *b = *b && *in_mask;
+                    }
+                }
+                pose.blend_masked(&sampled, self.weight, Some(bones.as_slice()));
+            },
+            LayerBlend::Additive => {
+                if let Some(reference) = self.reference_pose(armature, motion) {
+                    let delta = sampled.difference(&reference);
+                    pose.add(&delta, self.weight, mask);
+                }
+            }
+        }
+    }
+}
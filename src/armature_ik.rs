@@ -0,0 +1,416 @@
+use armature::ArmatureInstance;
+use transform;
+use vec;
+
+/// Target and pole are in armature space, the weight blends with the animated pose.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TwoBoneIk
+{
+    pub upper : String,
+    pub lower : String,
+    pub target : vec::Vec3,
+    /// the chain bends toward this point, else it keeps its current bend
+    pub pole : Option<vec::Vec3>,
+    pub weight : f64
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ChainAlgorithm
+{
+    Fabrik,
+    Ccd
+}
+
+/// Bones are named from the root of the chain to its tip,
+/// each bone must be the parent of the next one.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChainIk
+{
+    pub bones : Vec<String>,
+    pub target : vec::Vec3,
+    pub algorithm : ChainAlgorithm,
+    pub iterations : usize,
+    /// distance from the target under which the solver stops
+    pub tolerance : f64,
+    pub weight : f64
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum IkSolver
+{
+    TwoBone(TwoBoneIk),
+    Chain(ChainIk)
+}
+
+impl TwoBoneIk
+{
+    pub fn new(upper : &str, lower : &str) -> TwoBoneIk
+    {
+        TwoBoneIk {
+            upper : upper.to_owned(),
+            lower : lower.to_owned(),
+            target : vec::Vec3::zero(),
+            pole : None,
+            weight : 1f64
+        }
+    }
+
+    pub fn solve(&self, arm : &mut ArmatureInstance)
+    {
+        if self.weight <= 0f64 {
+            return;
+        }
+
+        let names = [self.upper.clone(), self.lower.clone()];
+        let chain = match find_chain(arm, &names) {
+            Some(c) => c,
+            None => return
+        };
+
+        let points = chain_points(arm, &chain);
+        let (a, b, c) = (points[0], points[1], points[2]);
+        let l1 = (b - a).length();
+        let l2 = (c - b).length();
+        if l1 <= 0f64 || l2 <= 0f64 {
+            return;
+        }
+
+        let to_target = self.target - a;
+        let mut d = to_target.length();
+        if d < 1e-9f64 {
+            return;
+        }
+
+        let u = to_target / d;
+        d = d.min(l1 + l2 - 1e-6f64).max((l1 - l2).abs() + 1e-6f64);
+
+        let bend = match self.pole {
+            Some(p) => p - a,
+            None => b - a
+        };
+
+        let mut n = bend - u * bend.dot(&u);
+        if n.length2() < 1e-12f64 {
+            n = u ^ vec::Vec3::x();
+            if n.length2() < 1e-12f64 {
+                n = u ^ vec::Vec3::y();
+            }
+        }
+        let n = n.normalized();
+
+        let cos_a = ((l1*l1 + d*d - l2*l2) / (2f64*l1*d)).max(-1f64).min(1f64);
+        let sin_a = (1f64 - cos_a*cos_a).max(0f64).sqrt();
+
+        let solved = [a, a + u * (l1 * cos_a) + n * (l1 * sin_a), a + u * d];
+        apply_points(arm, &chain, &points, &solved, self.weight);
+    }
+}
+
+impl ChainIk
+{
+    pub fn new(bones : &[&str], algorithm : ChainAlgorithm) -> ChainIk
+    {
+        ChainIk {
+            bones : bones.iter().map(|b| (*b).to_owned()).collect(),
+            target : vec::Vec3::zero(),
+            algorithm : algorithm,
+            iterations : 10,
+            tolerance : 0.001f64,
+            weight : 1f64
+        }
+    }
+
+    pub fn solve(&self, arm : &mut ArmatureInstance)
+    {
+        if self.weight <= 0f64 {
+            return;
+        }
+
+        let chain = match find_chain(arm, &self.bones) {
+            Some(c) => c,
+            None => return
+        };
+
+        let points = chain_points(arm, &chain);
+        let mut solved = points.clone();
+
+        match self.algorithm {
+            ChainAlgorithm::Fabrik => fabrik(&mut solved, &self.target, self.iterations, self.tolerance),
+            ChainAlgorithm::Ccd => ccd(&mut solved, &self.target, self.iterations, self.tolerance)
+        }
+
+        apply_points(arm, &chain, &points, &solved, self.weight);
+    }
+}
+
+impl IkSolver
+{
+    /// Must be called after the pose is set.
+    pub fn solve(&self, arm : &mut ArmatureInstance)
+    {
+        match *self {
+            IkSolver::TwoBone(ref s) => s.solve(arm),
+            IkSolver::Chain(ref s) => s.solve(arm)
+        }
+    }
+
+    pub fn set_target(&mut self, target : vec::Vec3)
+    {
+        match *self {
+            IkSolver::TwoBone(ref mut s) => s.target = target,
+            IkSolver::Chain(ref mut s) => s.target = target
+        }
+    }
+
+    pub fn set_weight(&mut self, weight : f64)
+    {
+        match *self {
+            IkSolver::TwoBone(ref mut s) => s.weight = weight,
+            IkSolver::Chain(ref mut s) => s.weight = weight
+        }
+    }
+}
+
+/// Converts a world position to the armature space of the object owning the armature.
+pub fn world_to_armature(owner : &transform::Transform, v : &vec::Vec3) -> vec::Vec3
+{
+    let local = owner.orientation.as_quat().conj().rotate_vec3(&(*v - owner.position));
+    let s = owner.scale;
+    vec::Vec3::new(
+        if s.x != 0f64 { local.x / s.x } else { 0f64 },
+        if s.y != 0f64 { local.y / s.y } else { 0f64 },
+        if s.z != 0f64 { local.z / s.z } else { 0f64 })
+}
+
+/// Bone indices from the names, None if a bone is missing or is not the child of the previous one.
+pub fn find_chain(arm : &ArmatureInstance, names : &[String]) -> Option<Vec<usize>>
+{
+    let mut chain = Vec::with_capacity(names.len());
+
+    for n in names {
+        let index = match arm.find_bone(n) {
+            Some(i) => i,
+            None => {
+                println!("ik : no bone '{}'", n);
+                return None;
+            }
+        };
+
+        if let Some(&previous) = chain.last() {
+            if arm.get_bone(index).parent != Some(previous) {
+                println!("ik : bone '{}' is not a child of the previous bone", n);
+                return None;
+            }
+        }
+
+        chain.push(index);
+    }
+
+    if chain.is_empty() {
+        None
+    }
+    else {
+        Some(chain)
+    }
+}
+
+fn bone_tail(arm : &ArmatureInstance, index : usize) -> vec::Vec3
+{
+    let bone = arm.get_bone(index);
+    let v = arm.scale_from_bone(index, &(bone.tail - bone.head));
+    arm.position_relative[index] + arm.rotation_relative[index].rotate_vec3(&v)
+}
+
+/// Heads of the bones and tail of the last one, in armature space.
+fn chain_points(arm : &ArmatureInstance, chain : &[usize]) -> Vec<vec::Vec3>
+{
+    let mut points : Vec<vec::Vec3> = chain.iter().map(|b| arm.position_relative[*b]).collect();
+    points.push(bone_tail(arm, chain[chain.len() - 1]));
+    points
+}
+
+/// Rotates the bones so the chain goes through the solved points.
+fn apply_points(
+    arm : &mut ArmatureInstance,
+    chain : &[usize],
+    points : &[vec::Vec3],
+    solved : &[vec::Vec3],
+    weight : f64)
+{
+    let mut rotations = Vec::with_capacity(chain.len());
+    for i in 0..chain.len() {
+        let delta = vec::Quat::new_rotation_between(
+            &(points[i+1] - points[i]),
+            &(solved[i+1] - solved[i]));
+        rotations.push(delta * arm.rotation_relative[chain[i]]);
+    }
+
+    let parent_rotation = match arm.get_bone(chain[0]).parent {
+        Some(p) => arm.rotation_relative[p],
+        None => vec::Quat::identity()
+    };
+
+    for i in 0..chain.len() {
+        let parent = if i == 0 { parent_rotation } else { rotations[i-1] };
+        let local = parent.conj() * rotations[i];
+        let bone = arm.get_mut_bone(chain[i]);
+        bone.rotation_diff = vec::quat_slerp(bone.rotation_diff, local, weight);
+    }
+
+    arm.update_relative_coords();
+}
+
+fn direction(from : &vec::Vec3, to : &vec::Vec3) -> vec::Vec3
+{
+    let d = *to - *from;
+    if d.length2() > 0f64 {
+        d.normalized()
+    }
+    else {
+        vec::Vec3::zero()
+    }
+}
+
+fn fabrik(points : &mut Vec<vec::Vec3>, target : &vec::Vec3, iterations : usize, tolerance : f64)
+{
+    let n = points.len() - 1;
+    let lengths : Vec<f64> = (0..n).map(|i| (points[i+1] - points[i]).length()).collect();
+    let total = lengths.iter().fold(0f64, |sum, l| sum + l);
+    let root = points[0];
+
+    // out of reach : the chain points straight to the target
+    if (*target - root).length() >= total {
+        for i in 0..n {
+            let d = direction(&points[i], target);
+            points[i+1] = points[i] + d * lengths[i];
+        }
+        return;
+    }
+
+    for _ in 0..iterations {
+        if (points[n] - *target).length() <= tolerance {
+            break;
+        }
+
+        points[n] = *target;
+        for i in (0..n).rev() {
+            let d = direction(&points[i+1], &points[i]);
+            points[i] = points[i+1] + d * lengths[i];
+        }
+
+        points[0] = root;
+        for i in 0..n {
+            let d = direction(&points[i], &points[i+1]);
+            points[i+1] = points[i] + d * lengths[i];
+        }
+    }
+}
+
+fn ccd(points : &mut Vec<vec::Vec3>, target : &vec::Vec3, iterations : usize, tolerance : f64)
+{
+    let n = points.len() - 1;
+
+    for _ in 0..iterations {
+        if (points[n] - *target).length() <= tolerance {
+            break;
+        }
+
+        for i in (0..n).rev() {
+            let pivot = points[i];
+            let q = vec::Quat::new_rotation_between(&(points[n] - pivot), &(*target - pivot));
+            for j in (i+1)..(n+1) {
+                points[j] = q.rotate_vec3_around(&points[j], &pivot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use armature::{Armature, Bone};
+
+    /// Bones of length 1 along y, each one child of the previous.
+    fn chain_armature(count : usize) -> Armature
+    {
+        let mut arm = Armature::new("chain");
+        for i in 0..count {
+            arm.add_bone(Bone {
+                name : format!("bone{}", i),
+                position_base : vec::Vec3::zero(),
+                rotation_base : vec::Quat::identity(),
+                parent : if i == 0 { None } else { Some(i - 1) },
+                children : Vec::new(),
+                head_from_arm : vec::Vec3::new(0f64, i as f64, 0f64),
+                head : vec::Vec3::zero(),
+                tail : vec::Vec3::new(0f64, 1f64, 0f64),
+                position_diff : vec::Vec3::zero(),
+                rotation_diff : vec::Quat::identity(),
+                scale_diff : vec::Vec3::one(),
+            });
+        }
+
+        arm
+    }
+
+    fn tip(arm : &ArmatureInstance, count : usize) -> vec::Vec3
+    {
+        let chain : Vec<usize> = (0..count).collect();
+        chain_points(arm, &chain)[count]
+    }
+
+    fn solve_two_bone(target : vec::Vec3) -> ArmatureInstance
+    {
+        let mut arm = chain_armature(2).create_instance();
+        let mut ik = TwoBoneIk::new("bone0", "bone1");
+        ik.target = target;
+        ik.solve(&mut arm);
+        arm
+    }
+
+    fn solve_chain(algorithm : ChainAlgorithm, target : vec::Vec3) -> ArmatureInstance
+    {
+        let mut arm = chain_armature(3).create_instance();
+        let mut ik = ChainIk::new(&["bone0", "bone1", "bone2"], algorithm);
+        ik.target = target;
+        ik.iterations = 50;
+        ik.solve(&mut arm);
+        arm
+    }
+
+    #[test]
+    fn test_two_bone_reachable()
+    {
+        let target = vec::Vec3::new(1f64, 1f64, 0f64);
+        let arm = solve_two_bone(target);
+        assert!((tip(&arm, 2) - target).length() < 1e-4f64);
+    }
+
+    #[test]
+    fn test_two_bone_unreachable()
+    {
+        let arm = solve_two_bone(vec::Vec3::new(3f64, 0f64, 0f64));
+        assert!((tip(&arm, 2) - vec::Vec3::new(2f64, 0f64, 0f64)).length() < 1e-3f64);
+    }
+
+    #[test]
+    fn test_chain_reachable()
+    {
+        let target = vec::Vec3::new(1.5f64, 1.5f64, 0f64);
+        for algorithm in [ChainAlgorithm::Fabrik, ChainAlgorithm::Ccd].iter() {
+            let arm = solve_chain(*algorithm, target);
+            assert!((tip(&arm, 3) - target).length() < 1e-2f64, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_chain_unreachable()
+    {
+        let target = vec::Vec3::new(0f64, 0f64, 10f64);
+        for algorithm in [ChainAlgorithm::Fabrik, ChainAlgorithm::Ccd].iter() {
+            let arm = solve_chain(*algorithm, target);
+            assert!((tip(&arm, 3) - vec::Vec3::new(0f64, 0f64, 3f64)).length() < 1e-2f64, "{:?}", algorithm);
+        }
+    }
+}
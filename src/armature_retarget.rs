@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+
+use armature::{Armature, Action, Curve, Data, FrameData, Pose};
+use vec;
+
+pub struct RetargetOptions
+{
+    /// source bone name to target bone name, when empty the bones are matched by name
+    pub mapping : HashMap<String, String>,
+    /// the target bones take the orientation of the source bones,
+    /// instead of the same rotation from their own rest pose
+    pub compensate_rest : bool,
+    /// scale of the translations, None to use the ratio of the bone sizes
+    pub translation_scale : Option<f64>
+}
+
+impl RetargetOptions
+{
+    pub fn new() -> RetargetOptions
+    {
+        RetargetOptions {
+            mapping : HashMap::new(),
+            compensate_rest : true,
+            translation_scale : None
+        }
+    }
+}
+
+/// Source bone of each target bone.
+fn bone_map(source : &Armature, target : &Armature, options : &RetargetOptions)
+    -> Vec<Option<usize>>
+{
+    let mut map = vec![None; target.bone_count()];
+
+    if options.mapping.is_empty() {
+        for t in 0..target.bone_count() {
+            map[t] = source.find_bone(&target.get_bone(t).name);
+        }
+    }
+    else {
+        for (s, t) in options.mapping.iter() {
+            match (source.find_bone(s), target.find_bone(t)) {
+                (Some(si), Some(ti)) => map[ti] = Some(si),
+                _ => println!("retarget : cannot map bone '{}' to '{}'", s, t)
+            }
+        }
+    }
+
+    map
+}
+
+fn bone_length(armature : &Armature, index : usize) -> f64
+{
+    let b = armature.get_bone(index);
+    (b.tail - b.head).length()
+}
+
+/// Root translations are scaled with the height of the root, the other ones
+/// with the length of their parent.
+fn translation_scale(source : &Armature, target : &Armature, s : usize, t : usize) -> f64
+{
+    let (ls, lt) = match (source.get_bone(s).parent, target.get_bone(t).parent) {
+        (Some(ps), Some(pt)) => (bone_length(source, ps), bone_length(target, pt)),
+        _ => (source.get_bone(s).head_from_arm.length(), target.get_bone(t).head_from_arm.length())
+    };
+
+    if ls > 0f64 { lt / ls } else { 1f64 }
+}
+
+/// Rotation of each bone in armature space for the pose.
+fn armature_rotations(armature : &Armature, pose : &Pose) -> Vec<vec::Quat>
+{
+    fn rotation(armature : &Armature, pose : &Pose, i : usize) -> vec::Quat
+    {
+        match armature.get_bone(i).parent {
+            Some(p) => rotation(armature, pose, p) * pose.rotation[i],
+            None => pose.rotation[i]
+        }
+    }
+
+    (0..armature.bone_count()).map(|i| rotation(armature, pose, i)).collect()
+}
+
+/// Bones of the armature animated by the action : position, rotation and scale.
+fn animated(action : &Action, bone_count : usize) -> Vec<(bool, bool, bool)>
+{
+    let mut animated = vec![(false, false, false); bone_count];
+
+    for c in action.get_curves() {
+        if c.bone_index >= bone_count {
+            continue;
+        }
+        let a = &mut animated[c.bone_index];
+        match c.data {
+            Data::Position => a.0 = true,
+            Data::Quaternion | Data::Euler(_) => a.1 = true,
+            Data::Scale => a.2 = true,
+            Data::Morph => {}
+        }
+    }
+
+    animated
+}
+
+/// Creates an action for the target armature from an action of the source armature.
+/// The action is baked at the key frames of the source action, it can then be added
+/// with Armature::add_action.
+pub fn retarget(
+    source : &Armature,
+    target : &Armature,
+    action_name : &str,
+    new_name : &str,
+    options : &RetargetOptions) -> Option<Action>
+{
+    let action = match source.find_action(action_name) {
+        Some(a) => a,
+        None => {
+            println!("retarget : no action '{}' in '{}'", action_name, source.name);
+            return None;
+        }
+    };
+
+    let map = bone_map(source, target, options);
+    let target_count = target.bone_count();
+    let source_animated = animated(action, source.bone_count());
+
+    // rotation taking the rest orientation of the target bone to the source one
+    let correction : Vec<vec::Quat> = (0..target_count).map(|t| {
+        match map[t] {
+            Some(s) if options.compensate_rest => {
+                source.bone_rest_rotation(s) * target.bone_rest_rotation(t).conj()
+            },
+            _ => vec::Quat::identity()
+        }
+    }).collect();
+
+    let scales : Vec<f64> = (0..target_count).map(|t| {
+        match (options.translation_scale, map[t]) {
+            (Some(k), _) => k,
+            (None, Some(s)) => translation_scale(source, target, s, t),
+            (None, None) => 1f64
+        }
+    }).collect();
+
+    let mut positions = Vec::new();
+    let mut rotations = Vec::new();
+    let mut scales_curves = Vec::new();
+
+    for t in 0..target_count {
+        let s = match map[t] {
+            Some(s) => s,
+            None => continue
+        };
+        let name = &target.get_bone(t).name;
+        let (p, r, sc) = source_animated[s];
+
+        if p {
+            positions.push(Curve::new_empty(name, t, Data::Position));
+        }
+        if r || options.compensate_rest {
+            rotations.push(Curve::new_empty(name, t, Data::Quaternion));
+        }
+        if sc {
+            scales_curves.push(Curve::new_empty(name, t, Data::Scale));
+        }
+    }
+
+    let start = action.get_frame_start();
+
+    for frame in action.key_frames() {
+        let time = (frame - start) / source.frame_rate;
+        let target_frame = start + time * target.frame_rate;
+
+        let mut pose = Pose::new(source.bone_count());
+        action.apply(source.frame_rate, time, &mut pose);
+        let source_rot = armature_rotations(source, &pose);
+
+        // armature space rotations of the target bones, in bone order
+        let mut target_rot = vec![vec::Quat::identity(); target_count];
+        let mut done = vec![false; target_count];
+        for t in 0..target_count {
+            compute_target_rotation(target, &map, &correction, &source_rot, &mut target_rot, &mut done, t);
+        }
+
+        let parent_rot = |t : usize, rots : &[vec::Quat]| {
+            match target.get_bone(t).parent {
+                Some(p) => rots[p],
+                None => vec::Quat::identity()
+            }
+        };
+
+        for c in rotations.iter_mut() {
+            let t = c.bone_index;
+            let local = parent_rot(t, &target_rot).conj() * target_rot[t];
+            c.add_frame(target_frame, FrameData::Orientation(local));
+        }
+
+        for c in positions.iter_mut() {
+            let t = c.bone_index;
+            let s = map[t].unwrap();
+            let source_parent = match source.get_bone(s).parent {
+                Some(p) => source_rot[p],
+                None => vec::Quat::identity()
+            };
+            let moved = source_parent.rotate_vec3(&(pose.position[s] * scales[t]));
+            let local = parent_rot(t, &target_rot).conj().rotate_vec3(&moved);
+            c.add_frame(target_frame, FrameData::Position(local));
+        }
+
+        for c in scales_curves.iter_mut() {
+            let s = map[c.bone_index].unwrap();
+            c.add_frame(target_frame, FrameData::Scale(pose.scale[s]));
+        }
+    }
+
+    let mut out = Action::new_empty(new_name);
+    for c in positions.into_iter().chain(rotations.into_iter()).chain(scales_curves.into_iter()) {
+        if !c.frames.is_empty() {
+            out.add_curve(c);
+        }
+    }
+
+    Some(out)
+}
+
+fn compute_target_rotation(
+    target : &Armature,
+    map : &[Option<usize>],
+    correction : &[vec::Quat],
+    source_rot : &[vec::Quat],
+    target_rot : &mut Vec<vec::Quat>,
+    done : &mut Vec<bool>,
+    t : usize)
+{
+    if done[t] {
+        return;
+    }
+
+    let parent = target.get_bone(t).parent;
+    if let Some(p) = parent {
+        compute_target_rotation(target, map, correction, source_rot, target_rot, done, p);
+    }
+
+    target_rot[t] = match (map[t], parent) {
+        (Some(s), _) => source_rot[s] * correction[t],
+        // bones which are not mapped follow their parent
+        (None, Some(p)) => target_rot[p],
+        (None, None) => vec::Quat::identity()
+    };
+
+    done[t] = true;
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use armature::Bone;
+
+    /// hips at the origin and a spine on top of it.
+    fn armature(hips_length : f64, spine_length : f64, spine_rest : vec::Quat) -> Armature
+    {
+        let mut arm = Armature::new("test");
+        let bones = [
+            ("hips", None, vec::Quat::identity(), hips_length, vec::Vec3::zero()),
+            ("spine", Some(0), spine_rest, spine_length, vec::Vec3::new(0f64, hips_length, 0f64))];
+
+        for &(name, parent, rest, length, head_from_arm) in bones.iter() {
+            arm.add_bone(Bone {
+                name : String::from(name),
+                position_base : vec::Vec3::zero(),
+                rotation_base : rest,
+                parent : parent,
+                children : Vec::new(),
+                head_from_arm : head_from_arm,
+                head : vec::Vec3::zero(),
+                tail : vec::Vec3::new(0f64, length, 0f64),
+                position_diff : vec::Vec3::zero(),
+                rotation_diff : vec::Quat::identity(),
+                scale_diff : vec::Vec3::one(),
+            });
+        }
+
+        arm
+    }
+
+    #[test]
+    fn test_retarget_different_rest_and_lengths()
+    {
+        let mut source = armature(1f64, 1f64, vec::Quat::identity());
+        let mut target = armature(2f64, 3f64, vec::Quat::new_axis_angle_deg(vec::Vec3::z(), 90f64));
+
+        let bend = vec::Quat::new_axis_angle_deg(vec::Vec3::x(), 45f64);
+
+        let mut hips = Curve::new_empty("hips", 0, Data::Position);
+        hips.add_frame(0f64, FrameData::Position(vec::Vec3::zero()));
+        hips.add_frame(10f64, FrameData::Position(vec::Vec3::new(1f64, 0f64, 0f64)));
+        let mut spine_position = Curve::new_empty("spine", 1, Data::Position);
+        spine_position.add_frame(0f64, FrameData::Position(vec::Vec3::zero()));
+        spine_position.add_frame(10f64, FrameData::Position(vec::Vec3::new(0f64, 1f64, 0f64)));
+        let mut spine_rotation = Curve::new_empty("spine", 1, Data::Quaternion);
+        spine_rotation.add_frame(0f64, FrameData::Orientation(vec::Quat::identity()));
+        spine_rotation.add_frame(10f64, FrameData::Orientation(bend));
+
+        let mut action = Action::new_empty("walk");
+        action.add_curve(hips);
+        action.add_curve(spine_position);
+        action.add_curve(spine_rotation);
+        source.add_action(action);
+
+        let retargeted = retarget(&source, &target, "walk", "walk", &RetargetOptions::new()).unwrap();
+        target.add_action(retargeted);
+
+        let time = 10f64 / source.frame_rate;
+        let pose = target.sample_pose("walk", time).unwrap();
+
+        // the root has no height, its translation is not scaled
+        assert!((pose.position[0] - vec::Vec3::new(1f64, 0f64, 0f64)).length() < 1e-6f64);
+        // the hips of the target are twice as long
+        assert!((pose.position[1] - vec::Vec3::new(0f64, 2f64, 0f64)).length() < 1e-6f64);
+
+        // the spine has the orientation of the source spine, despite its rest orientation
+        let mut source_instance = source.create_instance();
+        source_instance.set_pose(&source, "walk", time);
+        let mut target_instance = target.create_instance();
+        target_instance.set_pose(&target, "walk", time);
+
+        let expected = source_instance.bone_transform(1).orientation.as_quat();
+        let got = target_instance.bone_transform(1).orientation.as_quat();
+        assert!(expected.normalized().dot(&got.normalized()).abs() > 1f64 - 1e-9f64);
+    }
+}
@@ -0,0 +1,65 @@
+use armature::ArmatureInstance;
+use transform;
+use vec;
+
+/// Point attached to a bone, for example to hold a weapon in a hand.
+/// The offset is in the frame of the bone.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BoneSocket
+{
+    pub bone : String,
+    pub position : vec::Vec3,
+    pub rotation : vec::Quat
+}
+
+impl BoneSocket
+{
+    pub fn new(bone : &str) -> BoneSocket
+    {
+        BoneSocket {
+            bone : bone.to_owned(),
+            position : vec::Vec3::zero(),
+            rotation : vec::Quat::identity()
+        }
+    }
+
+    /// World transform of the socket, owner is the transform of the object owning the armature.
+    pub fn world_transform(&self, arm : &ArmatureInstance, owner : &transform::Transform)
+        -> Option<transform::Transform>
+    {
+        let index = match arm.find_bone(&self.bone) {
+            Some(i) => i,
+            None => {
+                println!("socket : no bone '{}'", self.bone);
+                return None;
+            }
+        };
+
+        let bone = arm.bone_world_transform(index, owner);
+        let rot = bone.orientation.as_quat();
+
+        Some(transform::Transform::from_position_orientation_scale(
+            bone.position + rot.rotate_vec3(&(self.position * bone.scale)),
+            transform::Orientation::new_with_quat(&(rot * self.rotation)),
+            bone.scale))
+    }
+
+    /// Moves the attached transform on the socket, to call each frame after the armature is updated.
+    /// The attached transform keeps its scale.
+    pub fn attach(
+        &self,
+        arm : &ArmatureInstance,
+        owner : &transform::Transform,
+        attached : &mut transform::Transform) -> bool
+    {
+        match self.world_transform(arm, owner) {
+            Some(t) => {
+                attached.position = t.position;
+                attached.orientation.set_with_quat(t.orientation.as_quat());
+                attached.set_as_dirty();
+                true
+            },
+            None => false
+        }
+    }
+}
@@ -0,0 +1,1012 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::mem;
+use std::ptr;
+use libc::c_void;
+
+use mesh::{CglBuffer, BufferType};
+use shader::{CglShader, CglShaderAttribute, CglShaderUniform};
+use texture::CglTexture;
+use fbo::{CglFbo, Attachment};
+use material::{Blend, Cull, PolygonOffset};
+
+/// Everything the renderer asks to the graphics api.
+/// The handles are opaque, a backend which is not cgl can give any non null value.
+pub trait GraphicsBackend
+{
+    /// data is count values of 4 bytes, f32 or u32 for an index buffer
+    fn buffer_init(&mut self, buffer_type : BufferType, data : *const c_void, count : usize)
+        -> *const CglBuffer;
+    fn buffer_update(&mut self, buffer : *const CglBuffer, data : *const c_void, count : usize);
+    fn attribute_send(&mut self, att : *const CglShaderAttribute, buffer : *const CglBuffer);
+
+    fn shader_init(&mut self, vert : &str, frag : &str) -> *const CglShader;
+    fn shader_use(&mut self, shader : *const CglShader);
+    fn shader_attributes(&mut self, shader : *const CglShader)
+        -> HashMap<String, *const CglShaderAttribute>;
+    fn shader_uniforms(&mut self, shader : *const CglShader)
+        -> HashMap<String, *const CglShaderUniform>;
+
+    /// Uniform found by name, null if the shader does not use it.
+    /// GL only lists the first element of the arrays, the other ones are found with this.
+    fn shader_uniform(&mut self, shader : *const CglShader, name : &str) -> *const CglShaderUniform
+    {
+        ptr::null()
+    }
+
+    fn uniform_int(&mut self, uni : *const CglShaderUniform, value : i32);
+    fn uniform_float(&mut self, uni : *const CglShaderUniform, value : f32);
+    fn uniform_vec2(&mut self, uni : *const CglShaderUniform, x : f32, y : f32);
+    fn uniform_vec3(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32);
+    fn uniform_vec4(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32, w : f32);
+    fn uniform_mat4(&mut self, uni : *const CglShaderUniform, m : &[f32; 16]);
+    fn uniform_texture(&mut self, uni : *const CglShaderUniform, tex : *const CglTexture, index : u32);
+    fn uniform_fbo(
+        &mut self,
+        uni : *const CglShaderUniform,
+        fbo : *const CglFbo,
+        attachment : Attachment,
+        index : u32);
+
+    /// rgba8 pixels
+    fn texture_init(&mut self, data : &[u8], width : u32, height : u32) -> *const CglTexture;
+
+    fn fbo_create(&mut self) -> *const CglFbo;
+    fn fbo_use(&mut self, fbo : *const CglFbo);
+    fn fbo_use_end(&mut self);
+    fn fbo_resize(&mut self, fbo : *const CglFbo, w : i32, h : i32);
+    fn fbo_destroy(&mut self, fbo : *const CglFbo);
+
+    fn draw(&mut self, vertex_count : usize);
+    fn draw_lines(&mut self, vertex_count : usize);
+    fn draw_faces(&mut self, buffer : *const CglBuffer, index_count : usize);
+    fn draw_end(&mut self);
+    fn clear(&mut self);
+
+    /// True when the render state calls below change how the next draws are done.
+    fn supports_render_state(&self) -> bool
+    {
+        false
+    }
+
+    /// Blending of the next draws, off by default.
+    fn blend(&mut self, blend : Blend)
+    {
+    }
+
+    /// Depth writing of the next draws, on by default.
+    fn depth_write(&mut self, enabled : bool)
+    {
+    }
+
+    /// Depth testing of the next draws, on by default.
+    fn depth_test(&mut self, enabled : bool)
+    {
+    }
+
+    /// Culling of the next draws, off by default.
+    fn cull(&mut self, cull : Cull)
+    {
+    }
+
+    fn polygon_offset(&mut self, offset : Option<PolygonOffset>)
+    {
+    }
+
+    /// Draws the edges of the faces instead of filling them.
+    fn wireframe(&mut self, enabled : bool)
+    {
+    }
+
+    /// True when the instanced calls below draw something.
+    fn supports_instancing(&self) -> bool
+    {
+        false
+    }
+
+    /// The buffer gives components floats per instance instead of per vertex,
+    /// 16 for a mat4 attribute.
+    fn instance_attribute_send(
+        &mut self,
+        att : *const CglShaderAttribute,
+        buffer : *const CglBuffer,
+        components : usize)
+    {
+    }
+
+    fn draw_faces_instanced(
+        &mut self,
+        buffer : *const CglBuffer,
+        index_count : usize,
+        instance_count : usize)
+    {
+    }
+}
+
+thread_local!(static BACKEND : RefCell<Box<GraphicsBackend>> = RefCell::new(default_backend()));
+
+#[cfg(feature = "cgl")]
+fn default_backend() -> Box<GraphicsBackend>
+{
+    box CglBackend
+}
+
+#[cfg(not(feature = "cgl"))]
+fn default_backend() -> Box<GraphicsBackend>
+{
+    box RecordingBackend::new()
+}
+
+/// Sets the backend used by the current thread and returns the old one.
+pub fn set_backend(backend : Box<GraphicsBackend>) -> Box<GraphicsBackend>
+{
+    BACKEND.with(|b| mem::replace(&mut *b.borrow_mut(), backend))
+}
+
+/// Calls f with the backend of the current thread, f must not call with_backend.
+pub fn with_backend<R, F : FnOnce(&mut GraphicsBackend) -> R>(f : F) -> R
+{
+    BACKEND.with(|b| f(&mut **b.borrow_mut()))
+}
+
+fn handle<T>(n : usize) -> *const T
+{
+    n as *const T
+}
+
+fn handle_index<T>(p : *const T) -> usize
+{
+    p as usize
+}
+
+#[cfg(feature = "cgl")]
+pub use self::cgl::CglBackend;
+
+#[cfg(feature = "cgl")]
+mod cgl
+{
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::mem;
+    use libc::{c_char, c_float, c_int, c_uint, c_void};
+
+    use mesh::{CglBuffer, BufferType};
+    use shader::{CglShader, CglShaderAttribute, CglShaderUniform};
+    use texture::CglTexture;
+    use fbo::{CglFbo, Attachment};
+    use material::{Blend, Cull, PolygonOffset};
+    use util;
+    use super::GraphicsBackend;
+
+    type ShaderUniformAddFn = extern fn(
+        data : *const c_void,
+        name : *const c_char,
+        cgl_uni : *const CglShaderUniform);
+
+    type ShaderAttributeAddFn = extern fn(
+        data : *const c_void,
+        name : *const c_char,
+        cgl_att : *const CglShaderAttribute);
+
+    #[link(name = "cypher")]
+    extern {
+        fn cgl_buffer_init(data : *const c_void, count : c_uint) -> *const CglBuffer;
+        fn cgl_buffer_index_init(data : *const c_void, count : c_uint) -> *const CglBuffer;
+        fn cgl_buffer_update(buffer : *const CglBuffer, data : *const c_void, count : c_uint);
+        fn cgl_shader_attribute_send(att : *const CglShaderAttribute, buffer : *const CglBuffer);
+
+        fn cgl_shader_init_string(vert : *const c_char, frag : *const c_char) -> *const CglShader;
+        fn cgl_shader_use(shader : *const CglShader);
+        fn cgl_shader_attributes_init(
+            shader : *const CglShader,
+            cb : ShaderAttributeAddFn,
+            data : *const c_void);
+        fn cgl_shader_uniforms_init(
+            shader : *const CglShader,
+            cb : ShaderUniformAddFn,
+            data : *const c_void);
+        fn cgl_shader_uniform_new(shader : *const CglShader, name : *const c_char) -> *const CglShaderUniform;
+
+        fn cgl_shader_uniform_int_set(uniform : *const CglShaderUniform, value : c_int);
+        fn cgl_shader_uniform_float_set(uniform : *const CglShaderUniform, value : c_float);
+        fn cgl_shader_uniform_vec2_set(uniform : *const CglShaderUniform, x : c_float, y : c_float);
+        fn cgl_shader_uniform_vec3_set(
+            uniform : *const CglShaderUniform,
+            x : c_float,
+            y : c_float,
+            z : c_float);
+        fn cgl_shader_uniform_vec4_set(
+            uniform : *const CglShaderUniform,
+            x : c_float,
+            y : c_float,
+            z : c_float,
+            w : c_float);
+        fn cgl_shader_uniform_mat4_set(uniform : *const CglShaderUniform, x : *const c_float);
+        fn cgl_shader_uniform_texture_set(
+            uniform : *const CglShaderUniform,
+            tex : *const CglTexture,
+            index : c_uint);
+        fn cgl_shader_uniform_fbo_depth_set(
+            uniform : *const CglShaderUniform,
+            fbo : *const CglFbo,
+            index : c_uint);
+        fn cgl_shader_uniform_fbo_color_set(
+            uniform : *const CglShaderUniform,
+            fbo : *const CglFbo,
+            index : c_uint);
+
+        fn cgl_texture_init(
+            data : *const c_void,
+            internal_format : c_uint,
+            width : c_uint,
+            height : c_uint) -> *const CglTexture;
+
+        fn cgl_create_fbo() -> *const CglFbo;
+        fn cgl_fbo_use(fbo : *const CglFbo);
+        fn cgl_fbo_use_end();
+        fn cgl_fbo_resize(fbo : *const CglFbo, w : c_int, h : c_int);
+        fn cgl_fbo_destroy(fbo : *const CglFbo);
+
+        fn cgl_draw(vertex_count : c_uint);
+        fn cgl_draw_lines(vertex_count : c_uint);
+        fn cgl_draw_faces(buffer : *const CglBuffer, index_count : c_uint);
+        fn cgl_draw_end();
+        fn cgl_clear();
+    }
+
+    #[cfg(feature = "render_state")]
+    #[link(name = "cypher")]
+    extern {
+        fn cgl_blend(src : c_uint, dst : c_uint);
+        fn cgl_blend_disable();
+        fn cgl_depth_write(enabled : c_int);
+        fn cgl_depth_test(enabled : c_int);
+        fn cgl_cull(mode : c_uint);
+        fn cgl_cull_disable();
+        fn cgl_polygon_offset(factor : c_float, units : c_float);
+        fn cgl_polygon_offset_disable();
+        fn cgl_polygon_mode(mode : c_uint);
+    }
+
+    // gl blend factors
+    #[cfg(feature = "render_state")]
+    const GL_ZERO : c_uint = 0;
+    #[cfg(feature = "render_state")]
+    const GL_ONE : c_uint = 1;
+    #[cfg(feature = "render_state")]
+    const GL_SRC_ALPHA : c_uint = 0x0302;
+    #[cfg(feature = "render_state")]
+    const GL_ONE_MINUS_SRC_ALPHA : c_uint = 0x0303;
+    #[cfg(feature = "render_state")]
+    const GL_DST_COLOR : c_uint = 0x0306;
+    #[cfg(feature = "render_state")]
+    const GL_FRONT : c_uint = 0x0404;
+    #[cfg(feature = "render_state")]
+    const GL_BACK : c_uint = 0x0405;
+    #[cfg(feature = "render_state")]
+    const GL_LINE : c_uint = 0x1B01;
+    #[cfg(feature = "render_state")]
+    const GL_FILL : c_uint = 0x1B02;
+
+    #[cfg(feature = "instancing")]
+    #[link(name = "cypher")]
+    extern {
+        fn cgl_shader_attribute_instance_send(
+            att : *const CglShaderAttribute,
+            buffer : *const CglBuffer,
+            components : c_uint);
+        fn cgl_draw_faces_instanced(
+            buffer : *const CglBuffer,
+            index_count : c_uint,
+            instance_count : c_uint);
+    }
+
+    extern fn shader_uniform_add(
+        data : *const c_void,
+        name : *const c_char,
+        cgl_uni : *const CglShaderUniform)
+    {
+        let uniforms : &mut HashMap<String, *const CglShaderUniform> = unsafe {mem::transmute(data) };
+        uniforms.insert(util::c_char_to_string(name), cgl_uni);
+    }
+
+    extern fn shader_attribute_add(
+        data : *const c_void,
+        name : *const c_char,
+        cgl_att : *const CglShaderAttribute)
+    {
+        let attributes : &mut HashMap<String, *const CglShaderAttribute> = unsafe {mem::transmute(data) };
+        attributes.insert(util::c_char_to_string(name), cgl_att);
+    }
+
+    /// Backend calling the cypher library.
+    pub struct CglBackend;
+
+    impl GraphicsBackend for CglBackend
+    {
+        fn buffer_init(&mut self, buffer_type : BufferType, data : *const c_void, count : usize)
+            -> *const CglBuffer
+        {
+            match buffer_type {
+                BufferType::Index => unsafe { cgl_buffer_index_init(data, count as c_uint) },
+                _ => unsafe { cgl_buffer_init(data, count as c_uint) }
+            }
+        }
+
+        fn buffer_update(&mut self, buffer : *const CglBuffer, data : *const c_void, count : usize)
+        {
+            unsafe { cgl_buffer_update(buffer, data, count as c_uint); }
+        }
+
+        fn attribute_send(&mut self, att : *const CglShaderAttribute, buffer : *const CglBuffer)
+        {
+            unsafe { cgl_shader_attribute_send(att, buffer); }
+        }
+
+        fn shader_init(&mut self, vert : &str, frag : &str) -> *const CglShader
+        {
+            let vertc = CString::new(vert.as_bytes()).unwrap();
+            let fragc = CString::new(frag.as_bytes()).unwrap();
+            unsafe { cgl_shader_init_string(vertc.as_ptr(), fragc.as_ptr()) }
+        }
+
+        fn shader_use(&mut self, shader : *const CglShader)
+        {
+            unsafe { cgl_shader_use(shader); }
+        }
+
+        fn shader_attributes(&mut self, shader : *const CglShader)
+            -> HashMap<String, *const CglShaderAttribute>
+        {
+            let mut attributes = HashMap::new();
+            unsafe { cgl_shader_attributes_init(
+                    shader,
+                    shader_attribute_add,
+                    mem::transmute(&mut attributes)); }
+            attributes
+        }
+
+        fn shader_uniforms(&mut self, shader : *const CglShader)
+            -> HashMap<String, *const CglShaderUniform>
+        {
+            let mut uniforms = HashMap::new();
+            unsafe { cgl_shader_uniforms_init(
+                    shader,
+                    shader_uniform_add,
+                    mem::transmute(&mut uniforms)); }
+            uniforms
+        }
+
+        fn shader_uniform(&mut self, shader : *const CglShader, name : &str) -> *const CglShaderUniform
+        {
+            let namec = CString::new(name.as_bytes()).unwrap();
+            unsafe { cgl_shader_uniform_new(shader, namec.as_ptr()) }
+        }
+
+        fn uniform_int(&mut self, uni : *const CglShaderUniform, value : i32)
+        {
+            unsafe { cgl_shader_uniform_int_set(uni, value); }
+        }
+
+        fn uniform_float(&mut self, uni : *const CglShaderUniform, value : f32)
+        {
+            unsafe { cgl_shader_uniform_float_set(uni, value); }
+        }
+
+        fn uniform_vec2(&mut self, uni : *const CglShaderUniform, x : f32, y : f32)
+        {
+            unsafe { cgl_shader_uniform_vec2_set(uni, x, y); }
+        }
+
+        fn uniform_vec3(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32)
+        {
+            unsafe { cgl_shader_uniform_vec3_set(uni, x, y, z); }
+        }
+
+        fn uniform_vec4(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32, w : f32)
+        {
+            unsafe { cgl_shader_uniform_vec4_set(uni, x, y, z, w); }
+        }
+
+        fn uniform_mat4(&mut self, uni : *const CglShaderUniform, m : &[f32; 16])
+        {
+            unsafe { cgl_shader_uniform_mat4_set(uni, m.as_ptr()); }
+        }
+
+        fn uniform_texture(&mut self, uni : *const CglShaderUniform, tex : *const CglTexture, index : u32)
+        {
+            unsafe { cgl_shader_uniform_texture_set(uni, tex, index); }
+        }
+
+        fn uniform_fbo(
+            &mut self,
+            uni : *const CglShaderUniform,
+            fbo : *const CglFbo,
+            attachment : Attachment,
+            index : u32)
+        {
+            match attachment {
+                Attachment::Depth => unsafe { cgl_shader_uniform_fbo_depth_set(uni, fbo, index); },
+                Attachment::Color => unsafe { cgl_shader_uniform_fbo_color_set(uni, fbo, index); }
+            }
+        }
+
+        fn texture_init(&mut self, data : &[u8], width : u32, height : u32) -> *const CglTexture
+        {
+            unsafe { cgl_texture_init(mem::transmute(data.as_ptr()), 4, width, height) }
+        }
+
+        fn fbo_create(&mut self) -> *const CglFbo
+        {
+            unsafe { cgl_create_fbo() }
+        }
+
+        fn fbo_use(&mut self, fbo : *const CglFbo)
+        {
+            unsafe { cgl_fbo_use(fbo); }
+        }
+
+        fn fbo_use_end(&mut self)
+        {
+            unsafe { cgl_fbo_use_end(); }
+        }
+
+        fn fbo_resize(&mut self, fbo : *const CglFbo, w : i32, h : i32)
+        {
+            unsafe { cgl_fbo_resize(fbo, w, h); }
+        }
+
+        fn fbo_destroy(&mut self, fbo : *const CglFbo)
+        {
+            unsafe { cgl_fbo_destroy(fbo); }
+        }
+
+        fn draw(&mut self, vertex_count : usize)
+        {
+            unsafe { cgl_draw(vertex_count as c_uint); }
+        }
+
+        fn draw_lines(&mut self, vertex_count : usize)
+        {
+            unsafe { cgl_draw_lines(vertex_count as c_uint); }
+        }
+
+        fn draw_faces(&mut self, buffer : *const CglBuffer, index_count : usize)
+        {
+            unsafe { cgl_draw_faces(buffer, index_count as c_uint); }
+        }
+
+        fn draw_end(&mut self)
+        {
+            unsafe { cgl_draw_end(); }
+        }
+
+        fn clear(&mut self)
+        {
+            unsafe { cgl_clear(); }
+        }
+
+        #[cfg(feature = "render_state")]
+        fn supports_render_state(&self) -> bool
+        {
+            true
+        }
+
+        #[cfg(feature = "render_state")]
+        fn blend(&mut self, blend : Blend)
+        {
+            let (src, dst) = match blend {
+                Blend::Off => {
+                    unsafe { cgl_blend_disable(); }
+                    return;
+                },
+                Blend::Alpha => (GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA),
+                Blend::Additive => (GL_SRC_ALPHA, GL_ONE),
+                Blend::Multiply => (GL_DST_COLOR, GL_ZERO),
+                Blend::Premultiplied => (GL_ONE, GL_ONE_MINUS_SRC_ALPHA)
+            };
+
+            unsafe { cgl_blend(src, dst); }
+        }
+
+        #[cfg(feature = "render_state")]
+        fn depth_write(&mut self, enabled : bool)
+        {
+            unsafe { cgl_depth_write(enabled as c_int); }
+        }
+
+        #[cfg(feature = "render_state")]
+        fn depth_test(&mut self, enabled : bool)
+        {
+            unsafe { cgl_depth_test(enabled as c_int); }
+        }
+
+        #[cfg(feature = "render_state")]
+        fn cull(&mut self, cull : Cull)
+        {
+            match cull {
+                Cull::Off => unsafe { cgl_cull_disable(); },
+                Cull::Back => unsafe { cgl_cull(GL_BACK); },
+                Cull::Front => unsafe { cgl_cull(GL_FRONT); }
+            }
+        }
+
+        #[cfg(feature = "render_state")]
+        fn polygon_offset(&mut self, offset : Option<PolygonOffset>)
+        {
+            match offset {
+                Some(o) => unsafe { cgl_polygon_offset(o.factor as c_float, o.units as c_float); },
+                None => unsafe { cgl_polygon_offset_disable(); }
+            }
+        }
+
+        #[cfg(feature = "render_state")]
+        fn wireframe(&mut self, enabled : bool)
+        {
+            unsafe { cgl_polygon_mode(if enabled { GL_LINE } else { GL_FILL }); }
+        }
+
+        #[cfg(feature = "instancing")]
+        fn supports_instancing(&self) -> bool
+        {
+            true
+        }
+
+        #[cfg(feature = "instancing")]
+        fn instance_attribute_send(
+            &mut self,
+            att : *const CglShaderAttribute,
+            buffer : *const CglBuffer,
+            components : usize)
+        {
+            unsafe { cgl_shader_attribute_instance_send(att, buffer, components as c_uint); }
+        }
+
+        #[cfg(feature = "instancing")]
+        fn draw_faces_instanced(
+            &mut self,
+            buffer : *const CglBuffer,
+            index_count : usize,
+            instance_count : usize)
+        {
+            unsafe { cgl_draw_faces_instanced(buffer, index_count as c_uint, instance_count as c_uint); }
+        }
+    }
+}
+
+/// Uniform and attribute names declared in glsl source with the keyword,
+/// arrays give one name per element : "lights[0]", "lights[1]"...
+pub fn glsl_declarations(source : &str, keyword : &str) -> Vec<String>
+{
+    let mut names = Vec::new();
+
+    for line in source.lines() {
+        let line = match line.find("//") {
+            Some(i) => &line[..i],
+            None => line
+        };
+
+        for statement in line.split(';') {
+            let words : Vec<&str> = statement.split_whitespace().collect();
+            if words.len() < 3 || words[0] != keyword {
+                continue;
+            }
+
+            // skips the precision and the type
+            let mut first = 1;
+            while first < words.len() && (words[first] == "lowp" || words[first] == "mediump" || words[first] == "highp") {
+                first = first + 1;
+            }
+
+            if first + 1 >= words.len() {
+                continue;
+            }
+
+            let declared = words[first+1..].join(" ");
+            for n in declared.split(',') {
+                let n = n.trim();
+                match (n.find('['), n.find(']')) {
+                    (Some(a), Some(b)) if b > a => {
+                        let count = n[a+1..b].trim().parse::<usize>().unwrap_or(1);
+                        for i in 0..count {
+                            names.push(format!("{}[{}]", n[..a].trim(), i));
+                        }
+                    },
+                    _ => if !n.is_empty() { names.push(n.to_owned()) }
+                }
+            }
+        }
+    }
+
+    names
+}
+
+/// Call received by the recording backend, the handles are numbers
+/// and the uniforms are identified by name.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call
+{
+    BufferInit(usize, BufferType, usize),
+    BufferUpdate(usize, usize),
+    AttributeSend(String, usize),
+    /// attribute, buffer, components
+    InstanceAttributeSend(String, usize, usize),
+    ShaderInit(usize),
+    ShaderUse(usize),
+    UniformInt(String, i32),
+    UniformFloat(String, f32),
+    /// vec2, vec3, vec4 and mat4
+    UniformVec(String, Vec<f32>),
+    UniformTexture(String, usize, u32),
+    UniformFbo(String, usize, Attachment, u32),
+    TextureInit(usize, u32, u32),
+    FboCreate(usize),
+    FboUse(usize),
+    FboUseEnd,
+    FboResize(usize, i32, i32),
+    FboDestroy(usize),
+    Draw(usize),
+    DrawLines(usize),
+    DrawFaces(usize, usize),
+    /// buffer, index count, instance count
+    DrawFacesInstanced(usize, usize, usize),
+    DrawEnd,
+    Clear,
+    Blend(Blend),
+    DepthWrite(bool),
+    DepthTest(bool),
+    Cull(Cull),
+    PolygonOffset(Option<PolygonOffset>),
+    Wireframe(bool),
+}
+
+/// Backend which does not draw anything, it finds the shader inputs
+/// in the glsl source and records the calls.
+pub struct RecordingBackend
+{
+    /// shared so the calls can be read after the backend is given to set_backend
+    pub calls : Rc<RefCell<Vec<Call>>>,
+    next : usize,
+    attributes : HashMap<usize, String>,
+    uniforms : HashMap<usize, String>,
+    shaders : HashMap<usize, (String, String)>,
+    /// only the first element of the uniform arrays is listed, like GL does
+    gl_arrays : bool
+}
+
+impl RecordingBackend
+{
+    pub fn new() -> RecordingBackend
+    {
+        RecordingBackend {
+            calls : Rc::new(RefCell::new(Vec::new())),
+            next : 0,
+            attributes : HashMap::new(),
+            uniforms : HashMap::new(),
+            shaders : HashMap::new(),
+            gl_arrays : false
+        }
+    }
+
+    /// Lists only the first element of the uniform arrays like GL,
+    /// the other elements are found with shader_uniform.
+    pub fn with_gl_arrays() -> RecordingBackend
+    {
+        RecordingBackend {
+            gl_arrays : true,
+            .. RecordingBackend::new()
+        }
+    }
+
+    fn declared_uniforms(&self, shader : *const CglShader) -> Vec<String>
+    {
+        match self.shaders.get(&handle_index(shader)) {
+            Some(&(ref vert, ref frag)) => {
+                let mut n = glsl_declarations(vert, "uniform");
+                n.extend(glsl_declarations(frag, "uniform"));
+                n
+            },
+            None => Vec::new()
+        }
+    }
+
+    fn new_handle(&mut self) -> usize
+    {
+        self.next = self.next + 1;
+        self.next
+    }
+
+    fn record(&mut self, call : Call)
+    {
+        self.calls.borrow_mut().push(call);
+    }
+
+    fn attribute_name(&self, att : *const CglShaderAttribute) -> String
+    {
+        match self.attributes.get(&handle_index(att)) {
+            Some(n) => n.clone(),
+            None => String::from("unknown")
+        }
+    }
+
+    fn uniform_name(&self, uni : *const CglShaderUniform) -> String
+    {
+        match self.uniforms.get(&handle_index(uni)) {
+            Some(n) => n.clone(),
+            None => String::from("unknown")
+        }
+    }
+}
+
+impl GraphicsBackend for RecordingBackend
+{
+    fn buffer_init(&mut self, buffer_type : BufferType, data : *const c_void, count : usize)
+        -> *const CglBuffer
+    {
+        let h = self.new_handle();
+        self.record(Call::BufferInit(h, buffer_type, count));
+        handle(h)
+    }
+
+    fn buffer_update(&mut self, buffer : *const CglBuffer, data : *const c_void, count : usize)
+    {
+        self.record(Call::BufferUpdate(handle_index(buffer), count));
+    }
+
+    fn attribute_send(&mut self, att : *const CglShaderAttribute, buffer : *const CglBuffer)
+    {
+        let name = self.attribute_name(att);
+        self.record(Call::AttributeSend(name, handle_index(buffer)));
+    }
+
+    fn shader_init(&mut self, vert : &str, frag : &str) -> *const CglShader
+    {
+        let h = self.new_handle();
+        self.shaders.insert(h, (vert.to_owned(), frag.to_owned()));
+        self.record(Call::ShaderInit(h));
+        handle(h)
+    }
+
+    fn shader_use(&mut self, shader : *const CglShader)
+    {
+        self.record(Call::ShaderUse(handle_index(shader)));
+    }
+
+    fn shader_attributes(&mut self, shader : *const CglShader)
+        -> HashMap<String, *const CglShaderAttribute>
+    {
+        let names = match self.shaders.get(&handle_index(shader)) {
+            Some(&(ref vert, _)) => glsl_declarations(vert, "attribute"),
+            None => return HashMap::new()
+        };
+
+        let mut attributes = HashMap::new();
+        for n in names {
+            let h = self.new_handle();
+            self.attributes.insert(h, n.clone());
+            attributes.insert(n, handle(h));
+        }
+        attributes
+    }
+
+    fn shader_uniforms(&mut self, shader : *const CglShader)
+        -> HashMap<String, *const CglShaderUniform>
+    {
+        let gl_arrays = self.gl_arrays;
+        let names = self.declared_uniforms(shader);
+
+        let mut uniforms = HashMap::new();
+        for n in names {
+            if uniforms.contains_key(&n) || (gl_arrays && n.contains('[') && !n.ends_with("[0]")) {
+                continue;
+            }
+            let h = self.new_handle();
+            self.uniforms.insert(h, n.clone());
+            uniforms.insert(n, handle(h));
+        }
+        uniforms
+    }
+
+    fn shader_uniform(&mut self, shader : *const CglShader, name : &str) -> *const CglShaderUniform
+    {
+        if !self.declared_uniforms(shader).iter().any(|n| n == name) {
+            return ptr::null();
+        }
+
+        let h = self.new_handle();
+        self.uniforms.insert(h, name.to_owned());
+        handle(h)
+    }
+
+    fn uniform_int(&mut self, uni : *const CglShaderUniform, value : i32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformInt(name, value));
+    }
+
+    fn uniform_float(&mut self, uni : *const CglShaderUniform, value : f32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformFloat(name, value));
+    }
+
+    fn uniform_vec2(&mut self, uni : *const CglShaderUniform, x : f32, y : f32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformVec(name, vec![x, y]));
+    }
+
+    fn uniform_vec3(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformVec(name, vec![x, y, z]));
+    }
+
+    fn uniform_vec4(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32, w : f32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformVec(name, vec![x, y, z, w]));
+    }
+
+    fn uniform_mat4(&mut self, uni : *const CglShaderUniform, m : &[f32; 16])
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformVec(name, m.to_vec()));
+    }
+
+    fn uniform_texture(&mut self, uni : *const CglShaderUniform, tex : *const CglTexture, index : u32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformTexture(name, handle_index(tex), index));
+    }
+
+    fn uniform_fbo(
+        &mut self,
+        uni : *const CglShaderUniform,
+        fbo : *const CglFbo,
+        attachment : Attachment,
+        index : u32)
+    {
+        let name = self.uniform_name(uni);
+        self.record(Call::UniformFbo(name, handle_index(fbo), attachment, index));
+    }
+
+    fn texture_init(&mut self, data : &[u8], width : u32, height : u32) -> *const CglTexture
+    {
+        let h = self.new_handle();
+        self.record(Call::TextureInit(h, width, height));
+        handle(h)
+    }
+
+    fn fbo_create(&mut self) -> *const CglFbo
+    {
+        let h = self.new_handle();
+        self.record(Call::FboCreate(h));
+        handle(h)
+    }
+
+    fn fbo_use(&mut self, fbo : *const CglFbo)
+    {
+        self.record(Call::FboUse(handle_index(fbo)));
+    }
+
+    fn fbo_use_end(&mut self)
+    {
+        self.record(Call::FboUseEnd);
+    }
+
+    fn fbo_resize(&mut self, fbo : *const CglFbo, w : i32, h : i32)
+    {
+        self.record(Call::FboResize(handle_index(fbo), w, h));
+    }
+
+    fn fbo_destroy(&mut self, fbo : *const CglFbo)
+    {
+        self.record(Call::FboDestroy(handle_index(fbo)));
+    }
+
+    fn draw(&mut self, vertex_count : usize)
+    {
+        self.record(Call::Draw(vertex_count));
+    }
+
+    fn draw_lines(&mut self, vertex_count : usize)
+    {
+        self.record(Call::DrawLines(vertex_count));
+    }
+
+    fn draw_faces(&mut self, buffer : *const CglBuffer, index_count : usize)
+    {
+        self.record(Call::DrawFaces(handle_index(buffer), index_count));
+    }
+
+    fn draw_end(&mut self)
+    {
+        self.record(Call::DrawEnd);
+    }
+
+    fn clear(&mut self)
+    {
+        self.record(Call::Clear);
+    }
+
+    fn supports_render_state(&self) -> bool
+    {
+        true
+    }
+
+    fn blend(&mut self, blend : Blend)
+    {
+        self.record(Call::Blend(blend));
+    }
+
+    fn depth_write(&mut self, enabled : bool)
+    {
+        self.record(Call::DepthWrite(enabled));
+    }
+
+    fn depth_test(&mut self, enabled : bool)
+    {
+        self.record(Call::DepthTest(enabled));
+    }
+
+    fn cull(&mut self, cull : Cull)
+    {
+        self.record(Call::Cull(cull));
+    }
+
+    fn polygon_offset(&mut self, offset : Option<PolygonOffset>)
+    {
+        self.record(Call::PolygonOffset(offset));
+    }
+
+    fn wireframe(&mut self, enabled : bool)
+    {
+        self.record(Call::Wireframe(enabled));
+    }
+
+    fn supports_instancing(&self) -> bool
+    {
+        true
+    }
+
+    fn instance_attribute_send(
+        &mut self,
+        att : *const CglShaderAttribute,
+        buffer : *const CglBuffer,
+        components : usize)
+    {
+        let name = self.attribute_name(att);
+        self.record(Call::InstanceAttributeSend(name, handle_index(buffer), components));
+    }
+
+    fn draw_faces_instanced(
+        &mut self,
+        buffer : *const CglBuffer,
+        index_count : usize,
+        instance_count : usize)
+    {
+        self.record(Call::DrawFacesInstanced(handle_index(buffer), index_count, instance_count));
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    fn test_glsl_declarations()
+    {
+        let vert = "attribute vec3 position;\n\
+                    attribute vec2 texcoord; // uv\n\
+                    uniform mat4 matrix;\n\
+                    uniform mediump vec3 light_color[2], ambient;\n";
+
+        assert_eq!(glsl_declarations(vert, "attribute"), vec!["position", "texcoord"]);
+        assert_eq!(
+            glsl_declarations(vert, "uniform"),
+            vec!["matrix", "light_color[0]", "light_color[1]", "ambient"]);
+    }
+}
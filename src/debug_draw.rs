@@ -0,0 +1,472 @@
+use std::cell::RefCell;
+use std::f64::consts;
+use std::time::{Duration, Instant};
+
+use vec;
+use matrix;
+use mesh;
+use shader;
+use resource;
+use camera2;
+use render;
+use backend;
+use shadow;
+use geometry::{Segment, AABox, OBox};
+use material::{Blend, Cull};
+use transform::Transform;
+
+/// Segments of the circles of spheres.
+const CIRCLE_SEGMENTS : usize = 24;
+
+pub const VERT : &'static str = "attribute vec3 position;
+attribute vec4 color;
+uniform mat4 matrix;
+varying vec4 vcolor;
+void main()
+{
+    vcolor = color;
+    gl_Position = matrix * vec4(position, 1.0);
+}
+";
+
+pub const FRAG : &'static str = "precision mediump float;
+varying vec4 vcolor;
+void main()
+{
+    gl_FragColor = vcolor;
+}
+";
+
+struct Shape
+{
+    segments : Vec<Segment>,
+    color : vec::Vec4,
+    depth_test : bool,
+    end : Instant
+}
+
+/// Text to draw at a world position, see DebugDraw::text_anchors.
+pub struct TextAnchor
+{
+    pub text : String,
+    pub position : vec::Vec3,
+    pub color : vec::Vec4,
+    end : Instant
+}
+
+/// Text anchor projected on the screen, in pixels from the top left corner.
+#[derive(Clone, Debug)]
+pub struct ScreenText
+{
+    pub text : String,
+    pub x : f64,
+    pub y : f64,
+    pub color : vec::Vec4
+}
+
+/// Shapes drawn with lines until their duration is over, a duration of 0 draws them
+/// for one frame. Shapes without depth test are drawn over everything.
+pub struct DebugDraw
+{
+    shapes : Vec<Shape>,
+    texts : Vec<TextAnchor>,
+    /// lines with and without depth test
+    depth : mesh::Mesh,
+    overlay : mesh::Mesh,
+    /// shapes were added or removed since the meshes were made
+    dirty : bool,
+    pub shader : shader::Shader
+}
+
+thread_local!(static DEBUG_DRAW : RefCell<DebugDraw> = RefCell::new(DebugDraw::new()));
+
+/// Calls f with the debug draw of the current thread, which render::draw_passes
+/// and RenderPass::draw_frame draw,
+/// f must not call with_debug_draw.
+pub fn with_debug_draw<R, F : FnOnce(&mut DebugDraw) -> R>(f : F) -> R
+{
+    DEBUG_DRAW.with(|d| f(&mut *d.borrow_mut()))
+}
+
+fn end_time(duration : f64) -> Instant
+{
+    let d = duration.max(0f64);
+    Instant::now() + Duration::new(d as u64, (d.fract() * 1e9f64) as u32)
+}
+
+/// Two vectors perpendicular to the direction and to each other.
+fn perpendiculars(direction : &vec::Vec3) -> (vec::Vec3, vec::Vec3)
+{
+    let d = direction.normalized();
+    let other = if d.dot(&vec::Vec3::up()).abs() > 0.9f64 { vec::Vec3::x() } else { vec::Vec3::up() };
+    let a = (d ^ other).normalized();
+    let b = d ^ a;
+    (a, b)
+}
+
+fn circle(center : &vec::Vec3, a : &vec::Vec3, b : &vec::Vec3, radius : f64) -> Vec<Segment>
+{
+    let point = |i : usize| {
+        let angle = i as f64 / CIRCLE_SEGMENTS as f64 * consts::PI * 2f64;
+        *center + *a * (angle.cos() * radius) + *b * (angle.sin() * radius)
+    };
+
+    (0..CIRCLE_SEGMENTS).map(|i| Segment::new(point(i), point(i + 1))).collect()
+}
+
+impl DebugDraw
+{
+    pub fn new() -> DebugDraw
+    {
+        let mut depth = mesh::Mesh::new();
+        depth.name = String::from("debug_draw_depth");
+        let mut overlay = mesh::Mesh::new();
+        overlay.name = String::from("debug_draw_overlay");
+
+        DebugDraw {
+            shapes : Vec::new(),
+            texts : Vec::new(),
+            depth : depth,
+            overlay : overlay,
+            dirty : false,
+            shader : shader::Shader::with_vert_frag(
+                String::from("debug_draw"),
+                String::from(VERT),
+                String::from(FRAG))
+        }
+    }
+
+    fn add(&mut self, segments : Vec<Segment>, color : vec::Vec4, duration : f64, depth_test : bool)
+    {
+        self.shapes.push(Shape {
+            segments : segments,
+            color : color,
+            depth_test : depth_test,
+            end : end_time(duration)
+        });
+        self.dirty = true;
+    }
+
+    pub fn line(
+        &mut self,
+        p0 : vec::Vec3,
+        p1 : vec::Vec3,
+        color : vec::Vec4,
+        duration : f64,
+        depth_test : bool)
+    {
+        self.add(vec![Segment::new(p0, p1)], color, duration, depth_test);
+    }
+
+    /// Line with a head at to, the head size depends on the length.
+    pub fn arrow(
+        &mut self,
+        from : vec::Vec3,
+        to : vec::Vec3,
+        color : vec::Vec4,
+        duration : f64,
+        depth_test : bool)
+    {
+        let d = to - from;
+        let length = d.length();
+        if length <= 0f64 {
+            return;
+        }
+
+        let head = length * 0.2f64;
+        let back = to - d.normalized() * head;
+        let (a, b) = perpendiculars(&d);
+        let side = head * 0.5f64;
+
+        let segments = vec![
+            Segment::new(from, to),
+            Segment::new(to, back + a * side),
+            Segment::new(to, back - a * side),
+            Segment::new(to, back + b * side),
+            Segment::new(to, back - b * side)];
+
+        self.add(segments, color, duration, depth_test);
+    }
+
+    pub fn aabox(&mut self, aabox : &AABox, color : vec::Vec4, duration : f64, depth_test : bool)
+    {
+        let (n, x) = (aabox.min, aabox.max);
+        let v = [
+            n,
+            vec::Vec3::new(x.x, n.y, n.z),
+            vec::Vec3::new(n.x, x.y, n.z),
+            vec::Vec3::new(n.x, n.y, x.z),
+            x,
+            vec::Vec3::new(n.x, x.y, x.z),
+            vec::Vec3::new(x.x, n.y, x.z),
+            vec::Vec3::new(x.x, x.y, n.z)];
+
+        self.obox(&OBox::new(v), color, duration, depth_test);
+    }
+
+    /// The corners are ordered like in AABox::to_obox.
+    pub fn obox(&mut self, obox : &OBox, color : vec::Vec4, duration : f64, depth_test : bool)
+    {
+        let edges : [(usize, usize); 12] = [
+            (0, 1), (0, 2), (0, 3),
+            (4, 5), (4, 6), (4, 7),
+            (1, 6), (1, 7), (2, 5),
+            (2, 7), (3, 5), (3, 6)];
+
+        let segments = edges.iter().map(|&(a, b)| Segment::new(obox.v[a], obox.v[b])).collect();
+        self.add(segments, color, duration, depth_test);
+    }
+
+    /// Three circles around the axes.
+    pub fn sphere(
+        &mut self,
+        center : vec::Vec3,
+        radius : f64,
+        color : vec::Vec4,
+        duration : f64,
+        depth_test : bool)
+    {
+        let (x, y, z) = (vec::Vec3::x(), vec::Vec3::y(), vec::Vec3::z());
+        let mut segments = circle(&center, &x, &y, radius);
+        segments.extend(circle(&center, &x, &z, radius));
+        segments.extend(circle(&center, &y, &z, radius));
+        self.add(segments, color, duration, depth_test);
+    }
+
+    /// What the camera at the transform sees, from its near to its far plane.
+    pub fn frustum(
+        &mut self,
+        camera : &camera2::Camera,
+        transform : &Transform,
+        color : vec::Vec4,
+        duration : f64,
+        depth_test : bool)
+    {
+        let c = shadow::frustum_corners(camera, transform, camera.near, camera.far);
+        let mut segments = Vec::with_capacity(12);
+        for i in 0..4 {
+            let j = (i + 1) % 4;
+            segments.push(Segment::new(c[i], c[j]));
+            segments.push(Segment::new(c[i + 4], c[j + 4]));
+            segments.push(Segment::new(c[i], c[i + 4]));
+        }
+
+        self.add(segments, color, duration, depth_test);
+    }
+
+    /// The x, y and z axes of the transform in red, green and blue.
+    pub fn axes(&mut self, transform : &Transform, size : f64, duration : f64, depth_test : bool)
+    {
+        let p = transform.position;
+        let axes = [
+            (vec::Vec3::x(), vec::Vec4::new(1f64, 0f64, 0f64, 1f64)),
+            (vec::Vec3::y(), vec::Vec4::new(0f64, 1f64, 0f64, 1f64)),
+            (vec::Vec3::z(), vec::Vec4::new(0f64, 0f64, 1f64, 1f64))];
+
+        for &(axis, color) in axes.iter() {
+            let end = p + transform.orientation.rotate_vec3(&axis) * size;
+            self.add(vec![Segment::new(p, end)], color, duration, depth_test);
+        }
+    }
+
+    /// Square grid on the horizontal plane with divisions cells on each side.
+    pub fn grid(
+        &mut self,
+        center : vec::Vec3,
+        size : f64,
+        divisions : usize,
+        color : vec::Vec4,
+        duration : f64,
+        depth_test : bool)
+    {
+        let divisions = divisions.max(1);
+        let half = size * 0.5f64;
+        let step = size / divisions as f64;
+
+        let mut segments = Vec::with_capacity((divisions + 1) * 2);
+        for i in 0..divisions + 1 {
+            let o = -half + step * i as f64;
+            segments.push(Segment::new(
+                center + vec::Vec3::new(o, 0f64, -half),
+                center + vec::Vec3::new(o, 0f64, half)));
+            segments.push(Segment::new(
+                center + vec::Vec3::new(-half, 0f64, o),
+                center + vec::Vec3::new(half, 0f64, o)));
+        }
+
+        self.add(segments, color, duration, depth_test);
+    }
+
+    /// Text at a world position, the ui draws it at the position from text_anchors.
+    pub fn text(&mut self, position : vec::Vec3, text : &str, color : vec::Vec4, duration : f64)
+    {
+        self.texts.push(TextAnchor {
+            text : String::from(text),
+            position : position,
+            color : color,
+            end : end_time(duration)
+        });
+    }
+
+    /// The texts in front of the camera, in pixels for a screen of width and height.
+    pub fn text_anchors(&self, camera : &matrix::Matrix4, width : f64, height : f64) -> Vec<ScreenText>
+    {
+        self.texts.iter().filter_map(|t| {
+            let p = camera * vec::Vec4::new(t.position.x, t.position.y, t.position.z, 1f64);
+            if p.w <= 0f64 {
+                return None;
+            }
+
+            Some(ScreenText {
+                text : t.text.clone(),
+                x : (p.x / p.w * 0.5f64 + 0.5f64) * width,
+                y : (0.5f64 - p.y / p.w * 0.5f64) * height,
+                color : t.color
+            })
+        }).collect()
+    }
+
+    /// Makes the meshes again if the shapes changed.
+    fn update_meshes(&mut self)
+    {
+        if !self.dirty {
+            return;
+        }
+
+        self.depth.reset_lines();
+        self.overlay.reset_lines();
+        for s in self.shapes.iter() {
+            let m = if s.depth_test { &mut self.depth } else { &mut self.overlay };
+            for seg in s.segments.iter() {
+                m.add_line(Segment::new(seg.p0, seg.p1), s.color);
+            }
+        }
+
+        self.dirty = false;
+    }
+
+    /// Draws the lines seen by the camera matrix, can be called for several cameras.
+    pub fn draw(&mut self, camera : &matrix::Matrix4, resource : &resource::ResourceGroup)
+    {
+        if self.shapes.is_empty() {
+            return;
+        }
+
+        self.update_meshes();
+
+        if self.shader.state == 1 {
+            self.shader.load_gl();
+        }
+        self.shader.utilise();
+
+        let input = render::ShaderInput::new();
+        let world = matrix::Matrix4::identity();
+
+        for &(m, depth_test) in [(&self.depth, true), (&self.overlay, false)].iter() {
+            if m.buffer_f32_get("position").map_or(true, |b| b.data.is_empty()) {
+                continue;
+            }
+
+            backend::with_backend(|g| {
+                g.blend(Blend::Alpha);
+                g.depth_test(depth_test);
+                g.depth_write(false);
+                g.cull(Cull::Off);
+            });
+
+            render::draw(camera, &world, &self.shader, m, &input, resource);
+        }
+
+        backend::with_backend(|g| {
+            g.blend(Blend::Off);
+            g.depth_test(true);
+            g.depth_write(true);
+        });
+    }
+
+    /// Removes the shapes and texts whose duration is over, after the frame is drawn.
+    pub fn end_frame(&mut self)
+    {
+        let now = Instant::now();
+        let count = self.shapes.len();
+        self.shapes.retain(|s| s.end > now);
+        self.texts.retain(|t| t.end > now);
+
+        if self.shapes.len() != count {
+            self.dirty = true;
+        }
+    }
+
+    /// Removes everything, whatever the duration.
+    pub fn clear(&mut self)
+    {
+        self.shapes.clear();
+        self.texts.clear();
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    fn white() -> vec::Vec4
+    {
+        vec::Vec4::new(1f64, 1f64, 1f64, 1f64)
+    }
+
+    fn position_count(m : &mesh::Mesh) -> usize
+    {
+        m.buffer_f32_get("position").map_or(0, |b| b.data.len())
+    }
+
+    #[test]
+    fn test_zero_duration_lasts_one_frame()
+    {
+        let mut d = DebugDraw::new();
+        d.line(vec::Vec3::zero(), vec::Vec3::x(), white(), 0f64, true);
+        d.text(vec::Vec3::zero(), "zero", white(), 0f64);
+        assert_eq!(d.shapes.len(), 1);
+
+        d.end_frame();
+        assert!(d.shapes.is_empty());
+        assert!(d.texts.is_empty());
+    }
+
+    #[test]
+    fn test_end_frame_expiry()
+    {
+        let mut d = DebugDraw::new();
+        d.line(vec::Vec3::zero(), vec::Vec3::x(), white(), 0.2f64, true);
+        d.line(vec::Vec3::zero(), vec::Vec3::y(), white(), 60f64, true);
+
+        d.end_frame();
+        assert_eq!(d.shapes.len(), 2);
+
+        thread::sleep(Duration::from_millis(300));
+        d.end_frame();
+        assert_eq!(d.shapes.len(), 1);
+        assert_eq!(d.shapes[0].segments[0].p1, vec::Vec3::y());
+    }
+
+    #[test]
+    fn test_depth_and_overlay_meshes()
+    {
+        let mut d = DebugDraw::new();
+        d.line(vec::Vec3::zero(), vec::Vec3::x(), white(), 1f64, true);
+        d.arrow(vec::Vec3::zero(), vec::Vec3::y(), white(), 1f64, false);
+
+        d.update_meshes();
+        // 2 points of 3 floats for each segment, the arrow has 5 segments
+        assert_eq!(position_count(&d.depth), 6);
+        assert_eq!(position_count(&d.overlay), 30);
+
+        d.clear();
+        d.update_meshes();
+        assert_eq!(position_count(&d.depth), 0);
+        assert_eq!(position_count(&d.overlay), 0);
+    }
+}
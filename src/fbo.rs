@@ -1,22 +1,16 @@
 use libc::{c_uint, c_int};
 use std::fmt;
 
+use backend;
+
 #[repr(C)]
 pub struct CglFbo;
 
 unsafe impl Send for CglFbo {}
 unsafe impl Sync for CglFbo {}
 
-#[link(name = "cypher")]
-extern {
-    pub fn cgl_create_fbo() -> *const CglFbo;
-    pub fn cgl_fbo_use(fbo : *const CglFbo);
-    pub fn cgl_fbo_use_end();
-    pub fn cgl_fbo_resize(fbo : *const CglFbo, w : c_int, h : c_int);
-    pub fn cgl_fbo_destroy(fbo : *const CglFbo);
-}
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub enum Attachment
 {
     Depth,
@@ -50,7 +44,7 @@ impl Fbo
             return
         }
 
-        self.cgl_fbo = unsafe {Some(cgl_create_fbo())};
+        self.cgl_fbo = Some(backend::with_backend(|b| b.fbo_create()));
 
         self.state = 1;
     }
@@ -58,20 +52,20 @@ impl Fbo
     pub fn cgl_resize(&self, w : c_int, h : c_int)
     {
         if let Some(f) = self.cgl_fbo {
-            unsafe { cgl_fbo_resize(f, w, h); }
+            backend::with_backend(|b| b.fbo_resize(f, w, h));
         }
     }
 
     pub fn cgl_use(&self)
     {
         if let Some(f) = self.cgl_fbo {
-            unsafe { cgl_fbo_use(f); }
+            backend::with_backend(|b| b.fbo_use(f));
         }
     }
 
     pub fn cgl_use_end()
     {
-        unsafe { cgl_fbo_use_end(); }
+        backend::with_backend(|b| b.fbo_use_end());
     }
 }
 
@@ -1,4 +1,5 @@
 use vec::{Vec3, Quat};
+use matrix::Matrix4;
 use std::fmt;
 use std::ops::{Mul};//, BitXor, Add, Sub, Div};
 
@@ -45,6 +46,34 @@ impl Plane
     }
 }
 
+/// Planes of the frustum of a projection * view matrix, in the order of
+/// Camera::get_frustum_planes_rect : near, far, up, down, right, left.
+/// The normals point inside.
+pub fn frustum_planes(view_projection : &Matrix4) -> [Plane; 6]
+{
+    let d = view_projection.to_f32();
+    let row = |i : usize| [d[i] as f64, d[4 + i] as f64, d[8 + i] as f64, d[12 + i] as f64];
+    let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+
+    let plane = |a : [f64; 4], b : [f64; 4], sign : f64| -> Plane {
+        let n = Vec3::new(a[0] + sign * b[0], a[1] + sign * b[1], a[2] + sign * b[2]);
+        let w = a[3] + sign * b[3];
+        let l = n.length();
+        if l == 0f64 {
+            return Plane::xz();
+        }
+        Plane::new(n * (-w / (l * l)), n / l)
+    };
+
+    [
+        plane(r3, r2, 1f64),
+        plane(r3, r2, -1f64),
+        plane(r3, r1, -1f64),
+        plane(r3, r1, 1f64),
+        plane(r3, r0, -1f64),
+        plane(r3, r0, 1f64)
+    ]
+}
 
 pub struct Sphere
 {
@@ -83,10 +83,26 @@ pub mod transform;
 //pub mod component;
 //pub use component::manager;
 pub mod armature_animation;
+pub mod armature_blend;
+pub mod animation_graph;
+pub mod armature_ik;
+pub mod root_motion;
+pub mod armature_socket;
+pub mod armature_retarget;
+pub mod property_animation;
+pub mod tween;
+pub mod backend;
+pub mod software;
+pub mod render_queue;
+pub mod light;
+pub mod shadow;
+pub mod pbr;
+pub mod debug_draw;
 
 
 mod util;
 
+#[cfg(feature = "cgl")]
 #[link(name = "GLESv2", kind="dylib")]
 #[link(name = "cypher")]
 extern
@@ -0,0 +1,328 @@
+use std::f64::consts;
+
+use vec;
+use matrix;
+use geometry;
+use shader;
+use uniform::UniformSend;
+use transform::Transform;
+use shadow::ShadowSettings;
+
+/// Number of lights sent to a shader, the size of the light uniform arrays.
+pub const MAX_LIGHTS : usize = 4;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LightType
+{
+    /// lights everything from its direction, like the sun
+    Directional,
+    /// lights around its position up to the range
+    Point,
+    /// lights in a cone from its position up to the range
+    Spot,
+}
+
+/// The position and direction come from the transform of the object,
+/// the light points to -z like the camera.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Light
+{
+    pub light_type : LightType,
+    pub color : vec::Vec3,
+    #[serde(default="default_intensity")]
+    pub intensity : f64,
+    /// distance at which point and spot lights stop lighting
+    #[serde(default="default_range")]
+    pub range : f64,
+    /// spot, angle in radians from the axis where the light starts to fade
+    #[serde(default="default_inner_angle")]
+    pub inner_angle : f64,
+    /// spot, angle in radians from the axis where there is no light
+    #[serde(default="default_outer_angle")]
+    pub outer_angle : f64,
+    /// directional and spot lights with settings cast shadows
+    #[serde(default)]
+    pub shadow : Option<ShadowSettings>,
+}
+
+fn default_intensity() -> f64
+{
+    1f64
+}
+
+fn default_range() -> f64
+{
+    10f64
+}
+
+fn default_inner_angle() -> f64
+{
+    consts::PI / 8f64
+}
+
+fn default_outer_angle() -> f64
+{
+    consts::PI / 6f64
+}
+
+/// A light placed in the world, what the renderer uses.
+#[derive(Clone, Debug)]
+pub struct WorldLight
+{
+    pub light : Light,
+    pub position : vec::Vec3,
+    pub direction : vec::Vec3
+}
+
+impl Light
+{
+    pub fn new(light_type : LightType, color : vec::Vec3) -> Light
+    {
+        Light {
+            light_type : light_type,
+            color : color,
+            intensity : default_intensity(),
+            range : default_range(),
+            inner_angle : default_inner_angle(),
+            outer_angle : default_outer_angle(),
+            shadow : None
+        }
+    }
+
+    pub fn directional(color : vec::Vec3, intensity : f64) -> Light
+    {
+        let mut l = Light::new(LightType::Directional, color);
+        l.intensity = intensity;
+        l
+    }
+
+    pub fn point(color : vec::Vec3, intensity : f64, range : f64) -> Light
+    {
+        let mut l = Light::new(LightType::Point, color);
+        l.intensity = intensity;
+        l.range = range;
+        l
+    }
+
+    pub fn spot(color : vec::Vec3, intensity : f64, range : f64, inner_angle : f64, outer_angle : f64)
+        -> Light
+    {
+        let mut l = Light::new(LightType::Spot, color);
+        l.intensity = intensity;
+        l.range = range;
+        l.inner_angle = inner_angle;
+        l.outer_angle = outer_angle;
+        l
+    }
+}
+
+impl WorldLight
+{
+    pub fn new(light : &Light, transform : &Transform) -> WorldLight
+    {
+        WorldLight {
+            light : light.clone(),
+            position : transform.position,
+            direction : transform.orientation.rotate_vec3(&vec::Vec3::forward()).normalized()
+        }
+    }
+
+    /// How much the light matters at this point, 0 when it does not light it.
+    /// Directional lights always come first.
+    pub fn importance(&self, point : &vec::Vec3) -> f64
+    {
+        let l = &self.light;
+        if l.light_type == LightType::Directional {
+            return ::std::f64::INFINITY;
+        }
+
+        let to_point = *point - self.position;
+        let distance = to_point.length();
+        if distance >= l.range {
+            return 0f64;
+        }
+
+        if l.light_type == LightType::Spot && distance > 0f64 {
+            let cos = to_point.dot(&self.direction) / distance;
+            if cos < l.outer_angle.cos() {
+                return 0f64;
+            }
+        }
+
+        let falloff = 1f64 - distance / l.range;
+        l.intensity * falloff * falloff
+    }
+
+    /// False if the light cannot light anything inside the planes,
+    /// from Camera::get_frustum_planes_rect or geometry::frustum_planes.
+    pub fn is_visible(&self, planes : &[geometry::Plane]) -> bool
+    {
+        if self.light.light_type == LightType::Directional {
+            return true;
+        }
+
+        // the sphere of the range, also for spots
+        !planes.iter().any(|p| {
+            (self.position - p.point).dot(&p.normal) < -self.light.range
+        })
+    }
+
+    fn type_index(&self) -> i32
+    {
+        match self.light.light_type {
+            LightType::Directional => 0,
+            LightType::Point => 1,
+            LightType::Spot => 2
+        }
+    }
+}
+
+/// Lights which can light something seen by the camera, view_projection is the camera matrix.
+pub fn cull<'a>(lights : &'a [WorldLight], view_projection : &matrix::Matrix4) -> Vec<&'a WorldLight>
+{
+    let planes = geometry::frustum_planes(view_projection);
+    lights.iter().filter(|l| l.is_visible(&planes)).collect()
+}
+
+/// The max most important lights at the point.
+pub fn gather<'a>(lights : &[&'a WorldLight], point : &vec::Vec3, max : usize) -> Vec<&'a WorldLight>
+{
+    let mut found : Vec<(f64, &WorldLight)> = lights.iter()
+        .map(|l| (l.importance(point), *l))
+        .filter(|&(i, _)| i > 0f64)
+        .collect();
+
+    found.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(::std::cmp::Ordering::Equal));
+    found.into_iter().take(max).map(|(_, l)| l).collect()
+}
+
+/// Sends the lights to the uniforms the shader declares :
+/// "light_count" and the arrays "light_type" (0 directional, 1 point, 2 spot),
+/// "light_color" (multiplied by the intensity), "light_position", "light_direction",
+/// "light_range" and "light_cone" (cosines of the inner and outer angles).
+pub fn send_lights(shader : &shader::Shader, lights : &[&WorldLight])
+{
+    if shader.has_uniform("light_count") {
+        shader.uniform_set("light_count", &(lights.len().min(MAX_LIGHTS) as i32));
+    }
+
+    for (i, l) in lights.iter().take(MAX_LIGHTS).enumerate() {
+        let set = |name : &str, value : &UniformSend| {
+            let name = format!("{}[{}]", name, i);
+            if shader.has_uniform(&name) {
+                shader.uniform_set(&name, value);
+            }
+        };
+
+        set("light_type", &l.type_index());
+        set("light_color", &(l.light.color * l.light.intensity));
+        set("light_position", &l.position);
+        set("light_direction", &l.direction);
+        set("light_range", &(l.light.range as f32));
+        set("light_cone", &vec::Vec2::new(l.light.inner_angle.cos(), l.light.outer_angle.cos()));
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use backend;
+    use backend::Call;
+    use super::*;
+
+    fn world(light : Light, position : vec::Vec3) -> WorldLight
+    {
+        WorldLight {
+            light : light,
+            position : position,
+            direction : vec::Vec3::new(0f64, 0f64, -1f64)
+        }
+    }
+
+    fn white() -> vec::Vec3
+    {
+        vec::Vec3::new(1f64, 1f64, 1f64)
+    }
+
+    #[test]
+    fn test_importance()
+    {
+        let sun = world(Light::directional(white(), 1f64), vec::Vec3::zero());
+        assert_eq!(sun.importance(&vec::Vec3::new(100f64, 0f64, 0f64)), ::std::f64::INFINITY);
+
+        let point = world(Light::point(white(), 2f64, 10f64), vec::Vec3::zero());
+        assert!((point.importance(&vec::Vec3::new(5f64, 0f64, 0f64)) - 0.5f64).abs() < 1e-9f64);
+        assert_eq!(point.importance(&vec::Vec3::new(10f64, 0f64, 0f64)), 0f64);
+
+        let spot = world(Light::spot(white(), 1f64, 10f64, 0.2f64, 0.4f64), vec::Vec3::zero());
+        assert!(spot.importance(&vec::Vec3::new(0f64, 0f64, -5f64)) > 0f64);
+        assert_eq!(spot.importance(&vec::Vec3::new(5f64, 0f64, 0f64)), 0f64);
+    }
+
+    #[test]
+    fn test_gather()
+    {
+        let near = world(Light::point(white(), 1f64, 10f64), vec::Vec3::new(1f64, 0f64, 0f64));
+        let far = world(Light::point(white(), 1f64, 10f64), vec::Vec3::new(5f64, 0f64, 0f64));
+        let out = world(Light::point(white(), 1f64, 1f64), vec::Vec3::new(5f64, 0f64, 0f64));
+        let sun = world(Light::directional(white(), 0.1f64), vec::Vec3::zero());
+
+        let lights = [&far, &out, &near, &sun];
+        let found = gather(&lights, &vec::Vec3::zero(), 4);
+
+        // the directional light first, then the nearest, the one out of range is not there
+        assert_eq!(found.len(), 3);
+        assert!(found[0] as *const WorldLight == &sun as *const WorldLight);
+        assert!(found[1] as *const WorldLight == &near as *const WorldLight);
+        assert!(found[2] as *const WorldLight == &far as *const WorldLight);
+
+        assert_eq!(gather(&lights, &vec::Vec3::zero(), 1).len(), 1);
+    }
+
+    #[test]
+    fn test_cull()
+    {
+        let lights = [
+            world(Light::point(white(), 1f64, 1f64), vec::Vec3::new(5f64, 0f64, 0f64)),
+            world(Light::point(white(), 1f64, 1f64), vec::Vec3::new(50f64, 0f64, 0f64)),
+            world(Light::directional(white(), 1f64), vec::Vec3::new(50f64, 0f64, 0f64))];
+
+        // sees from -10 to 10
+        let camera = matrix::Matrix4::scale(&vec::Vec3::new(0.1f64, 0.1f64, 0.1f64));
+        let visible = cull(&lights, &camera);
+
+        assert_eq!(visible.len(), 2);
+        assert_eq!(visible[0].position.x, 5f64);
+        assert_eq!(visible[1].light.light_type, LightType::Directional);
+    }
+
+    #[test]
+    fn test_send_lights_to_arrays()
+    {
+        // like GL, the backend only lists light_type[0] and light_color[0]
+        let recorder = backend::RecordingBackend::with_gl_arrays();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let mut s = shader::Shader::with_vert_frag(
+            String::from("lit"),
+            String::from("uniform int light_count;\nuniform int light_type[4];\nuniform vec3 light_color[4];\n"),
+            String::from(""));
+        s.load_gl();
+        assert!(s.has_uniform("light_type[3]"));
+        assert!(!s.has_uniform("light_type[4]"));
+
+        let a = world(Light::point(white(), 2f64, 10f64), vec::Vec3::zero());
+        let b = world(Light::directional(white(), 1f64), vec::Vec3::zero());
+        let c = world(Light::spot(white(), 1f64, 10f64, 0.2f64, 0.4f64), vec::Vec3::zero());
+        send_lights(&s, &[&a, &b, &c]);
+
+        let calls = calls.borrow();
+        assert!(calls.contains(&Call::UniformInt(String::from("light_count"), 3)));
+        assert!(calls.contains(&Call::UniformInt(String::from("light_type[0]"), 1)));
+        assert!(calls.contains(&Call::UniformInt(String::from("light_type[1]"), 0)));
+        assert!(calls.contains(&Call::UniformInt(String::from("light_type[2]"), 2)));
+        assert!(calls.contains(&Call::UniformVec(String::from("light_color[0]"), vec![2f32, 2f32, 2f32])));
+        assert!(!calls.iter().any(|c| if let Call::UniformInt(ref n, _) = *c { n == "light_type[3]" } else { false }));
+    }
+}
@@ -41,6 +41,171 @@ impl Sampler
     }
 }
 
+/// When the objects using the material are drawn, in this order.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Queue
+{
+    Opaque,
+    /// opaque but the shader discards some pixels
+    AlphaTest,
+    /// drawn back to front without writing depth
+    Transparent,
+    /// drawn last, back to front without writing depth
+    Overlay,
+}
+
+impl Default for Queue
+{
+    fn default() -> Queue
+    {
+        Queue::Opaque
+    }
+}
+
+impl Queue
+{
+    /// Sorted back to front, depth is not written.
+    pub fn is_transparent(&self) -> bool
+    {
+        match *self {
+            Queue::Transparent | Queue::Overlay => true,
+            _ => false
+        }
+    }
+}
+
+/// How the color of an object is combined with the color already drawn.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Blend
+{
+    /// the color replaces the one drawn
+    Off,
+    /// src * src_alpha + dst * (1 - src_alpha)
+    Alpha,
+    /// src * src_alpha + dst
+    Additive,
+    /// src * dst
+    Multiply,
+    /// src + dst * (1 - src_alpha), for colors already multiplied by alpha
+    Premultiplied,
+}
+
+impl Default for Blend
+{
+    fn default() -> Blend
+    {
+        Blend::Off
+    }
+}
+
+/// Faces which are not drawn, the front faces are counter clockwise.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Cull
+{
+    /// both sides are drawn
+    Off,
+    Back,
+    Front,
+}
+
+impl Default for Cull
+{
+    fn default() -> Cull
+    {
+        Cull::Off
+    }
+}
+
+/// Depth offset, to draw decals or outlines over faces at the same place.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PolygonOffset
+{
+    /// multiplied by the depth slope of the face
+    pub factor : f64,
+    /// multiplied by the smallest depth difference
+    pub units : f64
+}
+
+/// How the objects using the material are drawn, set before their draw.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RenderState
+{
+    #[serde(default)]
+    pub blend : Blend,
+    #[serde(default="default_true")]
+    pub depth_test : bool,
+    /// transparent materials never write depth
+    #[serde(default="default_true")]
+    pub depth_write : bool,
+    #[serde(default)]
+    pub cull : Cull,
+    #[serde(default)]
+    pub polygon_offset : Option<PolygonOffset>,
+    #[serde(default)]
+    pub wireframe : bool,
+}
+
+fn default_true() -> bool
+{
+    true
+}
+
+impl Default for RenderState
+{
+    fn default() -> RenderState
+    {
+        RenderState {
+            blend : Blend::Off,
+            depth_test : true,
+            depth_write : true,
+            cull : Cull::Off,
+            polygon_offset : None,
+            wireframe : false
+        }
+    }
+}
+
+impl RenderState
+{
+    /// Both sides drawn, like the default state.
+    pub fn double_sided() -> RenderState
+    {
+        RenderState {
+            cull : Cull::Off,
+            .. Default::default()
+        }
+    }
+
+    pub fn wireframe() -> RenderState
+    {
+        RenderState {
+            cull : Cull::Off,
+            wireframe : true,
+            .. Default::default()
+        }
+    }
+
+    /// Only the front faces drawn, for closed meshes.
+    pub fn back_culled() -> RenderState
+    {
+        RenderState {
+            cull : Cull::Back,
+            .. Default::default()
+        }
+    }
+
+    /// Additive blending without depth writing, for effects.
+    pub fn additive() -> RenderState
+    {
+        RenderState {
+            blend : Blend::Additive,
+            depth_write : false,
+            cull : Cull::Off,
+            .. Default::default()
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Material
 {
@@ -50,6 +215,10 @@ pub struct Material
     pub state : i32,
     pub textures : HashMap<String, Sampler>,
     pub uniforms : HashMap<String, Box<shader::UniformData>>,
+    #[serde(default)]
+    pub queue : Queue,
+    #[serde(default)]
+    pub render_state : RenderState,
 }
 
 unsafe impl Send for Material {}
@@ -80,6 +249,8 @@ impl Material
             state : 0,
             textures : HashMap::new(),
             uniforms : HashMap::new(),
+            queue : Queue::Opaque,
+            render_state : Default::default(),
         }
     }
 
@@ -140,6 +311,8 @@ impl Material
         }
 
         self.uniforms = mat.uniforms.clone();
+        self.queue = mat.queue;
+        self.render_state = mat.render_state;
     }
 
     pub fn save(&self)
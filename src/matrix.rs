@@ -134,6 +134,28 @@ impl Matrix4
         Matrix4 { data : m }
     }
 
+    /// Orthographic projection of the box given in view space.
+    pub fn orthographic_bounds(
+        left : f64,
+        right : f64,
+        bottom : f64,
+        top : f64,
+        near : f64,
+        far : f64) -> Matrix4
+    {
+        let mut m : [f64; 16] = [0f64; 16];
+
+        m[0] = 2f64 / (right - left);
+        m[5] = 2f64 / (top - bottom);
+        m[10] = -2f64 / (far - near);
+        m[12] = -(right + left) / (right - left);
+        m[13] = -(top + bottom) / (top - bottom);
+        m[14] = -(far + near) / (far - near);
+        m[15] = 1f64;
+
+        Matrix4 { data : m }
+    }
+
     pub fn translation(t : &vec::Vec3) -> Matrix4
     {
         let mut m : [f64; 16] = [0f64; 16];
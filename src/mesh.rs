@@ -3,7 +3,7 @@ use std::collections::hash_map::Entry::{Occupied,Vacant};
 use std::fs::File;
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::path::Path;
-use std::io::Read;
+use std::io::{self, Read};
 use std::cell::Cell;
 use std::fmt;
 
@@ -13,6 +13,7 @@ use std::mem;
 use resource;
 use shader;
 use geometry;
+use backend;
 use vec;
 
 use self::DrawType::{Faces,Vertices,Lines};
@@ -20,29 +21,7 @@ use self::DrawType::{Faces,Vertices,Lines};
 #[repr(C)]
 pub struct CglBuffer;
 
-#[link(name = "cypher")]
-extern {
-    pub fn cgl_buffer_init(
-        data : *const c_void,
-        count : c_uint
-        ) -> *const CglBuffer;
-
-    pub fn cgl_buffer_index_init(
-        data : *const c_void,
-        count : c_uint
-        ) -> *const CglBuffer;
-
-    pub fn cgl_shader_attribute_send(
-        att : *const shader::CglShaderAttribute,
-        buffer : *const CglBuffer) -> ();
-
-    pub fn cgl_buffer_update(
-        buffer : *const CglBuffer,
-        data : *const c_void,
-        count : c_uint);
-}
-
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BufferType
 {
     Vertex,
@@ -130,37 +109,20 @@ impl<T: BufferSend + Clone> BufferSendTest for T {}
 impl<T> BufferSend for Buffer<T> {
     fn send(&self) -> ()
     {
+        let data : *const c_void = unsafe { mem::transmute(self.data.as_ptr()) };
+        let count = self.data.len();
+
         match self.cgl_buffer.get() {
-            Some(b) => unsafe {
-                cgl_buffer_update(
-                    b,
-                    mem::transmute(self.data.as_ptr()),
-                    self.data.len() as c_uint);
+            Some(b) => {
+                backend::with_backend(|g| g.buffer_update(b, data, count));
                 return;
             },
             None => {}
         };
 
-        match self.buffer_type {
-            BufferType::Vertex => unsafe {
-                let cgl_buffer = cgl_buffer_init(
-                    mem::transmute(self.data.as_ptr()),
-                    self.data.len() as c_uint);
-                self.cgl_buffer.set(Some(cgl_buffer));
-            },
-            BufferType::Index => unsafe {
-                let cgl_buffer = cgl_buffer_index_init(
-                    mem::transmute(self.data.as_ptr()),
-                    self.data.len() as c_uint);
-                self.cgl_buffer.set(Some(cgl_buffer));
-            },
-            _ => unsafe {
-                let cgl_buffer = cgl_buffer_init(
-                    mem::transmute(self.data.as_ptr()),
-                    self.data.len() as c_uint);
-                self.cgl_buffer.set(Some(cgl_buffer));
-            }
-        }
+        let buffer_type = self.buffer_type;
+        let cgl_buffer = backend::with_backend(|g| g.buffer_init(buffer_type, data, count));
+        self.cgl_buffer.set(Some(cgl_buffer));
     }
 
     /*
@@ -183,9 +145,7 @@ impl<T> BufferSend for Buffer<T> {
     fn utilise(&self, att : *const shader::CglShaderAttribute) ->()
     {
         match self.cgl_buffer.get() {
-            Some(b) => unsafe {
-                cgl_shader_attribute_send(att, b);
-            },
+            Some(b) => backend::with_backend(|g| g.attribute_send(att, b)),
             None => ()
         }
     }
@@ -222,6 +182,23 @@ pub struct Weight
     pub weight : f32
 }
 
+/// Offset of one vertex in a morph target.
+#[derive(Clone)]
+pub struct MorphDelta
+{
+    pub index : usize,
+    pub position : vec::Vec3,
+    pub normal : vec::Vec3
+}
+
+/// Shape the mesh can be blended to, only the moved vertices have a delta.
+#[derive(Clone)]
+pub struct MorphTarget
+{
+    pub name : String,
+    pub deltas : Vec<MorphDelta>
+}
+
 #[derive(Clone)]
 pub struct Mesh
 {
@@ -234,7 +211,10 @@ pub struct Mesh
     pub draw_type : DrawType,
     pub aabox : Option<geometry::AABox>,
     buffers_f32_base : HashMap<String, Box<Buffer<f32>>>, //TODO check
-    pub weights : Vec<Vec<Weight>>
+    pub weights : Vec<Vec<Weight>>,
+    pub morph_targets : Vec<MorphTarget>,
+    // weight of each morph target
+    morph_weights : Vec<f64>
 }
 
 impl Mesh
@@ -249,7 +229,9 @@ impl Mesh
            draw_type : Faces,
            aabox : None,
            buffers_f32_base : HashMap::new(),
-           weights : Vec::new()
+           weights : Vec::new(),
+           morph_targets : Vec::new(),
+           morph_weights : Vec::new()
        };
 
        /*
@@ -276,6 +258,8 @@ impl Mesh
            aabox : None,
            buffers_f32_base : HashMap::new(),
            weights : Vec::new(),
+           morph_targets : Vec::new(),
+           morph_weights : Vec::new()
        };
 
        m
@@ -496,6 +480,20 @@ impl Mesh
            }
        }
 
+       // optional morph targets : name, then the moved vertices with
+       // their position and normal deltas
+       if let Ok(morph_count) = file.read_u16::<LittleEndian>() {
+           println!("morph target count : {} ", morph_count);
+           match read_morph_targets(&mut file, morph_count) {
+               Ok(targets) => {
+                   for t in targets {
+                       self.add_morph_target(t);
+                   }
+               },
+               Err(e) => println!("Error reading the morph targets of '{}'. Error: {}", self.name, e)
+           }
+       }
+
        self.state.set(1);
     }
 
@@ -677,6 +675,17 @@ impl Mesh
         self.buffers_f32.remove(&name);
     }
 
+    /// Removes the lines but keeps the buffers, so they are updated
+    /// instead of created again when lines are added.
+    pub fn reset_lines(&mut self)
+    {
+        for name in ["position", "color"].iter() {
+            if let Some(b) = self.buffers_f32.get_mut(*name) {
+                b.data.clear();
+            }
+        }
+    }
+
     pub fn add_quad(&mut self, w : f32, h : f32)
     {
         {
@@ -771,6 +780,111 @@ impl Mesh
         self.state.set(1);
     }
 
+    pub fn add_morph_target(&mut self, target : MorphTarget)
+    {
+        self.morph_targets.push(target);
+        self.morph_weights.push(0f64);
+    }
+
+    pub fn find_morph_target(&self, name : &str) -> Option<usize>
+    {
+        self.morph_targets.iter().position(|m| m.name == name)
+    }
+
+    /// Returns false if there is no such morph target.
+    pub fn set_morph_weight(&mut self, name : &str, weight : f64) -> bool
+    {
+        match self.find_morph_target(name) {
+            Some(i) => {
+                self.morph_weights[i] = weight;
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn get_morph_weight(&self, name : &str) -> f64
+    {
+        match self.find_morph_target(name) {
+            Some(i) => self.morph_weights[i],
+            None => 0f64
+        }
+    }
+
+    pub fn has_morph_targets(&self) -> bool
+    {
+        !self.morph_targets.is_empty()
+    }
+
+    /// Positions and normals of the base buffers with the weighted morph targets added.
+    /// The normals are empty if the mesh has none.
+    pub fn morphed_buffers(&self) -> Option<(Vec<f32>, Vec<f32>)>
+    {
+        let mut positions = match self.buffers_f32_base.get("position") {
+            Some(b) => b.data.clone(),
+            None => return None
+        };
+        let mut normals = match self.buffers_f32_base.get("normal") {
+            Some(b) => b.data.clone(),
+            None => Vec::new()
+        };
+
+        for (target, w) in self.morph_targets.iter().zip(self.morph_weights.iter()) {
+            if *w == 0f64 {
+                continue;
+            }
+
+            for d in target.deltas.iter() {
+                let i = d.index * 3;
+                if i + 2 < positions.len() {
+                    positions[i] = positions[i] + (d.position.x * *w) as f32;
+                    positions[i+1] = positions[i+1] + (d.position.y * *w) as f32;
+                    positions[i+2] = positions[i+2] + (d.position.z * *w) as f32;
+                }
+                if i + 2 < normals.len() {
+                    normals[i] = normals[i] + (d.normal.x * *w) as f32;
+                    normals[i+1] = normals[i+1] + (d.normal.y * *w) as f32;
+                    normals[i+2] = normals[i+2] + (d.normal.z * *w) as f32;
+                }
+            }
+        }
+
+        for n in normals.chunks_mut(3) {
+            if n.len() == 3 {
+                let l = (n[0]*n[0] + n[1]*n[1] + n[2]*n[2]).sqrt();
+                if l > 0f32 {
+                    n[0] = n[0] / l;
+                    n[1] = n[1] / l;
+                    n[2] = n[2] / l;
+                }
+            }
+        }
+
+        Some((positions, normals))
+    }
+
+    /// Writes the morphed positions and normals in the buffers, for meshes which
+    /// are not skinned (skinning applies the morph targets itself).
+    pub fn apply_morph_targets(&mut self)
+    {
+        let (positions, normals) = match self.morphed_buffers() {
+            Some(b) => b,
+            None => return
+        };
+
+        if let Some(b) = self.buffers_f32.get_mut("position") {
+            b.data = positions;
+        }
+
+        if !normals.is_empty() {
+            if let Some(b) = self.buffers_f32.get_mut("normal") {
+                b.data = normals;
+            }
+        }
+
+        self.set_dirty();
+    }
+
     /// Set as dirty to resend the buffers
     pub fn set_dirty(&mut self)
     {
@@ -799,6 +913,38 @@ impl resource::ResourceT for Mesh
     }
 }
 
+/// Morph targets written after the weights, the file can end in the middle.
+fn read_morph_targets<T:Read>(file : &mut T, count : u16) -> io::Result<Vec<MorphTarget>>
+{
+    let mut targets = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let name_len = file.read_u16::<LittleEndian>()?;
+        let mut name = vec![0u8; name_len as usize];
+        file.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let delta_count = file.read_u16::<LittleEndian>()? as usize;
+        let mut deltas = Vec::with_capacity(delta_count);
+        for _ in 0..delta_count {
+            let index = file.read_u16::<LittleEndian>()? as usize;
+            let mut v = [0f64; 6];
+            for c in v.iter_mut() {
+                *c = file.read_f32::<LittleEndian>()? as f64;
+            }
+            deltas.push(MorphDelta {
+                index : index,
+                position : vec::Vec3::new(v[0], v[1], v[2]),
+                normal : vec::Vec3::new(v[3], v[4], v[5])
+            });
+        }
+
+        targets.push(MorphTarget { name : name, deltas : deltas });
+    }
+
+    Ok(targets)
+}
+
 //pub fn read_string(file: &mut Read) -> String
 pub fn read_string<T:Read>(file: &mut T) -> String
 {
@@ -828,3 +974,73 @@ impl fmt::Debug for Mesh
 
 }
 
+
+#[cfg(test)]
+mod test
+{
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+    use super::*;
+
+    /// Two vertices on x, without normals, and a target moving the second one up.
+    fn morph_mesh() -> Mesh
+    {
+        let mut m = Mesh::new();
+        let position = Buffer::new(String::from("position"), vec![0f32, 0f32, 0f32, 1f32, 0f32, 0f32], BufferType::Vertex);
+        m.buffers_f32_base.insert(String::from("position"), box position.copy());
+        m.buffers_f32.insert(String::from("position"), box position);
+
+        m.add_morph_target(MorphTarget {
+            name : String::from("up"),
+            deltas : vec![MorphDelta {
+                index : 1,
+                position : vec::Vec3::new(0f64, 2f64, 0f64),
+                normal : vec::Vec3::zero()
+            }]
+        });
+        m
+    }
+
+    #[test]
+    fn test_apply_morph_targets()
+    {
+        let mut m = morph_mesh();
+        assert!(!m.set_morph_weight("missing", 1f64));
+        assert!(m.set_morph_weight("up", 0.5f64));
+
+        m.apply_morph_targets();
+        assert_eq!(m.buffer_f32_get("position").unwrap().data, vec![0f32, 0f32, 0f32, 1f32, 1f32, 0f32]);
+
+        // the weights apply to the base, not to the last result
+        m.set_morph_weight("up", 0f64);
+        m.apply_morph_targets();
+        assert_eq!(m.buffer_f32_get("position").unwrap().data, vec![0f32, 0f32, 0f32, 1f32, 0f32, 0f32]);
+    }
+
+    fn morph_bytes(floats : usize) -> Vec<u8>
+    {
+        let mut bytes = Vec::new();
+        bytes.write_u16::<LittleEndian>(2).unwrap();
+        bytes.extend_from_slice(b"up");
+        bytes.write_u16::<LittleEndian>(1).unwrap();
+        bytes.write_u16::<LittleEndian>(1).unwrap();
+        for i in 0..floats {
+            bytes.write_f32::<LittleEndian>(i as f32).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_morph_targets()
+    {
+        let targets = read_morph_targets(&mut Cursor::new(morph_bytes(6)), 1).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].name, "up");
+        assert_eq!(targets[0].deltas[0].index, 1);
+        assert_eq!(targets[0].deltas[0].normal, vec::Vec3::new(3f64, 4f64, 5f64));
+
+        // a truncated file is an error, not a panic
+        assert!(read_morph_targets(&mut Cursor::new(morph_bytes(4)), 1).is_err());
+        assert!(read_morph_targets(&mut Cursor::new(morph_bytes(6)), 2).is_err());
+    }
+}
@@ -17,6 +17,15 @@ pub struct MeshRender
 {
     pub mesh : ResTT<mesh::Mesh>,
     pub material : ResTT<material::Material>,
+    #[serde(default="default_true")]
+    pub cast_shadows : bool,
+    #[serde(default="default_true")]
+    pub receive_shadows : bool,
+}
+
+fn default_true() -> bool
+{
+    true
 }
 
 impl Default for MeshRender {
@@ -24,6 +33,8 @@ impl Default for MeshRender {
         MeshRender  {
             mesh : ResTT::new("no_mesh"),
             material : ResTT::new("no_mat"),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 }
@@ -45,6 +56,8 @@ impl MeshRender{
         MeshRender {
             mesh : resource.mesh_manager.borrow_mut().get_handle_instant(mesh),
             material : resource.material_manager.borrow_mut().get_handle_instant(material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -52,7 +65,9 @@ impl MeshRender{
     {
         MeshRender {
             mesh : resource::ResTT::new(mesh),
-            material : resource::ResTT::new(material)
+            material : resource::ResTT::new(material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -64,6 +79,8 @@ impl MeshRender{
         MeshRender {
             mesh : mesh,
             material : resource.material_manager.borrow_mut().get_handle_instant(material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -76,6 +93,8 @@ impl MeshRender{
             //TODO
             mesh : ResTT::new_with_instance("none", mesh),
             material : ResTT::new(material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -87,6 +106,8 @@ impl MeshRender{
         MeshRender {
             mesh : resource.mesh_manager.borrow_mut().get_handle_instant(mesh),
             material : ResTT::new_with_instance("no_name0", material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -98,6 +119,8 @@ impl MeshRender{
         MeshRender {
             mesh : resource::ResTT::new(mesh),
             material : ResTT::new_with_instance("no_name2", material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -110,6 +133,8 @@ impl MeshRender{
         MeshRender {
             mesh : resource.mesh_manager.borrow_mut().get_handle_instant(mesh),
             material : material,
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -122,6 +147,8 @@ impl MeshRender{
         MeshRender {
             mesh : mesh,
             material : material,
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
@@ -133,6 +160,8 @@ impl MeshRender{
         MeshRender {
             mesh : ResTT::new_with_instance("none", mesh),
             material : ResTT::new_with_instance("none", material),
+            cast_shadows : true,
+            receive_shadows : true,
         }
     }
 
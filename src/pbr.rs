@@ -0,0 +1,676 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use serde_json;
+
+use vec;
+use shader;
+use texture;
+use resource;
+use material::{Material, Sampler, Queue, Blend, Cull};
+
+/// Name of the reference shader in the shader manager, see register.
+pub const SHADER_NAME : &'static str = "pbr";
+
+/// Samplers of the pbr materials, metallic is in the blue channel and roughness
+/// in the green channel of the metallic roughness map, like in gltf.
+pub const BASE_COLOR_MAP : &'static str = "base_color_map";
+pub const METALLIC_ROUGHNESS_MAP : &'static str = "metallic_roughness_map";
+pub const NORMAL_MAP : &'static str = "normal_map";
+pub const OCCLUSION_MAP : &'static str = "occlusion_map";
+pub const EMISSIVE_MAP : &'static str = "emissive_map";
+
+/// Uniforms of the pbr materials, the maps are multiplied by the factors.
+pub const BASE_COLOR_FACTOR : &'static str = "base_color_factor";
+pub const METALLIC_FACTOR : &'static str = "metallic_factor";
+pub const ROUGHNESS_FACTOR : &'static str = "roughness_factor";
+pub const NORMAL_SCALE : &'static str = "normal_scale";
+pub const OCCLUSION_STRENGTH : &'static str = "occlusion_strength";
+pub const EMISSIVE_FACTOR : &'static str = "emissive_factor";
+pub const ALPHA_CUTOFF : &'static str = "alpha_cutoff";
+
+/// Textures used when a map is missing, they leave the factors unchanged.
+pub const DEFAULT_WHITE : &'static str = "pbr_default_white";
+pub const DEFAULT_NORMAL : &'static str = "pbr_default_normal";
+
+pub const VERT : &'static str = "attribute vec3 position;
+attribute vec3 normal;
+attribute vec2 texcoord;
+uniform mat4 matrix;
+uniform mat4 world_matrix;
+varying vec3 world_position;
+varying vec3 world_normal;
+varying vec2 uv;
+void main()
+{
+    // the normal is not corrected for non uniform scales
+    world_position = (world_matrix * vec4(position, 1.0)).xyz;
+    world_normal = mat3(world_matrix) * normal;
+    uv = texcoord;
+    gl_Position = matrix * vec4(position, 1.0);
+}
+";
+
+/// Cook-Torrance with the GGX distribution, lit by the lights of light::send_lights.
+/// The tangents come from the screen derivatives, meshes do not have them.
+pub const FRAG : &'static str = "#extension GL_OES_standard_derivatives : enable
+precision mediump float;
+
+const int MAX_LIGHTS = 4;
+const float PI = 3.14159265;
+
+uniform sampler2D base_color_map;
+uniform sampler2D metallic_roughness_map;
+uniform sampler2D normal_map;
+uniform sampler2D occlusion_map;
+uniform sampler2D emissive_map;
+
+uniform vec4 base_color_factor;
+uniform float metallic_factor;
+uniform float roughness_factor;
+uniform float normal_scale;
+uniform float occlusion_strength;
+uniform vec3 emissive_factor;
+uniform float alpha_cutoff;
+
+uniform vec3 camera_position;
+uniform int light_count;
+uniform int light_type[MAX_LIGHTS];
+uniform vec3 light_color[MAX_LIGHTS];
+uniform vec3 light_position[MAX_LIGHTS];
+uniform vec3 light_direction[MAX_LIGHTS];
+uniform float light_range[MAX_LIGHTS];
+uniform vec2 light_cone[MAX_LIGHTS];
+
+varying vec3 world_position;
+varying vec3 world_normal;
+varying vec2 uv;
+
+vec3 surface_normal()
+{
+    vec3 n = normalize(world_normal);
+    vec3 dp1 = dFdx(world_position);
+    vec3 dp2 = dFdy(world_position);
+    vec2 duv1 = dFdx(uv);
+    vec2 duv2 = dFdy(uv);
+
+    vec3 dp2perp = cross(dp2, n);
+    vec3 dp1perp = cross(n, dp1);
+    vec3 t = dp2perp * duv1.x + dp1perp * duv2.x;
+    vec3 b = dp2perp * duv1.y + dp1perp * duv2.y;
+    float len = max(dot(t, t), dot(b, b));
+    if (len < 1e-8) {
+        return n;
+    }
+    float inv = inversesqrt(len);
+
+    vec3 m = texture2D(normal_map, uv).xyz * 2.0 - 1.0;
+    m.xy *= normal_scale;
+    return normalize(mat3(t * inv, b * inv, n) * m);
+}
+
+void main()
+{
+    vec4 base = texture2D(base_color_map, uv);
+    base.rgb = pow(base.rgb, vec3(2.2));
+    base *= base_color_factor;
+    if (base.a < alpha_cutoff) {
+        discard;
+    }
+
+    vec4 mr = texture2D(metallic_roughness_map, uv);
+    float metallic = clamp(mr.b * metallic_factor, 0.0, 1.0);
+    float roughness = clamp(mr.g * roughness_factor, 0.04, 1.0);
+    float alpha = roughness * roughness;
+
+    vec3 n = surface_normal();
+    vec3 v = normalize(camera_position - world_position);
+    float nv = max(dot(n, v), 1e-4);
+    vec3 f0 = mix(vec3(0.04), base.rgb, metallic);
+    vec3 diffuse = base.rgb * (1.0 - metallic) / PI;
+
+    vec3 color = vec3(0.0);
+    for (int i = 0; i < MAX_LIGHTS; i++) {
+        if (i >= light_count) {
+            break;
+        }
+
+        vec3 l = -light_direction[i];
+        float attenuation = 1.0;
+        if (light_type[i] != 0) {
+            vec3 to_light = light_position[i] - world_position;
+            float d = length(to_light);
+            l = to_light / max(d, 1e-4);
+            float falloff = clamp(1.0 - d / light_range[i], 0.0, 1.0);
+            attenuation = falloff * falloff;
+            if (light_type[i] == 2) {
+                float c = dot(-l, light_direction[i]);
+                attenuation *= smoothstep(light_cone[i].y, light_cone[i].x, c);
+            }
+        }
+
+        float nl = max(dot(n, l), 0.0);
+        if (nl <= 0.0 || attenuation <= 0.0) {
+            continue;
+        }
+
+        vec3 h = normalize(l + v);
+        float nh = max(dot(n, h), 0.0);
+        float vh = max(dot(v, h), 0.0);
+
+        float a2 = alpha * alpha;
+        float dd = nh * nh * (a2 - 1.0) + 1.0;
+        float distribution = a2 / (PI * dd * dd);
+        float k = alpha * 0.5;
+        float visibility = 0.25 / ((nl * (1.0 - k) + k) * (nv * (1.0 - k) + k));
+        vec3 fresnel = f0 + (1.0 - f0) * pow(1.0 - vh, 5.0);
+
+        vec3 specular = fresnel * distribution * visibility;
+        color += ((1.0 - fresnel) * diffuse + specular) * light_color[i] * nl * attenuation;
+    }
+
+    float occlusion = mix(1.0, texture2D(occlusion_map, uv).r, occlusion_strength);
+    color += base.rgb * 0.03 * occlusion;
+
+    vec3 emissive = pow(texture2D(emissive_map, uv).rgb, vec3(2.2)) * emissive_factor;
+    color += emissive;
+
+    gl_FragColor = vec4(pow(color, vec3(1.0 / 2.2)), base.a);
+}
+";
+
+/// How the alpha of the base color is used, named like in gltf.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode
+{
+    /// alpha is ignored
+    #[serde(rename="OPAQUE")]
+    Opaque,
+    /// pixels with alpha under the cutoff are discarded
+    #[serde(rename="MASK")]
+    Mask,
+    /// alpha blended, in the transparent queue
+    #[serde(rename="BLEND")]
+    Blend,
+}
+
+impl Default for AlphaMode
+{
+    fn default() -> AlphaMode
+    {
+        AlphaMode::Opaque
+    }
+}
+
+/// Metallic roughness material. The maps are texture paths, None uses the default textures.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PbrMaterial
+{
+    /// linear rgba, multiplied by the base color map which is srgb
+    #[serde(default="default_base_color")]
+    pub base_color : vec::Vec4,
+    #[serde(default)]
+    pub base_color_map : Option<String>,
+    #[serde(default="default_one")]
+    pub metallic : f64,
+    #[serde(default="default_one")]
+    pub roughness : f64,
+    #[serde(default)]
+    pub metallic_roughness_map : Option<String>,
+    #[serde(default)]
+    pub normal_map : Option<String>,
+    #[serde(default="default_one")]
+    pub normal_scale : f64,
+    #[serde(default)]
+    pub occlusion_map : Option<String>,
+    #[serde(default="default_one")]
+    pub occlusion_strength : f64,
+    /// linear rgb, multiplied by the emissive map which is srgb
+    #[serde(default)]
+    pub emissive : vec::Vec3,
+    #[serde(default)]
+    pub emissive_map : Option<String>,
+    #[serde(default)]
+    pub alpha_mode : AlphaMode,
+    #[serde(default="default_alpha_cutoff")]
+    pub alpha_cutoff : f64,
+    #[serde(default)]
+    pub double_sided : bool,
+}
+
+fn default_one() -> f64
+{
+    1f64
+}
+
+fn default_base_color() -> vec::Vec4
+{
+    vec::Vec4::new(1f64, 1f64, 1f64, 1f64)
+}
+
+fn default_alpha_cutoff() -> f64
+{
+    0.5f64
+}
+
+impl Default for PbrMaterial
+{
+    fn default() -> PbrMaterial
+    {
+        PbrMaterial {
+            base_color : default_base_color(),
+            base_color_map : None,
+            metallic : 1f64,
+            roughness : 1f64,
+            metallic_roughness_map : None,
+            normal_map : None,
+            normal_scale : 1f64,
+            occlusion_map : None,
+            occlusion_strength : 1f64,
+            emissive : vec::Vec3::zero(),
+            emissive_map : None,
+            alpha_mode : AlphaMode::Opaque,
+            alpha_cutoff : default_alpha_cutoff(),
+            double_sided : false
+        }
+    }
+}
+
+fn image_sampler(path : &Option<String>, default : &str) -> Sampler
+{
+    let name = match *path {
+        Some(ref p) => p.as_str(),
+        None => default
+    };
+
+    Sampler::ImageFile(resource::ResTT::new(name))
+}
+
+impl PbrMaterial
+{
+    /// Sets the textures, uniforms, queue and render state of the material,
+    /// the other textures and uniforms are kept.
+    pub fn apply(&self, material : &mut Material)
+    {
+        material.set_texture_data(BASE_COLOR_MAP, image_sampler(&self.base_color_map, DEFAULT_WHITE));
+        material.set_texture_data(
+            METALLIC_ROUGHNESS_MAP,
+            image_sampler(&self.metallic_roughness_map, DEFAULT_WHITE));
+        material.set_texture_data(NORMAL_MAP, image_sampler(&self.normal_map, DEFAULT_NORMAL));
+        material.set_texture_data(OCCLUSION_MAP, image_sampler(&self.occlusion_map, DEFAULT_WHITE));
+        material.set_texture_data(EMISSIVE_MAP, image_sampler(&self.emissive_map, DEFAULT_WHITE));
+
+        let cutoff = if self.alpha_mode == AlphaMode::Mask { self.alpha_cutoff } else { 0f64 };
+
+        material.set_uniform_data(BASE_COLOR_FACTOR, shader::UniformData::Vec4(self.base_color));
+        material.set_uniform_data(METALLIC_FACTOR, shader::UniformData::Float(self.metallic as f32));
+        material.set_uniform_data(ROUGHNESS_FACTOR, shader::UniformData::Float(self.roughness as f32));
+        material.set_uniform_data(NORMAL_SCALE, shader::UniformData::Float(self.normal_scale as f32));
+        material.set_uniform_data(
+            OCCLUSION_STRENGTH,
+            shader::UniformData::Float(self.occlusion_strength as f32));
+        material.set_uniform_data(EMISSIVE_FACTOR, shader::UniformData::Vec3(self.emissive));
+        material.set_uniform_data(ALPHA_CUTOFF, shader::UniformData::Float(cutoff as f32));
+
+        let (queue, blend) = match self.alpha_mode {
+            AlphaMode::Opaque => (Queue::Opaque, Blend::Off),
+            AlphaMode::Mask => (Queue::AlphaTest, Blend::Off),
+            AlphaMode::Blend => (Queue::Transparent, Blend::Alpha),
+        };
+
+        material.queue = queue;
+        material.render_state.blend = blend;
+        material.render_state.cull = if self.double_sided { Cull::Off } else { Cull::Back };
+    }
+
+    /// Material using the reference shader.
+    pub fn to_material(&self, name : &str) -> Material
+    {
+        let mut m = Material::new(name);
+        m.shader = Some(resource::ResTT::new(SHADER_NAME));
+        self.apply(&mut m);
+        m
+    }
+
+    /// From a gltf material, textures are the image paths of the gltf textures,
+    /// None when the image could not be found.
+    pub fn from_gltf(m : &GltfMaterial, textures : &[Option<String>]) -> PbrMaterial
+    {
+        let path = |info : &Option<GltfTextureInfo>| -> Option<String> {
+            let info = match *info {
+                Some(ref i) => i,
+                None => return None
+            };
+
+            if info.tex_coord != 0 {
+                println!("pbr, material '{}' uses texcoord {}, only 0 is supported", m.name, info.tex_coord);
+            }
+
+            match textures.get(info.index) {
+                Some(t) => t.clone(),
+                None => {
+                    println!("pbr, material '{}' uses texture {} which does not exist", m.name, info.index);
+                    None
+                }
+            }
+        };
+
+        let pbr = &m.pbr;
+        let c = pbr.base_color_factor;
+        let e = m.emissive_factor;
+
+        PbrMaterial {
+            base_color : vec::Vec4::new(c[0], c[1], c[2], c[3]),
+            base_color_map : path(&pbr.base_color_texture),
+            metallic : pbr.metallic_factor,
+            roughness : pbr.roughness_factor,
+            metallic_roughness_map : path(&pbr.metallic_roughness_texture),
+            normal_map : path(&m.normal_texture),
+            normal_scale : m.normal_texture.as_ref().map_or(1f64, |t| t.scale),
+            occlusion_map : path(&m.occlusion_texture),
+            occlusion_strength : m.occlusion_texture.as_ref().map_or(1f64, |t| t.strength),
+            emissive : vec::Vec3::new(e[0], e[1], e[2]),
+            emissive_map : path(&m.emissive_texture),
+            alpha_mode : m.alpha_mode,
+            alpha_cutoff : m.alpha_cutoff,
+            double_sided : m.double_sided
+        }
+    }
+}
+
+/// Texture of a gltf material, scale is for normal maps and strength for occlusion maps.
+#[derive(Deserialize, Clone, Debug)]
+pub struct GltfTextureInfo
+{
+    pub index : usize,
+    #[serde(default, rename="texCoord")]
+    pub tex_coord : usize,
+    #[serde(default="default_one")]
+    pub scale : f64,
+    #[serde(default="default_one")]
+    pub strength : f64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GltfPbrMetallicRoughness
+{
+    #[serde(default="default_base_color_factor", rename="baseColorFactor")]
+    pub base_color_factor : [f64; 4],
+    #[serde(default, rename="baseColorTexture")]
+    pub base_color_texture : Option<GltfTextureInfo>,
+    #[serde(default="default_one", rename="metallicFactor")]
+    pub metallic_factor : f64,
+    #[serde(default="default_one", rename="roughnessFactor")]
+    pub roughness_factor : f64,
+    #[serde(default, rename="metallicRoughnessTexture")]
+    pub metallic_roughness_texture : Option<GltfTextureInfo>,
+}
+
+fn default_base_color_factor() -> [f64; 4]
+{
+    [1f64; 4]
+}
+
+impl Default for GltfPbrMetallicRoughness
+{
+    fn default() -> GltfPbrMetallicRoughness
+    {
+        GltfPbrMetallicRoughness {
+            base_color_factor : default_base_color_factor(),
+            base_color_texture : None,
+            metallic_factor : 1f64,
+            roughness_factor : 1f64,
+            metallic_roughness_texture : None
+        }
+    }
+}
+
+/// A material of the gltf "materials" array, the extensions are ignored.
+#[derive(Deserialize, Clone, Debug)]
+pub struct GltfMaterial
+{
+    #[serde(default)]
+    pub name : String,
+    #[serde(default, rename="pbrMetallicRoughness")]
+    pub pbr : GltfPbrMetallicRoughness,
+    #[serde(default, rename="normalTexture")]
+    pub normal_texture : Option<GltfTextureInfo>,
+    #[serde(default, rename="occlusionTexture")]
+    pub occlusion_texture : Option<GltfTextureInfo>,
+    #[serde(default, rename="emissiveTexture")]
+    pub emissive_texture : Option<GltfTextureInfo>,
+    #[serde(default, rename="emissiveFactor")]
+    pub emissive_factor : [f64; 3],
+    #[serde(default, rename="alphaMode")]
+    pub alpha_mode : AlphaMode,
+    #[serde(default="default_alpha_cutoff", rename="alphaCutoff")]
+    pub alpha_cutoff : f64,
+    #[serde(default, rename="doubleSided")]
+    pub double_sided : bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct GltfTexture
+{
+    #[serde(default)]
+    source : Option<usize>
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct GltfImage
+{
+    /// embedded images have no uri
+    #[serde(default)]
+    uri : Option<String>
+}
+
+/// The parts of a gltf file needed for the materials.
+#[derive(Deserialize, Clone, Debug)]
+struct GltfMaterials
+{
+    #[serde(default)]
+    materials : Vec<GltfMaterial>,
+    #[serde(default)]
+    textures : Vec<GltfTexture>,
+    #[serde(default)]
+    images : Vec<GltfImage>,
+}
+
+/// Materials of a gltf json file with their names, the image uris are relative to the file.
+/// Images which are embedded or in data uris are replaced by the default textures.
+pub fn read_gltf_materials(file_path : &str) -> Result<Vec<(String, PbrMaterial)>, String>
+{
+    let mut file = String::new();
+    match File::open(&Path::new(file_path)) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_string(&mut file) {
+                return Err(format!("error reading gltf '{}' : {}", file_path, e));
+            }
+        },
+        Err(e) => return Err(format!("error opening gltf '{}' : {}", file_path, e))
+    };
+
+    let gltf : GltfMaterials = match serde_json::from_str(&file) {
+        Ok(g) => g,
+        Err(e) => return Err(format!("error reading gltf materials '{}' : {:?}", file_path, e))
+    };
+
+    let dir = Path::new(file_path).parent().unwrap_or(Path::new(""));
+    let textures : Vec<Option<String>> = gltf.textures.iter().map(|t| {
+        let uri = t.source.and_then(|s| gltf.images.get(s)).and_then(|i| i.uri.as_ref());
+        match uri {
+            Some(u) if !u.starts_with("data:") => dir.join(u).to_str().map(|s| String::from(s)),
+            _ => None
+        }
+    }).collect();
+
+    Ok(gltf.materials.iter().enumerate().map(|(i, m)| {
+        let name = if m.name.is_empty() { format!("material_{}", i) } else { m.name.clone() };
+        (name, PbrMaterial::from_gltf(m, &textures))
+    }).collect())
+}
+
+/// The reference shader, for materials made with PbrMaterial.
+pub fn shader() -> shader::Shader
+{
+    shader::Shader::with_vert_frag(String::from(SHADER_NAME), String::from(VERT), String::from(FRAG))
+}
+
+fn default_texture(name : &str, pixel : [u8; 4]) -> texture::Texture
+{
+    // 2x2, texture init reads the first 8 bytes
+    let pixels = pixel.iter().cycle().take(16).cloned().collect();
+    texture::Texture::with_rgba(name, 2, 2, pixels)
+}
+
+/// Adds the reference shader and the default textures to the managers,
+/// before drawing pbr materials.
+pub fn register(resource : &resource::ResourceGroup)
+{
+    {
+        let texture_manager = &mut *resource.texture_manager.borrow_mut();
+        if !texture_manager.contains(DEFAULT_WHITE) {
+            texture_manager.add_resource(DEFAULT_WHITE, default_texture(DEFAULT_WHITE, [255, 255, 255, 255]));
+        }
+        if !texture_manager.contains(DEFAULT_NORMAL) {
+            texture_manager.add_resource(DEFAULT_NORMAL, default_texture(DEFAULT_NORMAL, [128, 128, 255, 255]));
+        }
+    }
+
+    let shader_manager = &mut *resource.shader_manager.borrow_mut();
+    if !shader_manager.contains(SHADER_NAME) {
+        shader_manager.add_resource(SHADER_NAME, shader());
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use backend;
+    use backend::Call;
+    use matrix;
+    use mesh;
+    use mesh_render;
+    use render;
+    use transform;
+    use super::*;
+
+    /// Draws a quad with the material, returns the textures sent to each sampler
+    /// with their size and unit, and the uniforms.
+    fn draw(pbr : &PbrMaterial) -> (HashMap<String, (usize, (u32, u32), u32)>, Vec<Call>)
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        register(&resources);
+        resources.texture_manager.borrow_mut().add_resource(
+            "albedo.png",
+            texture::Texture::with_rgba("albedo.png", 4, 4, vec![255u8; 64]));
+
+        let material = resources.material_manager.borrow_mut().add_resource("pbr_test", pbr.to_material("pbr_test"));
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+        let mr = mesh_render::MeshRender {
+            mesh : resources.mesh_manager.borrow_mut().add_resource("quad", m),
+            material : material,
+            cast_shadows : true,
+            receive_shadows : true
+        };
+
+        let camera = render::CameraIdMat {
+            id : 0usize,
+            orientation : transform::Orientation::new_quat(),
+            matrix : matrix::Matrix4::identity()
+        };
+
+        let mut passes = HashMap::new();
+        {
+            let material_manager = &mut *resources.material_manager.borrow_mut();
+            let shader_manager = &mut *resources.shader_manager.borrow_mut();
+            let cp = render::get_pass_from_mesh_render(
+                &mr, &mut passes, material_manager, shader_manager, &camera, Arc::new(Mutex::new(0)));
+            cp.unwrap().add_mmr(render::MatrixMeshRender::new(matrix::Matrix4::identity(), mr));
+        }
+        render::draw_passes(&passes, &resources, Arc::new(Mutex::new(0)));
+
+        let calls = calls.borrow().clone();
+        let mut sizes = HashMap::new();
+        let mut samplers = HashMap::new();
+        for c in calls.iter() {
+            match *c {
+                Call::TextureInit(h, w, hh) => { sizes.insert(h, (w, hh)); },
+                Call::UniformTexture(ref n, h, unit) => { samplers.insert(n.clone(), (h, sizes[&h], unit)); },
+                _ => {}
+            }
+        }
+
+        (samplers, calls)
+    }
+
+    fn float(calls : &[Call], name : &str) -> Option<f32>
+    {
+        calls.iter().filter_map(|c| match *c {
+            Call::UniformFloat(ref n, v) if n == name => Some(v),
+            _ => None
+        }).last()
+    }
+
+    fn vector(calls : &[Call], name : &str) -> Option<Vec<f32>>
+    {
+        calls.iter().filter_map(|c| match *c {
+            Call::UniformVec(ref n, ref v) if n == name => Some(v.clone()),
+            _ => None
+        }).last()
+    }
+
+    #[test]
+    fn test_texture_slots()
+    {
+        let mut pbr = PbrMaterial::default();
+        pbr.base_color_map = Some(String::from("albedo.png"));
+        let (samplers, _) = draw(&pbr);
+
+        assert_eq!(samplers.len(), 5);
+        assert_eq!(samplers[BASE_COLOR_MAP].1, (4, 4));
+
+        // the missing maps use the default textures, white except for the normal map
+        let white = samplers[METALLIC_ROUGHNESS_MAP].0;
+        assert_eq!(samplers[OCCLUSION_MAP].0, white);
+        assert_eq!(samplers[EMISSIVE_MAP].0, white);
+        assert!(samplers[NORMAL_MAP].0 != white);
+        assert!(samplers[BASE_COLOR_MAP].0 != white);
+
+        // each sampler has its own unit
+        let mut units : Vec<u32> = samplers.values().map(|s| s.2).collect();
+        units.sort();
+        assert_eq!(units, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_defaults()
+    {
+        let (samplers, calls) = draw(&PbrMaterial::default());
+
+        let white = samplers[BASE_COLOR_MAP].0;
+        assert_eq!(samplers[METALLIC_ROUGHNESS_MAP].0, white);
+        assert!(samplers[NORMAL_MAP].0 != white);
+
+        assert_eq!(vector(&calls, BASE_COLOR_FACTOR), Some(vec![1f32, 1f32, 1f32, 1f32]));
+        assert_eq!(vector(&calls, EMISSIVE_FACTOR), Some(vec![0f32, 0f32, 0f32]));
+        assert_eq!(float(&calls, METALLIC_FACTOR), Some(1f32));
+        assert_eq!(float(&calls, ROUGHNESS_FACTOR), Some(1f32));
+        assert_eq!(float(&calls, NORMAL_SCALE), Some(1f32));
+        assert_eq!(float(&calls, OCCLUSION_STRENGTH), Some(1f32));
+        // only used in mask mode
+        assert_eq!(float(&calls, ALPHA_CUTOFF), Some(0f32));
+
+        let mut mask = PbrMaterial::default();
+        mask.alpha_mode = AlphaMode::Mask;
+        let (_, calls) = draw(&mask);
+        assert_eq!(float(&calls, ALPHA_CUTOFF), Some(0.5f32));
+    }
+}
@@ -6,6 +6,7 @@ use transform;
 use resource;
 use mesh;
 use material;
+use shader;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::sync::{Arc, RwLock};
@@ -463,6 +464,14 @@ impl PropertyWrite for transform::Orientation
           None => {}
       }
 
+      match value.downcast_ref::<vec::Quat>() {
+          Some(q) => {
+              self.set_with_quat(*q);
+              return;
+          }
+          None => {}
+      }
+
       match value.downcast_ref::<String>() {
           Some(s) => {
               match s.as_ref() {
@@ -570,6 +579,43 @@ fn join_string(path : &Vec<String>) -> String
 }
 */
 
+impl PropertyWrite for material::Material
+{
+  /// "uniforms/<name>" sets a uniform, the value is a UniformData or
+  /// a f64, Vec2, Vec3 or Vec4 which is converted.
+  fn test_set_property_hier(&mut self, name : &str, value: &Any)
+  {
+      let v : Vec<&str> = name.split('/').collect();
+
+      if v.len() != 2 || v[0] != "uniforms" {
+          println!("material, no such member : {}", name);
+          return;
+      }
+
+      let data = if let Some(d) = value.downcast_ref::<shader::UniformData>() {
+          d.clone()
+      }
+      else if let Some(f) = value.downcast_ref::<f64>() {
+          shader::UniformData::Float(*f as f32)
+      }
+      else if let Some(x) = value.downcast_ref::<vec::Vec2>() {
+          shader::UniformData::Vec2(*x)
+      }
+      else if let Some(x) = value.downcast_ref::<vec::Vec3>() {
+          shader::UniformData::Vec3(*x)
+      }
+      else if let Some(x) = value.downcast_ref::<vec::Vec4>() {
+          shader::UniformData::Vec4(*x)
+      }
+      else {
+          println!("material, wrong type for uniform : {}", v[1]);
+          return;
+      };
+
+      self.set_uniform_data(v[1], data);
+  }
+}
+
 
 #[macro_export]
 macro_rules! property_set_impl(
@@ -701,6 +747,7 @@ macro_rules! property_set_impl(
 
 property_set_impl!(vec::Vec3,[x,y,z]);
 property_set_impl!(vec::Quat,[x,y,z,w]);
+property_set_impl!(vec::Vec4,[x,y,z,w]);
 //property_set_impl!(mesh_render::MeshRender,[mesh,material]);
 //property_set_impl!(armature::MeshRender,[mesh,material]);
 property_set_impl!(transform::Transform,[position,orientation,scale]);
@@ -754,6 +801,7 @@ macro_rules! property_get_impl(
 
 property_get_impl!(vec::Vec3,[x,y,z]);
 property_get_impl!(vec::Quat,[x,y,z,w]);
+property_get_impl!(vec::Vec4,[x,y,z,w]);
 property_get_impl!(resource::ResTT<mesh::Mesh>,[name]);
 property_get_impl!(resource::ResTT<material::Material>,[name]);
 //property_get_impl!(mesh_render::MeshRender,[mesh,material]);
@@ -0,0 +1,342 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use serde_json;
+
+use property::{PropertyWrite, WriteValue};
+use armature_animation::{Playback, LoopMode};
+use vec;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum PropertyValue
+{
+    Float(f64),
+    Vec3(vec::Vec3),
+    Vec4(vec::Vec4),
+    Quat(vec::Quat),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation
+{
+    Linear,
+    /// keeps the value of the previous key
+    Step,
+}
+
+/// time is in seconds
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct PropertyKey
+{
+    pub time : f64,
+    pub value : PropertyValue
+}
+
+/// Keys of one property, the path is the one given to PropertyWrite::set_property_hier,
+/// for example "position/x" or "orientation" on a transform.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PropertyCurve
+{
+    pub path : String,
+    pub interpolation : Interpolation,
+    pub keys : Vec<PropertyKey>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PropertyClip
+{
+    pub name : String,
+    pub curves : Vec<PropertyCurve>
+}
+
+/// Plays a clip on any object implementing PropertyWrite.
+pub struct PropertyAnimation
+{
+    pub clip : PropertyClip,
+    pub playback : Playback
+}
+
+fn lerp(a : f64, b : f64, t : f64) -> f64
+{
+    a + (b - a) * t
+}
+
+impl PropertyValue
+{
+    /// Values of different kinds do not interpolate, the first one is kept.
+    pub fn interpolate(&self, other : &PropertyValue, t : f64) -> PropertyValue
+    {
+        match (*self, *other) {
+            (PropertyValue::Float(a), PropertyValue::Float(b)) => {
+                PropertyValue::Float(lerp(a, b, t))
+            },
+            (PropertyValue::Vec3(a), PropertyValue::Vec3(b)) => {
+                PropertyValue::Vec3(a + (b - a) * t)
+            },
+            (PropertyValue::Vec4(a), PropertyValue::Vec4(b)) => {
+                PropertyValue::Vec4(vec::Vec4::new(
+                        lerp(a.x, b.x, t),
+                        lerp(a.y, b.y, t),
+                        lerp(a.z, b.z, t),
+                        lerp(a.w, b.w, t)))
+            },
+            (PropertyValue::Quat(a), PropertyValue::Quat(b)) => {
+                PropertyValue::Quat(vec::quat_slerp(a, b, t))
+            },
+            _ => *self
+        }
+    }
+
+    pub fn to_write_value(&self) -> WriteValue
+    {
+        match *self {
+            PropertyValue::Float(v) => WriteValue::Any(box v),
+            PropertyValue::Vec3(v) => WriteValue::Any(box v),
+            PropertyValue::Vec4(v) => WriteValue::Any(box v),
+            PropertyValue::Quat(v) => WriteValue::Any(box v),
+        }
+    }
+}
+
+impl PropertyCurve
+{
+    pub fn new(path : &str, interpolation : Interpolation) -> PropertyCurve
+    {
+        PropertyCurve {
+            path : path.to_owned(),
+            interpolation : interpolation,
+            keys : Vec::new()
+        }
+    }
+
+    /// Keeps the keys sorted, a key at the same time replaces the old one.
+    pub fn add_key(&mut self, time : f64, value : PropertyValue)
+    {
+        let key = PropertyKey { time : time, value : value };
+
+        match self.keys.iter().position(|k| k.time >= time) {
+            Some(i) => {
+                if self.keys[i].time == time {
+                    self.keys[i] = key;
+                }
+                else {
+                    self.keys.insert(i, key);
+                }
+            },
+            None => self.keys.push(key)
+        }
+    }
+
+    pub fn length(&self) -> f64
+    {
+        match self.keys.last() {
+            Some(k) => k.time,
+            None => 0f64
+        }
+    }
+
+    pub fn sample(&self, time : f64) -> Option<PropertyValue>
+    {
+        if self.keys.is_empty() {
+            return None;
+        }
+
+        let next = match self.keys.iter().position(|k| k.time > time) {
+            Some(0) => return Some(self.keys[0].value),
+            Some(i) => i,
+            None => return Some(self.keys[self.keys.len() - 1].value)
+        };
+
+        let a = &self.keys[next - 1];
+        let b = &self.keys[next];
+
+        match self.interpolation {
+            Interpolation::Step => Some(a.value),
+            Interpolation::Linear => {
+                let t = (time - a.time) / (b.time - a.time);
+                Some(a.value.interpolate(&b.value, t))
+            }
+        }
+    }
+}
+
+impl PropertyClip
+{
+    pub fn new(name : &str) -> PropertyClip
+    {
+        PropertyClip {
+            name : name.to_owned(),
+            curves : Vec::new()
+        }
+    }
+
+    pub fn new_from_file(file_path : &str) -> Option<PropertyClip>
+    {
+        let mut file = String::new();
+        let result = File::open(&Path::new(file_path))
+            .and_then(|mut f| f.read_to_string(&mut file));
+        if let Err(e) = result {
+            println!("Error reading file '{}'. Error: {}", file_path, e);
+            return None;
+        }
+
+        match serde_json::from_str(&file) {
+            Ok(clip) => Some(clip),
+            Err(e) => {
+                println!("{}, line {}: error reading property clip '{}': {:?}",
+                         file!(),
+                         line!(),
+                         file_path,
+                         e);
+                None
+            }
+        }
+    }
+
+    /// Writes the clip to the file with the name of the clip.
+    pub fn save(&self)
+    {
+        let s = match serde_json::to_string_pretty(self) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Error serializing property clip '{}'. Error: {:?}", self.name, e);
+                return;
+            }
+        };
+
+        let path : &Path = self.name.as_ref();
+        let result = File::create(path).and_then(|mut f| f.write_all(s.as_bytes()));
+        if let Err(e) = result {
+            println!("Error writing file '{}'. Error: {}", self.name, e);
+        }
+    }
+
+    pub fn add_curve(&mut self, curve : PropertyCurve)
+    {
+        self.curves.push(curve);
+    }
+
+    pub fn find_curve_mut(&mut self, path : &str) -> Option<&mut PropertyCurve>
+    {
+        self.curves.iter_mut().find(|c| c.path == path)
+    }
+
+    /// Adds a key to the curve of the path, the curve is created if needed.
+    pub fn add_key(&mut self, path : &str, time : f64, value : PropertyValue)
+    {
+        if self.find_curve_mut(path).is_none() {
+            self.curves.push(PropertyCurve::new(path, Interpolation::Linear));
+        }
+
+        self.find_curve_mut(path).unwrap().add_key(time, value);
+    }
+
+    /// Length in seconds, the time of the last key.
+    pub fn length(&self) -> f64
+    {
+        self.curves.iter().fold(0f64, |l, c| l.max(c.length()))
+    }
+
+    /// Writes the values of the clip at this time to the target.
+    pub fn apply(&self, time : f64, target : &mut PropertyWrite)
+    {
+        for c in self.curves.iter() {
+            if let Some(v) = c.sample(time) {
+                target.set_property_hier(&c.path, v.to_write_value());
+            }
+        }
+    }
+}
+
+impl PropertyAnimation
+{
+    pub fn new(clip : PropertyClip, loop_mode : LoopMode) -> PropertyAnimation
+    {
+        let mut playback = Playback::new();
+        playback.loop_mode = loop_mode;
+        playback.play();
+
+        PropertyAnimation {
+            clip : clip,
+            playback : playback
+        }
+    }
+
+    /// Moves the time and writes the values to the target.
+    /// Returns true when a clip which is not looping finishes.
+    pub fn update(&mut self, dt : f64, target : &mut PropertyWrite) -> bool
+    {
+        let finished = self.playback.update(dt, self.clip.length());
+        self.clip.apply(self.playback.get_time(), target);
+        finished
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    use transform;
+
+    fn float(v : Option<PropertyValue>) -> f64
+    {
+        match v {
+            Some(PropertyValue::Float(f)) => f,
+            _ => panic!("not a float")
+        }
+    }
+
+    #[test]
+    fn test_add_key()
+    {
+        let mut c = PropertyCurve::new("position/x", Interpolation::Linear);
+        c.add_key(2f64, PropertyValue::Float(2f64));
+        c.add_key(0f64, PropertyValue::Float(0f64));
+        c.add_key(1f64, PropertyValue::Float(1f64));
+        // replaces the key at 1
+        c.add_key(1f64, PropertyValue::Float(5f64));
+
+        let times : Vec<f64> = c.keys.iter().map(|k| k.time).collect();
+        assert_eq!(times, vec![0f64, 1f64, 2f64]);
+        assert_eq!(float(Some(c.keys[1].value)), 5f64);
+        assert_eq!(c.length(), 2f64);
+    }
+
+    #[test]
+    fn test_sample()
+    {
+        let mut linear = PropertyCurve::new("position/x", Interpolation::Linear);
+        linear.add_key(1f64, PropertyValue::Float(0f64));
+        linear.add_key(3f64, PropertyValue::Float(4f64));
+
+        let mut step = linear.clone();
+        step.interpolation = Interpolation::Step;
+
+        assert_eq!(float(linear.sample(2f64)), 2f64);
+        assert_eq!(float(step.sample(2f64)), 0f64);
+        assert_eq!(float(step.sample(3f64)), 4f64);
+
+        // clamped before the first key and after the last one
+        assert_eq!(float(linear.sample(0f64)), 0f64);
+        assert_eq!(float(linear.sample(10f64)), 4f64);
+
+        assert!(PropertyCurve::new("position/x", Interpolation::Linear).sample(0f64).is_none());
+    }
+
+    #[test]
+    fn test_apply_transform()
+    {
+        let mut clip = PropertyClip::new("move");
+        clip.add_key("position/x", 0f64, PropertyValue::Float(0f64));
+        clip.add_key("position/x", 2f64, PropertyValue::Float(4f64));
+        clip.add_key("scale", 0f64, PropertyValue::Vec3(vec::Vec3::one()));
+        clip.add_key("scale", 2f64, PropertyValue::Vec3(vec::Vec3::new(3f64, 3f64, 3f64)));
+        assert_eq!(clip.length(), 2f64);
+
+        let mut t = transform::Transform::default();
+        clip.apply(1f64, &mut t);
+
+        assert_eq!(t.position, vec::Vec3::new(2f64, 0f64, 0f64));
+        assert_eq!(t.scale, vec::Vec3::new(2f64, 2f64, 2f64));
+    }
+}
@@ -1,8 +1,8 @@
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use libc::{c_uint, c_int};
 use std::sync;
-use std::sync::{RwLock, Arc, RwLockReadGuard, Mutex};
+use std::sync::{RwLock, Arc, RwLockReadGuard, Mutex, Once, ONCE_INIT};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Occupied,Vacant};
 use uuid;
@@ -27,16 +27,15 @@ use armature_animation;
 use geometry;
 
 use mesh::BufferSend;
+use backend;
+use light;
+use shadow;
+use debug_draw;
+use render_queue::{self, RenderQueue, RenderStats};
 
+#[cfg(feature = "cgl")]
 #[link(name = "cypher")]
 extern {
-    pub fn cgl_draw(vertex_count : c_uint) -> ();
-    pub fn cgl_draw_lines(vertex_count : c_uint) -> ();
-    pub fn cgl_draw_faces(buffer : *const mesh::CglBuffer, index_count : c_uint) -> ();
-    pub fn cgl_draw_end() -> ();
-
-    pub fn cgl_clear() -> ();
-
     pub fn cypher_init_simple();
     pub fn cypher_draw_start(w : i32, h : i32);
     pub fn cypher_draw_end();
@@ -65,7 +64,9 @@ pub extern fn resize_cb(r : *mut Render, w : c_int, h : c_int) -> () {
 pub struct MatrixMeshRender
 {
     pub mat : matrix::Matrix4,
-    pub mr : mesh_render::MeshRender
+    pub mr : mesh_render::MeshRender,
+    /// sent as the "instance_color" attribute when the draw is instanced
+    pub color : Option<vec::Vec4>
 }
 
 impl MatrixMeshRender {
@@ -73,7 +74,18 @@ impl MatrixMeshRender {
     {
         MatrixMeshRender {
         mat : mat,
-        mr : mr
+        mr : mr,
+        color : None
+        }
+    }
+
+    pub fn with_color(mat : matrix::Matrix4, mr : mesh_render::MeshRender, color : vec::Vec4)
+        -> MatrixMeshRender
+    {
+        MatrixMeshRender {
+        mat : mat,
+        mr : mr,
+        color : Some(color)
         }
     }
 }
@@ -147,6 +159,37 @@ pub struct RenderPass<Id:Hash+Clone+Eq>
     pub shader : ResTT<shader::Shader>,
     //uuid is the camera id
     pub passes : HashMap<Id, Box<CameraPass>>,
+    /// sent to the shaders declaring the light uniforms, see light::send_lights
+    pub lights : Vec<light::WorldLight>,
+    /// sent to the shaders declaring the shadow uniforms, see shadow::send_shadows
+    pub shadow : Option<shadow::ShadowMap>,
+    stats : Cell<RenderStats>,
+    /// world matrices and colors of the instanced draws, updated for each draw
+    instance_matrices : RefCell<mesh::Buffer<f32>>,
+    instance_colors : RefCell<mesh::Buffer<f32>>,
+}
+
+/// What was bound by the previous draw, to skip binding it again.
+struct DrawState
+{
+    material : Option<String>,
+    mesh : Option<(String, usize)>,
+    /// camera matrix and the indices of the lights it sees
+    lights : Vec<(*const matrix::Matrix4, Vec<usize>)>,
+    stats : RenderStats
+}
+
+impl DrawState
+{
+    fn new() -> DrawState
+    {
+        DrawState {
+            material : None,
+            mesh : None,
+            lights : Vec::new(),
+            stats : Default::default()
+        }
+    }
 }
 
 impl<Id:Hash+Eq+Clone> RenderPass<Id>
@@ -159,16 +202,123 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
         RenderPass {
                   name : String::from("passtest"),
                   shader : shader,//.clone(),
-                  passes : HashMap::new()
+                  passes : HashMap::new(),
+                  lights : Vec::new(),
+                  shadow : None,
+                  stats : Cell::new(Default::default()),
+                  instance_matrices : RefCell::new(mesh::Buffer::new(
+                      String::from("instance_matrix"),
+                      Vec::new(),
+                      mesh::BufferType::Vertex)),
+                  instance_colors : RefCell::new(mesh::Buffer::new(
+                      String::from("instance_color"),
+                      Vec::new(),
+                      mesh::BufferType::Vertex))
               }
     }
 
+    /// Counts of the last draw.
+    pub fn get_stats(&self) -> RenderStats
+    {
+        self.stats.get()
+    }
+
+    /// Draws all the queues of this pass only, then the shapes of debug_draw for its cameras.
+    /// With several passes, the transparent objects of a pass would be drawn before the
+    /// opaque objects of the next ones, and the debug shapes only for the first pass :
+    /// draw them with draw_passes instead of calling draw_frame on each pass.
     pub fn draw_frame(
         &self,
         resource : &resource::ResourceGroup,
         load : Arc<Mutex<usize>>
         ) -> usize
     {
+        let not_loaded = self.draw_queues(
+            resource,
+            load,
+            &[material::Queue::Opaque,
+              material::Queue::AlphaTest,
+              material::Queue::Transparent,
+              material::Queue::Overlay]);
+
+        let cameras : Vec<&matrix::Matrix4> = self.passes.values().map(|p| &p.matrix).collect();
+        debug_draw_flush(&cameras, resource);
+
+        not_loaded
+    }
+
+    /// Draws the objects with an opaque or alpha test material.
+    pub fn draw_opaque(
+        &self,
+        resource : &resource::ResourceGroup,
+        load : Arc<Mutex<usize>>
+        ) -> usize
+    {
+        self.draw_queues(resource, load, &[material::Queue::Opaque, material::Queue::AlphaTest])
+    }
+
+    /// Draws the objects with a transparent or overlay material, after draw_opaque.
+    pub fn draw_transparent(
+        &self,
+        resource : &resource::ResourceGroup,
+        load : Arc<Mutex<usize>>
+        ) -> usize
+    {
+        self.draw_queues(resource, load, &[material::Queue::Transparent, material::Queue::Overlay])
+    }
+
+    fn draw_queues(
+        &self,
+        resource : &resource::ResourceGroup,
+        load : Arc<Mutex<usize>>,
+        queues : &[material::Queue]
+        ) -> usize
+    {
+        // opaque objects are sorted by material then mesh and front to back,
+        // transparent ones back to front
+        let mut queue = RenderQueue::new();
+        {
+            let material_manager = &*resource.material_manager.borrow();
+            for (_,p) in self.passes.iter() {
+                for m in p.mmr.iter() {
+                    let q = match m.mr.material.get_ref(material_manager) {
+                        Some(mat) => mat.queue,
+                        None => material::Queue::Opaque
+                    };
+
+                    if !queues.contains(&q) {
+                        continue;
+                    }
+
+                    let depth = view_depth(&p.matrix, &m.mat);
+                    let key = if q.is_transparent() {
+                        queue.key_sorted(
+                            q as u8,
+                            &self.shader.name,
+                            &m.mr.material.name,
+                            &m.mr.mesh.name,
+                            render_queue::depth_bits(depth, true))
+                    }
+                    else {
+                        queue.key(
+                            q as u8,
+                            &self.shader.name,
+                            &m.mr.material.name,
+                            &m.mr.mesh.name,
+                            render_queue::depth_bits(depth, false))
+                    };
+                    queue.push(key, (&p.matrix, m));
+                }
+            }
+        }
+
+        if queue.len() == 0 {
+            self.stats.set(Default::default());
+            return 0;
+        }
+
+        queue.sort();
+
         //let shader = &mut *self.shader.write().unwrap();
         let shader_manager = &mut *resource.shader_manager.borrow_mut();
         let shader = self.shader.get_from_manager_instant(shader_manager);
@@ -184,22 +334,56 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
 
         let mut not_loaded = 0;
 
-        for (_,p) in self.passes.iter() {
+        // shaders with instance attributes draw all the objects sharing a mesh and a material at once
+        let instancing = shader.attributes.contains_key("instance_matrix") &&
+            backend::with_backend(|b| b.supports_instancing());
+
+        let items : Vec<(&matrix::Matrix4, &MatrixMeshRender)> =
+            queue.iter().map(|&(_, item)| item).collect();
+
+        let mut state = DrawState::new();
+        if shader.has_uniform("light_count") {
+            for p in self.passes.values() {
+                let planes = geometry::frustum_planes(&p.matrix);
+                let visible = (0..self.lights.len()).filter(|i| self.lights[*i].is_visible(&planes)).collect();
+                state.lights.push((&p.matrix as *const matrix::Matrix4, visible));
+            }
+        }
 
-            for m in p.mmr.iter() {
+        if instancing {
+            let mut start = 0;
+            while start < items.len() {
+                let end = instance_group_end(&items, start);
+                not_loaded += self.draw_instanced(
+                    shader,
+                    &items[start..end],
+                    resource,
+                    load.clone(),
+                    &mut state);
+                start = end;
+            }
+        }
+        else {
+            for &(matrix, m) in items.iter() {
                 let not = self.draw_mmr(
                     shader,
                     &m.mat,
                     &m.mr,
-                    &p.matrix,
+                    matrix,
                     resource,
-                    load.clone()
+                    load.clone(),
+                    &mut state
                     );
 
                 not_loaded += not;
             }
         }
 
+        // the next draws start with the state of the backends
+        render_state_set(&Default::default());
+
+        self.stats.set(state.stats);
+
         not_loaded
     }
 
@@ -220,14 +404,14 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
         */
     }
 
-    fn draw_mmr(
+    /// Sends the material unless the previous object had the same.
+    fn bind_material(
         &self,
         shader : &shader::Shader,
-        world_matrix : &matrix::Matrix4,
         mesh_render : &mesh_render::MeshRender,
-        matrix : &matrix::Matrix4,
         resource : &resource::ResourceGroup,
-        load : Arc<Mutex<usize>>
+        load : Arc<Mutex<usize>>,
+        state : &mut DrawState
         ) -> usize
     {
         let mut not_loaded = 0;
@@ -237,12 +421,45 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
             let material_manager = &mut *resource.material_manager.borrow_mut();
             let m = mr.material.get_ref(material_manager).unwrap();
 
+            let mut render_state = m.render_state;
+            render_state.depth_write = render_state.depth_write && !m.queue.is_transparent();
+            render_state_set(&render_state);
+
             object_init_mat(m, shader, resource, load)
         };
 
-        not_loaded = init_material(mesh_render);
+        // an instanced material can differ from the one with the same name
+        let same_material = mesh_render.material.instance.is_none() &&
+            state.material.as_ref().map_or(false, |n| *n == mesh_render.material.name);
+
+        if same_material {
+            state.stats.material_binds_saved += 1;
+        }
+        else {
+            not_loaded = init_material(mesh_render);
+            state.stats.material_binds += 1;
+            state.material = if not_loaded == 0 && mesh_render.material.instance.is_none() {
+                Some(mesh_render.material.name.clone())
+            }
+            else {
+                None
+            };
+        }
         if not_loaded > 0 { println!("not loaded {}, init material", not_loaded); }
 
+        not_loaded
+    }
+
+    /// Binds the mesh buffers unless the previous object had the same,
+    /// returns if it can be drawn, the vertex data count and if the mesh is an instance.
+    fn bind_mesh(
+        &self,
+        shader : &shader::Shader,
+        mesh_render : &mesh_render::MeshRender,
+        resource : &resource::ResourceGroup,
+        state : &mut DrawState
+        ) -> ((bool, usize), bool)
+    {
         let init_mesh_render = |mr : &mesh_render::MeshRender|  -> ((bool, usize), bool)
         {
             let mesh_manager = &mut *resource.mesh_manager.borrow_mut();
@@ -258,11 +475,56 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
             }
         };
 
-        let ((can_render, vertex_data_count), instance) = init_mesh_render(mesh_render);
+        let same_mesh = match state.mesh {
+            Some((ref n, count)) if mesh_render.mesh.instance.is_none() && *n == mesh_render.mesh.name => {
+                Some(count)
+            },
+            _ => None
+        };
+
+        match same_mesh {
+            Some(count) => {
+                state.stats.mesh_binds_saved += 1;
+                ((true, count), false)
+            },
+            None => {
+                state.stats.mesh_binds += 1;
+                let r = init_mesh_render(mesh_render);
+                state.mesh = match r {
+                    ((true, count), false) => Some((mesh_render.mesh.name.clone(), count)),
+                    _ => None
+                };
+                r
+            }
+        }
+    }
+
+    fn draw_mmr(
+        &self,
+        shader : &shader::Shader,
+        world_matrix : &matrix::Matrix4,
+        mesh_render : &mesh_render::MeshRender,
+        matrix : &matrix::Matrix4,
+        resource : &resource::ResourceGroup,
+        load : Arc<Mutex<usize>>,
+        state : &mut DrawState
+        ) -> usize
+    {
+        let mut not_loaded = self.bind_material(shader, mesh_render, resource, load, state);
+
+        let ((can_render, vertex_data_count), instance) =
+            self.bind_mesh(shader, mesh_render, resource, state);
 
         if can_render {
             let object_mat_world = matrix * world_matrix ;
             shader.uniform_set("matrix", &object_mat_world);
+            send_world(shader, matrix, world_matrix);
+            if let Some(lights) = self.nearest_lights(matrix, world_matrix, state) {
+                light::send_lights(shader, &lights);
+            }
+            if let Some(ref map) = self.shadow {
+                shadow::send_shadows(shader, map, world_matrix, mesh_render.receive_shadows, resource);
+            }
 
             let draw_mesh = |mr : &mesh_render::MeshRender|
             {
@@ -272,6 +534,7 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
             };
 
             draw_mesh(mesh_render);
+            state.stats.draw_calls += 1;
         }
         else if instance {
             println!("TODO instance");
@@ -283,6 +546,256 @@ impl<Id:Hash+Eq+Clone> RenderPass<Id>
 
         not_loaded
     }
+
+    /// The lights seen by the camera which matter the most for the object,
+    /// None when the shader has no light uniforms.
+    fn nearest_lights(
+        &self,
+        camera : &matrix::Matrix4,
+        world_matrix : &matrix::Matrix4,
+        state : &DrawState) -> Option<Vec<&light::WorldLight>>
+    {
+        let visible = match state.lights.iter().find(|l| l.0 == camera as *const matrix::Matrix4) {
+            Some(&(_, ref v)) => v,
+            None => return None
+        };
+
+        let lights : Vec<&light::WorldLight> = visible.iter().map(|i| &self.lights[*i]).collect();
+        let p = world_matrix * vec::Vec4::new(0f64, 0f64, 0f64, 1f64);
+        Some(light::gather(&lights, &vec::Vec3::new(p.x, p.y, p.z), light::MAX_LIGHTS))
+    }
+
+    /// Draws objects sharing a mesh and a material, one draw for each camera and
+    /// each set of lights : the instances of a draw get the same lights.
+    /// "matrix" is the camera matrix and the world matrices go in the "instance_matrix" attribute.
+    fn draw_instanced(
+        &self,
+        shader : &shader::Shader,
+        items : &[(&matrix::Matrix4, &MatrixMeshRender)],
+        resource : &resource::ResourceGroup,
+        load : Arc<Mutex<usize>>,
+        state : &mut DrawState
+        ) -> usize
+    {
+        let mesh_render = &items[0].1.mr;
+        let not_loaded = self.bind_material(shader, mesh_render, resource, load, state);
+
+        let ((can_render, _), _) = self.bind_mesh(shader, mesh_render, resource, state);
+        if !can_render {
+            println!("not loaded {}, cannot render : {:?}", items.len(), mesh_render);
+            return not_loaded + items.len();
+        }
+
+        let faces = {
+            let mesh_manager = &mut *resource.mesh_manager.borrow_mut();
+            let m = mesh_render.mesh.get_ref(mesh_manager).unwrap();
+            match m.buffer_u32_get("faces") {
+                Some(f) => (**f).cgl_buffer_get().map(|b| (b, f.size_get())),
+                None => None
+            }
+        };
+
+        let (faces, faces_data_count) = match faces {
+            Some(f) => f,
+            None => {
+                println!("instanced draw needs a mesh with faces : {:?}", mesh_render);
+                return not_loaded + items.len();
+            }
+        };
+
+        let colors = shader.attributes.get("instance_color").cloned();
+
+        let lights : Vec<Option<Vec<&light::WorldLight>>> =
+            items.iter().map(|&(c, m)| self.nearest_lights(c, &m.mat, state)).collect();
+
+        let mut done = vec![false; items.len()];
+        for i in 0..items.len() {
+            if done[i] {
+                continue;
+            }
+
+            let camera = items[i].0;
+            let mut matrices = Vec::new();
+            let mut instance_colors = Vec::new();
+            for j in i..items.len() {
+                let (c, m) = items[j];
+                if done[j] || c as *const matrix::Matrix4 != camera as *const matrix::Matrix4 ||
+                    !same_lights(&lights[i], &lights[j]) {
+                    continue;
+                }
+                done[j] = true;
+
+                matrices.extend_from_slice(&m.mat.to_f32());
+                let color = m.color.unwrap_or(vec::Vec4::new(1f64, 1f64, 1f64, 1f64));
+                instance_colors.extend_from_slice(
+                    &[color.x as f32, color.y as f32, color.z as f32, color.w as f32]);
+            }
+
+            let count = matrices.len() / 16;
+            shader.uniform_set("matrix", camera);
+            if let Some(ref l) = lights[i] {
+                light::send_lights(shader, l);
+            }
+            if let Some(ref map) = self.shadow {
+                // the world matrices are in the instance attribute
+                shadow::send_shadows(shader, map, &matrix::Matrix4::identity(), mesh_render.receive_shadows, resource);
+            }
+            if let Some(att) = shader.attributes.get("instance_matrix") {
+                instance_attribute_send(*att, &mut *self.instance_matrices.borrow_mut(), matrices, 16);
+            }
+            if let Some(att) = colors {
+                instance_attribute_send(att, &mut *self.instance_colors.borrow_mut(), instance_colors, 4);
+            }
+
+            backend::with_backend(|g| {
+                g.draw_faces_instanced(faces, faces_data_count, count);
+                g.draw_end();
+            });
+            state.stats.draw_calls += 1;
+            state.stats.instances += count;
+        }
+
+        not_loaded
+    }
+}
+
+/// End of the group of items drawn together, they share a mesh and a material
+/// which are not instances, and receive shadows or not.
+fn instance_group_end(items : &[(&matrix::Matrix4, &MatrixMeshRender)], start : usize) -> usize
+{
+    let first = &items[start].1.mr;
+    if first.mesh.instance.is_some() || first.material.instance.is_some() {
+        return start + 1;
+    }
+
+    let mut end = start + 1;
+    while end < items.len() {
+        let mr = &items[end].1.mr;
+        if mr.mesh.instance.is_some() || mr.material.instance.is_some() ||
+            mr.mesh.name != first.mesh.name || mr.material.name != first.material.name ||
+            mr.receive_shadows != first.receive_shadows {
+            break;
+        }
+        end = end + 1;
+    }
+
+    end
+}
+
+fn same_lights(a : &Option<Vec<&light::WorldLight>>, b : &Option<Vec<&light::WorldLight>>) -> bool
+{
+    match (a, b) {
+        (&Some(ref a), &Some(ref b)) => {
+            a.len() == b.len() &&
+                a.iter().zip(b.iter()).all(|(x, y)| *x as *const light::WorldLight == *y as *const light::WorldLight)
+        },
+        (&None, &None) => true,
+        _ => false
+    }
+}
+
+/// Updates the buffer with the data and sends it as an instance attribute.
+fn instance_attribute_send(
+    att : *const shader::CglShaderAttribute,
+    buffer : &mut mesh::Buffer<f32>,
+    data : Vec<f32>,
+    components : usize)
+{
+    buffer.data = data;
+    buffer.send();
+
+    if let Some(b) = buffer.cgl_buffer_get() {
+        backend::with_backend(|g| g.instance_attribute_send(att, b, components));
+    }
+}
+
+/// Draws the passes ordered by name, so the order does not change between frames.
+/// The transparent objects of all the passes are drawn after the opaque ones,
+/// then the shapes of debug_draw.
+/// Returns the number of objects not loaded yet and the counts of all the passes.
+pub fn draw_passes<Id:Hash+Eq+Clone>(
+    passes : &HashMap<String, Box<RenderPass<Id>>>,
+    resource : &resource::ResourceGroup,
+    load : Arc<Mutex<usize>>
+    ) -> (usize, RenderStats)
+{
+    let mut names : Vec<&String> = passes.keys().collect();
+    names.sort();
+
+    let mut not_loaded = 0;
+    let mut stats : RenderStats = Default::default();
+
+    for n in names.iter() {
+        let p = &passes[*n];
+        not_loaded += p.draw_opaque(resource, load.clone());
+        stats.add(&p.get_stats());
+    }
+
+    for n in names.iter() {
+        let p = &passes[*n];
+        not_loaded += p.draw_transparent(resource, load.clone());
+        stats.add(&p.get_stats());
+    }
+
+    // the debug shapes, once for each camera
+    let mut cameras : Vec<&matrix::Matrix4> = Vec::new();
+    for n in names.iter() {
+        for cp in passes[*n].passes.values() {
+            if !cameras.iter().any(|c| c.to_f32() == cp.matrix.to_f32()) {
+                cameras.push(&cp.matrix);
+            }
+        }
+    }
+    debug_draw_flush(&cameras, resource);
+
+    (not_loaded, stats)
+}
+
+/// Draws the shapes of debug_draw for each camera, then removes the ones
+/// whose duration is over.
+fn debug_draw_flush(cameras : &[&matrix::Matrix4], resource : &resource::ResourceGroup)
+{
+    debug_draw::with_debug_draw(|d| {
+        for c in cameras.iter() {
+            d.draw(c, resource);
+        }
+        d.end_frame();
+    });
+}
+
+fn render_state_set(state : &material::RenderState)
+{
+    static WARN : Once = ONCE_INIT;
+
+    backend::with_backend(|g| {
+        if !g.supports_render_state() && *state != Default::default() {
+            WARN.call_once(|| {
+                println!("the graphics backend does not support render states, \
+                         blend, depth, cull and polygon mode of the materials are ignored \
+                         (the cgl backend needs the render_state feature)");
+            });
+        }
+
+        g.blend(state.blend);
+        g.depth_test(state.depth_test);
+        g.depth_write(state.depth_write);
+        g.cull(state.cull);
+        g.polygon_offset(state.polygon_offset);
+        g.wireframe(state.wireframe);
+    });
+}
+
+/// Depth of the object origin from 0 (near) to 1 (far).
+fn view_depth(camera : &matrix::Matrix4, world : &matrix::Matrix4) -> f64
+{
+    let m = camera * world;
+    let c = &m * vec::Vec4::new(0f64, 0f64, 0f64, 1f64);
+    if c.w <= 0f64 {
+        0f64
+    }
+    else {
+        c.z / c.w * 0.5f64 + 0.5f64
+    }
 }
 
 pub fn get_pass_from_mesh_render<'a, Id:Hash+Eq+Clone>(
@@ -456,6 +969,11 @@ fn init_mesh(
     let mut vertex_data_count = 0;
     for (name, cgl_att) in shader.attributes.iter() {
 
+        // sent by the instanced draw, not by the mesh
+        if name.starts_with("instance_") {
+            continue;
+        }
+
         match mb.buffer_f32_get(name.as_ref()){
             Some(ref cb) => {
                 cb.utilise(*cgl_att);
@@ -493,12 +1011,14 @@ fn object_draw_mesh(
 {
     match mb.buffer_u32_get("faces") {
         //Some(ref bind) =>
-        Some(bind) => unsafe {
+        Some(bind) => {
             match (**bind).cgl_buffer_get() {
                 Some(b) => {
                     let faces_data_count = bind.size_get();
-                    cgl_draw_faces(b, faces_data_count as c_uint);
-                    cgl_draw_end();
+                    backend::with_backend(|g| {
+                        g.draw_faces(b, faces_data_count);
+                        g.draw_end();
+                    });
                 },
                 None => ()
             }
@@ -507,14 +1027,10 @@ fn object_draw_mesh(
             match mb.draw_type {
                 mesh::DrawType::Lines => {
                     let vc : usize = vertex_data_count/3;
-                    unsafe {
-                        cgl_draw_lines(vc as c_uint);
-                    }
+                    backend::with_backend(|g| g.draw_lines(vc));
                 },
                 _ => {
-                    unsafe {
-                        cgl_draw(vertex_data_count as c_uint);
-                    }
+                    backend::with_backend(|g| g.draw(vertex_data_count));
                 }
             }
         }
@@ -539,7 +1055,7 @@ struct RenderByShader
 pub struct NewRender
 {
     //String is the name you want to give to the pass, for example the shader name
-    passes : HashMap<String, Box<RenderByShader>>,
+    passes : HashMap<String, Box<RenderPass<uuid::Uuid>>>,
     resource: Rc<resource::ResourceGroup>,
 }
 
@@ -579,27 +1095,41 @@ impl NewRender
         //cam_ortho.set_resolution(w, h);
     }
 
+    /// Draws the renderables seen by the camera, the passes are made again each frame.
+    /// Returns true if some objects are not loaded yet.
     pub fn draw(
         &mut self,
-        //mesh : &[component::mesh_render::MeshRenderer],
-        //transform : &Iterator<Item=&transform::Transform>,
-        renderables : &Iterator<Item=(&transform::Transform, mesh_render::MeshRender)>,
+        camera : &CameraIdMat<uuid::Uuid>,
+        renderables : &mut Iterator<Item=(&transform::Transform, mesh_render::MeshRender)>,
         loading : Arc<Mutex<usize>>
         ) -> bool
     {
-        /*
-        self.prepare_passes_objects_per(objects);
+        // the objects of the last frame
+        for p in self.passes.values_mut() {
+            p.passes.clear();
+        }
 
-        let mut not_yet_loaded = 0;
-        for p in self.passes.values()
         {
-            let r = p.draw_frame(&self.resource, loading.clone());
-            not_yet_loaded += r;
+            let material_manager = &mut *self.resource.material_manager.borrow_mut();
+            let shader_manager = &mut *self.resource.shader_manager.borrow_mut();
+            for (t, mr) in renderables {
+                let matrix = t.compute_return_local_matrix();
+                if let Some(cp) = get_pass_from_mesh_render(
+                    &mr,
+                    &mut self.passes,
+                    material_manager,
+                    shader_manager,
+                    camera,
+                    loading.clone()) {
+                    cp.add_mmr(MatrixMeshRender::new(matrix, mr));
+                }
+            }
         }
 
+        // all the opaque objects before the transparent ones, see draw_passes
+        let (not_yet_loaded, _) = draw_passes(&self.passes, &self.resource, loading);
+
         not_yet_loaded > 0
-        */
-        false
     }
 
     pub fn draw_frame(
@@ -738,6 +1268,23 @@ pub fn draw(
     draw_mesh(shader, mesh);
 }
 
+/// Sends "world_matrix" and "camera_position" to the shaders declaring them,
+/// for the shaders lighting in world space like pbr::FRAG.
+fn send_world(shader : &shader::Shader, camera : &matrix::Matrix4, world_matrix : &matrix::Matrix4)
+{
+    if shader.has_uniform("world_matrix") {
+        shader.uniform_set("world_matrix", world_matrix);
+    }
+
+    if shader.has_uniform("camera_position") {
+        // the eye is the point the camera matrix sends to w = 0
+        let e = &camera.get_inverse() * vec::Vec4::new(0f64, 0f64, 1f64, 0f64);
+        if e.w != 0f64 {
+            shader.uniform_set("camera_position", &vec::Vec3::new(e.x / e.w, e.y / e.w, e.z / e.w));
+        }
+    }
+}
+
 fn set_matrix(shader :&shader::Shader, matrix : &matrix::Matrix4)
 {
     shader.uniform_set("matrix", matrix);
@@ -834,3 +1381,420 @@ impl TransformCamera
         }
     }
 }
+
+#[cfg(test)]
+mod test
+{
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use backend;
+    use backend::Call;
+    use debug_draw;
+    use light;
+    use material;
+    use matrix;
+    use mesh;
+    use mesh_render;
+    use resource;
+    use shader;
+    use software;
+    use vec;
+    use super::{init_mesh, object_draw_mesh, draw_passes, RenderPass, CameraPass, MatrixMeshRender};
+
+    #[test]
+    fn test_draw_mesh_recorded()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let mut s = shader::Shader::with_vert_frag(
+            String::from("test"),
+            String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+            String::from("uniform vec4 color;\n"));
+        s.load_gl();
+        assert!(s.uniforms.contains_key("matrix"));
+        assert!(s.uniforms.contains_key("color"));
+
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+
+        let (can_render, vertex_count) = init_mesh(&m, &s);
+        assert!(can_render);
+        assert_eq!(vertex_count, 12);
+        object_draw_mesh(&m, vertex_count);
+
+        let calls = calls.borrow();
+        assert!(calls.iter().any(|c| {
+            if let Call::AttributeSend(ref n, _) = *c { n == "position" } else { false }
+        }));
+        assert!(calls.iter().any(|c| {
+            if let Call::DrawFaces(_, 6) = *c { true } else { false }
+        }));
+        assert_eq!(calls.last(), Some(&Call::DrawEnd));
+    }
+
+    #[test]
+    fn test_draw_frame_recorded()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+
+        let shader = resources.shader_manager.borrow_mut().add_resource(
+            "test_shader",
+            shader::Shader::with_vert_frag(
+                String::from("test_shader"),
+                String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+                String::from("uniform vec4 color;\n")));
+
+        let mut mat = material::Material::new("test_material");
+        mat.set_uniform_data("color", shader::UniformData::Vec4(vec::Vec4::new(1f64, 0f64, 0f64, 1f64)));
+        let material = resources.material_manager.borrow_mut().add_resource("test_material", mat);
+
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+        let mesh = resources.mesh_manager.borrow_mut().add_resource("quad", m);
+
+        let mr = mesh_render::MeshRender {
+            mesh : mesh,
+            material : material,
+            cast_shadows : true,
+            receive_shadows : true
+        };
+
+        let mut camera = box CameraPass::new(matrix::Matrix4::identity());
+        camera.add_mmr(MatrixMeshRender::new(matrix::Matrix4::identity(), mr.clone()));
+        camera.add_mmr(MatrixMeshRender::new(matrix::Matrix4::identity(), mr));
+
+        let mut pass : RenderPass<usize> = RenderPass::new(shader);
+        pass.passes.insert(0usize, camera);
+
+        let not_loaded = pass.draw_frame(&resources, Arc::new(Mutex::new(0)));
+        assert_eq!(not_loaded, 0);
+
+        // the second object has the same material and mesh
+        let stats = pass.get_stats();
+        assert_eq!(stats.draw_calls, 2);
+        assert_eq!(stats.material_binds, 1);
+        assert_eq!(stats.material_binds_saved, 1);
+
+        let calls = calls.borrow();
+        assert!(calls.iter().any(|c| if let Call::ShaderUse(_) = *c { true } else { false }));
+
+        let count = |f : &Fn(&Call) -> bool| calls.iter().filter(|c| f(c)).count();
+        assert_eq!(count(&|c| if let Call::UniformVec(ref n, _) = *c { n == "color" } else { false }), 1);
+        assert_eq!(count(&|c| if let Call::UniformVec(ref n, _) = *c { n == "matrix" } else { false }), 2);
+        assert_eq!(count(&|c| if let Call::DrawFaces(_, 6) = *c { true } else { false }), 2);
+    }
+
+    #[test]
+    fn test_draw_frame_bind_counts()
+    {
+        backend::set_backend(box backend::RecordingBackend::new());
+
+        let resources = resource::ResourceGroup::new();
+
+        let shader = resources.shader_manager.borrow_mut().add_resource(
+            "test_shader",
+            shader::Shader::with_vert_frag(
+                String::from("test_shader"),
+                String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+                String::from("uniform vec4 color;\n")));
+
+        let material = |name : &str| {
+            let mut mat = material::Material::new(name);
+            mat.set_uniform_data("color", shader::UniformData::Vec4(vec::Vec4::new(1f64, 1f64, 1f64, 1f64)));
+            resources.material_manager.borrow_mut().add_resource(name, mat)
+        };
+        let mesh = |name : &str, size : f32| {
+            let mut m = mesh::Mesh::new();
+            m.add_quad(size, size);
+            resources.mesh_manager.borrow_mut().add_resource(name, m)
+        };
+
+        let (red, blue) = (material("red"), material("blue"));
+        let (quad, big) = (mesh("quad", 1f32), mesh("big", 2f32));
+
+        // interleaved, the queue draws red quad, red quad, blue quad, blue big
+        let mut camera = box CameraPass::new(matrix::Matrix4::identity());
+        for &(mat, m) in [(&red, &quad), (&blue, &big), (&red, &quad), (&blue, &quad)].iter() {
+            let mr = mesh_render::MeshRender {
+                mesh : m.clone(),
+                material : mat.clone(),
+                cast_shadows : true,
+                receive_shadows : true
+            };
+            camera.add_mmr(MatrixMeshRender::new(matrix::Matrix4::identity(), mr));
+        }
+
+        let mut pass : RenderPass<usize> = RenderPass::new(shader);
+        pass.passes.insert(0usize, camera);
+        assert_eq!(pass.draw_frame(&resources, Arc::new(Mutex::new(0))), 0);
+
+        let stats = pass.get_stats();
+        assert_eq!(stats.draw_calls, 4);
+        assert_eq!(stats.material_binds, 2);
+        assert_eq!(stats.material_binds_saved, 2);
+        assert_eq!(stats.mesh_binds, 2);
+        assert_eq!(stats.mesh_binds_saved, 2);
+    }
+
+    #[test]
+    fn test_draw_frame_debug_draw()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        let shader = resources.shader_manager.borrow_mut().add_resource(
+            "test_shader",
+            shader::Shader::with_vert_frag(
+                String::from("test_shader"),
+                String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+                String::from("uniform vec4 color;\n")));
+
+        let mut pass : RenderPass<usize> = RenderPass::new(shader);
+        pass.passes.insert(0usize, box CameraPass::new(matrix::Matrix4::identity()));
+
+        debug_draw::with_debug_draw(|d| {
+            d.line(vec::Vec3::zero(), vec::Vec3::x(), vec::Vec4::new(1f64, 0f64, 0f64, 1f64), 0f64, true)
+        });
+
+        let lines = || calls.borrow().iter().filter(|c| if let Call::DrawLines(2) = **c { true } else { false }).count();
+
+        pass.draw_frame(&resources, Arc::new(Mutex::new(0)));
+        assert_eq!(lines(), 1);
+
+        // the line had no duration, it is not drawn again
+        calls.borrow_mut().clear();
+        pass.draw_frame(&resources, Arc::new(Mutex::new(0)));
+        assert_eq!(lines(), 0);
+    }
+
+    #[test]
+    fn test_draw_passes_opaque_first()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        let shader = resources.shader_manager.borrow_mut().add_resource(
+            "test_shader",
+            shader::Shader::with_vert_frag(
+                String::from("test_shader"),
+                String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+                String::from("uniform vec4 color;\n")));
+
+        let mut quad = mesh::Mesh::new();
+        quad.add_quad(1f32, 1f32);
+        let quad = resources.mesh_manager.borrow_mut().add_resource("quad", quad);
+
+        let pass = |name : &str, green : f64, queue : material::Queue| {
+            let mut mat = material::Material::new(name);
+            mat.set_uniform_data("color", shader::UniformData::Vec4(vec::Vec4::new(1f64 - green, green, 0f64, 1f64)));
+            mat.queue = queue;
+            let mr = mesh_render::MeshRender {
+                mesh : quad.clone(),
+                material : resources.material_manager.borrow_mut().add_resource(name, mat),
+                cast_shadows : true,
+                receive_shadows : true
+            };
+
+            let mut camera = box CameraPass::new(matrix::Matrix4::identity());
+            camera.add_mmr(MatrixMeshRender::new(matrix::Matrix4::identity(), mr));
+            let mut p : RenderPass<usize> = RenderPass::new(shader.clone());
+            p.passes.insert(0usize, camera);
+            box p
+        };
+
+        // "a" is drawn first but only holds a transparent object
+        let mut passes = HashMap::new();
+        passes.insert(String::from("a"), pass("green", 1f64, material::Queue::Transparent));
+        passes.insert(String::from("b"), pass("red", 0f64, material::Queue::Opaque));
+
+        let (not_loaded, stats) = draw_passes(&passes, &resources, Arc::new(Mutex::new(0)));
+        assert_eq!(not_loaded, 0);
+        assert_eq!(stats.draw_calls, 2);
+
+        let colors : Vec<Vec<f32>> = calls.borrow().iter().filter_map(|c| match *c {
+            Call::UniformVec(ref n, ref v) if n == "color" => Some(v.clone()),
+            _ => None
+        }).collect();
+        assert_eq!(colors, vec![vec![1f32, 0f32, 0f32, 1f32], vec![0f32, 1f32, 0f32, 1f32]]);
+    }
+
+    /// Pass with a shader drawing instances, and an object with a quad and a white material.
+    fn instanced_pass(resources : &resource::ResourceGroup, uniforms : &str)
+        -> (RenderPass<usize>, mesh_render::MeshRender)
+    {
+        let shader = resources.shader_manager.borrow_mut().add_resource(
+            "instanced",
+            shader::Shader::with_vert_frag(
+                String::from("instanced"),
+                format!("attribute vec3 position;\nattribute mat4 instance_matrix;\nuniform mat4 matrix;\n{}", uniforms),
+                String::from("uniform vec4 color;\n")));
+
+        let mut mat = material::Material::new("white");
+        mat.set_uniform_data("color", shader::UniformData::Vec4(vec::Vec4::new(1f64, 1f64, 1f64, 1f64)));
+        let material = resources.material_manager.borrow_mut().add_resource("white", mat);
+
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+        let mesh = resources.mesh_manager.borrow_mut().add_resource("quad", m);
+
+        let mr = mesh_render::MeshRender {
+            mesh : mesh,
+            material : material,
+            cast_shadows : true,
+            receive_shadows : true
+        };
+
+        (RenderPass::new(shader), mr)
+    }
+
+    fn instanced_draws(calls : &[Call]) -> Vec<usize>
+    {
+        calls.iter().filter_map(|c| match *c {
+            Call::DrawFacesInstanced(_, 6, count) => Some(count),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn test_draw_frame_instanced()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        let (mut pass, mr) = instanced_pass(&resources, "");
+
+        let mut camera = box CameraPass::new(matrix::Matrix4::identity());
+        for x in &[-0.5f64, 0f64, 0.5f64] {
+            let t = matrix::Matrix4::translation(&vec::Vec3::new(*x, 0f64, 0f64));
+            camera.add_mmr(MatrixMeshRender::new(t, mr.clone()));
+        }
+        // same mesh and material, but drawn without the shadows
+        let no_shadows = mesh_render::MeshRender { receive_shadows : false, .. mr.clone() };
+        camera.add_mmr(MatrixMeshRender::new(matrix::Matrix4::identity(), no_shadows));
+        pass.passes.insert(0usize, camera);
+
+        assert_eq!(pass.draw_frame(&resources, Arc::new(Mutex::new(0))), 0);
+
+        assert_eq!(instanced_draws(&calls.borrow()), vec![3, 1]);
+        let stats = pass.get_stats();
+        assert_eq!(stats.draw_calls, 2);
+        assert_eq!(stats.instances, 4);
+        assert_eq!(stats.material_binds, 1);
+    }
+
+    #[test]
+    fn test_draw_frame_instanced_lights()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        let (mut pass, mr) = instanced_pass(&resources, "uniform int light_count;\nuniform int light_type[4];\n");
+
+        // each light only reaches the objects at its position
+        for x in &[-5f64, 5f64] {
+            pass.lights.push(light::WorldLight {
+                light : light::Light::point(vec::Vec3::new(1f64, 1f64, 1f64), 1f64, 1f64),
+                position : vec::Vec3::new(*x, 0f64, 0f64),
+                direction : vec::Vec3::new(0f64, 0f64, -1f64)
+            });
+        }
+
+        // sees from -10 to 10
+        let mut camera = box CameraPass::new(matrix::Matrix4::scale(&vec::Vec3::new(0.1f64, 0.1f64, 0.1f64)));
+        for x in &[-5f64, 5f64, -5f64] {
+            let t = matrix::Matrix4::translation(&vec::Vec3::new(*x, 0f64, 0f64));
+            camera.add_mmr(MatrixMeshRender::new(t, mr.clone()));
+        }
+        pass.passes.insert(0usize, camera);
+
+        assert_eq!(pass.draw_frame(&resources, Arc::new(Mutex::new(0))), 0);
+
+        // one group, split by the lights
+        assert_eq!(instanced_draws(&calls.borrow()), vec![2, 1]);
+        let light_counts : Vec<i32> = calls.borrow().iter().filter_map(|c| match *c {
+            Call::UniformInt(ref n, count) if n == "light_count" => Some(count),
+            _ => None
+        }).collect();
+        assert_eq!(light_counts, vec![1, 1]);
+        assert_eq!(pass.get_stats().instances, 3);
+    }
+
+    /// Reference image of test_draw_frame_software, written again when
+    /// DORMIN_UPDATE_GOLDEN is set.
+    const GOLDEN_QUADS : &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/render_pass_quads.png");
+
+    #[test]
+    fn test_draw_frame_software()
+    {
+        let soft = software::SoftwareBackend::new(16, 16);
+        let frame = soft.target.clone();
+        backend::set_backend(box soft);
+        backend::with_backend(|b| b.clear());
+
+        let resources = resource::ResourceGroup::new();
+
+        let shader = resources.shader_manager.borrow_mut().add_resource(
+            "test_shader",
+            shader::Shader::with_vert_frag(
+                String::from("test_shader"),
+                String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+                String::from("uniform vec4 color;\n")));
+
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+        let mesh = resources.mesh_manager.borrow_mut().add_resource("quad", m);
+
+        let mut red = material::Material::new("red");
+        red.set_uniform_data("color", shader::UniformData::Vec4(vec::Vec4::new(1f64, 0f64, 0f64, 1f64)));
+        let red = resources.material_manager.borrow_mut().add_resource("red", red);
+
+        let mut green = material::Material::new("green");
+        green.set_uniform_data("color", shader::UniformData::Vec4(vec::Vec4::new(0f64, 1f64, 0f64, 0.5f64)));
+        green.queue = material::Queue::Transparent;
+        green.render_state.blend = material::Blend::Alpha;
+        let green = resources.material_manager.borrow_mut().add_resource("green", green);
+
+        let mr = |material| mesh_render::MeshRender {
+            mesh : mesh.clone(),
+            material : material,
+            cast_shadows : true,
+            receive_shadows : true
+        };
+
+        // a quarter of pixel to the right, so no pixel center is on the diagonal of the quads
+        let opaque = matrix::Matrix4::translation(&vec::Vec3::new(1f64/32f64, 0f64, 0f64));
+        let transparent = matrix::Matrix4::translation(&vec::Vec3::new(0.25f64 + 1f64/32f64, 0.25f64, -0.5f64));
+
+        // the transparent quad is added first but is drawn over the opaque one
+        let mut camera = box CameraPass::new(matrix::Matrix4::identity());
+        camera.add_mmr(MatrixMeshRender::new(transparent, mr(green)));
+        camera.add_mmr(MatrixMeshRender::new(opaque, mr(red)));
+
+        let mut pass : RenderPass<usize> = RenderPass::new(shader);
+        pass.passes.insert(0usize, camera);
+
+        assert_eq!(pass.draw_frame(&resources, Arc::new(Mutex::new(0))), 0);
+
+        let frame = frame.borrow();
+        if ::std::env::var("DORMIN_UPDATE_GOLDEN").is_ok() {
+            frame.save_png(GOLDEN_QUADS).unwrap();
+        }
+
+        let reference = software::Frame::load_png(GOLDEN_QUADS).unwrap();
+        assert_eq!(frame.difference(&reference, 1), 0);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+/// Bits of the sort key, from the most significant :
+/// pass (8), shader (12), material (16), mesh (12), depth (16).
+const PASS_SHIFT : u64 = 56;
+const SHADER_SHIFT : u64 = 44;
+const MATERIAL_SHIFT : u64 = 28;
+const MESH_SHIFT : u64 = 16;
+
+/// Items drawn back to front have the depth just after the pass :
+/// pass (8), depth (16), shader (12), material (16), mesh (12).
+const SORTED_DEPTH_SHIFT : u64 = 40;
+const SORTED_SHADER_SHIFT : u64 = 28;
+const SORTED_MATERIAL_SHIFT : u64 = 12;
+
+const SHADER_MASK : u64 = 0xfff;
+const MATERIAL_MASK : u64 = 0xffff;
+const MESH_MASK : u64 = 0xfff;
+const DEPTH_MASK : u64 = 0xffff;
+
+/// Counts of the last drawn frame.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RenderStats
+{
+    pub draw_calls : usize,
+    pub material_binds : usize,
+    pub mesh_binds : usize,
+    /// materials which were not sent again because the previous object had the same
+    pub material_binds_saved : usize,
+    /// meshes which were not bound again because the previous object had the same
+    pub mesh_binds_saved : usize,
+    /// objects drawn by instanced draws
+    pub instances : usize,
+}
+
+impl RenderStats
+{
+    pub fn add(&mut self, other : &RenderStats)
+    {
+        self.draw_calls = self.draw_calls + other.draw_calls;
+        self.material_binds = self.material_binds + other.material_binds;
+        self.mesh_binds = self.mesh_binds + other.mesh_binds;
+        self.material_binds_saved = self.material_binds_saved + other.material_binds_saved;
+        self.mesh_binds_saved = self.mesh_binds_saved + other.mesh_binds_saved;
+        self.instances = self.instances + other.instances;
+    }
+}
+
+/// Items sorted by a 64 bits key so the objects sharing a shader, material and mesh
+/// are drawn one after the other.
+pub struct RenderQueue<T>
+{
+    items : Vec<(u64, T)>,
+    shaders : HashMap<String, u64>,
+    materials : HashMap<String, u64>,
+    meshes : HashMap<String, u64>,
+}
+
+fn intern(ids : &mut HashMap<String, u64>, name : &str, mask : u64) -> u64
+{
+    if let Some(id) = ids.get(name) {
+        return *id;
+    }
+
+    let id = (ids.len() as u64) & mask;
+    ids.insert(name.to_owned(), id);
+    id
+}
+
+/// Depth from 0 to 1 to 16 bits, far objects are drawn last when back_to_front is false.
+pub fn depth_bits(depth : f64, back_to_front : bool) -> u64
+{
+    let d = (depth.max(0f64).min(1f64) * DEPTH_MASK as f64) as u64;
+    if back_to_front { DEPTH_MASK - d } else { d }
+}
+
+impl<T> RenderQueue<T>
+{
+    pub fn new() -> RenderQueue<T>
+    {
+        RenderQueue {
+            items : Vec::new(),
+            shaders : HashMap::new(),
+            materials : HashMap::new(),
+            meshes : HashMap::new()
+        }
+    }
+
+    /// Removes the items, the ids given to the names are kept.
+    pub fn clear(&mut self)
+    {
+        self.items.clear();
+    }
+
+    /// Key of an item, depth is from depth_bits.
+    pub fn key(&mut self, pass : u8, shader : &str, material : &str, mesh : &str, depth : u64) -> u64
+    {
+        let shader = intern(&mut self.shaders, shader, SHADER_MASK);
+        let material = intern(&mut self.materials, material, MATERIAL_MASK);
+        let mesh = intern(&mut self.meshes, mesh, MESH_MASK);
+
+        ((pass as u64) << PASS_SHIFT)
+            | (shader << SHADER_SHIFT)
+            | (material << MATERIAL_SHIFT)
+            | (mesh << MESH_SHIFT)
+            | (depth & DEPTH_MASK)
+    }
+
+    /// Key of an item which must be drawn in depth order, like a transparent object,
+    /// depth is from depth_bits with back_to_front.
+    pub fn key_sorted(&mut self, pass : u8, shader : &str, material : &str, mesh : &str, depth : u64) -> u64
+    {
+        let shader = intern(&mut self.shaders, shader, SHADER_MASK);
+        let material = intern(&mut self.materials, material, MATERIAL_MASK);
+        let mesh = intern(&mut self.meshes, mesh, MESH_MASK);
+
+        ((pass as u64) << PASS_SHIFT)
+            | ((depth & DEPTH_MASK) << SORTED_DEPTH_SHIFT)
+            | (shader << SORTED_SHADER_SHIFT)
+            | (material << SORTED_MATERIAL_SHIFT)
+            | mesh
+    }
+
+    pub fn push(&mut self, key : u64, item : T)
+    {
+        self.items.push((key, item));
+    }
+
+    /// The sort is stable, items with the same key keep their order.
+    pub fn sort(&mut self)
+    {
+        self.items.sort_by_key(|i| i.0);
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.items.len()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<(u64, T)>
+    {
+        self.items.iter()
+    }
+}
+
+/// Pass of a key.
+pub fn key_pass(key : u64) -> u8
+{
+    (key >> PASS_SHIFT) as u8
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[test]
+    fn test_key_order()
+    {
+        let mut q : RenderQueue<()> = RenderQueue::new();
+        let max = DEPTH_MASK;
+
+        // the first names get the smallest ids
+        let first = q.key(0, "s0", "m0", "q0", 0);
+        let depth = q.key(0, "s0", "m0", "q0", max);
+        let mesh = q.key(0, "s0", "m0", "q1", 0);
+        let material = q.key(0, "s0", "m1", "q0", 0);
+        let shader = q.key(0, "s1", "m0", "q0", 0);
+        let pass = q.key(1, "s0", "m0", "q0", 0);
+
+        // each field wins over all the ones after it
+        assert!(first < depth);
+        assert!(depth < mesh);
+        assert!(q.key(0, "s0", "m0", "q1", max) < material);
+        assert!(q.key(0, "s0", "m1", "q1", max) < shader);
+        assert!(q.key(0, "s1", "m1", "q1", max) < pass);
+
+        assert_eq!(key_pass(pass), 1);
+        assert_eq!(key_pass(q.key(255, "s1", "m1", "q1", max)), 255);
+    }
+
+    #[test]
+    fn test_key_sorted_order()
+    {
+        let mut q : RenderQueue<()> = RenderQueue::new();
+
+        let far = q.key_sorted(0, "s0", "m0", "q0", depth_bits(1f64, true));
+        let near = q.key_sorted(0, "s0", "m0", "q0", depth_bits(0f64, true));
+        let far_other = q.key_sorted(0, "s1", "m1", "q1", depth_bits(1f64, true));
+
+        // back to front, whatever the shader, material and mesh
+        assert!(far < near);
+        assert!(far_other < near);
+        assert!(near < q.key_sorted(1, "s0", "m0", "q0", depth_bits(1f64, true)));
+    }
+
+    #[test]
+    fn test_intern_wraparound()
+    {
+        let mut ids = HashMap::new();
+        for i in 0..(MESH_MASK + 1) {
+            assert_eq!(intern(&mut ids, &format!("mesh{}", i), MESH_MASK), i);
+        }
+
+        // the ids start again from 0 instead of going into the bits of other fields
+        assert_eq!(intern(&mut ids, "one_too_many", MESH_MASK), 0);
+        assert_eq!(intern(&mut ids, "mesh1", MESH_MASK), 1);
+
+        let mut q : RenderQueue<()> = RenderQueue::new();
+        for i in 0..(MESH_MASK + 1) {
+            q.key(0, "s", "m", &format!("mesh{}", i), 0);
+        }
+        let wrapped = q.key(3, "s", "m", "one_too_many", 0);
+        assert_eq!(wrapped, q.key(3, "s", "m", "mesh0", 0));
+        assert_eq!(key_pass(wrapped), 3);
+    }
+
+    /// Binds of the items in the order of the queue, the same way as the render pass :
+    /// a material or a mesh is not bound again when the previous item had the same.
+    fn binds(q : &RenderQueue<(&'static str, &'static str)>) -> RenderStats
+    {
+        let mut stats = RenderStats::default();
+        let mut last : Option<(&str, &str)> = None;
+        for &(_, (material, mesh)) in q.iter() {
+            if last.map_or(false, |l| l.0 == material) {
+                stats.material_binds_saved += 1;
+            }
+            else {
+                stats.material_binds += 1;
+            }
+            if last.map_or(false, |l| l.1 == mesh) {
+                stats.mesh_binds_saved += 1;
+            }
+            else {
+                stats.mesh_binds += 1;
+            }
+            last = Some((material, mesh));
+        }
+        stats
+    }
+
+    #[test]
+    fn test_redundant_binds()
+    {
+        let items = [("red", "quad"), ("blue", "cube"), ("red", "quad"), ("blue", "quad")];
+
+        let mut q = RenderQueue::new();
+        for &(material, mesh) in items.iter() {
+            let key = q.key(0, "shader", material, mesh, 0);
+            q.push(key, (material, mesh));
+        }
+
+        let unsorted = binds(&q);
+        assert_eq!(unsorted.material_binds, 4);
+        assert_eq!(unsorted.mesh_binds, 3);
+
+        // red quad, red quad, blue quad, blue cube
+        q.sort();
+        let sorted = binds(&q);
+        assert_eq!(sorted.material_binds, 2);
+        assert_eq!(sorted.material_binds_saved, 2);
+        assert_eq!(sorted.mesh_binds, 2);
+        assert_eq!(sorted.mesh_binds_saved, 2);
+    }
+}
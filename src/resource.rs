@@ -950,6 +950,12 @@ impl<T:'static+Create+Sync+Send> ResourceManager<T> {
         }
     }
 
+    /// True if a resource with this name was added or requested.
+    pub fn contains(&self, name : &str) -> bool
+    {
+        self.map.contains_key(name)
+    }
+
     //TODO put to private
     pub fn get_mut_or_panic(&mut self,index : usize) -> &mut T
     {
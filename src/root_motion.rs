@@ -0,0 +1,207 @@
+use armature;
+use armature::Pose;
+use armature_blend::{Motion, CrossFade};
+use transform;
+use vec;
+
+/// What is taken from the root bone of the armature.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct RootMotionOptions
+{
+    /// 1 for the axes of the translation which are extracted, 0 for the ones kept in the pose
+    pub position : vec::Vec3,
+    pub rotation : bool
+}
+
+impl RootMotionOptions
+{
+    pub fn new() -> RootMotionOptions
+    {
+        RootMotionOptions {
+            position : vec::Vec3::one(),
+            rotation : true
+        }
+    }
+}
+
+/// Movement of the root bone during an update, in the frame of the object
+/// at the start of the update.
+#[derive(Clone, Copy)]
+pub struct RootMotion
+{
+    pub position : vec::Vec3,
+    pub rotation : vec::Quat
+}
+
+impl RootMotion
+{
+    pub fn identity() -> RootMotion
+    {
+        RootMotion {
+            position : vec::Vec3::zero(),
+            rotation : vec::Quat::identity()
+        }
+    }
+
+    /// This motion followed by next.
+    pub fn then(&self, next : &RootMotion) -> RootMotion
+    {
+        RootMotion {
+            position : self.position + self.rotation.rotate_vec3(&next.position),
+            rotation : self.rotation * next.rotation
+        }
+    }
+
+    /// Moves the transform of the object owning the armature.
+    pub fn apply(&self, t : &mut transform::Transform)
+    {
+        let q = t.orientation.as_quat();
+        t.position = t.position + q.rotate_vec3(&(self.position * t.scale));
+        t.orientation.set_with_quat(q * self.rotation);
+        t.set_as_dirty();
+    }
+}
+
+fn find_root(armature : &armature::Armature) -> Option<usize>
+{
+    (0..armature.bone_count()).find(|i| armature.get_bone(*i).parent.is_none())
+}
+
+fn root_at(
+    motion : &Motion,
+    armature : &armature::Armature,
+    root : usize,
+    time : f64) -> Option<(vec::Vec3, vec::Quat)>
+{
+    motion.sample(armature, time).map(|p| (p.position[root], p.rotation[root]))
+}
+
+/// Motion of the root bone during the played segments, and the root
+/// at the start of the motion.
+fn motion_delta(
+    options : &RootMotionOptions,
+    motion : &Motion,
+    armature : &armature::Armature,
+    root : usize,
+    segments : &[(f64, f64)]) -> Option<(RootMotion, vec::Vec3, vec::Quat)>
+{
+    let mut delta = RootMotion::identity();
+
+    let (p0, q0) = match root_at(motion, armature, root, 0f64) {
+        Some(r) => r,
+        None => return None
+    };
+
+    for &(a, b) in segments {
+        let (pa, qa) = match root_at(motion, armature, root, a) {
+            Some(r) => r,
+            None => continue
+        };
+        let (pb, qb) = match root_at(motion, armature, root, b) {
+            Some(r) => r,
+            None => continue
+        };
+
+        let dp = (pb - pa) * options.position;
+
+        // the object already holds the root rotation at a, relative to the start
+        let (position, rotation) = if options.rotation {
+            let frame = q0 * qa.conj();
+            (frame.rotate_vec3(&dp), frame * qb * q0.conj())
+        }
+        else {
+            (dp, vec::Quat::identity())
+        };
+
+        delta.position = delta.position + delta.rotation.rotate_vec3(&position);
+        delta.rotation = delta.rotation * rotation;
+    }
+
+    Some((delta, p0, q0))
+}
+
+fn mix(
+    a : Option<(RootMotion, vec::Vec3, vec::Quat)>,
+    b : Option<(RootMotion, vec::Vec3, vec::Quat)>,
+    t : f64) -> Option<(RootMotion, vec::Vec3, vec::Quat)>
+{
+    match (a, b) {
+        (Some((da, pa, qa)), Some((db, pb, qb))) => {
+            let delta = RootMotion {
+                position : vec::vec3_lerp(da.position, db.position, t),
+                rotation : vec::quat_slerp(da.rotation, db.rotation, t)
+            };
+            Some((delta, vec::vec3_lerp(pa, pb, t), vec::quat_slerp(qa, qb, t)))
+        },
+        (None, b) => b,
+        (a, None) => a
+    }
+}
+
+/// Motion of the root bone of the motion fading out, itself blended with
+/// the fade which was playing when it started.
+fn fade_delta(
+    options : &RootMotionOptions,
+    fade : &CrossFade,
+    armature : &armature::Armature,
+    root : usize) -> Option<(RootMotion, vec::Vec3, vec::Quat)>
+{
+    let from = motion_delta(options, &fade.from, armature, root, fade.playback.get_segments());
+
+    match fade.previous {
+        Some(ref p) => mix(fade_delta(options, p, armature, root), from, p.weight()),
+        None => from
+    }
+}
+
+/// Motion of the root bone during the played segments, the pose is not changed.
+pub fn delta(
+    options : &RootMotionOptions,
+    motion : &Motion,
+    armature : &armature::Armature,
+    segments : &[(f64, f64)]) -> RootMotion
+{
+    find_root(armature)
+        .and_then(|root| motion_delta(options, motion, armature, root, segments))
+        .map(|(d, _, _)| d)
+        .unwrap_or(RootMotion::identity())
+}
+
+/// Computes the motion of the root bone during the played segments, and removes it
+/// from the pose : the root keeps the values it has at the start of the motion.
+/// The segments are split at the loop boundaries so looping does not jump back.
+/// During a cross fade, the motions are weighted like their poses.
+pub fn extract(
+    options : &RootMotionOptions,
+    motion : &Motion,
+    fade : Option<&CrossFade>,
+    armature : &armature::Armature,
+    segments : &[(f64, f64)],
+    pose : &mut Pose) -> RootMotion
+{
+    let root = match find_root(armature) {
+        Some(r) => r,
+        None => return RootMotion::identity()
+    };
+
+    let to = motion_delta(options, motion, armature, root, segments);
+    let result = match fade {
+        Some(f) => mix(fade_delta(options, f, armature, root), to, f.weight()),
+        None => to
+    };
+
+    let (delta, p0, q0) = match result {
+        Some(r) => r,
+        None => return RootMotion::identity()
+    };
+
+    if root < pose.len() {
+        let keep = vec::Vec3::one() - options.position;
+        pose.position[root] = pose.position[root] * keep + p0 * options.position;
+        if options.rotation {
+            pose.rotation[root] = q0;
+        }
+    }
+
+    delta
+}
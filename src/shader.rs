@@ -17,6 +17,7 @@ use resource;
 use uniform::UniformSend;
 use uniform::TextureSend;
 use texture;
+use backend;
 
 #[repr(C)]
 pub struct CglShader;
@@ -49,25 +50,6 @@ pub struct Shader
 unsafe impl Send for Shader {}
 unsafe impl Sync for Shader {}
 
-extern fn shader_uniform_add(
-    data : *const c_void,
-    name : *const c_char,
-    cgl_uni : *const CglShaderUniform)
-{
-    let uniforms : &mut HashMap<String, *const CglShaderUniform> = unsafe {mem::transmute(data) };
-    uniforms.insert(util::c_char_to_string(name), cgl_uni);
-}
-
-extern fn shader_attribute_add(
-    data : *const c_void,
-    name : *const c_char,
-    cgl_att : *const CglShaderAttribute)
-{
-    let attributes : &mut HashMap<String, *const CglShaderAttribute> = unsafe {mem::transmute(data) };
-    attributes.insert(util::c_char_to_string(name), cgl_att);
-}
-
-
 impl Shader
 {
     /*
@@ -115,6 +97,12 @@ impl Shader
         }
     }
 
+    /// True if the shader declares the uniform, to send optional uniforms without errors.
+    pub fn has_uniform(&self, name : &str) -> bool
+    {
+        self.uniforms.contains_key(name)
+    }
+
     pub fn texture_set(&self, name : &str, value : &TextureSend, index : u32)
     {
         match self.uniforms.get(&String::from(name)) {
@@ -127,10 +115,7 @@ impl Shader
     {
         match self.cgl_shader {
             None => {},
-            Some(cs) =>
-                unsafe {
-                    cgl_shader_use(cs);
-                }
+            Some(cs) => backend::with_backend(|b| b.shader_use(cs))
         }
     }
 
@@ -182,17 +167,7 @@ impl Shader
         self.frag_path = Some(frag);
 
         //TODO remove from here
-        self.cgl_init();
-
-        unsafe { cgl_shader_attributes_init(
-                self.cgl_shader.unwrap(),
-                shader_attribute_add,
-                mem::transmute(&mut self.attributes)); }
-
-        unsafe { cgl_shader_uniforms_init(
-                self.cgl_shader.unwrap(),
-                shader_uniform_add,
-                mem::transmute(&mut self.uniforms)); }
+        self.load_gl();
 
 
         self.state = 2;
@@ -232,31 +207,15 @@ impl Shader
 
     pub fn cgl_init(&mut self)
     {
-        let vertc;
-        match self.vert {
-            None => return,
-            Some(ref v) => {
-                vertc = CString::new(v.as_bytes()).unwrap();
-            }
-        }
-
-        let fragc;
-        match self.frag {
-            None => return,
-            Some(ref f) => {
-                fragc = CString::new(f.as_bytes()).unwrap();
-            }
-        }
-
-        let vertcp = vertc.as_ptr();
-        let fragcp = fragc.as_ptr();
-
-        println!("shader::: begin to init : {}", self.name);
+        let shader = match (&self.vert, &self.frag) {
+            (&Some(ref v), &Some(ref f)) => {
+                println!("shader::: begin to init : {}", self.name);
+                backend::with_backend(|b| b.shader_init(v, f))
+            },
+            _ => return
+        };
 
-        unsafe {
-            let shader = cgl_shader_init_string(vertcp, fragcp);
-            self.cgl_shader = Some(shader);
-        }
+        self.cgl_shader = Some(shader);
 
         self.state = 3;
     }
@@ -294,15 +253,38 @@ impl Shader
     {
         self.cgl_init();
 
-        unsafe { cgl_shader_attributes_init(
-                self.cgl_shader.unwrap(),
-                shader_attribute_add,
-                mem::transmute(&mut self.attributes)); }
+        let cs = match self.cgl_shader {
+            Some(cs) => cs,
+            None => return
+        };
 
-        unsafe { cgl_shader_uniforms_init(
-                self.cgl_shader.unwrap(),
-                shader_uniform_add,
-                mem::transmute(&mut self.uniforms)); }
+        self.attributes = backend::with_backend(|b| b.shader_attributes(cs));
+        self.uniforms = backend::with_backend(|b| {
+            let mut uniforms = b.shader_uniforms(cs);
+
+            // GL only lists the first element of the arrays
+            let arrays : Vec<String> = uniforms.keys()
+                .filter(|n| n.ends_with("[0]"))
+                .map(|n| String::from(&n[..n.len() - 3]))
+                .collect();
+
+            for a in arrays {
+                let mut i = 1;
+                loop {
+                    let name = format!("{}[{}]", a, i);
+                    if !uniforms.contains_key(&name) {
+                        let u = b.shader_uniform(cs, &name);
+                        if u.is_null() {
+                            break;
+                        }
+                        uniforms.insert(name, u);
+                    }
+                    i = i + 1;
+                }
+            }
+
+            uniforms
+        });
     }
 }
 
@@ -336,47 +318,6 @@ impl UniformSend for UniformData
     }
 }
 
-type ShaderUniformAddFn = extern fn(
-    data : *const c_void,
-    name : *const c_char,
-    cgl_uni : *const CglShaderUniform);
-
-type ShaderAttributeAddFn = extern fn(
-    data : *const c_void,
-    name : *const c_char,
-    cgl_att : *const CglShaderAttribute);
-
-#[link(name = "cypher")]
-extern {
-    fn cgl_shader_init_string(
-        vert : *const c_char,
-        frat : *const c_char) -> *const CglShader;
-
-    pub fn cgl_shader_use(shader : *const CglShader);
-
-    /*
-    pub fn cgl_shader_attribute_new(
-        shader : *const CglShader,
-        name : *const c_char,
-        size : c_uint) -> *const CglShaderAttribute;
-
-    pub fn cgl_shader_uniform_new(
-        shader : *const CglShader,
-        name : *const c_char) -> *const CglShaderUniform;
-        */
-
-    fn cgl_shader_attributes_init(
-        shader : *const CglShader, 
-        cb : ShaderAttributeAddFn,
-        data : *const c_void);
-
-    fn cgl_shader_uniforms_init(
-        shader : *const CglShader, 
-        cb : ShaderUniformAddFn,
-        data : *const c_void);
-
-}
-
 impl fmt::Debug for Shader
 {
     fn fmt(&self, fmt : &mut fmt::Formatter) -> fmt::Result
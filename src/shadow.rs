@@ -0,0 +1,529 @@
+use vec;
+use matrix;
+use shader;
+use resource;
+use camera2;
+use render;
+use backend;
+use fbo;
+use uniform::{self, UniformSend};
+use material::{Blend, Cull};
+use transform::Transform;
+use light::{WorldLight, LightType};
+
+/// Most cascades of a directional light, and size of the shadow uniform arrays.
+pub const MAX_CASCADES : usize = 4;
+
+/// Texture unit of the first shadow map, after the ones of the materials.
+pub const SHADOW_TEXTURE_UNIT : u32 = 8;
+
+/// Depth of the casters, "matrix" is the light matrix * world.
+pub const DEPTH_VERT : &'static str = "attribute vec3 position;
+uniform mat4 matrix;
+void main()
+{
+    gl_Position = matrix * vec4(position, 1.0);
+}
+";
+
+pub const DEPTH_FRAG : &'static str = "precision mediump float;
+void main()
+{
+    gl_FragColor = vec4(1.0);
+}
+";
+
+/// Glsl function for the material shaders receiving shadows, position is
+/// shadow_matrix[i] * vec4(position, 1.0) from the vertex shader.
+/// Returns 1 when lit and 0 in the shadow, the samples around are averaged.
+pub const PCF_GLSL : &'static str = "float shadow_pcf(sampler2D map, vec4 position, float bias, int radius, float texel)
+{
+    vec3 p = position.xyz / position.w * 0.5 + 0.5;
+    if (p.z > 1.0) {
+        return 1.0;
+    }
+
+    float lit = 0.0;
+    float count = 0.0;
+    for (int x = -4; x <= 4; x++) {
+        for (int y = -4; y <= 4; y++) {
+            if (x < -radius || x > radius || y < -radius || y > radius) {
+                continue;
+            }
+            float depth = texture2D(map, p.xy + vec2(float(x), float(y)) * texel).r;
+            lit += p.z - bias > depth ? 0.0 : 1.0;
+            count += 1.0;
+        }
+    }
+
+    return lit / count;
+}
+";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShadowSettings
+{
+    /// width and height of the shadow maps
+    #[serde(default="default_resolution")]
+    pub resolution : u32,
+    /// depth removed before comparing, against shadow acne
+    #[serde(default="default_bias")]
+    pub bias : f64,
+    /// texels sampled around, 0 for one sample and no filtering
+    #[serde(default="default_pcf_radius")]
+    pub pcf_radius : u32,
+    /// directional lights, number of maps splitting the camera frustum
+    #[serde(default="default_cascades")]
+    pub cascades : usize,
+    /// directional lights, 0 splits the camera frustum uniformly and 1 logarithmically
+    #[serde(default="default_split_lambda")]
+    pub split_lambda : f64,
+    /// directional lights, distance from the camera after which there are no shadows
+    #[serde(default="default_distance")]
+    pub distance : f64,
+}
+
+fn default_resolution() -> u32
+{
+    1024
+}
+
+fn default_bias() -> f64
+{
+    0.005f64
+}
+
+fn default_pcf_radius() -> u32
+{
+    1
+}
+
+fn default_cascades() -> usize
+{
+    3
+}
+
+fn default_split_lambda() -> f64
+{
+    0.5f64
+}
+
+fn default_distance() -> f64
+{
+    100f64
+}
+
+impl Default for ShadowSettings
+{
+    fn default() -> ShadowSettings
+    {
+        ShadowSettings {
+            resolution : default_resolution(),
+            bias : default_bias(),
+            pcf_radius : default_pcf_radius(),
+            cascades : default_cascades(),
+            split_lambda : default_split_lambda(),
+            distance : default_distance()
+        }
+    }
+}
+
+/// Shadow maps of a light, one for each cascade.
+pub struct ShadowMap
+{
+    /// fbos of the fbo manager, the map is the depth attachment
+    pub fbos : Vec<String>,
+    /// world to light clip space
+    pub matrices : Vec<matrix::Matrix4>,
+    /// camera view depth where each cascade ends
+    pub splits : Vec<f64>,
+    pub settings : ShadowSettings
+}
+
+/// View matrix of a light at the position, looking in the direction.
+fn light_view(position : &vec::Vec3, direction : &vec::Vec3) -> matrix::Matrix4
+{
+    let q = vec::Quat::new_rotation_between(&vec::Vec3::forward(), direction);
+    let world = &matrix::Matrix4::translation(position) * &matrix::Matrix4::rotation(&q);
+    world.get_inverse()
+}
+
+/// Perspective light matrix covering the cone of a spot light.
+pub fn spot_matrix(light : &WorldLight) -> matrix::Matrix4
+{
+    let far = light.light.range;
+    let near = (far * 0.01f64).max(0.01f64);
+    let half = light.light.outer_angle.min(1.5f64).tan() * near;
+
+    let projection = matrix::Matrix4::frustum(-half, half, -half, half, near, far);
+    &projection * &light_view(&light.position, &light.direction)
+}
+
+/// Camera view depths where the cascades end, mixing uniform and logarithmic splits.
+pub fn cascade_splits(near : f64, far : f64, count : usize, lambda : f64) -> Vec<f64>
+{
+    (1..count + 1).map(|i| {
+        let t = i as f64 / count as f64;
+        let uniform = near + (far - near) * t;
+        let log = near * (far / near).powf(t);
+        log * lambda + uniform * (1f64 - lambda)
+    }).collect()
+}
+
+/// Corners of the part of the camera frustum between the two view depths,
+/// the four near ones first.
+pub fn frustum_corners(
+    camera : &camera2::Camera,
+    transform : &Transform,
+    near : f64,
+    far : f64) -> [vec::Vec3; 8]
+{
+    let view = transform.compute_return_local_matrix().get_inverse();
+    let inverse = (&camera.get_perspective() * &view).get_inverse();
+
+    let unproject = |x : f64, y : f64, z : f64| {
+        let p = &inverse * vec::Vec4::new(x, y, z, 1f64);
+        vec::Vec3::new(p.x / p.w, p.y / p.w, p.z / p.w)
+    };
+
+    // the view depth changes linearly between the near and far corners
+    let t0 = (near - camera.near) / (camera.far - camera.near);
+    let t1 = (far - camera.near) / (camera.far - camera.near);
+
+    let mut corners = [vec::Vec3::zero(); 8];
+    let xy = [(-1f64, -1f64), (1f64, -1f64), (1f64, 1f64), (-1f64, 1f64)];
+    for (i, &(x, y)) in xy.iter().enumerate() {
+        let a = unproject(x, y, -1f64);
+        let b = unproject(x, y, 1f64);
+        corners[i] = a + (b - a) * t0;
+        corners[i + 4] = a + (b - a) * t1;
+    }
+
+    corners
+}
+
+/// Orthographic light matrix containing the corners. The size does not change when
+/// the camera turns and the center moves by whole texels, so the shadows do not flicker.
+pub fn directional_matrix(
+    direction : &vec::Vec3,
+    corners : &[vec::Vec3; 8],
+    resolution : u32) -> matrix::Matrix4
+{
+    let mut center = vec::Vec3::zero();
+    for c in corners.iter() {
+        center = center + *c;
+    }
+    center = center / 8f64;
+
+    let radius = corners.iter().fold(1e-3f64, |r, c| r.max((*c - center).length()));
+
+    let rotation = light_view(&vec::Vec3::zero(), direction);
+    let texel = radius * 2f64 / resolution.max(1) as f64;
+    let c = &rotation * vec::Vec4::new(center.x, center.y, center.z, 1f64);
+    let snapped = vec::Vec4::new((c.x / texel).floor() * texel, (c.y / texel).floor() * texel, c.z, 1f64);
+    let s = &rotation.get_inverse() * snapped;
+    let center = vec::Vec3::new(s.x, s.y, s.z);
+
+    // keeps the casters between the light and the camera frustum
+    let back = radius * 2f64;
+    let view = light_view(&(center - *direction * back), direction);
+    let projection = matrix::Matrix4::orthographic_bounds(
+        -radius, radius, -radius, radius, 0f64, back + radius);
+
+    &projection * &view
+}
+
+/// Renders the shadow maps with its own depth shader.
+pub struct ShadowPass
+{
+    pub shader : shader::Shader
+}
+
+impl ShadowPass
+{
+    pub fn new() -> ShadowPass
+    {
+        ShadowPass {
+            shader : shader::Shader::with_vert_frag(
+                String::from("shadow_depth"),
+                String::from(DEPTH_VERT),
+                String::from(DEPTH_FRAG))
+        }
+    }
+
+    /// Renders the depth of the objects casting shadows, seen from the light.
+    /// The camera fits the cascades of a directional light, the fbos are named after name.
+    /// None when the light has no shadow settings, or is a point light.
+    pub fn render(
+        &mut self,
+        name : &str,
+        light : &WorldLight,
+        camera : Option<(&camera2::Camera, &Transform)>,
+        objects : &[&render::MatrixMeshRender],
+        resource : &resource::ResourceGroup) -> Option<ShadowMap>
+    {
+        let settings = match light.light.shadow {
+            Some(ref s) => s.clone(),
+            None => return None
+        };
+
+        let (matrices, splits) = match light.light.light_type {
+            LightType::Point => {
+                println!("shadow, point lights do not cast shadows : {}", name);
+                return None;
+            },
+            LightType::Spot => {
+                (vec![spot_matrix(light)], vec![::std::f32::MAX as f64])
+            },
+            LightType::Directional => {
+                let (camera, transform) = match camera {
+                    Some(c) => c,
+                    None => {
+                        println!("shadow, directional light without camera : {}", name);
+                        return None;
+                    }
+                };
+
+                let far = settings.distance.min(camera.far);
+                let count = settings.cascades.max(1).min(MAX_CASCADES);
+                let splits = cascade_splits(camera.near, far, count, settings.split_lambda);
+
+                let mut near = camera.near;
+                let mut matrices = Vec::with_capacity(count);
+                for s in splits.iter() {
+                    let corners = frustum_corners(camera, transform, near, *s);
+                    matrices.push(directional_matrix(&light.direction, &corners, settings.resolution));
+                    near = *s;
+                }
+
+                (matrices, splits)
+            }
+        };
+
+        if self.shader.state == 1 {
+            self.shader.load_gl();
+        }
+        self.shader.utilise();
+
+        backend::with_backend(|g| {
+            g.blend(Blend::Off);
+            g.depth_test(true);
+            g.depth_write(true);
+            g.cull(Cull::Off);
+        });
+
+        let input = render::ShaderInput::new();
+        let mut fbos = Vec::with_capacity(matrices.len());
+
+        for (i, m) in matrices.iter().enumerate() {
+            let fbo_name = format!("{}_shadow_{}", name, i);
+            {
+                let mut fbo_manager = resource.fbo_manager.borrow_mut();
+                let fbo = fbo_manager.get_or_create(&fbo_name);
+                if fbo.state == 0 {
+                    fbo.cgl_create();
+                    fbo.cgl_resize(settings.resolution as i32, settings.resolution as i32);
+                }
+                fbo.cgl_use();
+            }
+            backend::with_backend(|g| g.clear());
+
+            for o in objects.iter().filter(|o| o.mr.cast_shadows) {
+                let mesh_manager = &mut *resource.mesh_manager.borrow_mut();
+                if let Some(mesh) = o.mr.mesh.get_ref(mesh_manager) {
+                    render::draw(m, &o.mat, &self.shader, mesh, &input, resource);
+                }
+            }
+
+            fbo::Fbo::cgl_use_end();
+            fbos.push(fbo_name);
+        }
+
+        Some(ShadowMap {
+            fbos : fbos,
+            matrices : matrices,
+            splits : splits,
+            settings : settings
+        })
+    }
+}
+
+/// Sends the shadow map to the uniforms the shader declares, world is the object matrix :
+/// "shadow_count" (0 when the object does not receive shadows), the arrays "shadow_matrix"
+/// (object to light clip space), "shadow_map" and "shadow_split" (compared with gl_Position.w),
+/// "shadow_bias", "shadow_pcf_radius" and "shadow_texel_size", see PCF_GLSL.
+pub fn send_shadows(
+    shader : &shader::Shader,
+    map : &ShadowMap,
+    world : &matrix::Matrix4,
+    receive : bool,
+    resource : &resource::ResourceGroup)
+{
+    let set = |name : &str, value : &UniformSend| {
+        if shader.has_uniform(name) {
+            shader.uniform_set(name, value);
+        }
+    };
+
+    let count = map.matrices.len().min(MAX_CASCADES);
+    set("shadow_count", &(if receive { count as i32 } else { 0i32 }));
+    if !receive {
+        return;
+    }
+
+    set("shadow_bias", &(map.settings.bias as f32));
+    set("shadow_pcf_radius", &(map.settings.pcf_radius as i32));
+    set("shadow_texel_size", &(1f32 / map.settings.resolution.max(1) as f32));
+
+    let mut fbo_manager = resource.fbo_manager.borrow_mut();
+    for i in 0..count {
+        set(&format!("shadow_matrix[{}]", i), &(&map.matrices[i] * world));
+        set(&format!("shadow_split[{}]", i), &(map.splits[i] as f32));
+
+        let name = format!("shadow_map[{}]", i);
+        if shader.has_uniform(&name) {
+            let fbo = fbo_manager.get_or_create(&map.fbos[i]);
+            let sampler = uniform::FboSampler {
+                fbo : &*fbo,
+                attachment : fbo::Attachment::Depth
+            };
+            shader.texture_set(&name, &sampler, SHADOW_TEXTURE_UNIT + i as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use backend::{self, Call};
+    use material;
+    use mesh;
+    use mesh_render;
+    use light::Light;
+    use super::*;
+
+    fn spot() -> WorldLight
+    {
+        let mut light = Light::spot(vec::Vec3::new(1f64, 1f64, 1f64), 1f64, 10f64, 0.4f64, 0.5f64);
+        light.shadow = Some(Default::default());
+        WorldLight {
+            light : light,
+            position : vec::Vec3::zero(),
+            direction : vec::Vec3::new(1f64, 0f64, 0f64)
+        }
+    }
+
+    fn ndc(m : &matrix::Matrix4, p : vec::Vec3) -> vec::Vec3
+    {
+        let c = m * vec::Vec4::new(p.x, p.y, p.z, 1f64);
+        vec::Vec3::new(c.x / c.w, c.y / c.w, c.z / c.w)
+    }
+
+    #[test]
+    fn test_skip_non_casters()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+        let quad = resources.mesh_manager.borrow_mut().add_resource("quad", m);
+        let white = resources.material_manager.borrow_mut().add_resource(
+            "white", material::Material::new("white"));
+
+        let object = |cast : bool| {
+            render::MatrixMeshRender::new(
+                matrix::Matrix4::translation(&vec::Vec3::new(5f64, 0f64, 0f64)),
+                mesh_render::MeshRender {
+                    mesh : quad.clone(),
+                    material : white.clone(),
+                    cast_shadows : cast,
+                    receive_shadows : true
+                })
+        };
+        let (caster, other) = (object(true), object(false));
+
+        let map = ShadowPass::new().render("spot", &spot(), None, &[&caster, &other], &resources).unwrap();
+        assert_eq!(map.fbos, vec![String::from("spot_shadow_0")]);
+        assert_eq!(map.matrices.len(), 1);
+
+        let calls = calls.borrow();
+        assert_eq!(calls.iter().filter(|c| if let Call::DrawFaces(_, _) = **c { true } else { false }).count(), 1);
+        assert!(calls.iter().any(|c| *c == Call::DepthWrite(true)));
+    }
+
+    #[test]
+    fn test_cascade_splits()
+    {
+        let uniform = cascade_splits(1f64, 100f64, 2, 0f64);
+        assert!((uniform[0] - 50.5f64).abs() < 1e-9);
+        assert!((uniform[1] - 100f64).abs() < 1e-9);
+
+        let log = cascade_splits(1f64, 100f64, 2, 1f64);
+        assert!((log[0] - 10f64).abs() < 1e-9);
+        assert!((log[1] - 100f64).abs() < 1e-9);
+
+        // the last cascade always ends at the far distance
+        let mixed = cascade_splits(0.1f64, 50f64, 4, 0.5f64);
+        assert_eq!(mixed.len(), 4);
+        assert!(mixed.windows(2).all(|w| w[0] < w[1]));
+        assert!((mixed[3] - 50f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_spot_matrix()
+    {
+        let m = spot_matrix(&spot());
+
+        // on the axis of the light, in range
+        let p = ndc(&m, vec::Vec3::new(5f64, 0f64, 0f64));
+        assert!(p.x.abs() < 1e-9 && p.y.abs() < 1e-9);
+        assert!(p.z > -1f64 && p.z < 1f64);
+
+        // after the range of the light
+        assert!(ndc(&m, vec::Vec3::new(20f64, 0f64, 0f64)).z > 1f64);
+
+        // outside of the cone
+        let side = ndc(&m, vec::Vec3::new(5f64, 5f64, 0f64));
+        assert!(side.x.abs() > 1f64 || side.y.abs() > 1f64);
+    }
+
+    #[test]
+    fn test_receive_shadows()
+    {
+        let recorder = backend::RecordingBackend::new();
+        let calls = recorder.calls.clone();
+        backend::set_backend(box recorder);
+
+        let resources = resource::ResourceGroup::new();
+        let mut s = shader::Shader::with_vert_frag(
+            String::from("receiver"),
+            String::from("attribute vec3 position;\nuniform mat4 matrix;\nuniform int shadow_count;\nuniform mat4 shadow_matrix[4];\nuniform float shadow_split[4];\n"),
+            String::from("uniform vec4 color;\n"));
+        s.load_gl();
+
+        let map = ShadowMap {
+            fbos : vec![String::from("spot_shadow_0")],
+            matrices : vec![matrix::Matrix4::identity()],
+            splits : vec![10f64],
+            settings : Default::default()
+        };
+
+        let sent = |name : &str| calls.borrow().iter().any(|c| match *c {
+            Call::UniformVec(ref n, _) | Call::UniformFloat(ref n, _) => n == name,
+            _ => false
+        });
+
+        send_shadows(&s, &map, &matrix::Matrix4::identity(), false, &resources);
+        assert!(calls.borrow().contains(&Call::UniformInt(String::from("shadow_count"), 0)));
+        assert!(!sent("shadow_matrix[0]"));
+
+        calls.borrow_mut().clear();
+        send_shadows(&s, &map, &matrix::Matrix4::identity(), true, &resources);
+        assert!(calls.borrow().contains(&Call::UniformInt(String::from("shadow_count"), 1)));
+        assert!(sent("shadow_matrix[0]"));
+        assert!(calls.borrow().contains(&Call::UniformFloat(String::from("shadow_split[0]"), 10f32)));
+    }
+}
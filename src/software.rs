@@ -0,0 +1,1137 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::path::Path;
+use std::slice;
+use libc::c_void;
+use png;
+
+use backend::{self, GraphicsBackend};
+use mesh::{CglBuffer, BufferType};
+use shader::{CglShader, CglShaderAttribute, CglShaderUniform};
+use texture::CglTexture;
+use fbo::{CglFbo, Attachment};
+use material::{Blend, Cull, PolygonOffset, RenderState};
+
+/// Shading done instead of the glsl, which cannot run on the cpu.
+/// It is chosen with a "// software : lambert" comment in the shader source,
+/// else from the inputs : a sampler2D gives UnlitTextured, a normal attribute Lambert.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadingModel
+{
+    /// uniform "color", or the "color" attribute for lines
+    UnlitColor,
+    /// first sampler multiplied by "color"
+    UnlitTextured,
+    /// color and texture lit by the uniform "light_direction", or "light_direction[0]"
+    Lambert,
+}
+
+/// RGBA8 color and depth, the first row is the top of the image.
+#[derive(Clone)]
+pub struct Frame
+{
+    pub width : usize,
+    pub height : usize,
+    pub color : Vec<u8>,
+    pub depth : Vec<f32>
+}
+
+impl Frame
+{
+    pub fn new(width : usize, height : usize) -> Frame
+    {
+        Frame {
+            width : width,
+            height : height,
+            color : vec![0u8; width * height * 4],
+            depth : vec![1f32; width * height]
+        }
+    }
+
+    pub fn resize(&mut self, width : usize, height : usize)
+    {
+        if width != self.width || height != self.height {
+            *self = Frame::new(width, height);
+        }
+    }
+
+    pub fn clear(&mut self, color : [u8; 4])
+    {
+        for p in self.color.chunks_mut(4) {
+            p.copy_from_slice(&color);
+        }
+        for d in self.depth.iter_mut() {
+            *d = 1f32;
+        }
+    }
+
+    pub fn get_pixel(&self, x : usize, y : usize) -> [u8; 4]
+    {
+        let i = (y * self.width + x) * 4;
+        [self.color[i], self.color[i+1], self.color[i+2], self.color[i+3]]
+    }
+
+    pub fn save_png(&self, path : &str) -> Result<(), String>
+    {
+        let mut img = png::Image {
+            width : self.width as u32,
+            height : self.height as u32,
+            pixels : png::PixelsByColorType::RGBA8(self.color.clone())
+        };
+
+        png::store_png(&mut img, Path::new(path))
+    }
+
+    /// None if the file cannot be read or is not rgba8.
+    pub fn load_png(path : &str) -> Option<Frame>
+    {
+        let img = match png::load_png(Path::new(path)) {
+            Ok(img) => img,
+            Err(e) => {
+                println!("frame, cannot load '{}' : {}", path, e);
+                return None;
+            }
+        };
+
+        match img.pixels {
+            png::PixelsByColorType::RGBA8(pixels) => {
+                let mut f = Frame::new(img.width as usize, img.height as usize);
+                f.color = pixels;
+                Some(f)
+            },
+            _ => {
+                println!("frame, '{}' is not rgba8", path);
+                None
+            }
+        }
+    }
+
+    /// Number of pixels which have a channel differing by more than the tolerance,
+    /// to compare a frame with a reference image.
+    pub fn difference(&self, other : &Frame, tolerance : u8) -> usize
+    {
+        if self.width != other.width || self.height != other.height {
+            return self.width * self.height;
+        }
+
+        self.color.chunks(4).zip(other.color.chunks(4)).filter(|&(a, b)| {
+            a.iter().zip(b.iter()).any(|(x, y)| (*x as i32 - *y as i32).abs() > tolerance as i32)
+        }).count()
+    }
+}
+
+enum BufferData
+{
+    F32(Vec<f32>),
+    U32(Vec<u32>)
+}
+
+enum Value
+{
+    Int(i32),
+    Floats(Vec<f32>),
+    Texture(usize),
+    Fbo(usize, Attachment)
+}
+
+struct SoftShader
+{
+    model : ShadingModel,
+    attributes : HashMap<String, usize>,
+    uniforms : HashMap<String, usize>,
+    samplers : Vec<String>
+}
+
+struct Image
+{
+    width : usize,
+    height : usize,
+    data : Vec<u8>
+}
+
+/// Values at a vertex, the position is in clip space.
+#[derive(Clone, Copy)]
+struct Vertex
+{
+    clip : [f64; 4],
+    uv : [f64; 2],
+    normal : [f64; 3],
+    color : [f64; 4]
+}
+
+/// What the shading model needs, read once per draw.
+struct ShadeInput<'a>
+{
+    model : ShadingModel,
+    color : [f64; 4],
+    light : [f64; 3],
+    texture : Option<(&'a [u8], usize, usize)>,
+    vertex_color : bool,
+    state : RenderState
+}
+
+/// Backend drawing on the cpu, the frame can be saved as a png.
+/// Only triangles and lines are drawn.
+pub struct SoftwareBackend
+{
+    /// shared so the frame can be read after the backend is given to set_backend
+    pub target : Rc<RefCell<Frame>>,
+    pub clear_color : [u8; 4],
+    next : usize,
+    buffers : HashMap<usize, BufferData>,
+    shaders : HashMap<usize, SoftShader>,
+    attribute_names : HashMap<usize, String>,
+    /// attribute name to buffer
+    bound : HashMap<String, usize>,
+    /// attribute name to buffer and floats per instance
+    instance_bound : HashMap<String, (usize, usize)>,
+    values : HashMap<usize, Value>,
+    textures : HashMap<usize, Image>,
+    fbos : HashMap<usize, Frame>,
+    current_shader : Option<usize>,
+    current_fbo : Option<usize>,
+    state : RenderState
+}
+
+fn shading_model(vert : &str, frag : &str, samplers : &[String]) -> ShadingModel
+{
+    for line in vert.lines().chain(frag.lines()) {
+        let line = line.trim();
+        if !line.starts_with("//") {
+            continue;
+        }
+        let words : Vec<&str> = line[2..].split(|c : char| c == ':' || c.is_whitespace())
+            .filter(|w| !w.is_empty()).collect();
+        if words.len() == 2 && words[0] == "software" {
+            match words[1] {
+                "unlit_color" => return ShadingModel::UnlitColor,
+                "unlit_textured" => return ShadingModel::UnlitTextured,
+                "lambert" => return ShadingModel::Lambert,
+                m => println!("software backend, unknown shading model '{}'", m)
+            }
+        }
+    }
+
+    if backend::glsl_declarations(vert, "attribute").iter().any(|a| a == "normal") {
+        ShadingModel::Lambert
+    }
+    else if !samplers.is_empty() {
+        ShadingModel::UnlitTextured
+    }
+    else {
+        ShadingModel::UnlitColor
+    }
+}
+
+fn sampler_names(source : &str) -> Vec<String>
+{
+    let mut names = Vec::new();
+    for line in source.lines().filter(|l| l.contains("sampler2D")) {
+        names.extend(backend::glsl_declarations(line, "uniform"));
+    }
+    names
+}
+
+fn lerp_vertex(a : &Vertex, b : &Vertex, t : f64) -> Vertex
+{
+    let mut v = *a;
+    for i in 0..4 {
+        v.clip[i] = a.clip[i] + (b.clip[i] - a.clip[i]) * t;
+        v.color[i] = a.color[i] + (b.color[i] - a.color[i]) * t;
+    }
+    for i in 0..3 {
+        v.normal[i] = a.normal[i] + (b.normal[i] - a.normal[i]) * t;
+    }
+    for i in 0..2 {
+        v.uv[i] = a.uv[i] + (b.uv[i] - a.uv[i]) * t;
+    }
+    v
+}
+
+/// Clips the triangle with the near plane, z > -w.
+/// Keeps the part of the polygon where dist is positive.
+fn clip_polygon<F : Fn(&Vertex) -> f64>(poly : &[Vertex], dist : F) -> Vec<Vertex>
+{
+    let mut out = Vec::with_capacity(poly.len() + 1);
+    for i in 0..poly.len() {
+        let a = &poly[i];
+        let b = &poly[(i + 1) % poly.len()];
+        let (da, db) = (dist(a), dist(b));
+
+        if da >= 0f64 {
+            out.push(*a);
+        }
+        if (da >= 0f64) != (db >= 0f64) {
+            out.push(lerp_vertex(a, b, da / (da - db)));
+        }
+    }
+    out
+}
+
+/// Clips the triangle with w > 0 and with the near plane, so the vertices
+/// can be divided by w.
+fn clip_near(tri : &[Vertex; 3]) -> Vec<Vertex>
+{
+    let eps = 1e-6f64;
+    let front = clip_polygon(tri, |v : &Vertex| v.clip[3] - eps);
+    clip_polygon(&front, |v : &Vertex| v.clip[2] + v.clip[3] - eps)
+}
+
+fn sample(texture : &(&[u8], usize, usize), uv : &[f64; 2]) -> [f64; 4]
+{
+    let &(data, w, h) = texture;
+    if w == 0 || h == 0 {
+        return [1f64; 4];
+    }
+
+    let wrap = |v : f64, size : usize| {
+        let i = (v - v.floor()) * size as f64;
+        (i as usize).min(size - 1)
+    };
+
+    let i = (wrap(uv[1], h) * w + wrap(uv[0], w)) * 4;
+    [data[i] as f64 / 255f64,
+     data[i+1] as f64 / 255f64,
+     data[i+2] as f64 / 255f64,
+     data[i+3] as f64 / 255f64]
+}
+
+fn shade(input : &ShadeInput, v : &Vertex) -> [f64; 4]
+{
+    let mut c = if input.vertex_color { v.color } else { input.color };
+
+    if input.model != ShadingModel::UnlitColor {
+        if let Some(ref t) = input.texture {
+            let s = sample(t, &v.uv);
+            for i in 0..4 {
+                c[i] = c[i] * s[i];
+            }
+        }
+    }
+
+    if input.model == ShadingModel::Lambert {
+        let n = v.normal;
+        let l = (n[0]*n[0] + n[1]*n[1] + n[2]*n[2]).sqrt();
+        let diffuse = if l > 0f64 {
+            (-(n[0]*input.light[0] + n[1]*input.light[1] + n[2]*input.light[2]) / l).max(0f64)
+        }
+        else {
+            0f64
+        };
+        let k = 0.2f64 + 0.8f64 * diffuse;
+        for i in 0..3 {
+            c[i] = c[i] * k;
+        }
+    }
+
+    c
+}
+
+/// Product of two column major matrices.
+fn mat_mul(a : &[f32], b : &[f32]) -> Vec<f32>
+{
+    let mut m = vec![0f32; 16];
+    for c in 0..4 {
+        for r in 0..4 {
+            let mut v = 0f32;
+            for k in 0..4 {
+                v = v + a[k*4 + r] * b[c*4 + k];
+            }
+            m[c*4 + r] = v;
+        }
+    }
+    m
+}
+
+fn write_pixel(frame : &mut Frame, x : usize, y : usize, c : &[f64; 4], blend : Blend)
+{
+    let i = (y * frame.width + x) * 4;
+    let a = c[3].max(0f64).min(1f64);
+    for k in 0..4 {
+        let dst = frame.color[i+k] as f64 / 255f64;
+        let src = c[k].max(0f64).min(1f64);
+        let v = match blend {
+            Blend::Off => src,
+            Blend::Alpha => src * a + dst * (1f64 - a),
+            Blend::Additive => src * a + dst,
+            Blend::Multiply => src * dst,
+            Blend::Premultiplied => src + dst * (1f64 - a)
+        };
+        frame.color[i+k] = (v.max(0f64).min(1f64) * 255f64 + 0.5f64) as u8;
+    }
+}
+
+/// Screen position, depth from 0 to 1 and 1/w.
+fn to_screen(frame : &Frame, v : &Vertex) -> (f64, f64, f64, f64)
+{
+    let iw = 1f64 / v.clip[3];
+    let x = (v.clip[0] * iw * 0.5f64 + 0.5f64) * frame.width as f64;
+    let y = (0.5f64 - v.clip[1] * iw * 0.5f64) * frame.height as f64;
+    let z = v.clip[2] * iw * 0.5f64 + 0.5f64;
+    (x, y, z, iw)
+}
+
+/// Smallest depth difference, used by the polygon offset units.
+const DEPTH_UNIT : f64 = 1f64 / 16777216f64;
+
+/// The triangle must have been clipped with clip_near.
+fn raster_triangle(frame : &mut Frame, input : &ShadeInput, tri : &[Vertex; 3])
+{
+    if tri.iter().any(|v| v.clip[3] <= 0f64) {
+        return;
+    }
+
+    let s = [to_screen(frame, &tri[0]), to_screen(frame, &tri[1]), to_screen(frame, &tri[2])];
+    if s.iter().any(|p| !p.0.is_finite() || !p.1.is_finite() || !p.2.is_finite()) {
+        return;
+    }
+
+    let edge = |a : &(f64, f64, f64, f64), b : &(f64, f64, f64, f64), x : f64, y : f64| {
+        (b.0 - a.0) * (y - a.1) - (b.1 - a.1) * (x - a.0)
+    };
+
+    let area = edge(&s[0], &s[1], s[2].0, s[2].1);
+    if area.abs() < 1e-12f64 {
+        return;
+    }
+
+    // the y of the screen goes down, front faces have a negative area
+    match input.state.cull {
+        Cull::Back if area > 0f64 => return,
+        Cull::Front if area < 0f64 => return,
+        _ => {}
+    }
+
+    let offset = match input.state.polygon_offset {
+        Some(o) => {
+            let dzdx = -(s[0].2 * (s[2].1 - s[1].1) + s[1].2 * (s[0].1 - s[2].1) + s[2].2 * (s[1].1 - s[0].1)) / area;
+            let dzdy = (s[0].2 * (s[2].0 - s[1].0) + s[1].2 * (s[0].0 - s[2].0) + s[2].2 * (s[1].0 - s[0].0)) / area;
+            o.factor * dzdx.abs().max(dzdy.abs()) + o.units * DEPTH_UNIT
+        },
+        None => 0f64
+    };
+
+    let minx = s.iter().fold(::std::f64::INFINITY, |m, p| m.min(p.0)).floor().max(0f64) as usize;
+    let miny = s.iter().fold(::std::f64::INFINITY, |m, p| m.min(p.1)).floor().max(0f64) as usize;
+    let maxx = s.iter().fold(::std::f64::NEG_INFINITY, |m, p| m.max(p.0)).ceil()
+        .min(frame.width as f64).max(0f64) as usize;
+    let maxy = s.iter().fold(::std::f64::NEG_INFINITY, |m, p| m.max(p.1)).ceil()
+        .min(frame.height as f64).max(0f64) as usize;
+
+    for y in miny..maxy {
+        for x in minx..maxx {
+            let px = x as f64 + 0.5f64;
+            let py = y as f64 + 0.5f64;
+
+            let l0 = edge(&s[1], &s[2], px, py) / area;
+            let l1 = edge(&s[2], &s[0], px, py) / area;
+            let l2 = edge(&s[0], &s[1], px, py) / area;
+            if l0 < 0f64 || l1 < 0f64 || l2 < 0f64 {
+                continue;
+            }
+
+            let z = l0 * s[0].2 + l1 * s[1].2 + l2 * s[2].2 + offset;
+            let di = y * frame.width + x;
+            if z < 0f64 || z > 1f64 || (input.state.depth_test && z >= frame.depth[di] as f64) {
+                continue;
+            }
+
+            // perspective correct interpolation
+            let w0 = l0 * s[0].3;
+            let w1 = l1 * s[1].3;
+            let w2 = l2 * s[2].3;
+            let iw = w0 + w1 + w2;
+            let (w0, w1, w2) = (w0 / iw, w1 / iw, w2 / iw);
+
+            let mut v = tri[0];
+            for i in 0..4 {
+                v.color[i] = tri[0].color[i] * w0 + tri[1].color[i] * w1 + tri[2].color[i] * w2;
+            }
+            for i in 0..3 {
+                v.normal[i] = tri[0].normal[i] * w0 + tri[1].normal[i] * w1 + tri[2].normal[i] * w2;
+            }
+            for i in 0..2 {
+                v.uv[i] = tri[0].uv[i] * w0 + tri[1].uv[i] * w1 + tri[2].uv[i] * w2;
+            }
+
+            if input.state.depth_write {
+                frame.depth[di] = z as f32;
+            }
+            write_pixel(frame, x, y, &shade(input, &v), input.state.blend);
+        }
+    }
+}
+
+fn raster_line(frame : &mut Frame, input : &ShadeInput, a : &Vertex, b : &Vertex)
+{
+    if a.clip[3] <= 0f64 || b.clip[3] <= 0f64 {
+        return;
+    }
+
+    let sa = to_screen(frame, a);
+    let sb = to_screen(frame, b);
+    let steps = (sb.0 - sa.0).abs().max((sb.1 - sa.1).abs()).ceil().max(1f64) as usize;
+
+    for i in 0..(steps + 1) {
+        let t = i as f64 / steps as f64;
+        let x = sa.0 + (sb.0 - sa.0) * t;
+        let y = sa.1 + (sb.1 - sa.1) * t;
+        if x < 0f64 || y < 0f64 || x >= frame.width as f64 || y >= frame.height as f64 {
+            continue;
+        }
+
+        let (x, y) = (x as usize, y as usize);
+        let z = sa.2 + (sb.2 - sa.2) * t;
+        let di = y * frame.width + x;
+        if z < 0f64 || z > 1f64 || (input.state.depth_test && z > frame.depth[di] as f64) {
+            continue;
+        }
+
+        if input.state.depth_write {
+            frame.depth[di] = z as f32;
+        }
+        write_pixel(frame, x, y, &shade(input, &lerp_vertex(a, b, t)), input.state.blend);
+    }
+}
+
+impl SoftwareBackend
+{
+    pub fn new(width : usize, height : usize) -> SoftwareBackend
+    {
+        SoftwareBackend {
+            target : Rc::new(RefCell::new(Frame::new(width, height))),
+            clear_color : [0u8, 0u8, 0u8, 255u8],
+            next : 0,
+            buffers : HashMap::new(),
+            shaders : HashMap::new(),
+            attribute_names : HashMap::new(),
+            bound : HashMap::new(),
+            instance_bound : HashMap::new(),
+            values : HashMap::new(),
+            textures : HashMap::new(),
+            fbos : HashMap::new(),
+            current_shader : None,
+            current_fbo : None,
+            state : Default::default()
+        }
+    }
+
+    fn new_handle(&mut self) -> usize
+    {
+        self.next = self.next + 1;
+        self.next
+    }
+
+    fn uniform_floats(&self, shader : &SoftShader, name : &str) -> Option<&[f32]>
+    {
+        match shader.uniforms.get(name).and_then(|h| self.values.get(h)) {
+            Some(&Value::Floats(ref f)) => Some(f),
+            _ => None
+        }
+    }
+
+    fn shade_input(&self, lines : bool) -> Option<ShadeInput>
+    {
+        let shader = match self.current_shader.and_then(|s| self.shaders.get(&s)) {
+            Some(s) => s,
+            None => return None
+        };
+
+        let color = match self.uniform_floats(shader, "color") {
+            Some(c) if c.len() >= 4 => [c[0] as f64, c[1] as f64, c[2] as f64, c[3] as f64],
+            Some(c) if c.len() == 3 => [c[0] as f64, c[1] as f64, c[2] as f64, 1f64],
+            _ => [1f64; 4]
+        };
+
+        // the first light of light::send_lights when there is no "light_direction"
+        let direction = self.uniform_floats(shader, "light_direction")
+            .or_else(|| self.uniform_floats(shader, "light_direction[0]"));
+        let light = match direction {
+            Some(l) if l.len() >= 3 => [l[0] as f64, l[1] as f64, l[2] as f64],
+            _ => [0f64, 0f64, -1f64]
+        };
+
+        let mut texture = None;
+        for s in shader.samplers.iter() {
+            let image = match shader.uniforms.get(s).and_then(|h| self.values.get(h)) {
+                Some(&Value::Texture(t)) => {
+                    self.textures.get(&t).map(|i| (&i.data[..], i.width, i.height))
+                },
+                Some(&Value::Fbo(f, _)) => {
+                    self.fbos.get(&f).map(|i| (&i.color[..], i.width, i.height))
+                },
+                _ => None
+            };
+            if image.is_some() {
+                texture = image;
+                break;
+            }
+        }
+
+        Some(ShadeInput {
+            model : shader.model,
+            color : color,
+            light : light,
+            texture : texture,
+            vertex_color : lines && self.bound.contains_key("color"),
+            state : self.state
+        })
+    }
+
+    fn attribute(&self, name : &str) -> Option<&[f32]>
+    {
+        match self.bound.get(name).and_then(|b| self.buffers.get(b)) {
+            Some(&BufferData::F32(ref d)) => Some(d),
+            _ => None
+        }
+    }
+
+    /// Vertex from the bound attributes, None if there is no position.
+    fn vertex(&self, matrix : &[f32], index : usize) -> Option<Vertex>
+    {
+        let get = |name : &str, size : usize, default : f64| -> Vec<f64> {
+            match self.attribute(name) {
+                Some(d) if d.len() >= (index + 1) * size => {
+                    d[index*size..(index+1)*size].iter().map(|f| *f as f64).collect()
+                },
+                _ => vec![default; size]
+            }
+        };
+
+        let p = match self.attribute("position") {
+            Some(d) if d.len() >= (index + 1) * 3 => {
+                [d[index*3] as f64, d[index*3+1] as f64, d[index*3+2] as f64]
+            },
+            _ => return None
+        };
+
+        // column major, as sent to gl
+        let m = |i : usize| matrix[i] as f64;
+        let mut clip = [0f64; 4];
+        for r in 0..4 {
+            clip[r] = m(r) * p[0] + m(4 + r) * p[1] + m(8 + r) * p[2] + m(12 + r);
+        }
+
+        let uv = get("texcoord", 2, 0f64);
+        let normal = get("normal", 3, 0f64);
+        let color = get("color", 4, 1f64);
+
+        Some(Vertex {
+            clip : clip,
+            uv : [uv[0], uv[1]],
+            normal : [normal[0], normal[1], normal[2]],
+            color : [color[0], color[1], color[2], color[3]]
+        })
+    }
+
+    fn matrix(&self) -> Option<Vec<f32>>
+    {
+        let shader = match self.current_shader.and_then(|s| self.shaders.get(&s)) {
+            Some(s) => s,
+            None => return None
+        };
+
+        match self.uniform_floats(shader, "matrix") {
+            Some(m) if m.len() == 16 => Some(m.to_vec()),
+            _ => None
+        }
+    }
+
+    /// Calls f with the frame of the fbo in use, or the target.
+    fn draw_to_target<F : FnOnce(&SoftwareBackend, &mut Frame)>(&mut self, f : F)
+    {
+        match self.current_fbo {
+            Some(h) => {
+                let mut frame = match self.fbos.remove(&h) {
+                    Some(frame) => frame,
+                    None => return
+                };
+                f(self, &mut frame);
+                self.fbos.insert(h, frame);
+            },
+            None => {
+                let target = self.target.clone();
+                let mut frame = target.borrow_mut();
+                f(self, &mut *frame);
+            }
+        }
+    }
+
+    /// Values of an instance attribute for one instance.
+    fn instance_attribute(&self, name : &str, instance : usize) -> Option<&[f32]>
+    {
+        let (buffer, size) = match self.instance_bound.get(name) {
+            Some(b) => *b,
+            None => return None
+        };
+
+        match self.buffers.get(&buffer) {
+            Some(&BufferData::F32(ref d)) if size > 0 && d.len() >= (instance + 1) * size => {
+                Some(&d[instance*size..(instance+1)*size])
+            },
+            _ => None
+        }
+    }
+
+    fn draw_triangles(&mut self, indices : Vec<usize>)
+    {
+        let matrix = match self.matrix() {
+            Some(m) => m,
+            None => {
+                println!("software backend, no matrix uniform, not drawing");
+                return;
+            }
+        };
+
+        self.draw_instances(indices, vec![(matrix, None)]);
+    }
+
+    /// Draws the triangles once for each matrix, the color replaces the uniform one.
+    fn draw_instances(&mut self, indices : Vec<usize>, instances : Vec<(Vec<f32>, Option<[f64; 4]>)>)
+    {
+        self.draw_to_target(|s, frame| {
+            let mut input = match s.shade_input(false) {
+                Some(i) => i,
+                None => return
+            };
+
+            for &(ref matrix, color) in instances.iter() {
+                if let Some(c) = color {
+                    input.color = c;
+                }
+
+                for t in indices.chunks(3) {
+                    if t.len() < 3 {
+                        break;
+                    }
+                    let tri = match (s.vertex(matrix, t[0]), s.vertex(matrix, t[1]), s.vertex(matrix, t[2])) {
+                        (Some(a), Some(b), Some(c)) => [a, b, c],
+                        _ => continue
+                    };
+
+                    let poly = clip_near(&tri);
+                    if input.state.wireframe {
+                        for i in 0..poly.len() {
+                            raster_line(frame, &input, &poly[i], &poly[(i + 1) % poly.len()]);
+                        }
+                        continue;
+                    }
+
+                    for i in 1..poly.len().saturating_sub(1) {
+                        raster_triangle(frame, &input, &[poly[0], poly[i], poly[i+1]]);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl GraphicsBackend for SoftwareBackend
+{
+    fn buffer_init(&mut self, buffer_type : BufferType, data : *const c_void, count : usize)
+        -> *const CglBuffer
+    {
+        let h = self.new_handle();
+        let data = match buffer_type {
+            BufferType::Index => {
+                BufferData::U32(unsafe { slice::from_raw_parts(data as *const u32, count) }.to_vec())
+            },
+            _ => BufferData::F32(unsafe { slice::from_raw_parts(data as *const f32, count) }.to_vec())
+        };
+        self.buffers.insert(h, data);
+        h as *const CglBuffer
+    }
+
+    fn buffer_update(&mut self, buffer : *const CglBuffer, data : *const c_void, count : usize)
+    {
+        if let Some(b) = self.buffers.get_mut(&(buffer as usize)) {
+            match *b {
+                BufferData::U32(ref mut d) => {
+                    *d = unsafe { slice::from_raw_parts(data as *const u32, count) }.to_vec();
+                },
+                BufferData::F32(ref mut d) => {
+                    *d = unsafe { slice::from_raw_parts(data as *const f32, count) }.to_vec();
+                }
+            }
+        }
+    }
+
+    fn attribute_send(&mut self, att : *const CglShaderAttribute, buffer : *const CglBuffer)
+    {
+        if let Some(n) = self.attribute_names.get(&(att as usize)) {
+            self.bound.insert(n.clone(), buffer as usize);
+        }
+    }
+
+    fn shader_init(&mut self, vert : &str, frag : &str) -> *const CglShader
+    {
+        let h = self.new_handle();
+
+        let mut samplers = sampler_names(vert);
+        samplers.extend(sampler_names(frag));
+
+        let mut uniforms = HashMap::new();
+        let mut names = backend::glsl_declarations(vert, "uniform");
+        names.extend(backend::glsl_declarations(frag, "uniform"));
+        for n in names {
+            if !uniforms.contains_key(&n) {
+                let u = self.new_handle();
+                uniforms.insert(n, u);
+            }
+        }
+
+        let mut attributes = HashMap::new();
+        for n in backend::glsl_declarations(vert, "attribute") {
+            let a = self.new_handle();
+            self.attribute_names.insert(a, n.clone());
+            attributes.insert(n, a);
+        }
+
+        self.shaders.insert(h, SoftShader {
+            model : shading_model(vert, frag, &samplers),
+            attributes : attributes,
+            uniforms : uniforms,
+            samplers : samplers
+        });
+
+        h as *const CglShader
+    }
+
+    fn shader_use(&mut self, shader : *const CglShader)
+    {
+        self.current_shader = Some(shader as usize);
+    }
+
+    fn shader_attributes(&mut self, shader : *const CglShader)
+        -> HashMap<String, *const CglShaderAttribute>
+    {
+        match self.shaders.get(&(shader as usize)) {
+            Some(s) => s.attributes.iter().map(|(n, a)| (n.clone(), *a as *const CglShaderAttribute)).collect(),
+            None => HashMap::new()
+        }
+    }
+
+    fn shader_uniforms(&mut self, shader : *const CglShader)
+        -> HashMap<String, *const CglShaderUniform>
+    {
+        match self.shaders.get(&(shader as usize)) {
+            Some(s) => s.uniforms.iter().map(|(n, u)| (n.clone(), *u as *const CglShaderUniform)).collect(),
+            None => HashMap::new()
+        }
+    }
+
+    fn uniform_int(&mut self, uni : *const CglShaderUniform, value : i32)
+    {
+        self.values.insert(uni as usize, Value::Int(value));
+    }
+
+    fn uniform_float(&mut self, uni : *const CglShaderUniform, value : f32)
+    {
+        self.values.insert(uni as usize, Value::Floats(vec![value]));
+    }
+
+    fn uniform_vec2(&mut self, uni : *const CglShaderUniform, x : f32, y : f32)
+    {
+        self.values.insert(uni as usize, Value::Floats(vec![x, y]));
+    }
+
+    fn uniform_vec3(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32)
+    {
+        self.values.insert(uni as usize, Value::Floats(vec![x, y, z]));
+    }
+
+    fn uniform_vec4(&mut self, uni : *const CglShaderUniform, x : f32, y : f32, z : f32, w : f32)
+    {
+        self.values.insert(uni as usize, Value::Floats(vec![x, y, z, w]));
+    }
+
+    fn uniform_mat4(&mut self, uni : *const CglShaderUniform, m : &[f32; 16])
+    {
+        self.values.insert(uni as usize, Value::Floats(m.to_vec()));
+    }
+
+    fn uniform_texture(&mut self, uni : *const CglShaderUniform, tex : *const CglTexture, index : u32)
+    {
+        self.values.insert(uni as usize, Value::Texture(tex as usize));
+    }
+
+    fn uniform_fbo(
+        &mut self,
+        uni : *const CglShaderUniform,
+        fbo : *const CglFbo,
+        attachment : Attachment,
+        index : u32)
+    {
+        self.values.insert(uni as usize, Value::Fbo(fbo as usize, attachment));
+    }
+
+    fn texture_init(&mut self, data : &[u8], width : u32, height : u32) -> *const CglTexture
+    {
+        let h = self.new_handle();
+        self.textures.insert(h, Image {
+            width : width as usize,
+            height : height as usize,
+            data : data.to_vec()
+        });
+        h as *const CglTexture
+    }
+
+    fn fbo_create(&mut self) -> *const CglFbo
+    {
+        let h = self.new_handle();
+        let (width, height) = {
+            let t = self.target.borrow();
+            (t.width, t.height)
+        };
+        self.fbos.insert(h, Frame::new(width, height));
+        h as *const CglFbo
+    }
+
+    fn fbo_use(&mut self, fbo : *const CglFbo)
+    {
+        self.current_fbo = Some(fbo as usize);
+    }
+
+    fn fbo_use_end(&mut self)
+    {
+        self.current_fbo = None;
+    }
+
+    fn fbo_resize(&mut self, fbo : *const CglFbo, w : i32, h : i32)
+    {
+        if let Some(f) = self.fbos.get_mut(&(fbo as usize)) {
+            f.resize(w.max(0) as usize, h.max(0) as usize);
+        }
+    }
+
+    fn fbo_destroy(&mut self, fbo : *const CglFbo)
+    {
+        self.fbos.remove(&(fbo as usize));
+    }
+
+    /// The renderer gives the size of the position buffer, three floats per vertex.
+    fn draw(&mut self, vertex_count : usize)
+    {
+        let indices = (0..vertex_count / 3).collect();
+        self.draw_triangles(indices);
+    }
+
+    fn draw_lines(&mut self, vertex_count : usize)
+    {
+        let matrix = match self.matrix() {
+            Some(m) => m,
+            None => return
+        };
+
+        self.draw_to_target(|s, frame| {
+            let input = match s.shade_input(true) {
+                Some(i) => i,
+                None => return
+            };
+
+            for i in 0..vertex_count / 2 {
+                if let (Some(a), Some(b)) = (s.vertex(&matrix, i*2), s.vertex(&matrix, i*2 + 1)) {
+                    raster_line(frame, &input, &a, &b);
+                }
+            }
+        });
+    }
+
+    fn draw_faces(&mut self, buffer : *const CglBuffer, index_count : usize)
+    {
+        let indices = match self.buffers.get(&(buffer as usize)) {
+            Some(&BufferData::U32(ref d)) => {
+                d.iter().take(index_count).map(|i| *i as usize).collect()
+            },
+            _ => return
+        };
+
+        self.draw_triangles(indices);
+    }
+
+    fn draw_end(&mut self)
+    {
+    }
+
+    fn clear(&mut self)
+    {
+        let color = self.clear_color;
+        self.draw_to_target(|_, frame| frame.clear(color));
+    }
+
+    fn supports_render_state(&self) -> bool
+    {
+        true
+    }
+
+    fn blend(&mut self, blend : Blend)
+    {
+        self.state.blend = blend;
+    }
+
+    fn depth_write(&mut self, enabled : bool)
+    {
+        self.state.depth_write = enabled;
+    }
+
+    fn depth_test(&mut self, enabled : bool)
+    {
+        self.state.depth_test = enabled;
+    }
+
+    fn cull(&mut self, cull : Cull)
+    {
+        self.state.cull = cull;
+    }
+
+    fn polygon_offset(&mut self, offset : Option<PolygonOffset>)
+    {
+        self.state.polygon_offset = offset;
+    }
+
+    fn wireframe(&mut self, enabled : bool)
+    {
+        self.state.wireframe = enabled;
+    }
+
+    fn supports_instancing(&self) -> bool
+    {
+        true
+    }
+
+    fn instance_attribute_send(
+        &mut self,
+        att : *const CglShaderAttribute,
+        buffer : *const CglBuffer,
+        components : usize)
+    {
+        if let Some(n) = self.attribute_names.get(&(att as usize)) {
+            self.instance_bound.insert(n.clone(), (buffer as usize, components));
+        }
+    }
+
+    /// "matrix" is the camera matrix and "instance_matrix" the world matrix of each instance,
+    /// "instance_color" replaces the uniform color.
+    fn draw_faces_instanced(
+        &mut self,
+        buffer : *const CglBuffer,
+        index_count : usize,
+        instance_count : usize)
+    {
+        let indices = match self.buffers.get(&(buffer as usize)) {
+            Some(&BufferData::U32(ref d)) => {
+                d.iter().take(index_count).map(|i| *i as usize).collect()
+            },
+            _ => return
+        };
+
+        let camera = match self.matrix() {
+            Some(m) => m,
+            None => {
+                println!("software backend, no matrix uniform, not drawing");
+                return;
+            }
+        };
+
+        let mut instances = Vec::with_capacity(instance_count);
+        for i in 0..instance_count {
+            let world = match self.instance_attribute("instance_matrix", i) {
+                Some(m) if m.len() == 16 => mat_mul(&camera, m),
+                _ => {
+                    println!("software backend, no instance_matrix for instance {}", i);
+                    return;
+                }
+            };
+
+            let color = match self.instance_attribute("instance_color", i) {
+                Some(c) if c.len() == 4 => Some([c[0] as f64, c[1] as f64, c[2] as f64, c[3] as f64]),
+                _ => None
+            };
+
+            instances.push((world, color));
+        }
+
+        self.draw_instances(indices, instances);
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::collections::HashMap;
+    use backend;
+    use matrix;
+    use mesh;
+    use render;
+    use resource;
+    use shader;
+    use vec;
+    use super::*;
+
+    #[test]
+    fn test_software_quad()
+    {
+        let soft = SoftwareBackend::new(16, 16);
+        let frame = soft.target.clone();
+        backend::set_backend(box soft);
+        backend::with_backend(|b| b.clear());
+
+        let mut s = shader::Shader::with_vert_frag(
+            String::from("test"),
+            String::from("attribute vec3 position;\nuniform mat4 matrix;\n"),
+            String::from("uniform vec4 color;\n"));
+        s.load_gl();
+        s.utilise();
+
+        let mut m = mesh::Mesh::new();
+        m.add_quad(1f32, 1f32);
+
+        let mut input = render::ShaderInput::new();
+        input.uniforms.insert(
+            String::from("color"),
+            box shader::UniformData::Vec4(vec::Vec4::new(1f64, 0f64, 0f64, 1f64)));
+
+        let resource = resource::ResourceGroup::new();
+        let identity = matrix::Matrix4::identity();
+        render::draw(&identity, &identity, &s, &m, &input, &resource);
+
+        let frame = frame.borrow();
+        assert_eq!(frame.get_pixel(8, 8), [255u8, 0u8, 0u8, 255u8]);
+        assert_eq!(frame.get_pixel(0, 0), [0u8, 0u8, 0u8, 255u8]);
+    }
+
+    #[test]
+    fn test_clip_behind_camera()
+    {
+        let v = |x : f64, y : f64, z : f64, w : f64| Vertex {
+            clip : [x, y, z, w],
+            uv : [0f64; 2],
+            normal : [0f64; 3],
+            color : [1f64; 4]
+        };
+
+        // the last vertex is behind the camera but not behind the near plane
+        let tri = [v(-0.5f64, -0.5f64, 0f64, 1f64), v(0.5f64, -0.5f64, 0f64, 1f64), v(0f64, 0.5f64, 1f64, -0.5f64)];
+        let poly = clip_near(&tri);
+        assert_eq!(poly.len(), 4);
+        assert!(poly.iter().all(|p| p.clip[3] > 0f64 && p.clip[2] + p.clip[3] >= 0f64));
+
+        let input = ShadeInput {
+            model : ShadingModel::UnlitColor,
+            color : [1f64; 4],
+            light : [0f64; 3],
+            texture : None,
+            vertex_color : false,
+            state : Default::default()
+        };
+
+        let mut frame = Frame::new(16, 16);
+        frame.clear([0u8, 0u8, 0u8, 255u8]);
+        for i in 1..(poly.len() - 1) {
+            raster_triangle(&mut frame, &input, &[poly[0], poly[i], poly[i+1]]);
+        }
+        assert_eq!(frame.get_pixel(8, 11), [255u8; 4]);
+        assert_eq!(frame.get_pixel(8, 12), [0u8, 0u8, 0u8, 255u8]);
+    }
+}
@@ -5,21 +5,14 @@ use std::path::Path;
 use std::cell::Cell;
 use std::fmt;
 
+use backend;
+
 #[repr(C)]
 pub struct CglTexture;
 
 unsafe impl Send for CglTexture {}
 unsafe impl Sync for CglTexture {}
 
-#[link(name = "cypher")]
-extern {
-    pub fn cgl_texture_init(
-        data : *const c_void,
-        internal_format : c_uint,
-        width : c_uint,
-        height : c_uint
-        ) -> *const CglTexture;
-}
 
 pub struct Texture
 {
@@ -46,6 +39,24 @@ impl Texture
         t
     }
 
+    /// Texture made in memory instead of read from a png file,
+    /// pixels are rgba8 rows.
+    pub fn with_rgba(name : &str, width : u32, height : u32, pixels : Vec<u8>) -> Texture
+    {
+        let t = Texture{
+            name: String::from(name),
+            state : Cell::new(1),
+            image : Some(png::Image {
+                width : width,
+                height : height,
+                pixels : png::PixelsByColorType::RGBA8(pixels)
+            }),
+            cgl_texture : Cell::new(None)
+        };
+
+        t
+    }
+
     pub fn load(&mut self)
     {
         if self.state.get() != 0 {
@@ -83,7 +94,7 @@ impl Texture
                          for i in 0usize..8 { 
                              println!("{}, RGBA{} : {}", self.name, i, pixels[i]);
                          }
-                         pixels.as_ptr()
+                         pixels
                      },
                      //png::K8(ref pixels) => pixels.as_ptr(),
                      //png::KA8(ref pixels) => pixels.as_ptr(),
@@ -91,18 +102,11 @@ impl Texture
                  };
                  //*/
 
-                unsafe {
-
-                    let cgltex =  cgl_texture_init(
-                        //mem::transmute(img.pixels.as_ptr()),
-                        mem::transmute(data),
-                        4,
-                        img.width as c_uint,
-                        img.height as c_uint
-                        );
+                let cgltex = backend::with_backend(|b| {
+                    b.texture_init(data, img.width as u32, img.height as u32)
+                });
 
-                    self.cgl_texture.set(Some(cgltex));
-                }
+                self.cgl_texture.set(Some(cgltex));
                 self.state.set(2);
             }
         }
@@ -0,0 +1,719 @@
+use std::any::Any;
+use std::f64::consts;
+
+use property::{PropertyWrite, WriteValue};
+use shader::UniformData;
+use transform;
+use vec;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EaseFunction
+{
+    Quad,
+    Cubic,
+    Quart,
+    Quint,
+    Sine,
+    Expo,
+    Circ,
+    /// oscillates around the end value
+    Elastic,
+    /// goes a bit past the end value
+    Back,
+    Bounce,
+}
+
+/// Out and InOut are computed from the In version of the function.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Ease
+{
+    Linear,
+    In(EaseFunction),
+    Out(EaseFunction),
+    InOut(EaseFunction),
+}
+
+impl EaseFunction
+{
+    fn ease_in(&self, t : f64) -> f64
+    {
+        match *self {
+            EaseFunction::Quad => t * t,
+            EaseFunction::Cubic => t * t * t,
+            EaseFunction::Quart => t * t * t * t,
+            EaseFunction::Quint => t * t * t * t * t,
+            EaseFunction::Sine => 1f64 - (t * consts::PI / 2f64).cos(),
+            EaseFunction::Expo => {
+                if t <= 0f64 { 0f64 } else { 2f64.powf(10f64 * t - 10f64) }
+            },
+            EaseFunction::Circ => 1f64 - (1f64 - t * t).max(0f64).sqrt(),
+            EaseFunction::Elastic => {
+                if t <= 0f64 || t >= 1f64 {
+                    t
+                }
+                else {
+                    let c = 2f64 * consts::PI / 3f64;
+                    -2f64.powf(10f64 * t - 10f64) * ((10f64 * t - 10.75f64) * c).sin()
+                }
+            },
+            EaseFunction::Back => {
+                let c1 = 1.70158f64;
+                (c1 + 1f64) * t * t * t - c1 * t * t
+            },
+            EaseFunction::Bounce => 1f64 - bounce_out(1f64 - t)
+        }
+    }
+}
+
+fn bounce_out(t : f64) -> f64
+{
+    let n = 7.5625f64;
+    let d = 2.75f64;
+
+    if t < 1f64 / d {
+        n * t * t
+    }
+    else if t < 2f64 / d {
+        let t = t - 1.5f64 / d;
+        n * t * t + 0.75f64
+    }
+    else if t < 2.5f64 / d {
+        let t = t - 2.25f64 / d;
+        n * t * t + 0.9375f64
+    }
+    else {
+        let t = t - 2.625f64 / d;
+        n * t * t + 0.984375f64
+    }
+}
+
+impl Ease
+{
+    /// t goes from 0 to 1, the result is 0 at the start and 1 at the end.
+    pub fn apply(&self, t : f64) -> f64
+    {
+        let t = t.max(0f64).min(1f64);
+
+        match *self {
+            Ease::Linear => t,
+            Ease::In(ref f) => f.ease_in(t),
+            Ease::Out(ref f) => 1f64 - f.ease_in(1f64 - t),
+            Ease::InOut(ref f) => {
+                if t < 0.5f64 {
+                    f.ease_in(2f64 * t) / 2f64
+                }
+                else {
+                    1f64 - f.ease_in(2f64 - 2f64 * t) / 2f64
+                }
+            }
+        }
+    }
+}
+
+/// Values which can be interpolated by a tween, t can go outside of 0 and 1
+/// with elastic and back easing.
+pub trait Tweenable : Clone + Any
+{
+    fn interpolate(&self, to : &Self, t : f64) -> Self;
+}
+
+fn lerp(a : f64, b : f64, t : f64) -> f64
+{
+    a + (b - a) * t
+}
+
+impl Tweenable for f64
+{
+    fn interpolate(&self, to : &f64, t : f64) -> f64
+    {
+        lerp(*self, *to, t)
+    }
+}
+
+impl Tweenable for vec::Vec2
+{
+    fn interpolate(&self, to : &vec::Vec2, t : f64) -> vec::Vec2
+    {
+        vec::Vec2::new(lerp(self.x, to.x, t), lerp(self.y, to.y, t))
+    }
+}
+
+impl Tweenable for vec::Vec3
+{
+    fn interpolate(&self, to : &vec::Vec3, t : f64) -> vec::Vec3
+    {
+        *self + (*to - *self) * t
+    }
+}
+
+impl Tweenable for vec::Vec4
+{
+    fn interpolate(&self, to : &vec::Vec4, t : f64) -> vec::Vec4
+    {
+        vec::Vec4::new(
+            lerp(self.x, to.x, t),
+            lerp(self.y, to.y, t),
+            lerp(self.z, to.z, t),
+            lerp(self.w, to.w, t))
+    }
+}
+
+impl Tweenable for vec::Quat
+{
+    fn interpolate(&self, to : &vec::Quat, t : f64) -> vec::Quat
+    {
+        vec::quat_slerp(*self, *to, t)
+    }
+}
+
+impl Tweenable for UniformData
+{
+    /// Uniforms of different types do not interpolate, the value jumps at the end.
+    fn interpolate(&self, to : &UniformData, t : f64) -> UniformData
+    {
+        match (self, to) {
+            (&UniformData::Int(a), &UniformData::Int(b)) => {
+                UniformData::Int(lerp(a as f64, b as f64, t).round() as i32)
+            },
+            (&UniformData::Float(a), &UniformData::Float(b)) => {
+                UniformData::Float(lerp(a as f64, b as f64, t) as f32)
+            },
+            (&UniformData::Vec2(ref a), &UniformData::Vec2(ref b)) => UniformData::Vec2(a.interpolate(b, t)),
+            (&UniformData::Vec3(ref a), &UniformData::Vec3(ref b)) => UniformData::Vec3(a.interpolate(b, t)),
+            (&UniformData::Vec4(ref a), &UniformData::Vec4(ref b)) => UniformData::Vec4(a.interpolate(b, t)),
+            _ => if t < 1f64 { self.clone() } else { to.clone() }
+        }
+    }
+}
+
+/// Value going from one value to another, the value can be polled or written
+/// to a property path with PropertyTween.
+pub struct Tween<T : Tweenable>
+{
+    pub from : T,
+    pub to : T,
+    /// in seconds
+    pub duration : f64,
+    /// time to wait before starting, in seconds
+    pub delay : f64,
+    pub ease : Ease,
+    time : f64,
+    on_finish : Option<Box<FnMut()>>,
+    finished : bool
+}
+
+impl<T : Tweenable> Tween<T>
+{
+    pub fn new(from : T, to : T, duration : f64, ease : Ease) -> Tween<T>
+    {
+        Tween {
+            from : from,
+            to : to,
+            duration : duration,
+            delay : 0f64,
+            ease : ease,
+            time : 0f64,
+            on_finish : None,
+            finished : false
+        }
+    }
+
+    pub fn with_delay(mut self, delay : f64) -> Tween<T>
+    {
+        self.delay = delay;
+        self
+    }
+
+    /// Called once when the tween finishes.
+    pub fn on_finish(mut self, f : Box<FnMut()>) -> Tween<T>
+    {
+        self.on_finish = Some(f);
+        self
+    }
+
+    pub fn value(&self) -> T
+    {
+        let t = if self.duration > 0f64 {
+            (self.time - self.delay) / self.duration
+        }
+        else if self.time >= self.delay {
+            1f64
+        }
+        else {
+            0f64
+        };
+
+        self.from.interpolate(&self.to, self.ease.apply(t))
+    }
+
+    /// Returns true when the tween finishes during this update.
+    pub fn update(&mut self, dt : f64) -> bool
+    {
+        if self.finished {
+            return false;
+        }
+
+        self.time = self.time + dt;
+
+        if self.time >= self.delay + self.duration {
+            self.time = self.delay + self.duration;
+            self.finished = true;
+            if let Some(ref mut f) = self.on_finish {
+                f();
+            }
+            return true;
+        }
+
+        false
+    }
+
+    pub fn is_finished(&self) -> bool
+    {
+        self.finished
+    }
+
+    /// Time left before the end, delay included.
+    pub fn remaining(&self) -> f64
+    {
+        (self.delay + self.duration - self.time).max(0f64)
+    }
+
+    pub fn reset(&mut self)
+    {
+        self.time = 0f64;
+        self.finished = false;
+    }
+}
+
+/// Something updated with time which writes values to a target,
+/// so tweens can be put in sequences and parallel groups.
+pub trait TweenPlayer
+{
+    /// Returns true when it finishes during this update.
+    fn update(&mut self, dt : f64, target : &mut PropertyWrite) -> bool;
+    fn is_finished(&self) -> bool;
+    /// Time left before the end, in seconds.
+    fn remaining(&self) -> f64;
+    fn reset(&mut self);
+}
+
+/// Tween writing its value to a property path of the target,
+/// for example "position" on a transform or "uniforms/color" on a material.
+pub struct PropertyTween<T : Tweenable>
+{
+    pub path : String,
+    pub tween : Tween<T>
+}
+
+impl<T : Tweenable> PropertyTween<T>
+{
+    pub fn new(path : &str, tween : Tween<T>) -> PropertyTween<T>
+    {
+        PropertyTween {
+            path : path.to_owned(),
+            tween : tween
+        }
+    }
+}
+
+impl<T : Tweenable> TweenPlayer for PropertyTween<T>
+{
+    fn update(&mut self, dt : f64, target : &mut PropertyWrite) -> bool
+    {
+        if self.tween.is_finished() {
+            return false;
+        }
+
+        let finished = self.tween.update(dt);
+        if self.tween.time >= self.tween.delay {
+            target.set_property_hier(&self.path, WriteValue::Any(box self.tween.value()));
+        }
+        finished
+    }
+
+    fn is_finished(&self) -> bool
+    {
+        self.tween.is_finished()
+    }
+
+    fn remaining(&self) -> f64
+    {
+        self.tween.remaining()
+    }
+
+    fn reset(&mut self)
+    {
+        self.tween.reset();
+    }
+}
+
+/// Waits, to put a pause in a sequence.
+pub struct Wait
+{
+    pub duration : f64,
+    time : f64
+}
+
+impl Wait
+{
+    pub fn new(duration : f64) -> Wait
+    {
+        Wait {
+            duration : duration,
+            time : 0f64
+        }
+    }
+}
+
+impl TweenPlayer for Wait
+{
+    fn update(&mut self, dt : f64, target : &mut PropertyWrite) -> bool
+    {
+        if self.is_finished() {
+            return false;
+        }
+
+        self.time = (self.time + dt).min(self.duration);
+        self.is_finished()
+    }
+
+    fn is_finished(&self) -> bool
+    {
+        self.time >= self.duration
+    }
+
+    fn remaining(&self) -> f64
+    {
+        self.duration - self.time
+    }
+
+    fn reset(&mut self)
+    {
+        self.time = 0f64;
+    }
+}
+
+/// Plays the tweens one after the other.
+pub struct Sequence
+{
+    pub items : Vec<Box<TweenPlayer>>,
+    current : usize,
+    on_finish : Option<Box<FnMut()>>,
+    finished : bool
+}
+
+impl Sequence
+{
+    pub fn new() -> Sequence
+    {
+        Sequence {
+            items : Vec::new(),
+            current : 0,
+            on_finish : None,
+            finished : false
+        }
+    }
+
+    pub fn then(mut self, item : Box<TweenPlayer>) -> Sequence
+    {
+        self.items.push(item);
+        self
+    }
+
+    pub fn wait(self, duration : f64) -> Sequence
+    {
+        self.then(box Wait::new(duration))
+    }
+
+    /// Called once when the last tween finishes.
+    pub fn on_finish(mut self, f : Box<FnMut()>) -> Sequence
+    {
+        self.on_finish = Some(f);
+        self
+    }
+}
+
+impl TweenPlayer for Sequence
+{
+    /// The time left when a tween finishes goes to the next one.
+    /// An empty sequence finishes on its first update.
+    fn update(&mut self, dt : f64, target : &mut PropertyWrite) -> bool
+    {
+        if self.finished {
+            return false;
+        }
+
+        let mut dt = dt;
+
+        while self.current < self.items.len() {
+            let remaining = self.items[self.current].remaining();
+            if !self.items[self.current].update(dt, target) && !self.items[self.current].is_finished() {
+                return false;
+            }
+
+            self.current = self.current + 1;
+            dt = (dt - remaining).max(0f64);
+        }
+
+        self.finished = true;
+        if let Some(ref mut f) = self.on_finish {
+            f();
+        }
+
+        true
+    }
+
+    fn is_finished(&self) -> bool
+    {
+        self.finished
+    }
+
+    fn remaining(&self) -> f64
+    {
+        self.items[self.current..].iter().fold(0f64, |r, i| r + i.remaining())
+    }
+
+    fn reset(&mut self)
+    {
+        for i in self.items.iter_mut() {
+            i.reset();
+        }
+        self.current = 0;
+        self.finished = false;
+    }
+}
+
+/// Plays the tweens at the same time, it finishes with the longest one.
+pub struct Parallel
+{
+    pub items : Vec<Box<TweenPlayer>>,
+    on_finish : Option<Box<FnMut()>>,
+    finished : bool
+}
+
+impl Parallel
+{
+    pub fn new() -> Parallel
+    {
+        Parallel {
+            items : Vec::new(),
+            on_finish : None,
+            finished : false
+        }
+    }
+
+    pub fn with(mut self, item : Box<TweenPlayer>) -> Parallel
+    {
+        self.items.push(item);
+        self
+    }
+
+    /// Called once when all the tweens are finished.
+    pub fn on_finish(mut self, f : Box<FnMut()>) -> Parallel
+    {
+        self.on_finish = Some(f);
+        self
+    }
+}
+
+impl TweenPlayer for Parallel
+{
+    fn update(&mut self, dt : f64, target : &mut PropertyWrite) -> bool
+    {
+        if self.finished {
+            return false;
+        }
+
+        for i in self.items.iter_mut() {
+            i.update(dt, target);
+        }
+
+        if self.items.iter().all(|i| i.is_finished()) {
+            self.finished = true;
+            if let Some(ref mut f) = self.on_finish {
+                f();
+            }
+            return true;
+        }
+
+        false
+    }
+
+    fn is_finished(&self) -> bool
+    {
+        self.finished
+    }
+
+    fn remaining(&self) -> f64
+    {
+        self.items.iter().fold(0f64, |r, i| r.max(i.remaining()))
+    }
+
+    fn reset(&mut self)
+    {
+        for i in self.items.iter_mut() {
+            i.reset();
+        }
+        self.finished = false;
+    }
+}
+
+pub fn move_to(from : vec::Vec3, to : vec::Vec3, duration : f64, ease : Ease)
+    -> PropertyTween<vec::Vec3>
+{
+    PropertyTween::new("position", Tween::new(from, to, duration, ease))
+}
+
+pub fn rotate_to(from : vec::Quat, to : vec::Quat, duration : f64, ease : Ease)
+    -> PropertyTween<vec::Quat>
+{
+    PropertyTween::new("orientation", Tween::new(from, to, duration, ease))
+}
+
+pub fn scale_to(from : vec::Vec3, to : vec::Vec3, duration : f64, ease : Ease)
+    -> PropertyTween<vec::Vec3>
+{
+    PropertyTween::new("scale", Tween::new(from, to, duration, ease))
+}
+
+/// Tweens the position, orientation and scale of a transform.
+pub fn transform_to(
+    from : &transform::Transform,
+    to : &transform::Transform,
+    duration : f64,
+    ease : Ease) -> Parallel
+{
+    Parallel::new()
+        .with(box move_to(from.position, to.position, duration, ease))
+        .with(box rotate_to(from.orientation.as_quat(), to.orientation.as_quat(), duration, ease))
+        .with(box scale_to(from.scale, to.scale, duration, ease))
+}
+
+/// Tweens a uniform of a material.
+pub fn uniform_to(name : &str, from : UniformData, to : UniformData, duration : f64, ease : Ease)
+    -> PropertyTween<UniformData>
+{
+    PropertyTween::new(&format!("uniforms/{}", name), Tween::new(from, to, duration, ease))
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use transform;
+    use vec;
+    use super::*;
+
+    fn counter() -> (Rc<Cell<usize>>, Box<FnMut()>)
+    {
+        let count = Rc::new(Cell::new(0));
+        let c = count.clone();
+        (count, box move || c.set(c.get() + 1))
+    }
+
+    #[test]
+    fn test_ease_endpoints()
+    {
+        let functions = [
+            EaseFunction::Quad, EaseFunction::Cubic, EaseFunction::Quart, EaseFunction::Quint,
+            EaseFunction::Sine, EaseFunction::Expo, EaseFunction::Circ, EaseFunction::Elastic,
+            EaseFunction::Back, EaseFunction::Bounce];
+
+        let mut eases = vec![Ease::Linear];
+        for f in functions.iter() {
+            eases.push(Ease::In(*f));
+            eases.push(Ease::Out(*f));
+            eases.push(Ease::InOut(*f));
+        }
+
+        for e in eases.iter() {
+            assert!(e.apply(0f64).abs() < 1e-9, "{:?} at 0 : {}", e, e.apply(0f64));
+            assert!((e.apply(1f64) - 1f64).abs() < 1e-9, "{:?} at 1 : {}", e, e.apply(1f64));
+            // clamped outside of 0 and 1
+            assert_eq!(e.apply(-1f64), e.apply(0f64));
+            assert_eq!(e.apply(2f64), e.apply(1f64));
+        }
+    }
+
+    #[test]
+    fn test_sequence_leftover_time()
+    {
+        let (count, f) = counter();
+        let mut s = Sequence::new()
+            .then(box move_to(vec::Vec3::zero(), vec::Vec3::new(1f64, 0f64, 0f64), 1f64, Ease::Linear))
+            .then(box move_to(vec::Vec3::new(1f64, 0f64, 0f64), vec::Vec3::new(3f64, 0f64, 0f64), 1f64, Ease::Linear))
+            .on_finish(f);
+
+        let mut t = transform::Transform::default();
+
+        // the half second left by the first move goes to the second one
+        assert!(!s.update(1.5f64, &mut t));
+        assert_eq!(t.position, vec::Vec3::new(2f64, 0f64, 0f64));
+        assert_eq!(s.remaining(), 0.5f64);
+
+        assert!(s.update(1f64, &mut t));
+        assert_eq!(t.position, vec::Vec3::new(3f64, 0f64, 0f64));
+        assert!(s.is_finished());
+        assert!(!s.update(1f64, &mut t));
+        assert_eq!(count.get(), 1);
+    }
+
+    #[test]
+    fn test_empty_sequence()
+    {
+        let (count, f) = counter();
+        let mut s = Sequence::new().on_finish(f);
+        let mut t = transform::Transform::default();
+
+        assert!(!s.is_finished());
+        assert!(s.update(0f64, &mut t));
+        assert!(s.is_finished());
+        assert!(!s.update(0f64, &mut t));
+        assert_eq!(count.get(), 1);
+    }
+
+    #[test]
+    fn test_parallel_longest()
+    {
+        let (count, f) = counter();
+        let mut p = Parallel::new()
+            .with(box Wait::new(1f64))
+            .with(box Wait::new(2f64))
+            .on_finish(f);
+        let mut t = transform::Transform::default();
+
+        assert_eq!(p.remaining(), 2f64);
+        assert!(!p.update(1.5f64, &mut t));
+        assert!(!p.is_finished());
+        assert!(p.update(1f64, &mut t));
+        assert!(p.is_finished());
+        assert_eq!(count.get(), 1);
+    }
+
+    #[test]
+    fn test_reset()
+    {
+        let (count, f) = counter();
+        let mut s = Sequence::new()
+            .then(box move_to(vec::Vec3::zero(), vec::Vec3::new(1f64, 0f64, 0f64), 1f64, Ease::Linear))
+            .on_finish(f);
+        let mut t = transform::Transform::default();
+
+        assert!(s.update(1f64, &mut t));
+        s.reset();
+        assert!(!s.is_finished());
+        assert_eq!(s.remaining(), 1f64);
+
+        assert!(!s.update(0.5f64, &mut t));
+        assert_eq!(t.position, vec::Vec3::new(0.5f64, 0f64, 0f64));
+        assert!(s.update(0.5f64, &mut t));
+        assert_eq!(count.get(), 2);
+
+        let mut tween = Tween::new(0f64, 1f64, 1f64, Ease::Linear).with_delay(1f64);
+        assert!(tween.update(2f64));
+        tween.reset();
+        assert!(!tween.is_finished());
+        assert_eq!(tween.value(), 0f64);
+    }
+}
@@ -6,60 +6,7 @@ use matrix;
 use vec;
 use texture;
 use fbo;
-
-#[link(name = "cypher")]
-extern {
-    pub fn cgl_shader_uniform_int_set(
-        uniform : *const shader::CglShaderUniform,
-        value : c_int) -> ();
-
-    pub fn cgl_shader_uniform_float_set(
-        uniform : *const shader::CglShaderUniform,
-        value : c_float) -> ();
-
-    pub fn cgl_shader_uniform_vec2_set(
-        uniform : *const shader::CglShaderUniform,
-        x : c_float,
-        y : c_float,
-        ) -> ();
-
-    pub fn cgl_shader_uniform_vec3_set(
-        uniform : *const shader::CglShaderUniform,
-        x : c_float,
-        y : c_float,
-        z : c_float,
-        ) -> ();
-
-    pub fn cgl_shader_uniform_vec4_set(
-        uniform : *const shader::CglShaderUniform,
-        x : c_float,
-        y : c_float,
-        z : c_float,
-        w : c_float
-        ) -> ();
-
-    pub fn cgl_shader_uniform_mat4_set(
-        uniform : *const shader::CglShaderUniform,
-        x : *const c_float) -> ();
-
-    pub fn cgl_shader_uniform_texture_set(
-        uniform : *const shader::CglShaderUniform,
-        tex : *const texture::CglTexture,
-        index : c_uint
-        ) -> ();
-
-    pub fn cgl_shader_uniform_fbo_depth_set(
-        uniform : *const shader::CglShaderUniform,
-        fbo : *const fbo::CglFbo,
-        index : c_uint
-        ) -> ();
-
-    pub fn cgl_shader_uniform_fbo_color_set(
-        uniform : *const shader::CglShaderUniform,
-        fbo : *const fbo::CglFbo,
-        index : c_uint
-        ) -> ();
-}
+use backend;
 
 pub trait UniformSend
 {
@@ -70,9 +17,8 @@ impl UniformSend for i32 {
 
     fn uniform_send(&self, uni : *const shader::CglShaderUniform) ->()
     {
-        unsafe {
-            cgl_shader_uniform_int_set(uni, *self);
-        }
+        let v = *self;
+        backend::with_backend(|b| b.uniform_int(uni, v));
     }
 }
 
@@ -80,9 +26,8 @@ impl UniformSend for f32 {
 
     fn uniform_send(&self, uni : *const shader::CglShaderUniform) ->()
     {
-        unsafe {
-            cgl_shader_uniform_float_set(uni, *self);
-        }
+        let v = *self;
+        backend::with_backend(|b| b.uniform_float(uni, v));
     }
 }
 
@@ -90,9 +35,7 @@ impl UniformSend for vec::Vec2 {
 
     fn uniform_send(&self, uni : *const shader::CglShaderUniform) ->()
     {
-        unsafe {
-            cgl_shader_uniform_vec2_set(uni, self.x as c_float, self.y as c_float);
-        }
+        backend::with_backend(|b| b.uniform_vec2(uni, self.x as f32, self.y as f32));
     }
 }
 
@@ -100,13 +43,9 @@ impl UniformSend for vec::Vec3 {
 
     fn uniform_send(&self, uni : *const shader::CglShaderUniform) ->()
     {
-        unsafe {
-            cgl_shader_uniform_vec3_set(
-                uni,
-                self.x as c_float,
-                self.y as c_float,
-                self.z as c_float);
-        }
+        backend::with_backend(|b| {
+            b.uniform_vec3(uni, self.x as f32, self.y as f32, self.z as f32)
+        });
     }
 }
 
@@ -115,9 +54,9 @@ impl UniformSend for vec::Vec4 {
 
     fn uniform_send(&self, uni : *const shader::CglShaderUniform) ->()
     {
-        unsafe {
-            cgl_shader_uniform_vec4_set(uni, self.x as f32, self.y as f32, self.z as f32, self.w as f32);
-        }
+        backend::with_backend(|b| {
+            b.uniform_vec4(uni, self.x as f32, self.y as f32, self.z as f32, self.w as f32)
+        });
     }
 }
 
@@ -126,9 +65,7 @@ impl UniformSend for matrix::Matrix4 {
     fn uniform_send(&self, uni : *const shader::CglShaderUniform) ->()
     {
         let data = self.to_f32();
-        unsafe {
-            cgl_shader_uniform_mat4_set(uni, data.as_ptr());
-        }
+        backend::with_backend(|b| b.uniform_mat4(uni, &data));
     }
 }
 
@@ -143,9 +80,7 @@ impl TextureSend for texture::Texture {
     {
         match self.cgl_texture.get() {
             None => {println!("no cgl texture...............................")},
-            Some(t) => unsafe {
-                cgl_shader_uniform_texture_set(uni, t, index);
-            }
+            Some(t) => backend::with_backend(|b| b.uniform_texture(uni, t, index))
         }
     }
 }
@@ -165,14 +100,8 @@ impl<'a> TextureSend for FboSampler<'a> {
             Some(f) => f
         };
 
-        match self.attachment {
-            fbo::Attachment::Depth => unsafe {
-                cgl_shader_uniform_fbo_depth_set(uni, f, index);
-            },
-            fbo::Attachment::Color => unsafe {
-                cgl_shader_uniform_fbo_color_set(uni, f, index);
-            }
-        }
+        let attachment = self.attachment;
+        backend::with_backend(|b| b.uniform_fbo(uni, f, attachment, index));
     }
 }
 
@@ -36,6 +36,19 @@ pub struct Quat
     pub w : f64
 }
 
+/// Order in which the euler rotations are applied, XYZ means X first, then Y, then Z
+/// (same meaning as the blender rotation modes).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RotationOrder
+{
+    XYZ,
+    XZY,
+    YXZ,
+    YZX,
+    ZXY,
+    ZYX
+}
+
 
 impl Vec4
 {
@@ -353,6 +366,23 @@ impl Quat
         Quat::new_angles_rad(&(*angles * r))
     }
 
+    pub fn new_euler_rad(angles : &Vec3, order : RotationOrder) -> Quat
+    {
+        let qx = Quat::new_axis_angle_rad(Vec3::new(1f64,0f64,0f64), angles.x);
+        let qy = Quat::new_axis_angle_rad(Vec3::new(0f64,1f64,0f64), angles.y);
+        let qz = Quat::new_axis_angle_rad(Vec3::new(0f64,0f64,1f64), angles.z);
+
+        // the first rotation applied is the one on the right
+        match order {
+            RotationOrder::XYZ => qz * qy * qx,
+            RotationOrder::XZY => qy * qz * qx,
+            RotationOrder::YXZ => qz * qx * qy,
+            RotationOrder::YZX => qx * qz * qy,
+            RotationOrder::ZXY => qy * qx * qz,
+            RotationOrder::ZYX => qx * qy * qz,
+        }
+    }
+
     pub fn rotate_vec3(&self, v : &Vec3) -> Vec3
     {
         let qvec = Vec3::new(self.x, self.y, self.z);
@@ -435,6 +465,32 @@ impl Quat
         q1.conj() * *q2
     }
 
+    /// Shortest rotation which turns the direction from into the direction to.
+    pub fn new_rotation_between(from : &Vec3, to : &Vec3) -> Quat
+    {
+        if from.length2() < EPSILON || to.length2() < EPSILON {
+            return Quat::identity();
+        }
+
+        let f = from.normalized();
+        let t = to.normalized();
+        let d = f.dot(&t);
+
+        if d >= 1f64 - 1e-9f64 {
+            return Quat::identity();
+        }
+
+        if d <= -1f64 + 1e-9f64 {
+            let mut axis = Vec3::x() ^ f;
+            if axis.length2() < 1e-9f64 {
+                axis = Vec3::y() ^ f;
+            }
+            return Quat::new_axis_angle_rad(axis, consts::PI);
+        }
+
+        Quat::new_axis_angle_rad(f ^ t, d.acos())
+    }
+
 }
 
 impl fmt::Debug for Vec2
@@ -616,6 +672,11 @@ fn test_quat_rotate() {
 }
 
 
+pub fn vec3_lerp(from : Vec3, to : Vec3, t : f64) -> Vec3
+{
+    from * (1f64 - t) + to * t
+}
+
 pub fn quat_slerp(from : Quat, to : Quat, t : f64) -> Quat
 {
   //double omega, cosomega, sinomega, scale_from, scale_to ;